@@ -0,0 +1,64 @@
+use std::{env, fs, path::PathBuf};
+
+use protocol::board::{Board, NOINIT_RAM_SIZE, RAM_ORIGIN};
+
+/// Partition offsets for the embassy-boot `BootLoader`, relative to the flash start.
+const BOOTLOADER_SYMBOLS: &str = "
+__bootloader_state_start = ORIGIN(BOOTLOADER_STATE) - ORIGIN(FLASH);
+__bootloader_state_end = ORIGIN(BOOTLOADER_STATE) + LENGTH(BOOTLOADER_STATE) - ORIGIN(FLASH);
+
+__bootloader_active_start = ORIGIN(ACTIVE) - ORIGIN(FLASH);
+__bootloader_active_end = ORIGIN(ACTIVE) + LENGTH(ACTIVE) - ORIGIN(FLASH);
+
+__bootloader_dfu_start = ORIGIN(DFU) - ORIGIN(FLASH);
+__bootloader_dfu_end = ORIGIN(DFU) + LENGTH(DFU) - ORIGIN(FLASH);
+";
+
+fn main() {
+    let out = PathBuf::from(env::var_os("OUT_DIR").unwrap());
+
+    let Some(board) = Board::ALL
+        .into_iter()
+        .find(|b| feature_enabled(b.feature()))
+    else {
+        panic!("No board selected, enable one of the board features of the bootloader crate.");
+    };
+    let Some(layout) = board.flash_layout() else {
+        panic!("The bootloader is not supported on {:?}", board);
+    };
+
+    let mut memory_x = String::from("MEMORY\n{\n");
+    for (name, partition) in [
+        ("FLASH", layout.bootloader),
+        ("BOOTLOADER_STATE", layout.state),
+        ("ACTIVE", layout.active),
+        ("DFU", layout.dfu),
+    ] {
+        memory_x += &format!(
+            "  {:<16} : ORIGIN = {:#010x}, LENGTH = {}\n",
+            name,
+            partition.origin(),
+            partition.size
+        );
+    }
+    // The top of the RAM holds the records of the firmware that survive a reset, such
+    // as the crash report, the bootloader must not touch it.
+    memory_x += &format!(
+        "  {:<16} : ORIGIN = {:#010x}, LENGTH = {}\n}}\n",
+        "RAM",
+        RAM_ORIGIN,
+        board.ram_size() - NOINIT_RAM_SIZE
+    );
+    fs::write(out.join("memory.x"), memory_x + BOOTLOADER_SYMBOLS).unwrap();
+
+    println!("cargo:rustc-link-search={}", out.display());
+    println!("cargo:rerun-if-changed=build.rs");
+
+    println!("cargo:rustc-link-arg-bins=--nmagic");
+    println!("cargo:rustc-link-arg-bins=-Tlink.x");
+}
+
+fn feature_enabled(feature: &str) -> bool {
+    let name = feature.to_uppercase().replace('-', "_");
+    env::var_os(format!("CARGO_FEATURE_{}", name)).is_some()
+}
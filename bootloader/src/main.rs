@@ -0,0 +1,45 @@
+//! Runs before the firmware on boards with a flash layout. Swaps in an update written
+//! to the DFU partition, or reverts it if the new firmware did not confirm itself.
+
+#![no_std]
+#![no_main]
+
+use core::cell::RefCell;
+
+use cortex_m_rt::{entry, exception};
+use embassy_boot_stm32::{BootLoader, BootLoaderConfig};
+use embassy_stm32::flash::{BANK1_REGION, Flash};
+use embassy_sync::blocking_mutex::Mutex;
+
+/// Size of the buffer used for the swap, a multiple of the 1 kB flash page.
+const PAGE_BUFFER_SIZE: usize = 2048;
+
+#[entry]
+fn main() -> ! {
+    let p = embassy_stm32::init(Default::default());
+
+    let layout = Flash::new_blocking(p.FLASH).into_blocking_regions();
+    let flash = Mutex::new(RefCell::new(layout.bank1_region));
+
+    let config = BootLoaderConfig::from_linkerfile_blocking(&flash, &flash, &flash);
+    let active_offset = config.active.offset();
+    let bl = BootLoader::prepare::<_, _, _, PAGE_BUFFER_SIZE>(config);
+
+    unsafe { bl.load(BANK1_REGION.base + active_offset) }
+}
+
+#[unsafe(no_mangle)]
+#[cfg_attr(target_os = "none", unsafe(link_section = ".HardFault.user"))]
+unsafe extern "C" fn HardFault() {
+    cortex_m::peripheral::SCB::sys_reset();
+}
+
+#[exception]
+unsafe fn DefaultHandler(_: i16) -> ! {
+    cortex_m::peripheral::SCB::sys_reset();
+}
+
+#[panic_handler]
+fn panic(_info: &core::panic::PanicInfo) -> ! {
+    cortex_m::asm::udf();
+}
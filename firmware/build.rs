@@ -1,5 +1,98 @@
+use std::{env, fs, path::PathBuf};
+
+use protocol::board::{Board, NOINIT_RAM_SIZE, Partition, RAM_ORIGIN};
+
+/// Partition offsets for the embassy-boot `FirmwareUpdater`, relative to the flash start.
+const BOOTLOADER_SYMBOLS: &str = "
+__bootloader_state_start = ORIGIN(BOOTLOADER_STATE) - ORIGIN(BOOTLOADER);
+__bootloader_state_end = ORIGIN(BOOTLOADER_STATE) + LENGTH(BOOTLOADER_STATE) - ORIGIN(BOOTLOADER);
+
+__bootloader_dfu_start = ORIGIN(DFU) - ORIGIN(BOOTLOADER);
+__bootloader_dfu_end = ORIGIN(DFU) + LENGTH(DFU) - ORIGIN(BOOTLOADER);
+";
+
+/// Records that survive a reset, placed with `#[link_section = ".noinit.<NAME>"]`. The
+/// runtime does not zero them and the bootloader does not use the region.
+const NOINIT_SECTION: &str = "
+SECTIONS
+{
+  .noinit (NOLOAD) : ALIGN(4)
+  {
+    KEEP(*(.noinit .noinit.*));
+  } > NOINIT
+}
+INSERT AFTER .uninit;
+";
+
 fn main() {
+    let out = PathBuf::from(env::var_os("OUT_DIR").unwrap());
+
+    // Without a board the lib reports the error.
+    let board = Board::ALL
+        .into_iter()
+        .find(|b| feature_enabled(b.feature()));
+    if let Some(board) = board {
+        fs::write(out.join("memory.x"), memory_x(board)).unwrap();
+    }
+    println!("cargo:rustc-link-search={}", out.display());
+    println!("cargo:rerun-if-changed=build.rs");
+
     println!("cargo:rustc-link-arg-bins=--nmagic");
     println!("cargo:rustc-link-arg-bins=-Tlink.x");
     println!("cargo:rustc-link-arg-bins=-Tdefmt.x");
 }
+
+fn feature_enabled(feature: &str) -> bool {
+    let name = feature.to_uppercase().replace('-', "_");
+    env::var_os(format!("CARGO_FEATURE_{}", name)).is_some()
+}
+
+/// The firmware gets the flash before the settings, or the active partition when behind
+/// the bootloader. On the F4 boards the settings are in the second sector and the code
+/// starts after them.
+fn memory_x(board: Board) -> String {
+    let mut regions = Vec::new();
+    let mut symbols = String::new();
+
+    if feature_enabled("bootloader") {
+        let Some(layout) = board.flash_layout() else {
+            panic!("The bootloader is not supported on {:?}", board);
+        };
+        regions.push(("BOOTLOADER", layout.bootloader));
+        regions.push(("BOOTLOADER_STATE", layout.state));
+        regions.push(("FLASH", layout.active));
+        regions.push(("DFU", layout.dfu));
+        symbols += BOOTLOADER_SYMBOLS;
+    } else if let Some(text_offset) = board.text_offset() {
+        regions.push(("FLASH", Partition::new(0, board.flash_size())));
+        symbols += &format!(
+            "\n_stext = {:#010x};\n",
+            Partition::new(text_offset, 0).origin()
+        );
+    } else {
+        regions.push(("FLASH", Partition::new(0, board.settings().offset)));
+    }
+
+    let mut memory_x = String::from("MEMORY\n{\n");
+    for (name, partition) in regions {
+        memory_x += &format!(
+            "  {:<16} : ORIGIN = {:#010x}, LENGTH = {}\n",
+            name,
+            partition.origin(),
+            partition.size
+        );
+    }
+    memory_x += &format!(
+        "  {:<16} : ORIGIN = {:#010x}, LENGTH = {}\n",
+        "RAM",
+        RAM_ORIGIN,
+        board.ram_size() - NOINIT_RAM_SIZE
+    );
+    memory_x += &format!(
+        "  {:<16} : ORIGIN = {:#010x}, LENGTH = {}\n}}\n",
+        "NOINIT",
+        board.noinit_ram_origin(),
+        NOINIT_RAM_SIZE
+    );
+    memory_x + NOINIT_SECTION + &symbols
+}
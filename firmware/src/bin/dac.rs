@@ -0,0 +1,528 @@
+#![no_std]
+#![no_main]
+
+use core::cell::RefCell;
+
+use defmt_rtt as _;
+use embassy_executor::Spawner;
+use embassy_stm32::{
+    Config,
+    gpio::{Level, Output, Speed},
+    interrupt,
+    interrupt::{InterruptExt, Priority},
+    mode::Blocking,
+    pac, peripherals,
+    spi::{self, Spi},
+    time::Hertz,
+    timer::low_level::Timer,
+};
+use embassy_sync::blocking_mutex::{Mutex, raw::CriticalSectionRawMutex};
+use postcard_rpc::{
+    define_dispatch,
+    header::VarHeader,
+    server::{
+        Dispatch, Server,
+        impls::embassy_usb_v0_4::dispatch_impl::{WireRxBuf, WireSpawnImpl},
+    },
+};
+use protocol::dac::*;
+
+use firmware::*;
+
+struct Context {
+    config: DacConfig,
+    update_rate_hz: f32,
+    timer: Timer<'static, peripherals::TIM3>,
+}
+
+type AppServer = Server<AppTx, AppRx, WireRxBuf, Metered<App>>;
+
+/// Within the limits of both supported DACs.
+const SPI_FREQ: Hertz = Hertz(9_000_000);
+/// MCP4922 control bits: unbuffered reference, 1x gain, output enabled.
+const MCP4922_ACTIVE: u16 = 0b0011 << 12;
+/// AD5686 command to write and update a DAC register, ORed with the channel bit.
+const AD5686_WRITE_UPDATE: u8 = 0b0011 << 4;
+
+static PLAYER: Mutex<CriticalSectionRawMutex, RefCell<Player>> =
+    Mutex::new(RefCell::new(Player::new()));
+
+define_dispatch! {
+    app: App;
+    spawn_fn: spawn_fn;
+    tx_impl: AppTx;
+    spawn_impl: WireSpawnImpl;
+    context: Context;
+
+    endpoints: {
+        list: ENDPOINT_LIST;
+
+        | EndpointTy                | kind      | handler                       |
+        | ----------                | ----      | -------                       |
+        | GetUniqueIdEndpoint       | blocking  | unique_id_handler             |
+        | GetCrashReport            | blocking  | crash_report_handler          |
+        | ClearCrashReport          | blocking  | clear_crash_report_handler    |
+        | ResetEndpoint             | blocking  | reset_handler                 |
+        | EnterBootloaderEndpoint   | blocking  | enter_bootloader_handler      |
+        | GetUpdateInfo             | blocking  | update_info_handler           |
+        | StartUpdate               | blocking  | start_update_handler          |
+        | WriteUpdateChunk          | blocking  | write_update_chunk_handler    |
+        | FinishUpdate              | blocking  | finish_update_handler         |
+        | ConfirmUpdate             | blocking  | confirm_update_handler        |
+        | GetMetrics                | blocking  | metrics_handler               |
+        | IdentifyEndpoint          | blocking  | identify_handler              |
+        | GetDeviceInfo             | blocking  | device_info_handler           |
+        | GetAlias                  | blocking  | get_alias_handler             |
+        | SetAlias                  | blocking  | set_alias_handler             |
+        | GetDacLimits              | blocking  | get_limits_handler            |
+        | GetDacConfig              | blocking  | get_config_handler            |
+        | SetDacConfig              | blocking  | set_config_handler            |
+        | SetWaveform               | blocking  | set_waveform_handler          |
+        | UploadTable               | blocking  | upload_table_handler          |
+        | StartOutput               | blocking  | start_handler                 |
+        | StopOutput                | blocking  | stop_handler                  |
+        | GetDacStatus              | blocking  | get_status_handler            |
+    };
+    topics_in: {
+        list: TOPICS_IN_LIST;
+
+        | TopicTy                   | kind      | handler                       |
+        | ----------                | ----      | -------                       |
+    };
+    topics_out: {
+        list: TOPICS_OUT_LIST;
+    };
+}
+
+#[embassy_executor::main]
+async fn main(spawner: Spawner) {
+    reset::check_bootloader_request();
+    metrics::init();
+
+    let mut config = Config::default();
+    enable_usb_clock(&mut config);
+    let p = embassy_stm32::init(config);
+    storage::init(p.FLASH);
+
+    let dac_config = DacConfig::new();
+
+    /********************************** SPI **********************************/
+    // SPI1 with SCK on PA5, MOSI on PA7 and chip select on PA4.
+    let spi = Spi::new_blocking_txonly(p.SPI1, p.PA5, p.PA7, spi_config(dac_config.model));
+    let cs = Output::new(p.PA4, Level::High, Speed::VeryHigh);
+    PLAYER.lock(|player| player.borrow_mut().bus = Some(DacBus { spi, cs }));
+
+    /******************************** Timer **********************************/
+    // The TIM3 update interrupt writes the next point of every channel, so the
+    // output rate does not depend on USB traffic.
+    let timer = Timer::new(p.TIM3);
+    let regs = timer.regs_gp16();
+    regs.cr1().modify(|w| w.set_arpe(true));
+    regs.dier().modify(|w| w.set_uie(true));
+
+    interrupt::TIM3.set_priority(Priority::P0);
+    unsafe { interrupt::TIM3.enable() };
+
+    // Prepare the context for the application.
+    let context = Context {
+        config: dac_config,
+        update_rate_hz: 0.0,
+        timer,
+    };
+
+    /********************************** USB **********************************/
+    // Create the driver for the selected board.
+    let driver = usb_driver!(p);
+
+    // Create embassy-usb Config
+    let usb_config = get_usb_config(USB_DEVICE_NAME);
+
+    let pbufs = PBUFS.take();
+    let (device, tx_impl, rx_impl) = init_usb(driver, usb_config, pbufs.tx_buf.as_mut_slice());
+    let dispatcher = Metered::new(App::new(context, spawner.into()), ENDPOINT_LIST.endpoints);
+    let vkk = dispatcher.min_key_len();
+    let server = AppServer::new(
+        tx_impl,
+        rx_impl,
+        pbufs.rx_buf.as_mut_slice(),
+        dispatcher,
+        vkk,
+    );
+
+    spawner.must_spawn(usb_task(device));
+    spawner.must_spawn(logging::logging_task(server.sender()));
+    spawner.must_spawn(reset::reset_task());
+    // On the Nucleo the LED is on PA5, which this firmware uses.
+    #[cfg(not(feature = "nucleo-f103rb"))]
+    spawner.must_spawn(status::status_led_task(status::led_output(status_led!(p))));
+    spawner.must_spawn(server_task(server));
+    spawner.must_spawn(idle_task());
+}
+
+#[embassy_executor::task]
+async fn server_task(mut server: AppServer) {
+    loop {
+        // If the host disconnects, we'll return an error here.
+        // If this happens, just wait until the host reconnects
+        let _ = server.run().await;
+    }
+}
+
+#[interrupt]
+fn TIM3() {
+    pac::TIM3.sr().modify(|w| w.set_uif(false));
+    PLAYER.lock(|p| p.borrow_mut().update());
+}
+
+/****************************** PLAYBACK *******************************/
+struct DacBus {
+    spi: Spi<'static, Blocking>,
+    cs: Output<'static>,
+}
+
+/// Waveform of a channel in DAC codes.
+#[derive(Clone, Copy)]
+enum Shape {
+    Static(u16),
+    Ramp { start: u16, end: u16, points: u32 },
+    Triangle { start: u16, end: u16, points: u32 },
+    Table { length: u16 },
+}
+
+impl Shape {
+    /// Number of updates before the waveform repeats.
+    fn period(&self) -> u32 {
+        match *self {
+            Shape::Static(_) => 1,
+            Shape::Ramp { points, .. } => points,
+            Shape::Triangle { points, .. } => 2 * (points - 1),
+            Shape::Table { length } => length as u32,
+        }
+    }
+}
+
+struct Player {
+    bus: Option<DacBus>,
+    model: DacModel,
+    shapes: [Shape; CHANNEL_COUNT],
+    positions: [u32; CHANNEL_COUNT],
+    /// Last code written to each channel, unchanged codes are not sent again.
+    written: [Option<u16>; CHANNEL_COUNT],
+    tables: [[u16; MAX_POINTS]; CHANNEL_COUNT],
+    loaded: [u16; CHANNEL_COUNT],
+    running: bool,
+    updates: u32,
+}
+
+impl Player {
+    const fn new() -> Self {
+        Self {
+            bus: None,
+            model: DacModel::Mcp4922,
+            shapes: [Shape::Static(0); CHANNEL_COUNT],
+            positions: [0; CHANNEL_COUNT],
+            written: [None; CHANNEL_COUNT],
+            tables: [[0; MAX_POINTS]; CHANNEL_COUNT],
+            loaded: [0; CHANNEL_COUNT],
+            running: false,
+            updates: 0,
+        }
+    }
+
+    /// Switch to a new DAC config. Uploaded tables are converted with the old calibration,
+    /// so they are cleared and every channel goes to the lowest code.
+    fn configure(&mut self, model: DacModel) {
+        if model != self.model {
+            if let Some(bus) = &mut self.bus {
+                if bus.spi.set_config(&spi_config(model)).is_err() {
+                    defmt::error!("Failed to reconfigure SPI");
+                }
+            }
+            self.model = model;
+        }
+        self.shapes = [Shape::Static(0); CHANNEL_COUNT];
+        self.positions = [0; CHANNEL_COUNT];
+        self.written = [None; CHANNEL_COUNT];
+        self.loaded = [0; CHANNEL_COUNT];
+        for channel in 0..model.channels() {
+            self.write(channel, 0);
+        }
+    }
+
+    fn set_shape(&mut self, channel: u8, shape: Shape) -> DacResult {
+        let ch = channel as usize;
+        if let Shape::Table { length } = shape {
+            if length == 0 || length > self.loaded[ch] {
+                return Err(DacError::TableTooShort);
+            }
+        }
+        self.shapes[ch] = shape;
+        self.positions[ch] = 0;
+        // While stopped the first point is output right away, so the waveform
+        // starts from a known value.
+        if !self.running {
+            self.write(channel, self.code(channel));
+        }
+        Ok(())
+    }
+
+    /// Write a chunk of codes, already converted and checked, to the table of `channel`.
+    fn upload(&mut self, channel: u8, offset: u16, codes: &[u16]) -> DacResult {
+        let ch = channel as usize;
+        if channel >= self.model.channels() {
+            return Err(DacError::InvalidChannel);
+        }
+        if self.running && matches!(self.shapes[ch], Shape::Table { .. }) {
+            return Err(DacError::Busy);
+        }
+        let start = offset as usize;
+        let end = start + codes.len();
+        // Chunks are written in order, a write at offset 0 starts a new table.
+        if start > self.loaded[ch] as usize || end > MAX_POINTS {
+            return Err(DacError::TableOverflow);
+        }
+        self.tables[ch][start..end].copy_from_slice(codes);
+        self.loaded[ch] = end as u16;
+        Ok(())
+    }
+
+    fn start(&mut self) {
+        self.positions = [0; CHANNEL_COUNT];
+        self.updates = 0;
+        self.running = true;
+    }
+
+    fn update(&mut self) {
+        if !self.running {
+            return;
+        }
+        for channel in 0..self.model.channels() {
+            let ch = channel as usize;
+            self.write(channel, self.code(channel));
+            self.positions[ch] = (self.positions[ch] + 1) % self.shapes[ch].period();
+        }
+        self.updates = self.updates.wrapping_add(1);
+    }
+
+    fn code(&self, channel: u8) -> u16 {
+        let ch = channel as usize;
+        let position = self.positions[ch];
+        match self.shapes[ch] {
+            Shape::Static(code) => code,
+            Shape::Ramp { start, end, points } => interpolate(start, end, position, points - 1),
+            Shape::Triangle { start, end, points } => {
+                let steps = points - 1;
+                let step = if position <= steps {
+                    position
+                } else {
+                    2 * steps - position
+                };
+                interpolate(start, end, step, steps)
+            }
+            Shape::Table { .. } => self.tables[ch][position as usize],
+        }
+    }
+
+    fn write(&mut self, channel: u8, code: u16) {
+        let ch = channel as usize;
+        if self.written[ch] == Some(code) {
+            return;
+        }
+        let Some(bus) = &mut self.bus else {
+            return;
+        };
+
+        bus.cs.set_low();
+        let result = match self.model {
+            DacModel::Mcp4922 => {
+                let word = ((channel as u16) << 15) | MCP4922_ACTIVE | (code & 0x0fff);
+                bus.spi.blocking_write(&word.to_be_bytes())
+            }
+            DacModel::Ad5686 => bus.spi.blocking_write(&[
+                AD5686_WRITE_UPDATE | (1 << channel),
+                (code >> 8) as u8,
+                code as u8,
+            ]),
+        };
+        bus.cs.set_high();
+
+        if result.is_ok() {
+            self.written[ch] = Some(code);
+        }
+    }
+}
+
+fn interpolate(start: u16, end: u16, step: u32, steps: u32) -> u16 {
+    let span = end as i64 - start as i64;
+    (start as i64 + span * step as i64 / steps as i64) as u16
+}
+
+fn spi_config(model: DacModel) -> spi::Config {
+    let mut config = spi::Config::default();
+    config.frequency = SPI_FREQ;
+    // The AD5686 samples on the falling edge of the clock.
+    config.mode = match model {
+        DacModel::Mcp4922 => spi::MODE_0,
+        DacModel::Ad5686 => spi::MODE_1,
+    };
+    config
+}
+
+/// Prescaler and auto-reload values giving the closest rate to `update_rate_hz`.
+fn timer_dividers(timer_hz: u32, update_rate_hz: u32) -> (u16, u16) {
+    let ticks = ((timer_hz + update_rate_hz / 2) / update_rate_hz).max(1);
+    let prescaler = (ticks - 1) / (1 << 16);
+    let reload = (ticks / (prescaler + 1)).max(1) - 1;
+    (prescaler as u16, reload as u16)
+}
+
+fn stop_timer() {
+    let regs = pac::TIM3;
+    regs.cr1().modify(|w| w.set_cen(false));
+    regs.sr().modify(|w| w.set_uif(false));
+}
+
+/***************************** HANDLERS ******************************/
+fn unique_id_handler(_context: &mut Context, _header: VarHeader, _rqst: ()) -> [u8; 24] {
+    defmt::info!("unique_id");
+    *embassy_stm32::uid::uid_hex_bytes()
+}
+
+fn get_limits_handler(_context: &mut Context, _header: VarHeader, _rqst: ()) -> DacLimits {
+    defmt::info!("get_limits");
+    DacLimits {
+        max_points: MAX_POINTS as u16,
+        chunk_size: CHUNK_SIZE as u16,
+        min_update_rate_hz: MIN_UPDATE_RATE_HZ,
+        max_update_rate_hz: MAX_UPDATE_RATE_HZ,
+    }
+}
+
+fn get_config_handler(context: &mut Context, _header: VarHeader, _rqst: ()) -> DacConfig {
+    defmt::info!("get_config");
+    context.config
+}
+
+fn set_config_handler(context: &mut Context, _header: VarHeader, rqst: DacConfig) -> DacResult {
+    defmt::info!("set_config");
+    rqst.validate()?;
+    PLAYER.lock(|p| {
+        let mut player = p.borrow_mut();
+        if player.running {
+            return Err(DacError::Busy);
+        }
+        player.configure(rqst.model);
+        Ok(())
+    })?;
+    context.config = rqst;
+    Ok(())
+}
+
+fn set_waveform_handler(
+    context: &mut Context,
+    _header: VarHeader,
+    rqst: (u8, Waveform),
+) -> DacResult {
+    let (channel, waveform) = rqst;
+    defmt::info!("set_waveform: channel {}", channel);
+    let config = &context.config;
+    let shape = match waveform {
+        Waveform::Static { volts } => Shape::Static(config.volts_to_code(channel, volts)?),
+        Waveform::Ramp {
+            start_v,
+            end_v,
+            points,
+        } => {
+            if points < 2 {
+                return Err(DacError::InvalidPoints);
+            }
+            Shape::Ramp {
+                start: config.volts_to_code(channel, start_v)?,
+                end: config.volts_to_code(channel, end_v)?,
+                points,
+            }
+        }
+        Waveform::Triangle {
+            start_v,
+            end_v,
+            points,
+        } => {
+            if points < 2 {
+                return Err(DacError::InvalidPoints);
+            }
+            Shape::Triangle {
+                start: config.volts_to_code(channel, start_v)?,
+                end: config.volts_to_code(channel, end_v)?,
+                points,
+            }
+        }
+        Waveform::Table { length } => {
+            if channel >= config.model.channels() {
+                return Err(DacError::InvalidChannel);
+            }
+            Shape::Table { length }
+        }
+    };
+    PLAYER.lock(|p| p.borrow_mut().set_shape(channel, shape))
+}
+
+fn upload_table_handler(context: &mut Context, _header: VarHeader, rqst: TableChunk) -> DacResult {
+    defmt::info!(
+        "upload_table: channel {}, {} points at {}",
+        rqst.channel,
+        rqst.volts.len(),
+        rqst.offset
+    );
+    // The whole chunk is converted and checked before the lock, which masks the update
+    // interrupt, so a failed upload leaves the table as it was and no update is missed.
+    let mut codes = [0; CHUNK_SIZE];
+    for (code, &volts) in codes.iter_mut().zip(&rqst.volts) {
+        *code = context.config.volts_to_code(rqst.channel, volts)?;
+    }
+    let codes = &codes[..rqst.volts.len()];
+    PLAYER.lock(|p| p.borrow_mut().upload(rqst.channel, rqst.offset, codes))
+}
+
+fn start_handler(context: &mut Context, _header: VarHeader, rqst: u32) -> StartResult {
+    defmt::info!("start: {} Hz", rqst);
+    if !(MIN_UPDATE_RATE_HZ..=MAX_UPDATE_RATE_HZ).contains(&rqst) {
+        return Err(DacError::InvalidUpdateRate);
+    }
+    let timer_hz = context.timer.get_clock_frequency().0;
+    let (prescaler, reload) = timer_dividers(timer_hz, rqst);
+
+    stop_timer();
+    let regs = context.timer.regs_gp16();
+    regs.psc().write_value(prescaler);
+    regs.arr().write(|w| w.set_arr(reload));
+    regs.cnt().write(|w| w.set_cnt(0));
+    // Load the dividers without triggering the update interrupt.
+    regs.dier().modify(|w| w.set_uie(false));
+    regs.egr().write(|w| w.set_ug(true));
+    regs.sr().modify(|w| w.set_uif(false));
+    regs.dier().modify(|w| w.set_uie(true));
+
+    PLAYER.lock(|p| p.borrow_mut().start());
+    regs.cr1().modify(|w| w.set_cen(true));
+
+    context.update_rate_hz =
+        timer_hz as f32 / ((prescaler as u32 + 1) * (reload as u32 + 1)) as f32;
+    Ok(context.update_rate_hz)
+}
+
+fn stop_handler(_context: &mut Context, _header: VarHeader, _rqst: ()) {
+    defmt::info!("stop");
+    stop_timer();
+    PLAYER.lock(|p| p.borrow_mut().running = false);
+}
+
+fn get_status_handler(context: &mut Context, _header: VarHeader, _rqst: ()) -> DacStatus {
+    PLAYER.lock(|p| {
+        let player = p.borrow();
+        DacStatus {
+            running: player.running,
+            update_rate_hz: context.update_rate_hz,
+            updates: player.updates,
+        }
+    })
+}
@@ -2,8 +2,9 @@
 #![no_main]
 
 /************************** 3rd party imports  **************************/
+use defmt_rtt as _;
 use embassy_executor::Spawner;
-use embassy_stm32::{Config, bind_interrupts, peripherals, usb};
+use embassy_stm32::Config;
 use postcard_rpc::{
     define_dispatch,
     header::VarHeader,
@@ -12,7 +13,6 @@ use postcard_rpc::{
         impls::embassy_usb_v0_4::dispatch_impl::{WireRxBuf, WireSpawnImpl},
     },
 };
-use {defmt_rtt as _, panic_probe as _};
 
 /**************************** Local imports  ****************************/
 use firmware::*;
@@ -23,12 +23,13 @@ use protocol::minimal::*; // Change minimal to your protocol module
 struct Context {}
 
 // Global type based on the protocol. No need to change this.
-type AppServer = Server<AppTx, AppRx, WireRxBuf, App>;
+type AppServer = Server<AppTx, AppRx, WireRxBuf, Metered<App>>;
 
-// Bind additional interrupts for used peripherals to use async API.
-bind_interrupts!(struct Irqs {
-    USB_LP_CAN1_RX0 => usb::InterruptHandler<peripherals::USB>;
-});
+// Bind interrupts for used peripherals to use async API. The USB interrupt is already
+// bound by the firmware lib for the selected board. For example:
+// bind_interrupts!(struct Irqs {
+//     TIM4 => timer::CaptureCompareInterruptHandler<peripherals::TIM4>;
+// });
 
 // Define the dispatch for the application by picking the endpoints/topics you need
 // and assigning handlers to them. You can think of this as a router for the incoming requests.
@@ -46,6 +47,20 @@ define_dispatch! {
         | EndpointTy                | kind      | handler                       |
         | ----------                | ----      | -------                       |
         | GetUniqueIdEndpoint       | blocking  | unique_id_handler             |
+        | GetCrashReport            | blocking  | crash_report_handler          |
+        | ClearCrashReport          | blocking  | clear_crash_report_handler    |
+        | ResetEndpoint             | blocking  | reset_handler                 |
+        | EnterBootloaderEndpoint   | blocking  | enter_bootloader_handler      |
+        | GetUpdateInfo             | blocking  | update_info_handler           |
+        | StartUpdate               | blocking  | start_update_handler          |
+        | WriteUpdateChunk          | blocking  | write_update_chunk_handler    |
+        | FinishUpdate              | blocking  | finish_update_handler         |
+        | ConfirmUpdate             | blocking  | confirm_update_handler        |
+        | GetMetrics                | blocking  | metrics_handler               |
+        | IdentifyEndpoint          | blocking  | identify_handler              |
+        | GetDeviceInfo             | blocking  | device_info_handler           |
+        | GetAlias                  | blocking  | get_alias_handler             |
+        | SetAlias                  | blocking  | set_alias_handler             |
     };
     topics_in: {
         list: TOPICS_IN_LIST;
@@ -61,9 +76,13 @@ define_dispatch! {
 /***************************** MAIN ******************************/
 #[embassy_executor::main]
 async fn main(spawner: Spawner) {
+    reset::check_bootloader_request();
+    metrics::init();
+
     let mut config = Config::default();
     enable_usb_clock(&mut config);
-    let mut p = embassy_stm32::init(config);
+    let p = embassy_stm32::init(config);
+    storage::init(p.FLASH);
 
     /******************************** Peri ***********************************/
     // Initialize the peripherals needed for the application and store them in the context if needed.
@@ -73,17 +92,15 @@ async fn main(spawner: Spawner) {
     let context = Context {};
 
     /********************************** USB **********************************/
-    reset_condition(&mut p.PA12).await;
-
-    // Create the driver, from the HAL.
-    let driver = usb::Driver::new(p.USB, Irqs, p.PA12, p.PA11);
+    // Create the driver for the selected board.
+    let driver = usb_driver!(p);
 
     // Create embassy-usb Config
     let usb_config = get_usb_config(USB_DEVICE_NAME);
 
     let pbufs = PBUFS.take();
-    let (device, tx_impl, rx_impl) = STORAGE.init(driver, usb_config, pbufs.tx_buf.as_mut_slice());
-    let dispatcher = App::new(context, spawner.into());
+    let (device, tx_impl, rx_impl) = init_usb(driver, usb_config, pbufs.tx_buf.as_mut_slice());
+    let dispatcher = Metered::new(App::new(context, spawner.into()), ENDPOINT_LIST.endpoints);
     let vkk = dispatcher.min_key_len();
     let server = AppServer::new(
         tx_impl,
@@ -94,6 +111,9 @@ async fn main(spawner: Spawner) {
     );
 
     spawner.must_spawn(usb_task(device));
+    spawner.must_spawn(logging::logging_task(server.sender()));
+    spawner.must_spawn(reset::reset_task());
+    spawner.must_spawn(status::status_led_task(status::led_output(status_led!(p))));
     spawner.must_spawn(server_task(server));
     spawner.must_spawn(idle_task());
 }
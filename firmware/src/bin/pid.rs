@@ -0,0 +1,270 @@
+#![no_std]
+#![no_main]
+
+use defmt_rtt as _;
+use embassy_executor::Spawner;
+use embassy_stm32::{
+    Config,
+    adc::{self, Adc, SampleTime},
+    bind_interrupts,
+    gpio::OutputType,
+    peripherals,
+    time::Hertz,
+    timer::{
+        low_level::CountingMode,
+        simple_pwm::{PwmPin, SimplePwm},
+    },
+};
+use embassy_sync::{blocking_mutex::raw::ThreadModeRawMutex, channel::Channel, signal::Signal};
+use embassy_time::{Duration, Instant, Ticker};
+use postcard_rpc::{
+    define_dispatch,
+    header::VarHeader,
+    server::{
+        Dispatch, Sender, Server,
+        impls::embassy_usb_v0_4::dispatch_impl::{WireRxBuf, WireSpawnImpl},
+    },
+};
+use protocol::pid::*;
+
+use firmware::*;
+
+struct Context {
+    config: PidConfig,
+}
+
+type AppServer = Server<AppTx, AppRx, WireRxBuf, Metered<App>>;
+
+const PWM_FREQ: Hertz = Hertz(20_000);
+const ADC_MAX: u16 = 4095;
+
+static CONFIG: Signal<ThreadModeRawMutex, PidConfig> = Signal::new();
+static RESET: Signal<ThreadModeRawMutex, ()> = Signal::new();
+static TELEMETRY: Channel<ThreadModeRawMutex, PidTelemetry, 8> = Channel::new();
+
+bind_interrupts!(struct Irqs {
+    ADC1_2 => adc::InterruptHandler<peripherals::ADC1>;
+});
+
+define_dispatch! {
+    app: App;
+    spawn_fn: spawn_fn;
+    tx_impl: AppTx;
+    spawn_impl: WireSpawnImpl;
+    context: Context;
+
+    endpoints: {
+        list: ENDPOINT_LIST;
+
+        | EndpointTy                | kind      | handler                       |
+        | ----------                | ----      | -------                       |
+        | GetUniqueIdEndpoint       | blocking  | unique_id_handler             |
+        | GetCrashReport            | blocking  | crash_report_handler          |
+        | ClearCrashReport          | blocking  | clear_crash_report_handler    |
+        | ResetEndpoint             | blocking  | reset_handler                 |
+        | EnterBootloaderEndpoint   | blocking  | enter_bootloader_handler      |
+        | GetUpdateInfo             | blocking  | update_info_handler           |
+        | StartUpdate               | blocking  | start_update_handler          |
+        | WriteUpdateChunk          | blocking  | write_update_chunk_handler    |
+        | FinishUpdate              | blocking  | finish_update_handler         |
+        | ConfirmUpdate             | blocking  | confirm_update_handler        |
+        | GetMetrics                | blocking  | metrics_handler               |
+        | IdentifyEndpoint          | blocking  | identify_handler              |
+        | GetDeviceInfo             | blocking  | device_info_handler           |
+        | GetAlias                  | blocking  | get_alias_handler             |
+        | SetAlias                  | blocking  | set_alias_handler             |
+        | GetPidConfig              | blocking  | get_config_handler            |
+        | SetPidConfig              | blocking  | set_config_handler            |
+        | SetSetpoint               | blocking  | set_setpoint_handler          |
+        | ResetController           | blocking  | reset_controller_handler      |
+    };
+    topics_in: {
+        list: TOPICS_IN_LIST;
+
+        | TopicTy                   | kind      | handler                       |
+        | ----------                | ----      | -------                       |
+    };
+    topics_out: {
+        list: TOPICS_OUT_LIST;
+    };
+}
+
+#[embassy_executor::main]
+async fn main(spawner: Spawner) {
+    reset::check_bootloader_request();
+    metrics::init();
+
+    let mut config = Config::default();
+    enable_usb_clock(&mut config);
+    let p = embassy_stm32::init(config);
+    storage::init(p.FLASH);
+
+    /********************************** ADC **********************************/
+    // The F1 `Adc::new` does not take the interrupt binding, but `read` waits on it.
+    let _ = Irqs;
+    let mut adc = Adc::new(p.ADC1);
+    adc.set_sample_time(SampleTime::CYCLES239_5);
+
+    /********************************** PWM **********************************/
+    let pwm = SimplePwm::new(
+        p.TIM3,
+        Some(PwmPin::new_ch1(p.PA6, OutputType::PushPull)),
+        None,
+        None,
+        None,
+        PWM_FREQ,
+        CountingMode::EdgeAlignedUp,
+    );
+    defmt::info!("Max Duty Cycle: {}", pwm.max_duty_cycle());
+
+    // Prepare the context for the application.
+    let context = Context {
+        config: PidConfig::new(),
+    };
+
+    /********************************** USB **********************************/
+    // Create the driver for the selected board.
+    let driver = usb_driver!(p);
+
+    // Create embassy-usb Config
+    let usb_config = get_usb_config(USB_DEVICE_NAME);
+
+    let pbufs = PBUFS.take();
+    let (device, tx_impl, rx_impl) = init_usb(driver, usb_config, pbufs.tx_buf.as_mut_slice());
+    let dispatcher = Metered::new(App::new(context, spawner.into()), ENDPOINT_LIST.endpoints);
+    let vkk = dispatcher.min_key_len();
+    let server = AppServer::new(
+        tx_impl,
+        rx_impl,
+        pbufs.rx_buf.as_mut_slice(),
+        dispatcher,
+        vkk,
+    );
+    let sender = server.sender();
+
+    spawner.must_spawn(usb_task(device));
+    spawner.must_spawn(logging::logging_task(server.sender()));
+    spawner.must_spawn(reset::reset_task());
+    spawner.must_spawn(status::status_led_task(status::led_output(status_led!(p))));
+    spawner.must_spawn(server_task(server));
+    spawner.must_spawn(control_task(adc, p.PA0, pwm));
+    spawner.must_spawn(telemetry_task(sender));
+    spawner.must_spawn(idle_task());
+}
+
+#[embassy_executor::task]
+async fn server_task(mut server: AppServer) {
+    loop {
+        // If the host disconnects, we'll return an error here.
+        // If this happens, just wait until the host reconnects
+        let _ = server.run().await;
+    }
+}
+
+/// Runs the controller at `rate_hz`, reading PA0 and driving the PWM on PA6.
+/// The output is held at 0 while the controller is disabled.
+#[embassy_executor::task]
+async fn control_task(
+    mut adc: Adc<'static, peripherals::ADC1>,
+    mut input: peripherals::PA0,
+    mut pwm: SimplePwm<'static, peripherals::TIM3>,
+) {
+    let mut config = PidConfig::new();
+    let mut controller = PidController::new();
+    let mut ticker = Ticker::every(Duration::from_hz(config.rate_hz as u64));
+    let mut steps: u32 = 0;
+
+    let max_duty_cycle = pwm.max_duty_cycle();
+    let mut output = pwm.ch1();
+    output.set_duty_cycle(0);
+    output.enable();
+
+    loop {
+        ticker.next().await;
+
+        if let Some(new_config) = CONFIG.try_take() {
+            if new_config.rate_hz != config.rate_hz {
+                ticker = Ticker::every(Duration::from_hz(new_config.rate_hz as u64));
+            }
+            if new_config.enabled != config.enabled {
+                controller.reset();
+            }
+            config = new_config;
+        }
+        if RESET.try_take().is_some() {
+            controller.reset();
+        }
+
+        let raw = adc.read(&mut input).await;
+        let measurement = raw as f32 / ADC_MAX as f32 * config.input_scale + config.input_offset;
+
+        let step = if config.enabled {
+            controller.update(&config, measurement, 1.0 / config.rate_hz as f32)
+        } else {
+            PidStep {
+                measurement,
+                error: config.setpoint - measurement,
+                output: 0.0,
+            }
+        };
+        output.set_duty_cycle((step.output * max_duty_cycle as f32) as u16);
+
+        steps = steps.wrapping_add(1);
+        if config.telemetry_divider != 0 && steps % config.telemetry_divider as u32 == 0 {
+            // Telemetry is dropped rather than delaying the control loop.
+            let _ = TELEMETRY.try_send(PidTelemetry {
+                time_us: Instant::now().as_micros() as u32,
+                setpoint: config.setpoint,
+                measurement: step.measurement,
+                output: step.output,
+                error: step.error,
+            });
+        }
+    }
+}
+
+#[embassy_executor::task]
+async fn telemetry_task(sender: Sender<AppTx>) {
+    let mut seq: u32 = 0;
+    loop {
+        let telemetry = TELEMETRY.receive().await;
+        // Publishing fails while the host is disconnected, nothing to do about it.
+        let _ = sender
+            .publish::<PidTelemetryTopic>(seq.into(), &telemetry)
+            .await;
+        seq = seq.wrapping_add(1);
+    }
+}
+
+/***************************** HANDLERS ******************************/
+fn unique_id_handler(_context: &mut Context, _header: VarHeader, _rqst: ()) -> [u8; 24] {
+    defmt::info!("unique_id");
+    *embassy_stm32::uid::uid_hex_bytes()
+}
+
+fn get_config_handler(context: &mut Context, _header: VarHeader, _rqst: ()) -> PidConfig {
+    defmt::info!("get_config");
+    context.config
+}
+
+fn set_config_handler(context: &mut Context, _header: VarHeader, rqst: PidConfig) -> PidResult {
+    defmt::info!("set_config");
+    rqst.validate()?;
+    context.config = rqst;
+    CONFIG.signal(rqst);
+    Ok(())
+}
+
+fn set_setpoint_handler(context: &mut Context, header: VarHeader, rqst: f32) -> PidResult {
+    defmt::info!("set_setpoint");
+    let config = PidConfig {
+        setpoint: rqst,
+        ..context.config
+    };
+    set_config_handler(context, header, config)
+}
+
+fn reset_controller_handler(_context: &mut Context, _header: VarHeader, _rqst: ()) {
+    defmt::info!("reset_controller");
+    RESET.signal(());
+}
@@ -0,0 +1,674 @@
+#![no_std]
+#![no_main]
+
+use core::{
+    cell::Cell,
+    future::Future,
+    sync::atomic::{AtomicU8, AtomicU16, Ordering},
+};
+
+use defmt_rtt as _;
+use embassy_executor::Spawner;
+use embassy_futures::select::{Either, select};
+use embassy_stm32::{
+    Config,
+    gpio::Flex,
+    interrupt,
+    interrupt::{InterruptExt, Priority},
+    pac::{
+        self,
+        adc::vals::{Extsel, SampleTime},
+        bdma::vals::{Dir, Pl, Size},
+        timer::vals::{Mms, Sms, Ts},
+    },
+    peripherals,
+    timer::low_level::Timer,
+};
+use embassy_sync::{
+    blocking_mutex::{
+        Mutex,
+        raw::{CriticalSectionRawMutex, ThreadModeRawMutex},
+    },
+    signal::Signal,
+};
+use embassy_time::Duration;
+use postcard_rpc::{
+    define_dispatch,
+    header::VarHeader,
+    server::{
+        Dispatch, Server,
+        impls::embassy_usb_v0_4::dispatch_impl::{WireRxBuf, WireSpawnImpl},
+    },
+};
+use protocol::scope::*;
+
+use firmware::*;
+
+struct Context {
+    // Pins and peripherals below are driven through the PAC, these are kept only
+    // to hold their configuration and ownership.
+    _inputs: [Flex<'static>; CHANNEL_COUNT as usize],
+    _timer: Timer<'static, peripherals::TIM3>,
+    _counter: Timer<'static, peripherals::TIM2>,
+    _dma: peripherals::DMA1_CH1,
+    timer_hz: u32,
+}
+
+type AppServer = Server<AppTx, AppRx, WireRxBuf, Metered<App>>;
+
+/// ADC clock with the default /6 prescaler from the 72 MHz APB2 clock.
+const ADC_CLOCK_HZ: u32 = 12_000_000;
+const MIN_SAMPLE_RATE_HZ: u32 = 1;
+const MAX_SAMPLE_RATE_HZ: u32 = 800_000;
+/// Samples searched backwards from the watchdog interrupt for the exact trigger sample.
+const TRIGGER_SEARCH: usize = 32;
+
+/// Sampling times in half ADC cycles, a conversion takes an additional 12.5 cycles.
+const SAMPLE_TIMES: [(u32, SampleTime); 8] = [
+    (479, SampleTime::CYCLES239_5),
+    (143, SampleTime::CYCLES71_5),
+    (111, SampleTime::CYCLES55_5),
+    (83, SampleTime::CYCLES41_5),
+    (57, SampleTime::CYCLES28_5),
+    (27, SampleTime::CYCLES13_5),
+    (15, SampleTime::CYCLES7_5),
+    (3, SampleTime::CYCLES1_5),
+];
+
+/// Ring buffer written by DMA1 channel 1 from the ADC data register.
+static mut SAMPLES: [u16; BUFFER_LEN] = [0; BUFFER_LEN];
+
+static CAPTURE: Mutex<ThreadModeRawMutex, Cell<Capture>> = Mutex::new(Cell::new(Capture::new()));
+static ARM: Signal<ThreadModeRawMutex, Acquisition> = Signal::new();
+static ABORT: Signal<ThreadModeRawMutex, ()> = Signal::new();
+static TRIGGERED: Signal<CriticalSectionRawMutex, ()> = Signal::new();
+static CAPTURED: Signal<CriticalSectionRawMutex, ()> = Signal::new();
+
+/// The analog watchdog is used in two stages for edge triggers, first it waits for the signal
+/// to move past the hysteresis band and then for the level crossing.
+const STAGE_ARMING: u8 = 0;
+const STAGE_FIRING: u8 = 1;
+static TRIGGER_STAGE: AtomicU8 = AtomicU8::new(STAGE_FIRING);
+static FIRING_LOW: AtomicU16 = AtomicU16::new(0);
+static FIRING_HIGH: AtomicU16 = AtomicU16::new(ADC_MAX);
+static TRIGGER_POSITION: AtomicU16 = AtomicU16::new(0);
+/// Sample counter value at the trigger, and how many samples to take after it.
+static TRIGGER_COUNT: AtomicU16 = AtomicU16::new(0);
+static STOP_AFTER: AtomicU16 = AtomicU16::new(0);
+
+define_dispatch! {
+    app: App;
+    spawn_fn: spawn_fn;
+    tx_impl: AppTx;
+    spawn_impl: WireSpawnImpl;
+    context: Context;
+
+    endpoints: {
+        list: ENDPOINT_LIST;
+
+        | EndpointTy                | kind      | handler                       |
+        | ----------                | ----      | -------                       |
+        | GetUniqueIdEndpoint       | blocking  | unique_id_handler             |
+        | GetCrashReport            | blocking  | crash_report_handler          |
+        | ClearCrashReport          | blocking  | clear_crash_report_handler    |
+        | ResetEndpoint             | blocking  | reset_handler                 |
+        | EnterBootloaderEndpoint   | blocking  | enter_bootloader_handler      |
+        | GetUpdateInfo             | blocking  | update_info_handler           |
+        | StartUpdate               | blocking  | start_update_handler          |
+        | WriteUpdateChunk          | blocking  | write_update_chunk_handler    |
+        | FinishUpdate              | blocking  | finish_update_handler         |
+        | ConfirmUpdate             | blocking  | confirm_update_handler        |
+        | GetMetrics                | blocking  | metrics_handler               |
+        | IdentifyEndpoint          | blocking  | identify_handler              |
+        | GetDeviceInfo             | blocking  | device_info_handler           |
+        | GetAlias                  | blocking  | get_alias_handler             |
+        | SetAlias                  | blocking  | set_alias_handler             |
+        | GetScopeLimits            | blocking  | get_limits_handler            |
+        | ArmCapture                | blocking  | arm_capture_handler           |
+        | AbortCapture              | blocking  | abort_capture_handler         |
+        | GetCaptureStatus          | blocking  | get_capture_status_handler    |
+        | ReadCapture               | blocking  | read_capture_handler          |
+    };
+    topics_in: {
+        list: TOPICS_IN_LIST;
+
+        | TopicTy                   | kind      | handler                       |
+        | ----------                | ----      | -------                       |
+    };
+    topics_out: {
+        list: TOPICS_OUT_LIST;
+    };
+}
+
+#[embassy_executor::main]
+async fn main(spawner: Spawner) {
+    reset::check_bootloader_request();
+    metrics::init();
+
+    let mut config = Config::default();
+    enable_usb_clock(&mut config);
+    config.rcc.adc_pre = embassy_stm32::rcc::ADCPrescaler::DIV6;
+    let p = embassy_stm32::init(config);
+    storage::init(p.FLASH);
+
+    /********************************* Inputs ********************************/
+    let mut inputs = [
+        Flex::new(p.PA0),
+        Flex::new(p.PA1),
+        Flex::new(p.PA2),
+        Flex::new(p.PA3),
+        Flex::new(p.PA4),
+        Flex::new(p.PA5),
+        Flex::new(p.PA6),
+        Flex::new(p.PA7),
+        Flex::new(p.PB0),
+        Flex::new(p.PB1),
+    ];
+    for input in inputs.iter_mut() {
+        input.set_as_analog();
+    }
+
+    /********************************** ADC **********************************/
+    // Conversions are started by TIM3 update events and moved by DMA1 channel 1 into
+    // the ring buffer, so sampling does not involve the CPU at all. The analog watchdog
+    // interrupt detects the trigger condition.
+    pac::RCC.apb2enr().modify(|w| w.set_adc1en(true));
+    pac::RCC.ahbenr().modify(|w| w.set_dma1en(true));
+    let adc = pac::ADC1;
+    adc.cr2().modify(|w| w.set_adon(true));
+    embassy_time::block_for(Duration::from_micros(10));
+    adc.cr2().modify(|w| w.set_rstcal(true));
+    while adc.cr2().read().rstcal() {}
+    adc.cr2().modify(|w| w.set_cal(true));
+    while adc.cr2().read().cal() {}
+    adc.cr2().modify(|w| {
+        w.set_dma(true);
+        w.set_extsel(Extsel::TIM3_TRGO);
+        w.set_exttrig(true);
+    });
+    adc.cr1().modify(|w| w.set_awdsgl(true));
+    adc.sqr1().modify(|w| w.set_l(0));
+
+    interrupt::ADC1_2.set_priority(Priority::P0);
+    unsafe { interrupt::ADC1_2.enable() };
+
+    /******************************** Timer **********************************/
+    let timer = Timer::new(p.TIM3);
+    let timer_hz = timer.get_clock_frequency().0;
+    timer.regs_gp16().cr2().modify(|w| w.set_mms(Mms::UPDATE));
+
+    // TIM2 counts the samples, clocked by the TIM3 update events. Its compare interrupt
+    // stops the sampling after the trigger, and the count tells how many samples were
+    // written even when the DMA went around the buffer more than once.
+    let counter = Timer::new(p.TIM2);
+    counter.regs_gp16().smcr().modify(|w| {
+        w.set_ts(Ts::ITR2);
+        w.set_sms(Sms::EXT_CLOCK_MODE);
+    });
+    interrupt::TIM2.set_priority(Priority::P0);
+    unsafe { interrupt::TIM2.enable() };
+
+    // Prepare the context for the application.
+    let context = Context {
+        _inputs: inputs,
+        _timer: timer,
+        _counter: counter,
+        _dma: p.DMA1_CH1,
+        timer_hz,
+    };
+
+    /********************************** USB **********************************/
+    // Create the driver for the selected board.
+    let driver = usb_driver!(p);
+
+    // Create embassy-usb Config
+    let usb_config = get_usb_config(USB_DEVICE_NAME);
+
+    let pbufs = PBUFS.take();
+    let (device, tx_impl, rx_impl) = init_usb(driver, usb_config, pbufs.tx_buf.as_mut_slice());
+    let dispatcher = Metered::new(App::new(context, spawner.into()), ENDPOINT_LIST.endpoints);
+    let vkk = dispatcher.min_key_len();
+    let server = AppServer::new(
+        tx_impl,
+        rx_impl,
+        pbufs.rx_buf.as_mut_slice(),
+        dispatcher,
+        vkk,
+    );
+
+    spawner.must_spawn(usb_task(device));
+    spawner.must_spawn(logging::logging_task(server.sender()));
+    spawner.must_spawn(reset::reset_task());
+    // On the Nucleo the LED is on PA5, which this firmware uses.
+    #[cfg(not(feature = "nucleo-f103rb"))]
+    spawner.must_spawn(status::status_led_task(status::led_output(status_led!(p))));
+    spawner.must_spawn(server_task(server));
+    spawner.must_spawn(capture_task());
+    spawner.must_spawn(idle_task());
+}
+
+#[embassy_executor::task]
+async fn server_task(mut server: AppServer) {
+    loop {
+        // If the host disconnects, we'll return an error here.
+        // If this happens, just wait until the host reconnects
+        let _ = server.run().await;
+    }
+}
+
+#[interrupt]
+fn ADC1_2() {
+    let adc = pac::ADC1;
+    if !adc.sr().read().awd() {
+        return;
+    }
+    adc.sr().modify(|w| w.set_awd(false));
+
+    if TRIGGER_STAGE.load(Ordering::Relaxed) == STAGE_ARMING {
+        set_watchdog(
+            FIRING_LOW.load(Ordering::Relaxed),
+            FIRING_HIGH.load(Ordering::Relaxed),
+        );
+        TRIGGER_STAGE.store(STAGE_FIRING, Ordering::Relaxed);
+    } else {
+        mark_trigger();
+        adc.cr1().modify(|w| w.set_awdie(false));
+        TRIGGERED.signal(());
+    }
+}
+
+#[interrupt]
+fn TIM2() {
+    pac::TIM2.sr().modify(|w| w.set_ccif(0, false));
+    stop_sampling();
+    CAPTURED.signal(());
+}
+
+/// Runs a single capture per arm request: fill the pre-trigger samples, wait for
+/// the trigger and wait for the sample counter interrupt to stop the sampling.
+#[embassy_executor::task]
+async fn capture_task() {
+    loop {
+        let Acquisition {
+            request,
+            prescaler,
+            reload,
+            period_ns,
+        } = ARM.wait().await;
+        let samples_duration = |samples: u64| Duration::from_micros(period_ns * samples / 1_000);
+        let post_trigger = (request.length - request.pre_trigger) as usize;
+
+        // Take a few more samples than needed, the trigger is found a few samples
+        // before the watchdog interrupt.
+        STOP_AFTER.store(post_trigger as u16 + 1, Ordering::Relaxed);
+        CAPTURED.reset();
+        start_sampling(&request, prescaler, reload);
+
+        // Fill the pre-trigger part of the buffer before looking for the trigger.
+        let fill = samples_duration(request.pre_trigger as u64 + 1);
+        if !wait_or_abort(embassy_time::Timer::after(fill)).await {
+            continue;
+        }
+
+        update_capture(|c| c.status.state = CaptureState::Waiting);
+        if request.trigger == ScopeTrigger::Immediate {
+            cortex_m::interrupt::free(|_| mark_trigger());
+        } else {
+            enable_trigger(&request);
+            if !wait_or_abort(TRIGGERED.wait()).await {
+                continue;
+            }
+        }
+        let position = TRIGGER_POSITION.load(Ordering::Relaxed) as usize;
+        let trigger = find_trigger(&request, position);
+        update_capture(|c| c.status.state = CaptureState::Triggered);
+
+        if !wait_or_abort(CAPTURED.wait()).await {
+            continue;
+        }
+
+        // Samples written from the trigger on, counted rather than taken from the DMA
+        // position, so a capture that was stopped too late is still caught.
+        let counted = pac::TIM2
+            .cnt()
+            .read()
+            .cnt()
+            .wrapping_sub(TRIGGER_COUNT.load(Ordering::Relaxed));
+        let written = (position + BUFFER_LEN - trigger) % BUFFER_LEN + counted as usize;
+        let start = (trigger + BUFFER_LEN - request.pre_trigger as usize) % BUFFER_LEN;
+        let overrun = written + request.pre_trigger as usize > BUFFER_LEN;
+        update_capture(|c| {
+            c.status.state = CaptureState::Done;
+            c.status.trigger_index = request.pre_trigger;
+            c.start = start as u16;
+            c.overrun = overrun;
+        });
+        if overrun {
+            host_warn!("Capture overrun, the start of the capture was overwritten");
+        }
+        defmt::info!("Capture done, overrun: {}", overrun);
+    }
+}
+
+/// Await `fut`, unless the capture is aborted first. Sampling is stopped on abort.
+async fn wait_or_abort<F: Future>(fut: F) -> bool {
+    match select(fut, ABORT.wait()).await {
+        Either::First(_) => true,
+        Either::Second(()) => {
+            stop_sampling();
+            update_capture(|c| c.status.state = CaptureState::Idle);
+            false
+        }
+    }
+}
+
+/***************************** CAPTURE ******************************/
+/// Validated arm request together with the sampling timer settings.
+struct Acquisition {
+    request: CaptureRequest,
+    prescaler: u16,
+    reload: u16,
+    period_ns: u64,
+}
+
+#[derive(Clone, Copy)]
+struct Capture {
+    status: CaptureStatus,
+    /// Ring buffer index of the first sample of the capture.
+    start: u16,
+    overrun: bool,
+}
+
+impl Capture {
+    const fn new() -> Self {
+        Self {
+            status: CaptureStatus {
+                state: CaptureState::Idle,
+                sample_rate_hz: 0.0,
+                length: 0,
+                trigger_index: 0,
+            },
+            start: 0,
+            overrun: false,
+        }
+    }
+}
+
+fn update_capture(f: impl FnOnce(&mut Capture)) {
+    CAPTURE.lock(|c| {
+        let mut capture = c.get();
+        f(&mut capture);
+        c.set(capture);
+    });
+}
+
+/// Pick timer dividers for the requested sample rate, returns the prescaler and reload values.
+fn timer_dividers(timer_hz: u32, sample_rate_hz: u32) -> (u16, u16) {
+    let ticks = ((timer_hz + sample_rate_hz / 2) / sample_rate_hz).max(1);
+    let prescaler = (ticks - 1) / (1 << 16);
+    let reload = (ticks / (prescaler + 1)).max(1) - 1;
+    (prescaler as u16, reload as u16)
+}
+
+/// Longest sampling time that still fits in the sample period, for the best accuracy.
+fn sample_time(sample_rate_hz: u32) -> SampleTime {
+    let period_half_cycles = 2 * ADC_CLOCK_HZ / sample_rate_hz;
+    SAMPLE_TIMES
+        .iter()
+        .find(|(half_cycles, _)| half_cycles + 25 <= period_half_cycles)
+        .map(|(_, sample_time)| *sample_time)
+        .unwrap_or(SampleTime::CYCLES1_5)
+}
+
+fn start_sampling(request: &CaptureRequest, prescaler: u16, reload: u16) {
+    let adc = pac::ADC1;
+    adc.cr1().modify(|w| {
+        w.set_awden(false);
+        w.set_awdie(false);
+        w.set_awdch(request.channel);
+    });
+    adc.sqr3().modify(|w| w.set_sq(0, request.channel));
+    adc.smpr2().modify(|w| {
+        w.set_smp(
+            request.channel as usize,
+            sample_time(request.sample_rate_hz),
+        )
+    });
+
+    let dma = pac::DMA1.ch(0);
+    dma.cr().write(|w| {
+        w.set_dir(Dir::FROMPERIPHERAL);
+        w.set_circ(true);
+        w.set_minc(true);
+        w.set_psize(Size::BITS16);
+        w.set_msize(Size::BITS16);
+        w.set_pl(Pl::VERYHIGH);
+    });
+    dma.par().write_value(adc.dr().as_ptr() as u32);
+    dma.mar().write_value(&raw mut SAMPLES as u32);
+    dma.ndtr().write(|w| w.set_ndt(BUFFER_LEN as u16));
+    dma.cr().modify(|w| w.set_en(true));
+
+    let counter = pac::TIM2;
+    counter.dier().modify(|w| w.set_ccie(0, false));
+    counter.cnt().write(|w| w.set_cnt(0));
+    counter.cr1().modify(|w| w.set_cen(true));
+
+    let timer = pac::TIM3;
+    timer.psc().write_value(prescaler);
+    timer.arr().write(|w| w.set_arr(reload));
+    timer.egr().write(|w| w.set_ug(true));
+    timer.cnt().write(|w| w.set_cnt(0));
+    timer.cr1().modify(|w| w.set_cen(true));
+}
+
+fn stop_sampling() {
+    pac::TIM3.cr1().modify(|w| w.set_cen(false));
+    pac::TIM2.dier().modify(|w| w.set_ccie(0, false));
+    pac::TIM2.cr1().modify(|w| w.set_cen(false));
+    pac::ADC1.cr1().modify(|w| {
+        w.set_awden(false);
+        w.set_awdie(false);
+    });
+    pac::DMA1.ch(0).cr().modify(|w| w.set_en(false));
+}
+
+fn set_watchdog(low: u16, high: u16) {
+    let adc = pac::ADC1;
+    adc.ltr().write(|w| w.set_lt(low));
+    adc.htr().write(|w| w.set_ht(high));
+}
+
+/// The watchdog fires when a sample is outside of the `[low, high]` window.
+fn enable_trigger(request: &CaptureRequest) {
+    let level = request.level;
+    let (arming, firing) = match request.trigger {
+        ScopeTrigger::Rising => (
+            Some((level.saturating_sub(request.hysteresis), ADC_MAX)),
+            (0, level),
+        ),
+        ScopeTrigger::Falling => (
+            Some((0, (level + request.hysteresis).min(ADC_MAX))),
+            (level, ADC_MAX),
+        ),
+        ScopeTrigger::Above => (None, (0, level)),
+        ScopeTrigger::Below => (None, (level, ADC_MAX)),
+        ScopeTrigger::Immediate => return,
+    };
+
+    FIRING_LOW.store(firing.0, Ordering::Relaxed);
+    FIRING_HIGH.store(firing.1, Ordering::Relaxed);
+    match arming {
+        Some((low, high)) => {
+            set_watchdog(low, high);
+            TRIGGER_STAGE.store(STAGE_ARMING, Ordering::Relaxed);
+        }
+        None => {
+            set_watchdog(firing.0, firing.1);
+            TRIGGER_STAGE.store(STAGE_FIRING, Ordering::Relaxed);
+        }
+    }
+
+    TRIGGERED.reset();
+    let adc = pac::ADC1;
+    adc.sr().modify(|w| w.set_awd(false));
+    adc.cr1().modify(|w| {
+        w.set_awden(true);
+        w.set_awdie(true);
+    });
+}
+
+/// The watchdog interrupt runs a few samples after the crossing, step back
+/// to the first sample that meets the trigger condition.
+fn find_trigger(request: &CaptureRequest, position: usize) -> usize {
+    let meets = |sample: u16| match request.trigger {
+        ScopeTrigger::Rising | ScopeTrigger::Above => sample > request.level,
+        ScopeTrigger::Falling | ScopeTrigger::Below => sample < request.level,
+        ScopeTrigger::Immediate => false,
+    };
+
+    let mut trigger = (position + BUFFER_LEN - 1) % BUFFER_LEN;
+    for _ in 0..TRIGGER_SEARCH {
+        let previous = (trigger + BUFFER_LEN - 1) % BUFFER_LEN;
+        if !meets(read_sample(previous)) {
+            break;
+        }
+        trigger = previous;
+    }
+    trigger
+}
+
+/// Remember where the trigger happened and schedule the end of the capture on the
+/// sample counter.
+fn mark_trigger() {
+    let counter = pac::TIM2;
+    let count = counter.cnt().read().cnt();
+    TRIGGER_POSITION.store(dma_position() as u16, Ordering::Relaxed);
+    TRIGGER_COUNT.store(count, Ordering::Relaxed);
+    let stop = count.wrapping_add(STOP_AFTER.load(Ordering::Relaxed));
+    counter.ccr(0).write(|w| w.set_ccr(stop));
+    counter.sr().modify(|w| w.set_ccif(0, false));
+    counter.dier().modify(|w| w.set_ccie(0, true));
+}
+
+/// Index of the next sample the DMA writes.
+fn dma_position() -> usize {
+    (BUFFER_LEN - pac::DMA1.ch(0).ndtr().read().ndt() as usize) % BUFFER_LEN
+}
+
+fn read_sample(index: usize) -> u16 {
+    // The buffer is written by the DMA behind the compiler's back.
+    unsafe {
+        (&raw const SAMPLES as *const u16)
+            .add(index)
+            .read_volatile()
+    }
+}
+
+/***************************** HANDLERS ******************************/
+fn unique_id_handler(_context: &mut Context, _header: VarHeader, _rqst: ()) -> [u8; 24] {
+    defmt::info!("unique_id");
+    *embassy_stm32::uid::uid_hex_bytes()
+}
+
+fn get_limits_handler(_context: &mut Context, _header: VarHeader, _rqst: ()) -> ScopeLimits {
+    defmt::info!("get_limits");
+    ScopeLimits {
+        max_length: MAX_LENGTH as u16,
+        chunk_size: CHUNK_SIZE as u16,
+        channels: CHANNEL_COUNT,
+        min_sample_rate_hz: MIN_SAMPLE_RATE_HZ,
+        max_sample_rate_hz: MAX_SAMPLE_RATE_HZ,
+    }
+}
+
+fn arm_capture_handler(
+    context: &mut Context,
+    _header: VarHeader,
+    rqst: CaptureRequest,
+) -> ArmResult {
+    defmt::info!(
+        "arm_capture: channel {}, {} Hz, {} samples",
+        rqst.channel,
+        rqst.sample_rate_hz,
+        rqst.length
+    );
+
+    if rqst.channel >= CHANNEL_COUNT {
+        return Err(ScopeError::InvalidChannel);
+    }
+    if !(MIN_SAMPLE_RATE_HZ..=MAX_SAMPLE_RATE_HZ).contains(&rqst.sample_rate_hz) {
+        return Err(ScopeError::InvalidSampleRate);
+    }
+    if rqst.length == 0 || rqst.length as usize > MAX_LENGTH || rqst.pre_trigger > rqst.length {
+        return Err(ScopeError::InvalidLength);
+    }
+    if rqst.level > ADC_MAX {
+        return Err(ScopeError::InvalidLevel);
+    }
+
+    let state = CAPTURE.lock(|c| c.get().status.state);
+    if matches!(
+        state,
+        CaptureState::Armed | CaptureState::Waiting | CaptureState::Triggered
+    ) {
+        return Err(ScopeError::Busy);
+    }
+
+    let (prescaler, reload) = timer_dividers(context.timer_hz, rqst.sample_rate_hz);
+    let ticks = (prescaler as u64 + 1) * (reload as u64 + 1);
+    let sample_rate_hz = context.timer_hz as f32 / ticks as f32;
+
+    update_capture(|c| {
+        c.status = CaptureStatus {
+            state: CaptureState::Armed,
+            sample_rate_hz,
+            length: rqst.length,
+            trigger_index: rqst.pre_trigger,
+        };
+        c.overrun = false;
+    });
+    ABORT.reset();
+    ARM.signal(Acquisition {
+        request: rqst,
+        prescaler,
+        reload,
+        period_ns: ticks * 1_000_000_000 / context.timer_hz as u64,
+    });
+    Ok(sample_rate_hz)
+}
+
+fn abort_capture_handler(_context: &mut Context, _header: VarHeader, _rqst: ()) {
+    defmt::info!("abort_capture");
+    ABORT.signal(());
+}
+
+fn get_capture_status_handler(
+    _context: &mut Context,
+    _header: VarHeader,
+    _rqst: (),
+) -> CaptureStatus {
+    CAPTURE.lock(|c| c.get().status)
+}
+
+fn read_capture_handler(_context: &mut Context, _header: VarHeader, rqst: u16) -> ReadResult {
+    let capture = CAPTURE.lock(|c| c.get());
+    if capture.status.state != CaptureState::Done {
+        return Err(ScopeError::NotReady);
+    }
+    if capture.overrun {
+        return Err(ScopeError::Overrun);
+    }
+
+    let offset = rqst.min(capture.status.length) as usize;
+    let count = (capture.status.length as usize - offset).min(CHUNK_SIZE);
+    let mut samples = heapless::Vec::new();
+    for i in 0..count {
+        let index = (capture.start as usize + offset + i) % BUFFER_LEN;
+        let _ = samples.push(read_sample(index));
+    }
+    Ok(CaptureChunk {
+        offset: offset as u16,
+        samples,
+    })
+}
@@ -0,0 +1,487 @@
+#![no_std]
+#![no_main]
+
+use core::cell::RefCell;
+
+use defmt_rtt as _;
+use embassy_executor::Spawner;
+use embassy_stm32::{
+    Config,
+    gpio::{Input, Level, Output, Pull, Speed},
+    interrupt,
+    interrupt::{InterruptExt, Priority},
+    pac,
+    pac::timer::vals::{Mms, Sms, Ts},
+    peripherals,
+    timer::low_level::Timer,
+};
+use embassy_sync::blocking_mutex::{Mutex, raw::CriticalSectionRawMutex};
+use postcard_rpc::{
+    define_dispatch,
+    header::VarHeader,
+    server::{
+        Dispatch, Server,
+        impls::embassy_usb_v0_4::dispatch_impl::{WireRxBuf, WireSpawnImpl},
+    },
+};
+use protocol::sequencer::*;
+
+use firmware::*;
+
+struct Context {
+    // Outputs are driven directly through BSRR from the timer interrupt,
+    // these are kept only to hold the pin configuration.
+    _outputs: [Output<'static>; CHANNEL_COUNT as usize],
+    _timer: Timer<'static, peripherals::TIM3>,
+    _trigger: Input<'static>,
+    _trigger_timer: Timer<'static, peripherals::TIM2>,
+}
+
+type AppServer = Server<AppTx, AppRx, WireRxBuf, Metered<App>>;
+
+/// Outputs are PB8-PB15, channel `n` is pin `OUTPUT_SHIFT + n`.
+const OUTPUT_SHIFT: u32 = 8;
+/// Longest period the 16 bit timer can count at 1 MHz, longer gaps are split.
+const MAX_TIMER_TICKS: u32 = 1 << 16;
+/// First period of a sequence started by a trigger edge, the timer needs at least two
+/// ticks to reach its first update. Entries at 0 µs are applied this late.
+const TRIGGERED_FIRST_TICKS: u32 = 2;
+
+static PLAYBACK: Mutex<CriticalSectionRawMutex, RefCell<Playback>> =
+    Mutex::new(RefCell::new(Playback::new()));
+
+define_dispatch! {
+    app: App;
+    spawn_fn: spawn_fn;
+    tx_impl: AppTx;
+    spawn_impl: WireSpawnImpl;
+    context: Context;
+
+    endpoints: {
+        list: ENDPOINT_LIST;
+
+        | EndpointTy                | kind      | handler                       |
+        | ----------                | ----      | -------                       |
+        | GetUniqueIdEndpoint       | blocking  | unique_id_handler             |
+        | GetCrashReport            | blocking  | crash_report_handler          |
+        | ClearCrashReport          | blocking  | clear_crash_report_handler    |
+        | ResetEndpoint             | blocking  | reset_handler                 |
+        | EnterBootloaderEndpoint   | blocking  | enter_bootloader_handler      |
+        | GetUpdateInfo             | blocking  | update_info_handler           |
+        | StartUpdate               | blocking  | start_update_handler          |
+        | WriteUpdateChunk          | blocking  | write_update_chunk_handler    |
+        | FinishUpdate              | blocking  | finish_update_handler         |
+        | ConfirmUpdate             | blocking  | confirm_update_handler        |
+        | GetMetrics                | blocking  | metrics_handler               |
+        | IdentifyEndpoint          | blocking  | identify_handler              |
+        | GetDeviceInfo             | blocking  | device_info_handler           |
+        | GetAlias                  | blocking  | get_alias_handler             |
+        | SetAlias                  | blocking  | set_alias_handler             |
+        | GetSequencerLimits        | blocking  | get_limits_handler            |
+        | UploadChunk               | blocking  | upload_chunk_handler          |
+        | ArmSequencer              | blocking  | arm_handler                   |
+        | StartSequencer            | blocking  | start_handler                 |
+        | StopSequencer             | blocking  | stop_handler                  |
+        | GetSequencerStatus        | blocking  | get_status_handler            |
+    };
+    topics_in: {
+        list: TOPICS_IN_LIST;
+
+        | TopicTy                   | kind      | handler                       |
+        | ----------                | ----      | -------                       |
+    };
+    topics_out: {
+        list: TOPICS_OUT_LIST;
+    };
+}
+
+#[embassy_executor::main]
+async fn main(spawner: Spawner) {
+    reset::check_bootloader_request();
+    metrics::init();
+
+    let mut config = Config::default();
+    enable_usb_clock(&mut config);
+    let p = embassy_stm32::init(config);
+    storage::init(p.FLASH);
+
+    /******************************* Outputs *********************************/
+    let outputs = [
+        Output::new(p.PB8, Level::Low, Speed::VeryHigh),
+        Output::new(p.PB9, Level::Low, Speed::VeryHigh),
+        Output::new(p.PB10, Level::Low, Speed::VeryHigh),
+        Output::new(p.PB11, Level::Low, Speed::VeryHigh),
+        Output::new(p.PB12, Level::Low, Speed::VeryHigh),
+        Output::new(p.PB13, Level::Low, Speed::VeryHigh),
+        Output::new(p.PB14, Level::Low, Speed::VeryHigh),
+        Output::new(p.PB15, Level::Low, Speed::VeryHigh),
+    ];
+    // Trigger input on PA0, which is TIM2_CH1.
+    let trigger = Input::new(p.PA0, Pull::Down);
+
+    /******************************** Timer **********************************/
+    // TIM3 ticks at 1 MHz and its update interrupt plays the table. Each update
+    // reloads the auto-reload register with the time to the next entry, so the
+    // playback runs entirely in the interrupt and does not depend on USB traffic.
+    let timer = Timer::new(p.TIM3);
+    let regs = timer.regs_gp16();
+    let prescaler = timer.get_clock_frequency().0 / 1_000_000 - 1;
+    defmt::info!("Sequencer timer prescaler: {}", prescaler);
+
+    regs.cr1().modify(|w| w.set_arpe(false));
+    regs.psc().write_value(prescaler as u16);
+    // Load the prescaler before enabling the interrupt.
+    regs.egr().write(|w| w.set_ug(true));
+    regs.sr().modify(|w| w.set_uif(false));
+    regs.dier().modify(|w| w.set_uie(true));
+
+    interrupt::TIM3.set_priority(Priority::P0);
+    unsafe { interrupt::TIM3.enable() };
+
+    // TIM2 starts on the trigger edge and its enable bit is the trigger output (TRGO),
+    // which TIM3 sees as ITR1. An external trigger therefore starts the playback in
+    // hardware, see `gate_timer`.
+    let trigger_timer = Timer::new(p.TIM2);
+    trigger_timer
+        .regs_gp16()
+        .cr2()
+        .modify(|w| w.set_mms(Mms::ENABLE));
+    regs.smcr().modify(|w| w.set_ts(Ts::ITR1));
+
+    // Prepare the context for the application.
+    let context = Context {
+        _outputs: outputs,
+        _timer: timer,
+        _trigger: trigger,
+        _trigger_timer: trigger_timer,
+    };
+
+    /********************************** USB **********************************/
+    // Create the driver for the selected board.
+    let driver = usb_driver!(p);
+
+    // Create embassy-usb Config
+    let usb_config = get_usb_config(USB_DEVICE_NAME);
+
+    let pbufs = PBUFS.take();
+    let (device, tx_impl, rx_impl) = init_usb(driver, usb_config, pbufs.tx_buf.as_mut_slice());
+    let dispatcher = Metered::new(App::new(context, spawner.into()), ENDPOINT_LIST.endpoints);
+    let vkk = dispatcher.min_key_len();
+    let server = AppServer::new(
+        tx_impl,
+        rx_impl,
+        pbufs.rx_buf.as_mut_slice(),
+        dispatcher,
+        vkk,
+    );
+
+    spawner.must_spawn(usb_task(device));
+    spawner.must_spawn(logging::logging_task(server.sender()));
+    spawner.must_spawn(reset::reset_task());
+    spawner.must_spawn(status::status_led_task(status::led_output(status_led!(p))));
+    spawner.must_spawn(server_task(server));
+    spawner.must_spawn(idle_task());
+}
+
+#[embassy_executor::task]
+async fn server_task(mut server: AppServer) {
+    loop {
+        // If the host disconnects, we'll return an error here.
+        // If this happens, just wait until the host reconnects
+        let _ = server.run().await;
+    }
+}
+
+#[interrupt]
+fn TIM3() {
+    pac::TIM3.sr().modify(|w| w.set_uif(false));
+    PLAYBACK.lock(|p| p.borrow_mut().on_update());
+}
+
+/***************************** PLAYBACK ******************************/
+struct Playback {
+    times: [u32; MAX_ENTRIES],
+    states: [u8; MAX_ENTRIES],
+    loaded: u16,
+    length: u16,
+    period_us: u32,
+    repeat: u32,
+    iteration: u32,
+    next: u16,
+    /// Timer ticks left until the next entry, after the currently counting period.
+    remaining: u32,
+    state: SequencerState,
+}
+
+impl Playback {
+    const fn new() -> Self {
+        Self {
+            times: [0; MAX_ENTRIES],
+            states: [0; MAX_ENTRIES],
+            loaded: 0,
+            length: 0,
+            period_us: 0,
+            repeat: 0,
+            iteration: 0,
+            next: 0,
+            remaining: 0,
+            state: SequencerState::Idle,
+        }
+    }
+
+    fn is_busy(&self) -> bool {
+        matches!(self.state, SequencerState::Armed | SequencerState::Running)
+    }
+
+    fn write(&mut self, chunk: &TableChunk) -> SequencerResult {
+        if self.is_busy() {
+            return Err(SequencerError::Busy);
+        }
+        let start = chunk.offset as usize;
+        let end = start + chunk.entries.len();
+        // Chunks are written in order, a write at offset 0 starts a new table.
+        if start > self.loaded as usize || end > MAX_ENTRIES {
+            return Err(SequencerError::OutOfRange);
+        }
+        for (i, entry) in chunk.entries.iter().enumerate() {
+            self.times[start + i] = entry.time_us;
+            self.states[start + i] = entry.state;
+        }
+        self.loaded = end as u16;
+        self.state = SequencerState::Idle;
+        Ok(())
+    }
+
+    fn arm(&mut self, request: &ArmRequest) -> SequencerResult {
+        if self.is_busy() {
+            return Err(SequencerError::Busy);
+        }
+        let length = request.length as usize;
+        if length == 0 {
+            return Err(SequencerError::Empty);
+        }
+        if length > self.loaded as usize {
+            return Err(SequencerError::OutOfRange);
+        }
+        let times = &self.times[..length];
+        // A single tick cannot be counted, the timer stops with a zero auto-reload.
+        if times[0] == 1 {
+            return Err(SequencerError::InvalidTiming);
+        }
+        if times.windows(2).any(|w| {
+            w[0].checked_add(MIN_STEP_US)
+                .is_none_or(|earliest| w[1] < earliest)
+        }) {
+            return Err(SequencerError::InvalidTiming);
+        }
+        // Time from the last entry to the first one of the next pass, also the limit of
+        // the times in `fire`.
+        let Some(wrap_us) = request.period_us.checked_add(times[0]) else {
+            return Err(SequencerError::InvalidTiming);
+        };
+        let wraps = request.repeat != 1;
+        if wraps
+            && times[length - 1]
+                .checked_add(MIN_STEP_US)
+                .is_none_or(|earliest| wrap_us < earliest)
+        {
+            return Err(SequencerError::InvalidTiming);
+        }
+
+        self.length = request.length;
+        self.period_us = request.period_us;
+        self.repeat = request.repeat;
+        self.iteration = 0;
+        self.next = 0;
+        self.state = SequencerState::Armed;
+        set_outputs(request.idle_state);
+
+        if request.trigger != Trigger::Software {
+            // Load the first period now, the edge starts the timer without the CPU and
+            // its first update applies the first entry.
+            pac::TIM3.cnt().write(|w| w.set_cnt(0));
+            self.remaining = self.times[0].max(TRIGGERED_FIRST_TICKS);
+            self.schedule();
+            gate_timer(request.trigger);
+        }
+        Ok(())
+    }
+
+    fn start(&mut self) -> SequencerResult {
+        if self.state != SequencerState::Armed {
+            return Err(SequencerError::NotArmed);
+        }
+        // A software start replaces a pending trigger.
+        ungate_timer();
+        let regs = pac::TIM3;
+        regs.cnt().write(|w| w.set_cnt(0));
+
+        self.state = SequencerState::Running;
+        self.remaining = self.times[0];
+        if self.remaining == 0 {
+            self.fire();
+        } else {
+            self.schedule();
+        }
+        if self.state == SequencerState::Running {
+            regs.cr1().modify(|w| w.set_cen(true));
+        }
+        Ok(())
+    }
+
+    fn stop(&mut self) {
+        ungate_timer();
+        stop_timer();
+        self.state = SequencerState::Idle;
+    }
+
+    fn on_update(&mut self) {
+        if self.state == SequencerState::Armed {
+            // Started by the trigger edge.
+            ungate_timer();
+            self.state = SequencerState::Running;
+            defmt::info!("External trigger");
+        }
+        if self.state != SequencerState::Running {
+            return;
+        }
+        if self.remaining > 0 {
+            self.schedule();
+        } else {
+            self.fire();
+        }
+    }
+
+    /// Apply the pending entry and schedule the following one.
+    fn fire(&mut self) {
+        let i = self.next as usize;
+        set_outputs(self.states[i]);
+
+        let now = self.times[i];
+        if i + 1 < self.length as usize {
+            self.next += 1;
+            self.remaining = self.times[i + 1] - now;
+        } else {
+            self.iteration += 1;
+            if self.repeat != 0 && self.iteration >= self.repeat {
+                stop_timer();
+                self.state = SequencerState::Done;
+                return;
+            }
+            self.next = 0;
+            // Checked against overflow by `arm`.
+            self.remaining = self.period_us + self.times[0] - now;
+        }
+        self.schedule();
+    }
+
+    /// Load the timer with the next period, at most `MAX_TIMER_TICKS` long. A split gap
+    /// leaves at least `MIN_STEP_US` for its last piece, as a period of a single tick
+    /// would need a zero auto-reload, which stops the timer.
+    fn schedule(&mut self) {
+        let ticks = if self.remaining <= MAX_TIMER_TICKS {
+            self.remaining
+        } else if self.remaining - MAX_TIMER_TICKS < MIN_STEP_US {
+            self.remaining - MIN_STEP_US
+        } else {
+            MAX_TIMER_TICKS
+        };
+        self.remaining -= ticks;
+        pac::TIM3
+            .arr()
+            .write(|w| w.set_arr((ticks.max(2) - 1) as u16));
+    }
+
+    fn status(&self) -> SequencerStatus {
+        SequencerStatus {
+            state: self.state,
+            loaded: self.loaded,
+            iteration: self.iteration,
+            next_entry: self.next,
+        }
+    }
+}
+
+fn set_outputs(state: u8) {
+    let set = (state as u32) << OUTPUT_SHIFT;
+    let reset = ((!state) as u32) << (OUTPUT_SHIFT + 16);
+    pac::GPIOB
+        .bsrr()
+        .write_value(pac::gpio::regs::Bsrr(set | reset));
+}
+
+/// Let the trigger edge on PA0 start TIM3: TIM2 starts on the edge of TI1 and sets its
+/// trigger output, which starts TIM3 in trigger mode.
+fn gate_timer(trigger: Trigger) {
+    let gate = pac::TIM2;
+    gate.cr1().modify(|w| w.set_cen(false));
+    gate.cnt().write(|w| w.set_cnt(0));
+    gate.ccer()
+        .modify(|w| w.set_ccp(0, trigger == Trigger::FallingEdge));
+    gate.smcr().modify(|w| w.set_ts(Ts::TI1FP1));
+    gate.smcr().modify(|w| w.set_sms(Sms::TRIGGER_MODE));
+    pac::TIM3.smcr().modify(|w| w.set_sms(Sms::TRIGGER_MODE));
+}
+
+/// Ignore the trigger edge, TIM3 keeps running if it was already started.
+fn ungate_timer() {
+    pac::TIM3.smcr().modify(|w| w.set_sms(Sms::DISABLED));
+    let gate = pac::TIM2;
+    gate.smcr().modify(|w| w.set_sms(Sms::DISABLED));
+    gate.cr1().modify(|w| w.set_cen(false));
+}
+
+fn stop_timer() {
+    let regs = pac::TIM3;
+    regs.cr1().modify(|w| w.set_cen(false));
+    regs.sr().modify(|w| w.set_uif(false));
+}
+
+/***************************** HANDLERS ******************************/
+fn unique_id_handler(_context: &mut Context, _header: VarHeader, _rqst: ()) -> [u8; 24] {
+    defmt::info!("unique_id");
+    *embassy_stm32::uid::uid_hex_bytes()
+}
+
+fn get_limits_handler(_context: &mut Context, _header: VarHeader, _rqst: ()) -> SequencerLimits {
+    defmt::info!("get_limits");
+    SequencerLimits {
+        max_entries: MAX_ENTRIES as u16,
+        chunk_size: CHUNK_SIZE as u16,
+        channels: CHANNEL_COUNT,
+        resolution_us: 1,
+        min_step_us: MIN_STEP_US,
+    }
+}
+
+fn upload_chunk_handler(
+    _context: &mut Context,
+    _header: VarHeader,
+    rqst: TableChunk,
+) -> SequencerResult {
+    defmt::info!(
+        "upload_chunk: {} entries at {}",
+        rqst.entries.len(),
+        rqst.offset
+    );
+    PLAYBACK.lock(|p| p.borrow_mut().write(&rqst))
+}
+
+fn arm_handler(_context: &mut Context, _header: VarHeader, rqst: ArmRequest) -> SequencerResult {
+    defmt::info!("arm: {} entries, repeat {}", rqst.length, rqst.repeat);
+    PLAYBACK.lock(|p| p.borrow_mut().arm(&rqst))
+}
+
+fn start_handler(_context: &mut Context, _header: VarHeader, _rqst: ()) -> SequencerResult {
+    defmt::info!("start");
+    PLAYBACK.lock(|p| p.borrow_mut().start())
+}
+
+fn stop_handler(_context: &mut Context, _header: VarHeader, _rqst: ()) {
+    defmt::info!("stop");
+    PLAYBACK.lock(|p| p.borrow_mut().stop());
+}
+
+fn get_status_handler(_context: &mut Context, _header: VarHeader, _rqst: ()) -> SequencerStatus {
+    PLAYBACK.lock(|p| p.borrow().status())
+}
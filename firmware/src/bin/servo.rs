@@ -1,9 +1,14 @@
 #![no_std]
 #![no_main]
 
+use core::cell::RefCell;
+
+use defmt_rtt as _;
 use embassy_executor::Spawner;
+use embassy_futures::select::{Either, select};
 use embassy_stm32::{
-    Config, bind_interrupts,
+    Config,
+    adc::{Adc, AnyAdcChannel},
     gpio::OutputType,
     peripherals,
     time::Hertz,
@@ -11,35 +16,64 @@ use embassy_stm32::{
         self,
         simple_pwm::{PwmPin, SimplePwm, SimplePwmChannel},
     },
-    usb,
 };
+use embassy_sync::{
+    blocking_mutex::{Mutex, raw::ThreadModeRawMutex},
+    channel::Channel,
+};
+use embassy_time::{Duration, Instant, Ticker};
 use postcard_rpc::{
     define_dispatch,
     header::VarHeader,
     server::{
-        Dispatch, Server,
+        Dispatch, Sender, Server,
         impls::embassy_usb_v0_4::dispatch_impl::{WireRxBuf, WireSpawnImpl},
     },
 };
 use protocol::{servo::*, utils::PwmChannel};
-use {defmt_rtt as _, panic_probe as _};
+use static_cell::StaticCell;
 
 use firmware::*;
 
-struct Context {
-    pwm: SimplePwm<'static, peripherals::TIM4>, // Possibly expand to more timers in the future
+/// Shared by the handlers, the failsafe and the ADC tasks.
+struct Servos {
+    pwm: SimplePwm<'static, ServoTimer>, // Possibly expand to more timers in the future
     config: ServoConfig,
+    /// Averaged readings of the channels with feedback enabled.
+    feedback: [Option<u16>; 4],
+}
+
+/// Current sense inputs of the channels and of the supply rail.
+struct CurrentPins {
+    channels: [AnyAdcChannel<peripherals::ADC1>; 4],
+    rail: AnyAdcChannel<peripherals::ADC1>,
+}
+
+type ServosMutex = Mutex<ThreadModeRawMutex, RefCell<Servos>>;
+
+struct Context {
+    servos: &'static ServosMutex,
 }
 
-type AppServer = Server<AppTx, AppRx, WireRxBuf, App>;
+static SERVOS: StaticCell<ServosMutex> = StaticCell::new();
+static CURRENTS: Channel<ThreadModeRawMutex, ServoCurrent, 4> = Channel::new();
+static STALLS: Channel<ThreadModeRawMutex, ServoStall, 4> = Channel::new();
+
+type AppServer = Server<AppTx, AppRx, WireRxBuf, Metered<App>>;
 
 const SERVO_FREQ: Hertz = Hertz(50);
 const SERVO_MIN_US: u32 = 500;
 const SERVO_MAX_US: u32 = 2500;
-
-bind_interrupts!(struct Irqs {
-    TIM4 => timer::CaptureCompareInterruptHandler<peripherals::TIM4>;
-    USB_LP_CAN1_RX0 => usb::InterruptHandler<peripherals::USB>;
+const SERVO_NEUTRAL_US: u32 = 1500;
+/// How often the failsafe task checks the connection to the host.
+const FAILSAFE_INTERVAL: Duration = Duration::from_millis(50);
+const ADC_INTERVAL: Duration = Duration::from_millis(10);
+/// Readings averaged per sample, the potentiometers and shunts pick up the PWM noise.
+const ADC_OVERSAMPLING: u32 = 8;
+
+#[cfg(feature = "stm32f1")]
+embassy_stm32::bind_interrupts!(struct Irqs {
+    ADC1_2 => embassy_stm32::adc::InterruptHandler<peripherals::ADC1>;
 });
 
 define_dispatch! {
@@ -55,9 +89,26 @@ define_dispatch! {
         | EndpointTy                | kind      | handler                       |
         | ----------                | ----      | -------                       |
         | GetUniqueIdEndpoint       | blocking  | unique_id_handler             |
+        | GetCrashReport            | blocking  | crash_report_handler          |
+        | ClearCrashReport          | blocking  | clear_crash_report_handler    |
+        | ResetEndpoint             | blocking  | reset_handler                 |
+        | EnterBootloaderEndpoint   | blocking  | enter_bootloader_handler      |
+        | GetUpdateInfo             | blocking  | update_info_handler           |
+        | StartUpdate               | blocking  | start_update_handler          |
+        | WriteUpdateChunk          | blocking  | write_update_chunk_handler    |
+        | FinishUpdate              | blocking  | finish_update_handler         |
+        | ConfirmUpdate             | blocking  | confirm_update_handler        |
+        | GetMetrics                | blocking  | metrics_handler               |
+        | IdentifyEndpoint          | blocking  | identify_handler              |
+        | GetDeviceInfo             | blocking  | device_info_handler           |
+        | GetAlias                  | blocking  | get_alias_handler             |
+        | SetAlias                  | blocking  | set_alias_handler             |
         | ConfigureChannel          | blocking  | configure_channel_handler     |
         | GetServoConfig            | blocking  | get_servo_config_handler      |
         | SetFrequencyEndpoint      | blocking  | set_frequency_handler         |
+        | SetFailsafeTimeout        | blocking  | set_failsafe_timeout_handler  |
+        | GetServoFeedback          | blocking  | servo_feedback_handler        |
+        | SetCurrentConfig          | blocking  | set_current_config_handler    |
     };
     topics_in: {
         list: TOPICS_IN_LIST;
@@ -72,17 +123,22 @@ define_dispatch! {
 
 #[embassy_executor::main]
 async fn main(spawner: Spawner) {
+    reset::check_bootloader_request();
+    metrics::init();
+
     let mut config = Config::default();
     enable_usb_clock(&mut config);
-    let mut p = embassy_stm32::init(config);
+    let p = embassy_stm32::init(config);
+    storage::init(p.FLASH);
 
     /********************************** PWM **********************************/
+    let (tim, ch1, ch2, ch3, ch4) = servo_pins!(p);
     let pwm = SimplePwm::new(
-        p.TIM4,
-        Some(PwmPin::new_ch1(p.PB6, OutputType::PushPull)),
-        Some(PwmPin::new_ch2(p.PB7, OutputType::PushPull)),
-        Some(PwmPin::new_ch3(p.PB8, OutputType::PushPull)),
-        Some(PwmPin::new_ch4(p.PB9, OutputType::PushPull)),
+        tim,
+        Some(PwmPin::new_ch1(ch1, OutputType::PushPull)),
+        Some(PwmPin::new_ch2(ch2, OutputType::PushPull)),
+        Some(PwmPin::new_ch3(ch3, OutputType::PushPull)),
+        Some(PwmPin::new_ch4(ch4, OutputType::PushPull)),
         SERVO_FREQ,
         timer::low_level::CountingMode::CenterAlignedBothInterrupts,
     );
@@ -90,37 +146,53 @@ async fn main(spawner: Spawner) {
     defmt::info!("Max Duty Cycle: {}", max_duty_cycle);
     let servo_min = (max_duty_cycle as u32) * SERVO_FREQ.0 / 1_000 * SERVO_MIN_US / 1_000;
     let servo_max = (max_duty_cycle as u32) * SERVO_FREQ.0 / 1_000 * SERVO_MAX_US / 1_000;
+    let servo_neutral = (max_duty_cycle as u32) * SERVO_FREQ.0 / 1_000 * SERVO_NEUTRAL_US / 1_000;
 
     defmt::info!("Servo min: {}, Servo max: {}", servo_min, servo_max);
 
+    let channel_config = ServoChannelConfig {
+        min_angle_duty_cycle: servo_min as u16,
+        max_angle_duty_cycle: servo_max as u16,
+        neutral_duty_cycle: servo_neutral as u16,
+        ..Default::default()
+    };
     let servo_config = ServoConfig {
         servo_frequency: SERVO_FREQ.0,
         max_duty_cycle,
         channels: [ServoChannelConfig {
-            min_angle_duty_cycle: servo_min as u16,
-            max_angle_duty_cycle: servo_max as u16,
-            ..Default::default()
+            angle_step: channel_config.quantization_step(),
+            ..channel_config
         }; 4],
+        failsafe_timeout_ms: 0,
+        current: CurrentConfig::new(),
     };
 
+    /********************************** ADC **********************************/
+    // The F1 `Adc::new` does not take the interrupt binding, but `read` waits on it.
+    #[cfg(feature = "stm32f1")]
+    let _ = Irqs;
+    let (adc, feedback_pins) = servo_feedback_pins!(p);
+    let (channels, rail) = servo_current_pins!(p);
+    let adc = slow_adc(adc);
+
     // Prepare the context for the application.
-    let context = Context {
+    let servos: &'static ServosMutex = SERVOS.init(Mutex::new(RefCell::new(Servos {
         config: servo_config,
         pwm,
-    };
+        feedback: [None; 4],
+    })));
+    let context = Context { servos };
 
     /********************************** USB **********************************/
-    reset_condition(&mut p.PA12).await;
-
-    // Create the driver, from the HAL.
-    let driver = usb::Driver::new(p.USB, Irqs, p.PA12, p.PA11);
+    // Create the driver for the selected board.
+    let driver = usb_driver!(p);
 
     // Create embassy-usb Config
     let usb_config = get_usb_config("bluepill-servo");
 
     let pbufs = PBUFS.take();
-    let (device, tx_impl, rx_impl) = STORAGE.init(driver, usb_config, pbufs.tx_buf.as_mut_slice());
-    let dispatcher = App::new(context, spawner.into());
+    let (device, tx_impl, rx_impl) = init_usb(driver, usb_config, pbufs.tx_buf.as_mut_slice());
+    let dispatcher = Metered::new(App::new(context, spawner.into()), ENDPOINT_LIST.endpoints);
     let vkk = dispatcher.min_key_len();
     let server = AppServer::new(
         tx_impl,
@@ -130,7 +202,19 @@ async fn main(spawner: Spawner) {
         vkk,
     );
 
+    let sender = server.sender();
     spawner.must_spawn(usb_task(device));
+    spawner.must_spawn(logging::logging_task(server.sender()));
+    spawner.must_spawn(reset::reset_task());
+    spawner.must_spawn(status::status_led_task(status::led_output(status_led!(p))));
+    spawner.must_spawn(failsafe_task(servos));
+    spawner.must_spawn(adc_task(
+        servos,
+        adc,
+        feedback_pins,
+        CurrentPins { channels, rail },
+    ));
+    spawner.must_spawn(report_task(sender));
     spawner.must_spawn(server_task(server));
     spawner.must_spawn(idle_task());
 }
@@ -144,46 +228,294 @@ async fn server_task(mut server: AppServer) {
     }
 }
 
+/// Stop the continuous servos when USB is disconnected or reset, or no request came for
+/// the failsafe timeout. Positional servos hold their angle.
+#[embassy_executor::task]
+async fn failsafe_task(servos: &'static ServosMutex) {
+    let mut ticker = Ticker::every(FAILSAFE_INTERVAL);
+    loop {
+        ticker.next().await;
+        servos.lock(|servos| {
+            let servos = &mut *servos.borrow_mut();
+            let timeout = servos.config.failsafe_timeout_ms;
+            let timed_out = timeout > 0
+                && Instant::now() > status::last_request() + Duration::from_millis(timeout as u64);
+            if status::usb_configured() && !timed_out {
+                return;
+            }
+
+            for channel in [
+                PwmChannel::Channel1,
+                PwmChannel::Channel2,
+                PwmChannel::Channel3,
+                PwmChannel::Channel4,
+            ] {
+                let config = &mut servos.config.channels[channel as usize];
+                if config.mode != ServoMode::Continuous
+                    || !config.enabled
+                    || config.current_duty_cycle == config.neutral_duty_cycle
+                {
+                    continue;
+                }
+                config.current_duty_cycle = config.neutral_duty_cycle;
+                get_channel(&mut servos.pwm, channel).set_duty_cycle(config.output_duty_cycle());
+                host_warn!("Failsafe, stopped channel {}", channel as usize + 1);
+            }
+        });
+    }
+}
+
+async fn read_averaged(
+    adc: &mut Adc<'static, peripherals::ADC1>,
+    pin: &mut AnyAdcChannel<peripherals::ADC1>,
+) -> u16 {
+    let mut sum = 0;
+    for _ in 0..ADC_OVERSAMPLING {
+        sum += read_adc(adc, pin).await as u32;
+    }
+    (sum / ADC_OVERSAMPLING) as u16
+}
+
+/// Sample the feedback inputs of the channels that have it enabled and the current sense
+/// inputs. A channel, or every channel with the rail sense, whose current stays above the
+/// stall current for the stall time is disabled or backed off.
+#[embassy_executor::task]
+async fn adc_task(
+    servos: &'static ServosMutex,
+    mut adc: Adc<'static, peripherals::ADC1>,
+    mut feedback_pins: [AnyAdcChannel<peripherals::ADC1>; 4],
+    mut current_pins: CurrentPins,
+) {
+    let mut ticker = Ticker::every(ADC_INTERVAL);
+    // When the current of each channel, or of the rail in the last slot, went over the limit.
+    let mut over_since: [Option<Instant>; 5] = [None; 5];
+    let mut samples: u32 = 0;
+
+    loop {
+        ticker.next().await;
+
+        for (i, pin) in feedback_pins.iter_mut().enumerate() {
+            let enabled = servos.lock(|servos| servos.borrow().config.channels[i].feedback);
+            let reading = if enabled {
+                Some(read_averaged(&mut adc, pin).await)
+            } else {
+                None
+            };
+            servos.lock(|servos| servos.borrow_mut().feedback[i] = reading);
+        }
+
+        let config = servos.lock(|servos| servos.borrow().config.current);
+        let current = match config.sense {
+            CurrentSense::Disabled => {
+                over_since = [None; 5];
+                continue;
+            }
+            CurrentSense::PerChannel => {
+                let mut channels_ma = [0.0; 4];
+                for (current, pin) in channels_ma.iter_mut().zip(&mut current_pins.channels) {
+                    *current = config.to_ma(read_averaged(&mut adc, pin).await);
+                }
+                ServoCurrent {
+                    channels_ma: Some(channels_ma),
+                    rail_ma: None,
+                }
+            }
+            CurrentSense::Rail => ServoCurrent {
+                channels_ma: None,
+                rail_ma: Some(config.to_ma(read_averaged(&mut adc, &mut current_pins.rail).await)),
+            },
+        };
+
+        samples = samples.wrapping_add(1);
+        if config.report_divider != 0 && samples % config.report_divider as u32 == 0 {
+            // Reports are dropped rather than delaying the protection.
+            let _ = CURRENTS.try_send(current);
+        }
+
+        let readings = current.channels_ma.unwrap_or([f32::NAN; 4]);
+        let readings = [
+            readings[0],
+            readings[1],
+            readings[2],
+            readings[3],
+            current.rail_ma.unwrap_or(f32::NAN),
+        ];
+        for (i, current_ma) in readings.into_iter().enumerate() {
+            // NaN, a sensor that is not used, is never over the limit.
+            let over_limit = config.stall_current_ma > 0.0 && current_ma > config.stall_current_ma;
+            if !over_limit {
+                over_since[i] = None;
+                continue;
+            }
+            let since = *over_since[i].get_or_insert_with(Instant::now);
+            if since.elapsed() < Duration::from_millis(config.stall_time_ms as u64) {
+                continue;
+            }
+            over_since[i] = None;
+
+            let mut channels = [false; 4];
+            match channels.get_mut(i) {
+                Some(channel) => *channel = true,
+                None => channels = [true; 4],
+            }
+            let stall = servos.lock(|servos| {
+                stall_protection(&mut servos.borrow_mut(), channels, current_ma, &config)
+            });
+            if stall.channels.iter().any(|stalled| *stalled) {
+                let _ = STALLS.try_send(stall);
+            }
+        }
+    }
+}
+
+/// Take the stall action on the enabled channels among `channels`.
+fn stall_protection(
+    servos: &mut Servos,
+    channels: [bool; 4],
+    current_ma: f32,
+    config: &CurrentConfig,
+) -> ServoStall {
+    let mut stalled = [false; 4];
+    for (i, channel) in [
+        PwmChannel::Channel1,
+        PwmChannel::Channel2,
+        PwmChannel::Channel3,
+        PwmChannel::Channel4,
+    ]
+    .into_iter()
+    .enumerate()
+    {
+        let channel_config = &mut servos.config.channels[i];
+        if !channels[i] || !channel_config.enabled {
+            continue;
+        }
+        stalled[i] = true;
+
+        match (config.stall_action, channel_config.mode) {
+            (StallAction::Disable, _) => {
+                channel_config.enabled = false;
+                get_channel(&mut servos.pwm, channel).disable();
+            }
+            (StallAction::BackOff, ServoMode::Continuous) => {
+                channel_config.current_duty_cycle = channel_config.neutral_duty_cycle;
+            }
+            (StallAction::BackOff, ServoMode::Positional) => {
+                let angle = channel_config.duty_cycle_to_angle(channel_config.current_duty_cycle);
+                let target = match servos.feedback[i] {
+                    Some(raw) => channel_config.feedback_to_angle(raw),
+                    None => {
+                        let middle = (channel_config.min_angle + channel_config.max_angle) / 2.0;
+                        let back_off = config.back_off_angle.min((middle - angle).abs());
+                        if middle > angle {
+                            angle + back_off
+                        } else {
+                            angle - back_off
+                        }
+                    }
+                };
+                channel_config.current_duty_cycle = channel_config.angle_to_duty_cycle(target);
+                channel_config.current_duty_cycle = channel_config.limited_duty_cycle();
+            }
+        }
+        if config.stall_action == StallAction::BackOff {
+            get_channel(&mut servos.pwm, channel)
+                .set_duty_cycle(channel_config.output_duty_cycle());
+        }
+        host_warn!(
+            "Channel {} stalled at {} mA, {}",
+            i + 1,
+            current_ma,
+            match config.stall_action {
+                StallAction::Disable => "disabled",
+                StallAction::BackOff => "backed off",
+            }
+        );
+    }
+
+    ServoStall {
+        channels: stalled,
+        current_ma,
+        action: config.stall_action,
+    }
+}
+
+/// Publish the current reports and the stall events.
+#[embassy_executor::task]
+async fn report_task(sender: Sender<AppTx>) {
+    let mut seq: u32 = 0;
+    loop {
+        // Publishing fails while the host is disconnected, nothing to do about it.
+        let _ = match select(CURRENTS.receive(), STALLS.receive()).await {
+            Either::First(current) => {
+                sender
+                    .publish::<ServoCurrentTopic>(seq.into(), &current)
+                    .await
+            }
+            Either::Second(stall) => sender.publish::<ServoStallTopic>(seq.into(), &stall).await,
+        };
+        seq = seq.wrapping_add(1);
+    }
+}
+
 fn unique_id_handler(_context: &mut Context, _header: VarHeader, _rqst: ()) -> [u8; 24] {
     defmt::info!("unique_id");
     *embassy_stm32::uid::uid_hex_bytes()
 }
 
+/// The commanded duty cycle is kept within the soft limits, the applied config is returned
+/// with the angle step of the channel.
 fn configure_channel_handler(
     context: &mut Context,
     _header: VarHeader,
     rqst: (PwmChannel, ServoChannelConfig),
-) {
+) -> ServoChannelConfig {
     defmt::info!("configure_channel");
 
-    let (channel, config) = rqst;
-    let mut ch = get_channel(&mut context.pwm, channel);
-
-    defmt::info!(
-        "Configuring channel {}: {}/{}",
-        channel as usize,
-        config.current_duty_cycle,
-        context.config.max_duty_cycle
-    );
-
-    ch.set_duty_cycle(config.current_duty_cycle);
-    if config.enabled {
-        ch.enable();
-    } else {
-        ch.disable();
-    }
-    context.config.channels[channel as usize] = config;
+    let (channel, mut config) = rqst;
+    context.servos.lock(|servos| {
+        let servos = &mut *servos.borrow_mut();
+        let mut ch = get_channel(&mut servos.pwm, channel);
+
+        let limited = config.limited_duty_cycle();
+        if limited != config.current_duty_cycle {
+            host_warn!(
+                "Channel {} duty cycle {} is past the soft limits, limited to {}",
+                channel as usize + 1,
+                config.current_duty_cycle,
+                limited
+            );
+            config.current_duty_cycle = limited;
+        }
+        config.angle_step = config.quantization_step();
+        let output = config.output_duty_cycle();
+
+        defmt::info!(
+            "Configuring channel {}: {}/{}",
+            channel as usize,
+            output,
+            servos.config.max_duty_cycle
+        );
+
+        ch.set_duty_cycle(output);
+        if config.enabled {
+            ch.enable();
+        } else {
+            ch.disable();
+        }
+        servos.config.channels[channel as usize] = config;
+    });
+    config
 }
 
 fn get_servo_config_handler(context: &mut Context, _header: VarHeader, _rqst: ()) -> ServoConfig {
     defmt::info!("get_servo_config");
-    context.config.clone()
+    context.servos.lock(|servos| servos.borrow().config.clone())
 }
 
 fn get_channel<'d>(
-    pwm: &'d mut SimplePwm<peripherals::TIM4>,
+    pwm: &'d mut SimplePwm<ServoTimer>,
     channel: PwmChannel,
-) -> SimplePwmChannel<'d, peripherals::TIM4> {
+) -> SimplePwmChannel<'d, ServoTimer> {
     match channel {
         PwmChannel::Channel1 => pwm.ch1(),
         PwmChannel::Channel2 => pwm.ch2(),
@@ -195,19 +527,53 @@ fn get_channel<'d>(
 fn set_frequency_handler(context: &mut Context, _header: VarHeader, rqst: u32) {
     defmt::info!("set_frequency");
 
-    context.pwm.ch1().disable();
-    context.pwm.ch2().disable();
-    context.pwm.ch3().disable();
-    context.pwm.ch4().disable();
+    context.servos.lock(|servos| {
+        let servos = &mut *servos.borrow_mut();
+        servos.pwm.ch1().disable();
+        servos.pwm.ch2().disable();
+        servos.pwm.ch3().disable();
+        servos.pwm.ch4().disable();
+
+        servos.pwm.set_frequency(Hertz(rqst));
+        host_warn!(
+            "Frequency change, max duty cycle changed from {} to {}. Disabling all channels...",
+            servos.config.max_duty_cycle,
+            servos.pwm.max_duty_cycle()
+        );
+
+        for i in 0..4 {
+            servos.config.channels[i].enabled = false;
+        }
+    });
+}
 
-    context.pwm.set_frequency(Hertz(rqst));
-    defmt::warn!(
-        "Frequency change, max duty cycle changed from {} to {}. Disabling all channels...",
-        context.config.max_duty_cycle,
-        context.pwm.max_duty_cycle()
-    );
+fn set_failsafe_timeout_handler(context: &mut Context, _header: VarHeader, rqst: u32) {
+    defmt::info!("set_failsafe_timeout: {} ms", rqst);
+    context
+        .servos
+        .lock(|servos| servos.borrow_mut().config.failsafe_timeout_ms = rqst);
+}
 
-    for i in 0..4 {
-        context.config.channels[i].enabled = false;
-    }
+fn servo_feedback_handler(
+    context: &mut Context,
+    _header: VarHeader,
+    rqst: PwmChannel,
+) -> ServoFeedback {
+    context.servos.lock(|servos| {
+        let servos = servos.borrow();
+        let config = &servos.config.channels[rqst as usize];
+        let raw = servos.feedback[rqst as usize];
+        ServoFeedback {
+            commanded_angle: config.duty_cycle_to_angle(config.current_duty_cycle),
+            measured_angle: raw.map(|raw| config.feedback_to_angle(raw)),
+            raw,
+        }
+    })
+}
+
+fn set_current_config_handler(context: &mut Context, _header: VarHeader, rqst: CurrentConfig) {
+    defmt::info!("set_current_config");
+    context
+        .servos
+        .lock(|servos| servos.borrow_mut().config.current = rqst);
 }
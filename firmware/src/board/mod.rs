@@ -0,0 +1,79 @@
+//! Board support. The board is selected with one of the cargo features of this crate,
+//! this module provides the clock setup, the USB driver and the default pin maps for it.
+
+use protocol::board::Board;
+
+#[cfg(feature = "stm32f1")]
+mod stm32f1;
+#[cfg(feature = "stm32f1")]
+pub use stm32f1::*;
+
+#[cfg(feature = "stm32f4")]
+mod stm32f4;
+#[cfg(feature = "stm32f4")]
+pub use stm32f4::*;
+
+#[cfg(not(any(feature = "stm32f1", feature = "stm32f4")))]
+compile_error!("No board selected, enable one of the board features of the firmware crate.");
+
+#[cfg(all(feature = "stm32f1", feature = "stm32f4"))]
+compile_error!("Only one board can be selected at a time.");
+
+#[cfg(feature = "bluepill-f103c8")]
+pub const BOARD: Board = Board::BluepillF103c8;
+#[cfg(feature = "bluepill-f103cb")]
+pub const BOARD: Board = Board::BluepillF103cb;
+#[cfg(feature = "nucleo-f103rb")]
+pub const BOARD: Board = Board::NucleoF103rb;
+#[cfg(feature = "blackpill-f401")]
+pub const BOARD: Board = Board::BlackpillF401;
+#[cfg(feature = "blackpill-f411")]
+pub const BOARD: Board = Board::BlackpillF411;
+
+/// Timer driving the servo outputs, the same on every supported board.
+pub type ServoTimer = embassy_stm32::peripherals::TIM4;
+
+/// Servo timer and its four output pins: `(TIM4, PB6, PB7, PB8, PB9)`.
+/// Takes the peripherals returned by `embassy_stm32::init`.
+#[macro_export]
+macro_rules! servo_pins {
+    ($p:ident) => {
+        ($p.TIM4, $p.PB6, $p.PB7, $p.PB8, $p.PB9)
+    };
+}
+
+/// ADC and the feedback inputs of the four servo channels: `(ADC1, [PA0, PA1, PA4, PB0])`,
+/// the A0-A3 pins of the Arduino header on the Nucleo. Read them with `read_adc`.
+#[macro_export]
+macro_rules! servo_feedback_pins {
+    ($p:ident) => {{
+        use embassy_stm32::adc::AdcChannel;
+        (
+            $p.ADC1,
+            [
+                $p.PA0.degrade_adc(),
+                $p.PA1.degrade_adc(),
+                $p.PA4.degrade_adc(),
+                $p.PB0.degrade_adc(),
+            ],
+        )
+    }};
+}
+
+/// Current sense inputs of the servos: `([PA2, PA3, PA6, PA7], PB1)`, one per channel and
+/// one for the supply rail. PA2 and PA3 are wired to the ST-LINK UART on the Nucleo.
+#[macro_export]
+macro_rules! servo_current_pins {
+    ($p:ident) => {{
+        use embassy_stm32::adc::AdcChannel;
+        (
+            [
+                $p.PA2.degrade_adc(),
+                $p.PA3.degrade_adc(),
+                $p.PA6.degrade_adc(),
+                $p.PA7.degrade_adc(),
+            ],
+            $p.PB1.degrade_adc(),
+        )
+    }};
+}
@@ -0,0 +1,102 @@
+use embassy_stm32::{
+    Config, Peripheral,
+    adc::{Adc, AnyAdcChannel, SampleTime},
+    bind_interrupts,
+    gpio::{Level, Output, Speed},
+    peripherals,
+    time::Hertz,
+    usb::{self, DpPin, Instance},
+};
+use embassy_time::Timer;
+
+bind_interrupts!(pub struct UsbIrqs {
+    USB_LP_CAN1_RX0 => usb::InterruptHandler<peripherals::USB>;
+});
+
+pub type AppDriver = usb::Driver<'static, peripherals::USB>;
+
+/// Vector table of the system memory bootloader. On the F103 it only talks over USART1.
+pub const SYSTEM_MEMORY: u32 = 0x1fff_f000;
+
+/// The on-board LED, PC13 on the Blue Pill and LD2 on PA5 for the Nucleo.
+#[cfg(not(feature = "nucleo-f103rb"))]
+#[macro_export]
+macro_rules! status_led {
+    ($p:ident) => {
+        $p.PC13
+    };
+}
+#[cfg(feature = "nucleo-f103rb")]
+#[macro_export]
+macro_rules! status_led {
+    ($p:ident) => {
+        $p.PA5
+    };
+}
+
+/// The Blue Pill LED is wired to 3.3 V, so it lights up when the pin is low.
+pub const STATUS_LED_ACTIVE_LOW: bool = cfg!(not(feature = "nucleo-f103rb"));
+
+pub fn enable_usb_clock(config: &mut Config) {
+    use embassy_stm32::rcc::*;
+    config.rcc.hse = Some(Hse {
+        freq: Hertz(8_000_000),
+        // The Nucleo has no crystal and gets the clock from the MCO of its ST-LINK.
+        mode: if cfg!(feature = "nucleo-f103rb") {
+            HseMode::Bypass
+        } else {
+            HseMode::Oscillator
+        },
+    });
+    config.rcc.pll = Some(Pll {
+        src: PllSource::HSE,
+        prediv: PllPreDiv::DIV1,
+        mul: PllMul::MUL9,
+    });
+    config.rcc.sys = Sysclk::PLL1_P;
+    config.rcc.ahb_pre = AHBPrescaler::DIV1;
+    config.rcc.apb1_pre = APBPrescaler::DIV2;
+    config.rcc.apb2_pre = APBPrescaler::DIV1;
+}
+
+pub async fn reset_condition<T: Instance>(dplus_pin: &mut impl Peripheral<P = impl DpPin<T>>) {
+    // BluePill board has a pull-up resistor on the D+ line.
+    // Pull the D+ pin down to send a RESET condition to the USB bus.
+    // This forced reset is needed only for development, without it host
+    // will not reset your device when you upload new firmware.
+    let _dp = Output::new(dplus_pin, Level::Low, Speed::Low);
+    Timer::after_millis(10).await;
+}
+
+pub async fn usb_driver(
+    usb: peripherals::USB,
+    mut dp: peripherals::PA12,
+    dm: peripherals::PA11,
+) -> AppDriver {
+    reset_condition(&mut dp).await;
+    usb::Driver::new(usb, UsbIrqs, dp, dm)
+}
+
+/// Create the USB driver on PA11/PA12, use in `main` with the initialized peripherals.
+#[macro_export]
+macro_rules! usb_driver {
+    ($p:ident) => {
+        $crate::board::usb_driver($p.USB, $p.PA12, $p.PA11).await
+    };
+}
+
+/// ADC with the longest sample time, for high impedance sources like servo potentiometers.
+pub fn slow_adc(adc: peripherals::ADC1) -> Adc<'static, peripherals::ADC1> {
+    let mut adc = Adc::new(adc);
+    adc.set_sample_time(SampleTime::CYCLES239_5);
+    adc
+}
+
+/// The F1 conversion waits on the `ADC1_2` interrupt, which the binary has to bind to
+/// `adc::InterruptHandler<peripherals::ADC1>`.
+pub async fn read_adc(
+    adc: &mut Adc<'static, peripherals::ADC1>,
+    channel: &mut AnyAdcChannel<peripherals::ADC1>,
+) -> u16 {
+    adc.read(channel).await
+}
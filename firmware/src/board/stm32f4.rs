@@ -0,0 +1,95 @@
+use embassy_stm32::{
+    Config,
+    adc::{Adc, AnyAdcChannel, SampleTime},
+    bind_interrupts, peripherals,
+    time::Hertz,
+    usb,
+};
+use static_cell::ConstStaticCell;
+
+bind_interrupts!(pub struct UsbIrqs {
+    OTG_FS => usb::InterruptHandler<peripherals::USB_OTG_FS>;
+});
+
+pub type AppDriver = usb::Driver<'static, peripherals::USB_OTG_FS>;
+
+/// Vector table of the system memory bootloader, which supports USB DFU.
+pub const SYSTEM_MEMORY: u32 = 0x1fff_0000;
+
+static EP_OUT_BUFFER: ConstStaticCell<[u8; 256]> = ConstStaticCell::new([0; 256]);
+
+/// The on-board LED of the Black Pill, PC13.
+#[macro_export]
+macro_rules! status_led {
+    ($p:ident) => {
+        $p.PC13
+    };
+}
+
+/// The Black Pill LED is wired to 3.3 V, so it lights up when the pin is low.
+pub const STATUS_LED_ACTIVE_LOW: bool = true;
+
+pub fn enable_usb_clock(config: &mut Config) {
+    use embassy_stm32::rcc::*;
+    config.rcc.hse = Some(Hse {
+        freq: Hertz(25_000_000),
+        mode: HseMode::Oscillator,
+    });
+    // 1 MHz PLL input. The F401 runs at 84 MHz and the F411 at 96 MHz,
+    // both with the 48 MHz USB clock on the Q output.
+    config.rcc.pll_src = PllSource::HSE;
+    config.rcc.pll = Some(Pll {
+        prediv: PllPreDiv::DIV25,
+        #[cfg(feature = "blackpill-f401")]
+        mul: PllMul::MUL336,
+        #[cfg(feature = "blackpill-f411")]
+        mul: PllMul::MUL384,
+        divp: Some(PllPDiv::DIV4),
+        #[cfg(feature = "blackpill-f401")]
+        divq: Some(PllQDiv::DIV7),
+        #[cfg(feature = "blackpill-f411")]
+        divq: Some(PllQDiv::DIV8),
+        divr: None,
+    });
+    config.rcc.sys = Sysclk::PLL1_P;
+    config.rcc.ahb_pre = AHBPrescaler::DIV1;
+    config.rcc.apb1_pre = APBPrescaler::DIV2;
+    config.rcc.apb2_pre = APBPrescaler::DIV1;
+}
+
+/// The OTG peripheral drives the D+ pull-up itself, so unlike the F1 boards there is
+/// no need to force a bus reset. Async only to keep the signature of the F1 version.
+pub async fn usb_driver(
+    usb: peripherals::USB_OTG_FS,
+    dp: peripherals::PA12,
+    dm: peripherals::PA11,
+) -> AppDriver {
+    let mut config = usb::Config::default();
+    // VBUS is not routed to PA9 on the Black Pill.
+    config.vbus_detection = false;
+    usb::Driver::new_fs(usb, UsbIrqs, dp, dm, EP_OUT_BUFFER.take(), config)
+}
+
+/// Create the USB driver on PA11/PA12, use in `main` with the initialized peripherals.
+#[macro_export]
+macro_rules! usb_driver {
+    ($p:ident) => {
+        $crate::board::usb_driver($p.USB_OTG_FS, $p.PA12, $p.PA11).await
+    };
+}
+
+/// ADC with the longest sample time, for high impedance sources like servo potentiometers.
+pub fn slow_adc(adc: peripherals::ADC1) -> Adc<'static, peripherals::ADC1> {
+    let mut adc = Adc::new(adc);
+    adc.set_sample_time(SampleTime::CYCLES480);
+    adc
+}
+
+/// Blocking on the F4, a conversion only takes a few microseconds. Async only to keep the
+/// signature of the F1 version.
+pub async fn read_adc(
+    adc: &mut Adc<'static, peripherals::ADC1>,
+    channel: &mut AnyAdcChannel<peripherals::ADC1>,
+) -> u16 {
+    adc.blocking_read(channel)
+}
@@ -0,0 +1,148 @@
+use core::{fmt::Write, mem::MaybeUninit, panic::PanicInfo};
+
+use cortex_m::peripheral::{DCB, SCB};
+use cortex_m_rt::{ExceptionFrame, exception};
+use embassy_time::Instant;
+use heapless::String;
+use postcard_rpc::header::VarHeader;
+use protocol::utils::{CRASH_MESSAGE_LEN, CrashKind, CrashReport, LastCrash};
+
+/// Marks a valid record, anything else is the random content of RAM after power-up.
+const CRASH_MAGIC: u32 = 0xdead_c0de;
+
+#[repr(C)]
+struct CrashRecord {
+    magic: u32,
+    /// Bitwise complement of `magic`, makes a false positive after power-up even less likely.
+    check: u32,
+    /// `CrashKind` as a number, an invalid enum in uninitialized RAM would be undefined behaviour.
+    kind: u32,
+    uptime_ms: u64,
+    registers: [u32; 8],
+    len: usize,
+    message: [u8; CRASH_MESSAGE_LEN],
+}
+
+// In the RAM kept out of reach of the runtime and the bootloader, so the record survives
+// the reset after a crash.
+#[unsafe(link_section = ".noinit.CRASH")]
+static mut CRASH: MaybeUninit<CrashRecord> = MaybeUninit::uninit();
+
+/// Get the report of the crash that caused the last reset, if any.
+pub fn last_crash() -> LastCrash {
+    // SAFETY: every field is valid for any bit pattern, so reading the record
+    // from uninitialized RAM is fine. It is only used once the magic matches.
+    let record = unsafe { (&raw const CRASH).cast::<CrashRecord>().read_volatile() };
+    if record.magic != CRASH_MAGIC || record.check != !CRASH_MAGIC {
+        return None;
+    }
+
+    let len = record.len.min(CRASH_MESSAGE_LEN);
+    // The message may be cut in the middle of a character, keep the valid part.
+    let message = match core::str::from_utf8(&record.message[..len]) {
+        Ok(message) => message,
+        Err(e) => unsafe { core::str::from_utf8_unchecked(&record.message[..e.valid_up_to()]) },
+    };
+
+    Some(CrashReport {
+        kind: match record.kind {
+            0 => CrashKind::Panic,
+            _ => CrashKind::HardFault,
+        },
+        uptime_ms: record.uptime_ms,
+        message: String::try_from(message).unwrap_or_default(),
+        registers: record.registers,
+    })
+}
+
+/// Forget the last crash report.
+pub fn clear_crash() {
+    unsafe { (&raw mut CRASH).cast::<u32>().write_volatile(0) };
+}
+
+pub fn crash_report_handler<C>(_context: &mut C, _header: VarHeader, _rqst: ()) -> LastCrash {
+    defmt::info!("crash_report");
+    last_crash()
+}
+
+pub fn clear_crash_report_handler<C>(_context: &mut C, _header: VarHeader, _rqst: ()) {
+    defmt::info!("clear_crash_report");
+    clear_crash();
+}
+
+/// Fills the record message, dropping whatever does not fit.
+struct MessageWriter<'a> {
+    record: &'a mut CrashRecord,
+}
+
+impl Write for MessageWriter<'_> {
+    fn write_str(&mut self, s: &str) -> core::fmt::Result {
+        let len = self.record.len;
+        let n = s.len().min(CRASH_MESSAGE_LEN - len);
+        self.record.message[len..len + n].copy_from_slice(&s.as_bytes()[..n]);
+        self.record.len += n;
+        Ok(())
+    }
+}
+
+fn store_crash(kind: CrashKind, registers: [u32; 8]) -> &'static mut CrashRecord {
+    // SAFETY: only called once the program has stopped, nothing else touches the record.
+    let record = unsafe { &mut *(&raw mut CRASH) };
+    record.write(CrashRecord {
+        magic: 0,
+        check: !CRASH_MAGIC,
+        kind: kind as u32,
+        uptime_ms: Instant::now().as_millis(),
+        registers,
+        len: 0,
+        message: [0; CRASH_MESSAGE_LEN],
+    })
+}
+
+/// With a debugger attached stop where we are, otherwise reset so the board comes back.
+fn halt_or_reset() -> ! {
+    if DCB::is_debugger_attached() {
+        loop {
+            cortex_m::asm::bkpt();
+        }
+    }
+    SCB::sys_reset();
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    cortex_m::interrupt::disable();
+    defmt::error!("{}", defmt::Display2Format(info));
+
+    let record = store_crash(CrashKind::Panic, [0; 8]);
+    let mut writer = MessageWriter { record };
+    if let Some(location) = info.location() {
+        let _ = write!(writer, "{}: ", location);
+    }
+    let _ = write!(writer, "{}", info.message());
+    writer.record.magic = CRASH_MAGIC;
+
+    halt_or_reset()
+}
+
+#[exception]
+unsafe fn HardFault(frame: &ExceptionFrame) -> ! {
+    defmt::error!("HardFault at {:#010x}", frame.pc());
+
+    let record = store_crash(
+        CrashKind::HardFault,
+        [
+            frame.r0(),
+            frame.r1(),
+            frame.r2(),
+            frame.r3(),
+            frame.r12(),
+            frame.lr(),
+            frame.pc(),
+            frame.xpsr(),
+        ],
+    );
+    record.magic = CRASH_MAGIC;
+
+    halt_or_reset()
+}
@@ -1,62 +1,78 @@
 #![no_std]
 #![no_main]
 
-use embassy_stm32::{
-    Config, Peripheral,
-    gpio::{Level, Output, Speed},
-    peripherals,
-    time::Hertz,
-    usb::{self, DpPin, Instance},
-};
+use core::fmt::Write;
+
 use embassy_sync::blocking_mutex::raw::ThreadModeRawMutex;
-use embassy_time::Timer;
 use embassy_usb::UsbDevice;
+use heapless::String;
+use metrics::{MeteredTx, UsbHandler};
 use postcard_rpc::server::impls::embassy_usb_v0_4::{
     PacketBuffers,
     dispatch_impl::{WireRxImpl, WireStorage, WireTxImpl},
 };
-use static_cell::ConstStaticCell;
+use static_cell::{ConstStaticCell, StaticCell};
+
+pub mod board;
+pub mod crash;
+pub mod logging;
+pub mod metrics;
+pub mod reset;
+pub mod status;
+pub mod storage;
+pub mod update;
+
+pub use board::*;
+pub use crash::{clear_crash_report_handler, crash_report_handler};
+pub use metrics::{Metered, metrics_handler};
+pub use reset::{enter_bootloader_handler, reset_handler};
+pub use status::identify_handler;
+pub use storage::{device_info_handler, get_alias_handler, set_alias_handler};
+pub use update::{
+    confirm_update_handler, finish_update_handler, start_update_handler, update_info_handler,
+    write_update_chunk_handler,
+};
 
-pub type AppDriver = usb::Driver<'static, peripherals::USB>;
 pub type AppStorage = WireStorage<ThreadModeRawMutex, AppDriver, 256, 256, 64, 256>;
 pub type BufStorage = PacketBuffers<1024, 1024>;
-pub type AppTx = WireTxImpl<ThreadModeRawMutex, AppDriver>;
+pub type AppTx = MeteredTx<WireTxImpl<ThreadModeRawMutex, AppDriver>>;
 pub type AppRx = WireRxImpl<AppDriver>;
 
 pub static PBUFS: ConstStaticCell<BufStorage> = ConstStaticCell::new(BufStorage::new());
 pub static STORAGE: AppStorage = AppStorage::new();
+static USB_HANDLER: StaticCell<UsbHandler> = StaticCell::new();
+static PRODUCT: StaticCell<String<64>> = StaticCell::new();
 
-pub fn enable_usb_clock(config: &mut Config) {
-    use embassy_stm32::rcc::*;
-    config.rcc.hse = Some(Hse {
-        freq: Hertz(8_000_000),
-        // Oscillator for bluepill, Bypass for nucleos.
-        mode: HseMode::Oscillator,
-    });
-    config.rcc.pll = Some(Pll {
-        src: PllSource::HSE,
-        prediv: PllPreDiv::DIV1,
-        mul: PllMul::MUL9,
-    });
-    config.rcc.sys = Sysclk::PLL1_P;
-    config.rcc.ahb_pre = AHBPrescaler::DIV1;
-    config.rcc.apb1_pre = APBPrescaler::DIV2;
-    config.rcc.apb2_pre = APBPrescaler::DIV1;
+/// Create the USB device and the postcard-rpc transport on top of it, counting the
+/// USB resets and the failed sends for the metrics. Can only be called once.
+pub fn init_usb(
+    driver: AppDriver,
+    config: embassy_usb::Config<'static>,
+    tx_buf: &'static mut [u8],
+) -> (UsbDevice<'static, AppDriver>, AppTx, AppRx) {
+    let (mut builder, tx_impl, rx_impl) = STORAGE.init_without_build(driver, config, tx_buf);
+    builder.handler(USB_HANDLER.init(UsbHandler));
+    (builder.build(), MeteredTx::new(tx_impl), rx_impl)
 }
 
-pub async fn reset_condition<T: Instance>(dplus_pin: &mut impl Peripheral<P = impl DpPin<T>>) {
-    // BluePill board has a pull-up resistor on the D+ line.
-    // Pull the D+ pin down to send a RESET condition to the USB bus.
-    // This forced reset is needed only for development, without it host
-    // will not reset your device when you upload new firmware.
-    let _dp = Output::new(dplus_pin, Level::Low, Speed::Low);
-    Timer::after_millis(10).await;
+/// The product name, followed by the alias if it was set to be shown in the USB product
+/// string. Can only be called once, after `storage::init`.
+fn product_string(product_name: &'static str) -> &'static str {
+    let settings = storage::alias_settings();
+    let mut product = String::new();
+    if !settings.usb_product
+        || settings.alias.is_empty()
+        || write!(product, "{} ({})", product_name, settings.alias).is_err()
+    {
+        return product_name;
+    }
+    PRODUCT.init(product)
 }
 
 pub fn get_usb_config(product_name: &'static str) -> embassy_usb::Config<'static> {
-    let mut config = embassy_usb::Config::new(0xc0de, 0xcafe);
+    let mut config = embassy_usb::Config::new(protocol::device::USB_VID, protocol::device::USB_PID);
     config.manufacturer = Some("QOD Lab");
-    config.product = Some(product_name);
+    config.product = Some(product_string(product_name));
     config.serial_number = Some(embassy_stm32::uid::uid_hex());
 
     defmt::info!("Serial number: {}", embassy_stm32::uid::uid_hex());
@@ -83,9 +99,8 @@ pub async fn idle_task() {
     // In such state flashing new firmware will not be possible without a reset, which our
     // probes (bluepill ST-LINK) do not support.
     // https://embassy.dev/book/#_how_can_i_prevent_the_thread_mode_executor_from_going_to_sleep
-    loop {
-        embassy_futures::yield_now().await;
-    }
+    // The time it gets to run is reported as the idle percentage in the metrics.
+    metrics::idle_loop().await
 }
 
 /// This handles the low level USB management
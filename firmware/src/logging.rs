@@ -0,0 +1,189 @@
+use core::{
+    cell::Cell,
+    fmt::{Arguments, Write},
+    sync::atomic::{AtomicU32, Ordering},
+};
+
+use embassy_sync::{
+    blocking_mutex::{Mutex, raw::CriticalSectionRawMutex},
+    channel::Channel,
+};
+use embassy_time::{Duration, Instant, Timer};
+use heapless::String;
+use postcard_rpc::server::Sender;
+pub use protocol::utils::LogLevel;
+
+use crate::AppTx;
+
+/// Longest message sent to the host, longer ones are truncated.
+pub const MESSAGE_LEN: usize = 96;
+/// Records kept while the host is disconnected, newer ones are dropped when it is full.
+const LOG_BUFFER: usize = 16;
+/// Sustained number of records per second, records above it are dropped.
+const RATE_PER_SECOND: u64 = 20;
+/// Number of records that can be logged at once before the rate limit applies.
+const BURST: u64 = 16;
+const RETRY_INTERVAL: Duration = Duration::from_millis(100);
+
+struct LogRecord {
+    level: LogLevel,
+    timestamp: Instant,
+    message: String<MESSAGE_LEN>,
+}
+
+static RECORDS: Channel<CriticalSectionRawMutex, LogRecord, LOG_BUFFER> = Channel::new();
+static LIMITER: Mutex<CriticalSectionRawMutex, Cell<RateLimiter>> =
+    Mutex::new(Cell::new(RateLimiter::new()));
+static DROPPED: AtomicU32 = AtomicU32::new(0);
+
+/// Token bucket refilled at `RATE_PER_SECOND`, holding at most `BURST` tokens.
+#[derive(Clone, Copy)]
+struct RateLimiter {
+    tokens: u64,
+    refilled_ms: u64,
+}
+
+impl RateLimiter {
+    const fn new() -> Self {
+        Self {
+            tokens: BURST,
+            refilled_ms: 0,
+        }
+    }
+
+    fn take(&mut self, now: Instant) -> bool {
+        let refill = (now.as_millis() - self.refilled_ms) * RATE_PER_SECOND / 1000;
+        if refill > 0 {
+            self.tokens = (self.tokens + refill).min(BURST);
+            // Advance only by the refilled tokens, so the remainder is not lost.
+            self.refilled_ms += refill * 1000 / RATE_PER_SECOND;
+        }
+        if self.tokens == 0 {
+            return false;
+        }
+        self.tokens -= 1;
+        true
+    }
+}
+
+/// Log a record to defmt and queue it for the host. Use the `host_*!` macros instead of calling this directly.
+/// It does not block, so it can be used from handlers and interrupts.
+pub fn log(level: LogLevel, args: Arguments) {
+    let mut message = String::new();
+    // Whatever does not fit in the message is cut off.
+    let _ = message.write_fmt(args);
+
+    match level {
+        LogLevel::Trace => defmt::trace!("{}", message.as_str()),
+        LogLevel::Debug => defmt::debug!("{}", message.as_str()),
+        LogLevel::Info => defmt::info!("{}", message.as_str()),
+        LogLevel::Warn => defmt::warn!("{}", message.as_str()),
+        LogLevel::Error => defmt::error!("{}", message.as_str()),
+    }
+
+    let timestamp = Instant::now();
+    let allowed = LIMITER.lock(|limiter| {
+        let mut state = limiter.get();
+        let allowed = state.take(timestamp);
+        limiter.set(state);
+        allowed
+    });
+    let record = LogRecord {
+        level,
+        timestamp,
+        message,
+    };
+    if !allowed || RECORDS.try_send(record).is_err() {
+        DROPPED.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+/// Publishes the queued records on `LoggingTopic` as "[LEVEL seconds] message".
+/// While the host is disconnected, the oldest record is retried and the rest stay queued.
+#[embassy_executor::task]
+pub async fn logging_task(sender: Sender<AppTx>) {
+    loop {
+        let record = RECORDS.receive().await;
+
+        let dropped = DROPPED.swap(0, Ordering::Relaxed);
+        if dropped > 0 {
+            publish(
+                &sender,
+                LogLevel::Warn,
+                Instant::now(),
+                format_args!("{} log records dropped", dropped),
+            )
+            .await;
+        }
+
+        publish(
+            &sender,
+            record.level,
+            record.timestamp,
+            format_args!("{}", record.message.as_str()),
+        )
+        .await;
+    }
+}
+
+async fn publish(
+    sender: &Sender<AppTx>,
+    level: LogLevel,
+    timestamp: Instant,
+    message: Arguments<'_>,
+) {
+    let millis = timestamp.as_millis();
+    while sender
+        .log_fmt(format_args!(
+            "[{} {}.{:03}] {}",
+            level.as_str(),
+            millis / 1000,
+            millis % 1000,
+            message
+        ))
+        .await
+        .is_err()
+    {
+        Timer::after(RETRY_INTERVAL).await;
+    }
+}
+
+/// Log a trace message to defmt and the host, formatted like `format!`.
+#[macro_export]
+macro_rules! host_trace {
+    ($($arg:tt)*) => {
+        $crate::logging::log($crate::logging::LogLevel::Trace, format_args!($($arg)*))
+    };
+}
+
+/// Log a debug message to defmt and the host, formatted like `format!`.
+#[macro_export]
+macro_rules! host_debug {
+    ($($arg:tt)*) => {
+        $crate::logging::log($crate::logging::LogLevel::Debug, format_args!($($arg)*))
+    };
+}
+
+/// Log an info message to defmt and the host, formatted like `format!`.
+#[macro_export]
+macro_rules! host_info {
+    ($($arg:tt)*) => {
+        $crate::logging::log($crate::logging::LogLevel::Info, format_args!($($arg)*))
+    };
+}
+
+/// Log a warning to defmt and the host, formatted like `format!`.
+#[macro_export]
+macro_rules! host_warn {
+    ($($arg:tt)*) => {
+        $crate::logging::log($crate::logging::LogLevel::Warn, format_args!($($arg)*))
+    };
+}
+
+/// Log an error to defmt and the host, formatted like `format!`.
+#[macro_export]
+macro_rules! host_error {
+    ($($arg:tt)*) => {
+        $crate::logging::log($crate::logging::LogLevel::Error, format_args!($($arg)*))
+    };
+}
@@ -0,0 +1,266 @@
+//! Runtime counters reported to the host with the `GetMetrics` endpoint.
+//!
+//! The counters are hooked into the postcard-rpc server with the `Metered` dispatcher and the
+//! `MeteredTx` sender, and into the USB stack with `UsbHandler`. Binaries get them by wrapping
+//! their dispatcher and creating the USB device with `init_usb`.
+
+use core::{
+    cell::Cell,
+    fmt::Arguments,
+    sync::atomic::{AtomicU32, Ordering},
+};
+
+use cortex_m::peripheral::DWT;
+use embassy_sync::blocking_mutex::{Mutex, raw::CriticalSectionRawMutex};
+use embassy_time::{Duration, Instant};
+use postcard_rpc::{
+    Key,
+    header::{VarHeader, VarKey, VarKeyKind},
+    server::{Dispatch, Sender, WireTx},
+    standard_icd::{ERROR_KEY, WireError},
+};
+use protocol::metrics::{EndpointRequests, MAX_ENDPOINTS, Metrics};
+use serde::Serialize;
+
+use crate::status;
+
+/// Pattern filling the unused stack, the deepest overwritten word is the peak usage.
+const STACK_PAINT: u32 = 0xcccc_cccc;
+
+/// The idle percentage is averaged over this window.
+const IDLE_WINDOW: Duration = Duration::from_secs(1);
+
+type EndpointList = &'static [(&'static str, Key, Key)];
+
+static ENDPOINTS: Mutex<CriticalSectionRawMutex, Cell<EndpointList>> = Mutex::new(Cell::new(&[]));
+static REQUESTS: [AtomicU32; MAX_ENDPOINTS] = [const { AtomicU32::new(0) }; MAX_ENDPOINTS];
+static DESERIALIZATION_ERRORS: AtomicU32 = AtomicU32::new(0);
+static DROPPED_MESSAGES: AtomicU32 = AtomicU32::new(0);
+static USB_RESETS: AtomicU32 = AtomicU32::new(0);
+static USB_CONFIGURED: AtomicU32 = AtomicU32::new(0);
+/// Tenths of a percent, there is no atomic float.
+static IDLE_PERMILLE: AtomicU32 = AtomicU32::new(0);
+
+unsafe extern "C" {
+    // Top of the stack, provided by the cortex-m-rt linker script.
+    static _stack_start: u32;
+}
+
+/// Paint the free stack and start the cycle counter used for the idle time.
+/// Has to be called early in `main`, while little of the stack is in use.
+pub fn init() {
+    // The rest of the core peripherals are not used by embassy.
+    let mut cp = unsafe { cortex_m::Peripherals::steal() };
+    cp.DCB.enable_trace();
+    cp.DWT.enable_cycle_counter();
+
+    // Leave some room for the frame of this function.
+    let end = (cortex_m::register::msp::read() as *mut u32).wrapping_sub(16);
+    let mut word = cortex_m_rt::heap_start();
+    while word < end {
+        unsafe {
+            word.write_volatile(STACK_PAINT);
+            word = word.add(1);
+        }
+    }
+}
+
+fn stack_top() -> *const u32 {
+    &raw const _stack_start
+}
+
+fn stack_size() -> u32 {
+    stack_top() as u32 - cortex_m_rt::heap_start() as u32
+}
+
+fn stack_peak() -> u32 {
+    let top = stack_top();
+    let mut word = cortex_m_rt::heap_start() as *const u32;
+    while word < top && unsafe { word.read_volatile() } == STACK_PAINT {
+        word = unsafe { word.add(1) };
+    }
+    top as u32 - word as u32
+}
+
+pub fn metrics_handler<C>(_context: &mut C, _header: VarHeader, _rqst: ()) -> Metrics {
+    defmt::info!("metrics");
+
+    let endpoints = ENDPOINTS.lock(Cell::get);
+    let requests = endpoints
+        .iter()
+        .zip(&REQUESTS)
+        .map(|((_, key, _), count)| EndpointRequests {
+            key: key.to_bytes(),
+            count: count.load(Ordering::Relaxed),
+        })
+        .filter(|requests| requests.count > 0)
+        .collect();
+
+    Metrics {
+        uptime_ms: Instant::now().as_millis(),
+        requests,
+        deserialization_errors: DESERIALIZATION_ERRORS.load(Ordering::Relaxed),
+        dropped_messages: DROPPED_MESSAGES.load(Ordering::Relaxed),
+        usb_resets: USB_RESETS.load(Ordering::Relaxed),
+        usb_reconnects: USB_CONFIGURED.load(Ordering::Relaxed).saturating_sub(1),
+        stack_peak: stack_peak(),
+        stack_size: stack_size(),
+        idle_percent: IDLE_PERMILLE.load(Ordering::Relaxed) as f32 / 10.0,
+    }
+}
+
+/// Dispatcher counting the requests per endpoint before handing them to the application.
+pub struct Metered<D> {
+    inner: D,
+}
+
+impl<D: Dispatch> Metered<D> {
+    /// `endpoints` is the `ENDPOINT_LIST.endpoints` of the protocol module.
+    pub fn new(inner: D, endpoints: EndpointList) -> Self {
+        ENDPOINTS.lock(|e| e.set(endpoints));
+        Self { inner }
+    }
+}
+
+impl<D: Dispatch> Dispatch for Metered<D> {
+    type Tx = D::Tx;
+
+    fn min_key_len(&self) -> VarKeyKind {
+        self.inner.min_key_len()
+    }
+
+    async fn handle(
+        &mut self,
+        tx: &Sender<Self::Tx>,
+        hdr: &VarHeader,
+        body: &[u8],
+    ) -> Result<(), <Self::Tx as WireTx>::Error> {
+        let endpoints = ENDPOINTS.lock(Cell::get);
+        // Keys of different lengths compare equal if the shorter one matches.
+        if let Some(i) = endpoints
+            .iter()
+            .position(|(_, key, _)| hdr.key == VarKey::Key8(*key))
+        {
+            if let Some(count) = REQUESTS.get(i) {
+                count.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+        status::request_received();
+        self.inner.handle(tx, hdr, body).await
+    }
+}
+
+/// Sender counting the failed sends and the deserialization errors reported to the host.
+#[derive(Clone)]
+pub struct MeteredTx<Tx> {
+    inner: Tx,
+}
+
+impl<Tx> MeteredTx<Tx> {
+    pub fn new(inner: Tx) -> Self {
+        Self { inner }
+    }
+}
+
+fn count_dropped<E>(result: Result<(), E>) -> Result<(), E> {
+    if result.is_err() {
+        DROPPED_MESSAGES.fetch_add(1, Ordering::Relaxed);
+    }
+    result
+}
+
+/// The dispatcher answers a request it cannot deserialize with `WireError::DeserFailed`.
+fn is_deserialization_error<T: Serialize + ?Sized>(hdr: &VarHeader, msg: &T) -> bool {
+    if hdr.key != VarKey::Key8(ERROR_KEY) {
+        return false;
+    }
+    let mut buf = [0; 16];
+    postcard::to_slice(msg, &mut buf)
+        .ok()
+        .and_then(|bytes| postcard::from_bytes::<WireError>(bytes).ok())
+        .is_some_and(|error| matches!(error, WireError::DeserFailed))
+}
+
+impl<Tx: WireTx> WireTx for MeteredTx<Tx> {
+    type Error = Tx::Error;
+
+    async fn wait_connection(&self) {
+        self.inner.wait_connection().await
+    }
+
+    async fn send<T: Serialize + ?Sized>(
+        &self,
+        hdr: VarHeader,
+        msg: &T,
+    ) -> Result<(), Self::Error> {
+        if is_deserialization_error(&hdr, msg) {
+            DESERIALIZATION_ERRORS.fetch_add(1, Ordering::Relaxed);
+        }
+        count_dropped(self.inner.send(hdr, msg).await)
+    }
+
+    async fn send_raw(&self, buf: &[u8]) -> Result<(), Self::Error> {
+        count_dropped(self.inner.send_raw(buf).await)
+    }
+
+    async fn send_log_str(&self, kkind: VarKeyKind, s: &str) -> Result<(), Self::Error> {
+        count_dropped(self.inner.send_log_str(kkind, s).await)
+    }
+
+    async fn send_log_fmt<'a>(
+        &self,
+        kkind: VarKeyKind,
+        a: Arguments<'a>,
+    ) -> Result<(), Self::Error> {
+        count_dropped(self.inner.send_log_fmt(kkind, a).await)
+    }
+}
+
+/// Counts the bus resets and the enumerations of the device, and shows them on the status LED.
+pub struct UsbHandler;
+
+impl embassy_usb::Handler for UsbHandler {
+    fn reset(&mut self) {
+        USB_RESETS.fetch_add(1, Ordering::Relaxed);
+        status::set_usb_configured(false);
+    }
+
+    fn configured(&mut self, configured: bool) {
+        if configured {
+            USB_CONFIGURED.fetch_add(1, Ordering::Relaxed);
+        }
+        status::set_usb_configured(configured);
+    }
+}
+
+/// Keeps the executor busy and measures how much of the time it has nothing else to run.
+///
+/// Each yield goes around the executor once. When no other task is ready it comes back after
+/// the executor overhead alone, the fastest round trip seen. Anything longer was spent in
+/// other tasks or interrupts.
+pub(crate) async fn idle_loop() -> ! {
+    let mut window_start = Instant::now();
+    let mut window_cycles = DWT::cycle_count();
+    let mut busy = 0u32;
+    let mut min_round_trip = u32::MAX;
+
+    loop {
+        let before = DWT::cycle_count();
+        embassy_futures::yield_now().await;
+        let after = DWT::cycle_count();
+
+        let round_trip = after.wrapping_sub(before);
+        min_round_trip = min_round_trip.min(round_trip);
+        busy = busy.saturating_add(round_trip - min_round_trip);
+
+        if window_start.elapsed() >= IDLE_WINDOW {
+            let total = after.wrapping_sub(window_cycles).max(1) as u64;
+            let idle = total.saturating_sub(busy as u64) * 1000 / total;
+            IDLE_PERMILLE.store(idle as u32, Ordering::Relaxed);
+
+            window_start = Instant::now();
+            window_cycles = after;
+            busy = 0;
+        }
+    }
+}
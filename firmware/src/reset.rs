@@ -0,0 +1,83 @@
+use core::mem::MaybeUninit;
+
+use cortex_m::peripheral::SCB;
+use embassy_sync::{blocking_mutex::raw::CriticalSectionRawMutex, signal::Signal};
+use embassy_time::Timer;
+use postcard_rpc::header::VarHeader;
+
+use crate::board::SYSTEM_MEMORY;
+
+/// Stored before resetting into the bootloader. A custom bootloader placed before the
+/// firmware can check for it too, otherwise the firmware jumps to the system memory one.
+pub const BOOTLOADER_MAGIC: u32 = 0xb007_10ad;
+
+// In the RAM kept out of reach of the runtime and the bootloader, so the request survives
+// the reset.
+#[unsafe(link_section = ".noinit.BOOTLOADER")]
+static mut BOOTLOADER_REQUEST: MaybeUninit<u32> = MaybeUninit::uninit();
+
+#[derive(Clone, Copy)]
+enum ResetKind {
+    Reset,
+    Bootloader,
+}
+
+static RESET: Signal<CriticalSectionRawMutex, ResetKind> = Signal::new();
+
+fn bootloader_request() -> u32 {
+    // SAFETY: any bit pattern is a valid u32, the value is only trusted if it matches.
+    unsafe {
+        (&raw const BOOTLOADER_REQUEST)
+            .cast::<u32>()
+            .read_volatile()
+    }
+}
+
+fn set_bootloader_request(value: u32) {
+    unsafe {
+        (&raw mut BOOTLOADER_REQUEST)
+            .cast::<u32>()
+            .write_volatile(value)
+    }
+}
+
+/// Reset the MCU once the response of the current request is sent.
+pub fn request_reset() {
+    RESET.signal(ResetKind::Reset);
+}
+
+pub fn reset_handler<C>(_context: &mut C, _header: VarHeader, _rqst: ()) {
+    defmt::info!("reset");
+    request_reset();
+}
+
+pub fn enter_bootloader_handler<C>(_context: &mut C, _header: VarHeader, _rqst: ()) {
+    defmt::info!("enter_bootloader");
+    RESET.signal(ResetKind::Bootloader);
+}
+
+/// Resets the MCU when requested by the host. The reset is delayed, so the response
+/// of the endpoint is sent before the board drops off the bus.
+#[embassy_executor::task]
+pub async fn reset_task() {
+    let kind = RESET.wait().await;
+    Timer::after_millis(50).await;
+
+    if let ResetKind::Bootloader = kind {
+        set_bootloader_request(BOOTLOADER_MAGIC);
+    }
+    defmt::info!("Resetting...");
+    SCB::sys_reset();
+}
+
+/// Jump to the system memory bootloader, if it was requested before the last reset.
+/// Has to be called first thing in `main`, before any peripheral is initialized.
+pub fn check_bootloader_request() {
+    if bootloader_request() != BOOTLOADER_MAGIC {
+        return;
+    }
+
+    defmt::info!("Entering the system bootloader");
+    set_bootloader_request(0);
+    unsafe { cortex_m::asm::bootload(SYSTEM_MEMORY as *const u32) }
+}
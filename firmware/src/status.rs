@@ -0,0 +1,121 @@
+//! Board state shown on the status LED.
+//!
+//! | State              | Pattern                                  |
+//! | ------------------ | ---------------------------------------- |
+//! | Booting            | 1 Hz blink until the host configures USB |
+//! | USB enumerated     | short flash every 2 s                    |
+//! | Host session       | on, with a short gap every 2 s           |
+//! | Crash on last boot | double flash until the report is cleared |
+//! | Firmware update    | 4 Hz blink while an image is uploaded    |
+//! | Identify           | 10 Hz blink for a few seconds            |
+
+use core::{
+    cell::Cell,
+    sync::atomic::{AtomicBool, Ordering},
+};
+
+use embassy_futures::select::{Either, select};
+use embassy_stm32::{
+    Peripheral,
+    gpio::{Level, Output, Pin, Speed},
+};
+use embassy_sync::{
+    blocking_mutex::{Mutex, raw::CriticalSectionRawMutex},
+    signal::Signal,
+};
+use embassy_time::{Duration, Instant, Timer};
+use postcard_rpc::header::VarHeader;
+
+use crate::{STATUS_LED_ACTIVE_LOW, crash, update};
+
+/// How long the LED blinks after an `identify` request.
+const IDENTIFY_DURATION: Duration = Duration::from_secs(5);
+
+/// On and off times in milliseconds, starting with on.
+const BOOTING: &[u64] = &[500, 500];
+const ENUMERATED: &[u64] = &[100, 1900];
+const SESSION: &[u64] = &[1900, 100];
+const CRASHED: &[u64] = &[100, 100, 100, 700];
+const UPDATING: &[u64] = &[125, 125];
+const IDENTIFY: &[u64] = &[50, 50];
+
+static USB_CONFIGURED: AtomicBool = AtomicBool::new(false);
+static SESSION_ACTIVE: AtomicBool = AtomicBool::new(false);
+static IDENTIFY_UNTIL: Mutex<CriticalSectionRawMutex, Cell<Option<Instant>>> =
+    Mutex::new(Cell::new(None));
+static LAST_REQUEST: Mutex<CriticalSectionRawMutex, Cell<Instant>> =
+    Mutex::new(Cell::new(Instant::MIN));
+/// Wakes the LED task to show a new state without finishing the current pattern.
+static CHANGED: Signal<CriticalSectionRawMutex, ()> = Signal::new();
+
+/// Called by the USB handler when the host configures the device, or it is reset.
+pub(crate) fn set_usb_configured(configured: bool) {
+    USB_CONFIGURED.store(configured, Ordering::Relaxed);
+    if !configured {
+        SESSION_ACTIVE.store(false, Ordering::Relaxed);
+    }
+    CHANGED.signal(());
+}
+
+/// Called for every request, the session lasts until the device is reset or deconfigured.
+pub(crate) fn request_received() {
+    LAST_REQUEST.lock(|last| last.set(Instant::now()));
+    if !SESSION_ACTIVE.swap(true, Ordering::Relaxed) {
+        CHANGED.signal(());
+    }
+}
+
+/// Whether the host has configured the device since the last USB reset.
+pub fn usb_configured() -> bool {
+    USB_CONFIGURED.load(Ordering::Relaxed)
+}
+
+/// When the last request from the host came, `Instant::MIN` before the first one.
+pub fn last_request() -> Instant {
+    LAST_REQUEST.lock(Cell::get)
+}
+
+pub fn identify_handler<C>(_context: &mut C, _header: VarHeader, _rqst: ()) {
+    defmt::info!("identify");
+    IDENTIFY_UNTIL.lock(|until| until.set(Some(Instant::now() + IDENTIFY_DURATION)));
+    CHANGED.signal(());
+}
+
+fn pattern() -> &'static [u64] {
+    let identify = IDENTIFY_UNTIL.lock(Cell::get);
+    if identify.is_some_and(|until| Instant::now() < until) {
+        IDENTIFY
+    } else if update::in_progress() {
+        UPDATING
+    } else if crash::last_crash().is_some() {
+        CRASHED
+    } else if SESSION_ACTIVE.load(Ordering::Relaxed) {
+        SESSION
+    } else if USB_CONFIGURED.load(Ordering::Relaxed) {
+        ENUMERATED
+    } else {
+        BOOTING
+    }
+}
+
+/// Output driving the LED of the board, pass it the pin from `status_led!(p)`.
+pub fn led_output(pin: impl Peripheral<P = impl Pin> + 'static) -> Output<'static> {
+    Output::new(pin, led_level(false), Speed::Low)
+}
+
+fn led_level(on: bool) -> Level {
+    Level::from(on != STATUS_LED_ACTIVE_LOW)
+}
+
+/// Blinks the pattern of the current board state.
+#[embassy_executor::task]
+pub async fn status_led_task(mut led: Output<'static>) {
+    loop {
+        for (i, millis) in pattern().iter().enumerate() {
+            led.set_level(led_level(i % 2 == 0));
+            if let Either::Second(()) = select(Timer::after_millis(*millis), CHANGED.wait()).await {
+                break;
+            }
+        }
+    }
+}
@@ -0,0 +1,137 @@
+//! Settings kept in their own flash page or sector, see `Board::settings`.
+//!
+//! The module owns the flash peripheral, the update service borrows it with `take_flash`
+//! and gives it back with `put_flash`.
+
+use core::cell::{Cell, RefCell};
+
+use embassy_stm32::{
+    flash::{Blocking, Flash},
+    peripherals,
+};
+use embassy_sync::blocking_mutex::{Mutex, raw::CriticalSectionRawMutex};
+use heapless::String;
+use postcard_rpc::header::VarHeader;
+use protocol::device::{
+    ALIAS_LEN, Alias, AliasError, AliasResult, AliasSettings, DeviceInfo, is_valid_alias,
+};
+
+use crate::board::BOARD;
+
+/// Marks a written settings record, erased flash reads as `0xff`.
+const SETTINGS_MAGIC: u32 = 0x5e77_1a55;
+/// Magic, flags, alias length and the alias, padded to a multiple of the write size.
+const RECORD_LEN: usize = 32;
+const FLAG_USB_PRODUCT: u8 = 1 << 0;
+
+pub type StorageFlash = Flash<'static, Blocking>;
+
+static FLASH: Mutex<CriticalSectionRawMutex, Cell<Option<StorageFlash>>> =
+    Mutex::new(Cell::new(None));
+static SETTINGS: Mutex<CriticalSectionRawMutex, RefCell<AliasSettings>> =
+    Mutex::new(RefCell::new(AliasSettings {
+        alias: String::new(),
+        usb_product: false,
+    }));
+
+/// Take the flash and load the settings. Has to be called before `get_usb_config`,
+/// which shows the alias in the product string.
+pub fn init(flash: peripherals::FLASH) {
+    let mut flash = Flash::new_blocking(flash);
+    let mut record = [0; RECORD_LEN];
+    if flash
+        .blocking_read(BOARD.settings().offset, &mut record)
+        .is_ok()
+    {
+        if let Some(settings) = decode(&record) {
+            defmt::info!("Alias: {}", settings.alias.as_str());
+            SETTINGS.lock(|s| *s.borrow_mut() = settings);
+        }
+    }
+    FLASH.lock(|f| f.set(Some(flash)));
+}
+
+/// Borrow the flash, `None` if it is already borrowed or `init` was not called.
+pub(crate) fn take_flash() -> Option<StorageFlash> {
+    FLASH.lock(Cell::take)
+}
+
+pub(crate) fn put_flash(flash: StorageFlash) {
+    FLASH.lock(|f| f.set(Some(flash)));
+}
+
+/// Alias settings stored on the board.
+pub fn alias_settings() -> AliasSettings {
+    SETTINGS.lock(|s| s.borrow().clone())
+}
+
+fn decode(record: &[u8; RECORD_LEN]) -> Option<AliasSettings> {
+    let magic = u32::from_le_bytes([record[0], record[1], record[2], record[3]]);
+    let len = record[5] as usize;
+    if magic != SETTINGS_MAGIC || len > ALIAS_LEN {
+        return None;
+    }
+    let alias = core::str::from_utf8(&record[6..6 + len]).ok()?;
+    Some(AliasSettings {
+        alias: Alias::try_from(alias).ok()?,
+        usb_product: record[4] & FLAG_USB_PRODUCT != 0,
+    })
+}
+
+fn encode(settings: &AliasSettings) -> [u8; RECORD_LEN] {
+    let mut record = [0xff; RECORD_LEN];
+    let alias = settings.alias.as_bytes();
+    record[..4].copy_from_slice(&SETTINGS_MAGIC.to_le_bytes());
+    record[4] = if settings.usb_product {
+        FLAG_USB_PRODUCT
+    } else {
+        0
+    };
+    record[5] = alias.len() as u8;
+    record[6..6 + alias.len()].copy_from_slice(alias);
+    record
+}
+
+fn save(settings: &AliasSettings) -> AliasResult {
+    let mut flash = take_flash().ok_or(AliasError::Flash)?;
+    let settings_page = BOARD.settings();
+    let result = flash
+        .blocking_erase(
+            settings_page.offset,
+            settings_page.offset + settings_page.size,
+        )
+        .and_then(|()| flash.blocking_write(settings_page.offset, &encode(settings)));
+    put_flash(flash);
+    result.map_err(|_| AliasError::Flash)
+}
+
+pub fn device_info_handler<C>(_context: &mut C, _header: VarHeader, _rqst: ()) -> DeviceInfo {
+    defmt::info!("device_info");
+    DeviceInfo {
+        board: BOARD,
+        version: String::try_from(env!("CARGO_PKG_VERSION")).unwrap_or_default(),
+        serial_number: *embassy_stm32::uid::uid_hex_bytes(),
+        alias: alias_settings(),
+    }
+}
+
+pub fn get_alias_handler<C>(_context: &mut C, _header: VarHeader, _rqst: ()) -> Alias {
+    defmt::info!("get_alias");
+    alias_settings().alias
+}
+
+/// Erases the settings page, which blocks the executor for a while. The USB product
+/// string only changes after the next reset.
+pub fn set_alias_handler<C>(
+    _context: &mut C,
+    _header: VarHeader,
+    rqst: AliasSettings,
+) -> AliasResult {
+    defmt::info!("set_alias: {}", rqst.alias.as_str());
+    if !is_valid_alias(&rqst.alias) {
+        return Err(AliasError::Invalid);
+    }
+    save(&rqst)?;
+    SETTINGS.lock(|s| *s.borrow_mut() = rqst);
+    Ok(())
+}
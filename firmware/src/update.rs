@@ -0,0 +1,225 @@
+use core::cell::Cell;
+
+use embassy_sync::blocking_mutex::{Mutex, raw::CriticalSectionRawMutex};
+use postcard_rpc::header::VarHeader;
+use protocol::update::{
+    UPDATE_ALIGNMENT, UpdateChunk, UpdateError, UpdateInfo, UpdateResult, UpdateStart,
+};
+
+use crate::{board::BOARD, host_info, reset};
+
+/// Image being uploaded to the DFU partition.
+#[derive(Clone, Copy)]
+struct Upload {
+    size: u32,
+    crc: u32,
+    written: u32,
+}
+
+static UPLOAD: Mutex<CriticalSectionRawMutex, Cell<Option<Upload>>> = Mutex::new(Cell::new(None));
+
+/// Whether an image is being uploaded, shown on the status LED.
+pub fn in_progress() -> bool {
+    UPLOAD.lock(|upload| upload.get().is_some())
+}
+
+pub fn update_info_handler<C>(_context: &mut C, _header: VarHeader, _rqst: ()) -> UpdateInfo {
+    defmt::info!("update_info");
+    UpdateInfo {
+        board: BOARD,
+        supported: cfg!(feature = "bootloader"),
+        max_size: BOARD.flash_layout().map_or(0, |layout| layout.active.size),
+        state: flash::state(),
+    }
+}
+
+/// Erases the DFU partition, which blocks the executor for a while.
+pub fn start_update_handler<C>(
+    _context: &mut C,
+    _header: VarHeader,
+    rqst: UpdateStart,
+) -> UpdateResult {
+    defmt::info!("start_update: {} bytes", rqst.size);
+
+    let max_size = BOARD.flash_layout().map_or(0, |layout| layout.active.size);
+    if rqst.size == 0 || rqst.size > max_size || rqst.size as usize % UPDATE_ALIGNMENT != 0 {
+        return Err(UpdateError::InvalidSize);
+    }
+
+    UPLOAD.lock(|upload| upload.set(None));
+    flash::prepare()?;
+    UPLOAD.lock(|upload| {
+        upload.set(Some(Upload {
+            size: rqst.size,
+            crc: rqst.crc,
+            written: 0,
+        }))
+    });
+    Ok(())
+}
+
+pub fn write_update_chunk_handler<C>(
+    _context: &mut C,
+    _header: VarHeader,
+    rqst: UpdateChunk,
+) -> UpdateResult {
+    let Some(mut upload) = UPLOAD.lock(Cell::get) else {
+        return Err(UpdateError::NotStarted);
+    };
+
+    let len = rqst.data.len() as u32;
+    if rqst.offset != upload.written {
+        return Err(UpdateError::InvalidOffset);
+    }
+    if rqst.offset + len > upload.size || rqst.data.len() % UPDATE_ALIGNMENT != 0 {
+        return Err(UpdateError::InvalidSize);
+    }
+
+    flash::write(rqst.offset, &rqst.data)?;
+    upload.written += len;
+    UPLOAD.lock(|u| u.set(Some(upload)));
+    Ok(())
+}
+
+/// Checks the CRC of the written image, marks it for the swap and resets.
+pub fn finish_update_handler<C>(_context: &mut C, _header: VarHeader, _rqst: ()) -> UpdateResult {
+    defmt::info!("finish_update");
+
+    let upload = UPLOAD
+        .lock(|upload| upload.take())
+        .filter(|upload| upload.written == upload.size)
+        .ok_or(UpdateError::NotStarted)?;
+    flash::finish(upload.size, upload.crc)?;
+
+    host_info!("Update verified, resetting into the new firmware");
+    reset::request_reset();
+    Ok(())
+}
+
+/// Keep the running image, otherwise the bootloader reverts it on the next reset.
+pub fn confirm_update_handler<C>(_context: &mut C, _header: VarHeader, _rqst: ()) -> UpdateResult {
+    defmt::info!("confirm_update");
+    flash::confirm()
+}
+
+#[cfg(feature = "bootloader")]
+mod flash {
+    use core::cell::RefCell;
+
+    use embassy_boot_stm32::{
+        AlignedBuffer, BlockingFirmwareUpdater, FirmwareUpdaterConfig, FirmwareUpdaterError, State,
+    };
+    use embassy_embedded_hal::flash::partition::BlockingPartition;
+    use embassy_stm32::flash::WRITE_SIZE;
+    use embassy_sync::blocking_mutex::{Mutex, raw::NoopRawMutex};
+    use embedded_storage::nor_flash::{NorFlash, ReadNorFlash};
+    use protocol::update::{FirmwareState, UpdateError, UpdateResult, crc32};
+
+    use crate::storage::{self, StorageFlash};
+
+    type Partition<'a> = BlockingPartition<'a, NoopRawMutex, StorageFlash>;
+    type Config<'a> = FirmwareUpdaterConfig<Partition<'a>, Partition<'a>>;
+
+    /// The partitions borrow the flash, it is put back once they are dropped.
+    fn with_config<R>(
+        f: impl FnOnce(Config<'_>) -> Result<R, UpdateError>,
+    ) -> Result<R, UpdateError> {
+        let flash = storage::take_flash().ok_or(UpdateError::Flash)?;
+        let flash = Mutex::<NoopRawMutex, _>::new(RefCell::new(flash));
+        // Partition offsets come from the symbols in the generated memory.x.
+        let result = f(FirmwareUpdaterConfig::from_linkerfile_blocking(
+            &flash, &flash,
+        ));
+        storage::put_flash(flash.into_inner().into_inner());
+        result
+    }
+
+    fn with_updater<R>(
+        f: impl FnOnce(
+            &mut BlockingFirmwareUpdater<'_, Partition<'_>, Partition<'_>>,
+        ) -> Result<R, FirmwareUpdaterError>,
+    ) -> Result<R, UpdateError> {
+        with_config(|config| {
+            let mut aligned = AlignedBuffer([0; WRITE_SIZE]);
+            let mut updater = BlockingFirmwareUpdater::new(config, &mut aligned.0);
+            f(&mut updater).map_err(|e| match e {
+                FirmwareUpdaterError::BadState => UpdateError::Unconfirmed,
+                _ => UpdateError::Flash,
+            })
+        })
+    }
+
+    pub fn state() -> FirmwareState {
+        match with_updater(|updater| updater.get_state()) {
+            Ok(State::Swap) => FirmwareState::Unconfirmed,
+            _ => FirmwareState::Confirmed,
+        }
+    }
+
+    pub fn prepare() -> UpdateResult {
+        with_updater(|updater| updater.prepare_update().map(|_| ()))
+    }
+
+    pub fn write(offset: u32, data: &[u8]) -> UpdateResult {
+        with_config(|mut config| {
+            config
+                .dfu
+                .write(offset, data)
+                .map_err(|_| UpdateError::Flash)
+        })
+    }
+
+    pub fn finish(size: u32, crc: u32) -> UpdateResult {
+        with_config(|mut config| {
+            let mut buf = [0; 64];
+            let mut actual = 0;
+            for offset in (0..size).step_by(buf.len()) {
+                let len = (size - offset).min(buf.len() as u32) as usize;
+                config
+                    .dfu
+                    .read(offset, &mut buf[..len])
+                    .map_err(|_| UpdateError::Flash)?;
+                actual = crc32(actual, &buf[..len]);
+            }
+            if actual != crc {
+                return Err(UpdateError::CrcMismatch {
+                    expected: crc,
+                    actual,
+                });
+            }
+            Ok(())
+        })?;
+        with_updater(|updater| updater.mark_updated())
+    }
+
+    pub fn confirm() -> UpdateResult {
+        with_updater(|updater| updater.mark_booted())
+    }
+}
+
+/// Without the `bootloader` feature the firmware does not run behind the bootloader and
+/// updates are rejected.
+#[cfg(not(feature = "bootloader"))]
+mod flash {
+    use protocol::update::{FirmwareState, UpdateError, UpdateResult};
+
+    pub fn state() -> FirmwareState {
+        FirmwareState::Confirmed
+    }
+
+    pub fn prepare() -> UpdateResult {
+        Err(UpdateError::Unsupported)
+    }
+
+    pub fn write(_offset: u32, _data: &[u8]) -> UpdateResult {
+        Err(UpdateError::Unsupported)
+    }
+
+    pub fn finish(_size: u32, _crc: u32) -> UpdateResult {
+        Err(UpdateError::Unsupported)
+    }
+
+    pub fn confirm() -> UpdateResult {
+        Err(UpdateError::Unsupported)
+    }
+}
@@ -0,0 +1,119 @@
+//! Support for the methods generated by `macros::blocking_async`.
+//!
+//! The blocking methods wait in `block_on`, which lets Ctrl-C through. Each awaitable
+//! `*_async` method runs the same call as a task of the shared runtime, so the asyncio
+//! event loop keeps running while the board answers.
+//!
+//! The state of each client is behind a `tokio::sync::RwLock`. Calls that only read it
+//! overlap, calls that change it, such as `set_angle`, wait for the calls before them,
+//! whether they come from other threads or from `asyncio.gather`.
+
+use std::{marker::PhantomData, time::Duration};
+
+use pyo3::{IntoPyObjectExt, prelude::*};
+use pyo3_stub_gen::{PyStubType, TypeInfo};
+use tokio::sync::{RwLock, RwLockReadGuard, RwLockWriteGuard};
+
+/// How often a blocking method checks for Ctrl-C and other signals.
+const SIGNAL_CHECK_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Run `future` to completion on the shared runtime. If a signal handler raises while it
+/// waits, e.g. KeyboardInterrupt, the future is dropped with its request and the
+/// exception is returned.
+pub fn block_on<F: Future>(future: F) -> PyResult<F::Output> {
+    pyo3_async_runtimes::tokio::get_runtime().block_on(async move {
+        let mut future = std::pin::pin!(future);
+        let mut signals = tokio::time::interval(SIGNAL_CHECK_INTERVAL);
+        loop {
+            tokio::select! {
+                biased;
+                output = &mut future => return Ok(output),
+                _ = signals.tick() => Python::with_gil(|py| py.check_signals())?,
+            }
+        }
+    })
+}
+
+/// Python awaitable resolving to the Python value of the method result `T`.
+pub struct Awaitable<T> {
+    future: PyObject,
+    result: PhantomData<T>,
+}
+
+impl<'py, T> IntoPyObject<'py> for Awaitable<T> {
+    type Target = PyAny;
+    type Output = Bound<'py, PyAny>;
+    type Error = std::convert::Infallible;
+
+    fn into_pyobject(self, py: Python<'py>) -> Result<Self::Output, Self::Error> {
+        Ok(self.future.into_bound(py))
+    }
+}
+
+impl<T: PyStubType> PyStubType for Awaitable<T> {
+    fn type_output() -> TypeInfo {
+        let TypeInfo { name, mut import } = T::type_output();
+        import.insert("typing".into());
+        TypeInfo {
+            name: format!("typing.Awaitable[{}]", name),
+            import,
+        }
+    }
+}
+
+/// Awaitable resolving to `value` right away, such as the client from `__aenter__`.
+pub fn ready<T>(py: Python<'_>, value: T) -> PyResult<Awaitable<T>>
+where
+    T: for<'py> IntoPyObject<'py> + Send + 'static,
+{
+    let future = pyo3_async_runtimes::tokio::future_into_py(py, async move { Ok(value) })?;
+    Ok(Awaitable {
+        future: future.unbind(),
+        result: PhantomData,
+    })
+}
+
+/// Lock the state of a client for a method that only reads it, waiting for the calls that
+/// change it without the GIL.
+pub fn read_state<S: Send + Sync>(lock: &RwLock<S>) -> RwLockReadGuard<'_, S> {
+    Python::with_gil(|py| py.allow_threads(|| lock.blocking_read()))
+}
+
+/// Lock the state of a client for a method that changes it, waiting for the other calls
+/// without the GIL.
+pub fn write_state<S: Send + Sync>(lock: &RwLock<S>) -> RwLockWriteGuard<'_, S> {
+    Python::with_gil(|py| py.allow_threads(|| lock.blocking_write()))
+}
+
+/// Results of the client methods, converted to Python once the call is done.
+pub trait IntoPyResult {
+    fn into_py_result(self, py: Python<'_>) -> PyResult<PyObject>;
+}
+
+impl<T, E> IntoPyResult for Result<T, E>
+where
+    T: for<'py> IntoPyObject<'py>,
+    E: Into<PyErr>,
+{
+    fn into_py_result(self, py: Python<'_>) -> PyResult<PyObject> {
+        self.map_err(Into::into)?.into_py_any(py)
+    }
+}
+
+/// Run `future` on the shared runtime and return an awaitable of its result, converted to
+/// Python once it is done.
+pub fn spawn<F, R>(py: Python<'_>, future: F) -> PyResult<Awaitable<R>>
+where
+    F: Future<Output = R> + Send + 'static,
+    R: IntoPyResult + Send + 'static,
+{
+    let future = pyo3_async_runtimes::tokio::future_into_py(py, async move {
+        let result = future.await;
+        Python::with_gil(|py| result.into_py_result(py))
+    })?;
+
+    Ok(Awaitable {
+        future: future.unbind(),
+        result: PhantomData,
+    })
+}
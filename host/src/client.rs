@@ -0,0 +1,301 @@
+//! Request timeouts and retries of the clients.
+
+use std::{
+    fmt::Debug,
+    ops::Deref,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use postcard_rpc::{
+    Endpoint,
+    host_client::{HostClient, HostErr},
+    standard_icd::WireError,
+};
+use postcard_schema::Schema;
+use pyo3::prelude::*;
+use pyo3_stub_gen::derive::*;
+use serde::{Serialize, de::DeserializeOwned};
+
+use crate::common::{BoardError, BoardResult};
+
+/// Default request timeout of the clients in seconds.
+pub const DEFAULT_TIMEOUT: f64 = 2.0;
+
+/// How long a request waits for the board and how often an idempotent one is sent again.
+#[derive(Debug, Clone, Copy)]
+pub struct RequestPolicy {
+    /// None waits forever.
+    pub timeout: Option<Duration>,
+    /// Attempts after the first one, only for idempotent requests that timed out.
+    pub retries: u8,
+}
+
+impl RequestPolicy {
+    /// Policy from the Python arguments, with the timeout in seconds.
+    pub fn from_secs(timeout: Option<f64>, retries: u8) -> BoardResult<Self> {
+        Ok(Self {
+            timeout: timeout_from_secs(timeout)?,
+            retries,
+        })
+    }
+}
+
+/// Timeout in seconds from Python, None waits forever.
+pub fn timeout_from_secs(timeout: Option<f64>) -> BoardResult<Option<Duration>> {
+    timeout
+        .map(|secs| {
+            Duration::try_from_secs_f64(secs)
+                .ok()
+                .filter(|timeout| !timeout.is_zero())
+                .ok_or_else(|| BoardError::InvalidData(format!("Invalid timeout: {} s", secs)))
+        })
+        .transpose()
+}
+
+/// Time to wait for the board to show up on the bus, in seconds from Python. Zero looks
+/// for it once.
+pub fn connect_timeout_from_secs(secs: f64) -> BoardResult<Duration> {
+    Duration::try_from_secs_f64(secs)
+        .map_err(|_| BoardError::InvalidData(format!("Invalid connect timeout: {} s", secs)))
+}
+
+tokio::task_local! {
+    /// Timeout of the requests of one Python call, set by its `timeout` argument.
+    static CALL_TIMEOUT: Option<Duration>;
+}
+
+/// Timeout of a single call from its `timeout` argument in seconds. None keeps the timeout
+/// of the client and `math.inf` waits forever.
+pub fn call_timeout(timeout: Option<f64>) -> PyResult<Option<Option<Duration>>> {
+    match timeout {
+        None => Ok(None),
+        Some(secs) if secs == f64::INFINITY => Ok(Some(None)),
+        Some(secs) => Ok(Some(timeout_from_secs(Some(secs))?)),
+    }
+}
+
+/// Run the requests of a call with its own timeout, without touching the policy the other
+/// calls and background tasks of the client use.
+pub async fn with_call_timeout<F: Future>(
+    timeout: Option<Option<Duration>>,
+    future: F,
+) -> F::Output {
+    match timeout {
+        Some(timeout) => CALL_TIMEOUT.scope(timeout, future).await,
+        None => future.await,
+    }
+}
+
+/// Endpoints that only read the state of the board, so they can be sent again when the
+/// response does not arrive in time.
+pub trait Idempotent: Endpoint {}
+
+macro_rules! idempotent {
+    ($($endpoint:path),* $(,)?) => {
+        $(impl Idempotent for $endpoint {})*
+    };
+}
+
+macro_rules! idempotent_shared {
+    ($($module:ident),*) => {
+        $(idempotent!(
+            protocol::$module::GetUniqueIdEndpoint,
+            protocol::$module::GetCrashReport,
+            protocol::$module::GetMetrics,
+            protocol::$module::GetDeviceInfo,
+            protocol::$module::GetAlias,
+        );)*
+    };
+}
+
+idempotent_shared!(minimal, servo, sequencer, pid, scope, dac);
+idempotent!(
+    protocol::servo::GetServoConfig,
+    protocol::servo::GetServoFeedback,
+);
+
+/// Error of a request sent through `BoardClient`.
+#[derive(Debug)]
+pub enum RequestError {
+    Comms(HostErr<WireError>),
+    Timeout(Duration),
+}
+
+impl<E: Debug> From<RequestError> for BoardError<E> {
+    fn from(value: RequestError) -> Self {
+        match value {
+            RequestError::Comms(err) => Self::Comms(err),
+            RequestError::Timeout(timeout) => Self::Timeout(timeout),
+        }
+    }
+}
+
+/// Client that gives up on requests after the timeout of its policy and sends idempotent
+/// ones again. Derefs to the `HostClient` for subscriptions and closing.
+///
+/// Clones share the policy, so the background tasks follow `set_timeout` too.
+#[derive(Clone)]
+pub struct BoardClient {
+    client: HostClient<WireError>,
+    policy: Arc<Mutex<RequestPolicy>>,
+}
+
+impl BoardClient {
+    pub fn new(client: HostClient<WireError>, policy: RequestPolicy) -> Self {
+        Self {
+            client,
+            policy: Arc::new(Mutex::new(policy)),
+        }
+    }
+
+    /// Use the client of the reconnected board, keeping the policy.
+    pub fn replace(&mut self, client: HostClient<WireError>) {
+        self.client = client;
+    }
+
+    pub fn policy(&self) -> RequestPolicy {
+        *self.policy.lock().unwrap()
+    }
+
+    pub fn set_policy(&self, policy: RequestPolicy) {
+        *self.policy.lock().unwrap() = policy;
+    }
+
+    /// Timeout of the current call if it has one, else the one of the policy.
+    pub fn timeout(&self) -> Option<Duration> {
+        CALL_TIMEOUT
+            .try_with(|timeout| *timeout)
+            .unwrap_or_else(|_| self.policy().timeout)
+    }
+
+    /// Context manager changing the timeout until the `with` block ends.
+    pub fn timeout_override(&self, timeout: Option<Duration>) -> TimeoutOverride {
+        TimeoutOverride {
+            policy: self.policy.clone(),
+            timeout,
+            previous: None,
+        }
+    }
+
+    /// Send a request with the timeout of the call or the policy.
+    pub async fn send_resp<E>(&self, req: &E::Request) -> Result<E::Response, RequestError>
+    where
+        E: Endpoint,
+        E::Request: Serialize + Schema,
+        E::Response: DeserializeOwned + Schema,
+    {
+        self.send_resp_with::<E>(req, self.timeout()).await
+    }
+
+    /// Send a request with a timeout for this call only.
+    pub async fn send_resp_with<E>(
+        &self,
+        req: &E::Request,
+        timeout: Option<Duration>,
+    ) -> Result<E::Response, RequestError>
+    where
+        E: Endpoint,
+        E::Request: Serialize + Schema,
+        E::Response: DeserializeOwned + Schema,
+    {
+        let response = self.client.send_resp::<E>(req);
+        match timeout {
+            Some(timeout) => tokio::time::timeout(timeout, response)
+                .await
+                .map_err(|_| RequestError::Timeout(timeout))?,
+            None => response.await,
+        }
+        .map_err(RequestError::Comms)
+    }
+
+    /// Send an idempotent request, again up to `retries` times if it times out.
+    pub async fn query<E>(&self, req: &E::Request) -> Result<E::Response, RequestError>
+    where
+        E: Idempotent,
+        E::Request: Serialize + Schema,
+        E::Response: DeserializeOwned + Schema,
+    {
+        let policy = self.policy();
+        let timeout = self.timeout();
+        let mut attempt = 0;
+        loop {
+            match self.send_resp_with::<E>(req, timeout).await {
+                Err(RequestError::Timeout(timeout)) if attempt < policy.retries => {
+                    attempt += 1;
+                    log::warn!(
+                        "{} timed out after {:?}, retrying ({}/{})",
+                        E::PATH,
+                        timeout,
+                        attempt,
+                        policy.retries
+                    );
+                }
+                result => return result,
+            }
+        }
+    }
+}
+
+impl Deref for BoardClient {
+    type Target = HostClient<WireError>;
+
+    fn deref(&self) -> &Self::Target {
+        &self.client
+    }
+}
+
+/// Longest a dropped client waits for its cleanup before closing the connection anyway.
+const CLEANUP_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Run the cleanup of a dropped client, such as disabling the outputs, then close the
+/// connection. Blocks until it is done or `CLEANUP_TIMEOUT` passed, with the GIL released,
+/// unless the client is dropped on a runtime thread, where blocking is not allowed and the
+/// cleanup runs in the background instead.
+pub fn cleanup_on_drop(client: BoardClient, cleanup: impl Future<Output = ()> + Send + 'static) {
+    let cleanup = async move {
+        if tokio::time::timeout(CLEANUP_TIMEOUT, cleanup)
+            .await
+            .is_err()
+        {
+            log::warn!(
+                "Cleanup of the dropped client timed out after {:?}",
+                CLEANUP_TIMEOUT
+            );
+        }
+        client.close();
+    };
+    let runtime = pyo3_async_runtimes::tokio::get_runtime();
+    if tokio::runtime::Handle::try_current().is_ok() {
+        core::mem::drop(runtime.spawn(cleanup));
+    } else {
+        Python::with_gil(|py| py.allow_threads(|| runtime.block_on(cleanup)));
+    }
+}
+
+/// Changes the request timeout of a client inside a `with` block. The change is client-wide,
+/// so calls from other threads and the background tasks use it too and overlapping blocks
+/// restore each other's timeout. A single call takes its own `timeout` argument instead.
+#[gen_stub_pyclass]
+#[pyclass]
+pub struct TimeoutOverride {
+    policy: Arc<Mutex<RequestPolicy>>,
+    timeout: Option<Duration>,
+    previous: Option<RequestPolicy>,
+}
+
+#[gen_stub_pymethods]
+#[pymethods]
+impl TimeoutOverride {
+    fn __enter__(&mut self) {
+        let mut policy = self.policy.lock().unwrap();
+        self.previous = Some(*policy);
+        policy.timeout = self.timeout;
+    }
+
+    fn __exit__(&mut self, _exc_type: PyObject, _exc_value: PyObject, _traceback: PyObject) {
+        if let Some(previous) = self.previous.take() {
+            self.policy.lock().unwrap().timeout = previous.timeout;
+        }
+    }
+}
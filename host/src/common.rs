@@ -1,88 +1,373 @@
-use std::{convert::Infallible, fmt::Debug};
+use std::{
+    collections::HashSet,
+    convert::Infallible,
+    fmt::Debug,
+    time::{Duration, Instant},
+};
 
+use nusb::DeviceInfo;
 use postcard_rpc::{
     header::VarSeqKind,
     host_client::{HostClient, HostErr, SchemaError},
     standard_icd::{ERROR_PATH, LoggingTopic, WireError},
 };
+use protocol::{
+    device::{USB_PID, USB_VID},
+    utils::LogLevel,
+};
 use pyo3::prelude::*;
 
+use crate::{
+    client::{BoardClient, RequestError, RequestPolicy},
+    exceptions::{self, EndpointPayload},
+};
+
+/// How long to wait for the board to come back after a reset.
+const RECONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+const RECONNECT_INTERVAL: Duration = Duration::from_millis(100);
+/// The board resets shortly after responding, do not find it on the bus before that.
+pub const RESET_DELAY: Duration = Duration::from_millis(500);
+
+/// Connect to the board with the given serial number or alias, otherwise to the first
+/// board with the product string. Waits up to `connect_timeout` for the board to show up
+/// on the bus, e.g. while it is being plugged in. The requests of the connection follow
+/// `policy`.
 pub async fn connect_to_board(
     product_string: &str,
     serial_number: Option<&str>,
-) -> Result<HostClient<WireError>, BoardError<Infallible>> {
-    if serial_number.is_some() {
-        log::info!("Connecting to device with S/N: {}", serial_number.unwrap());
+    alias: Option<&str>,
+    connect_timeout: Duration,
+    policy: RequestPolicy,
+) -> BoardResult<HostClient<WireError>> {
+    if let Some(alias) = alias {
+        log::info!("Connecting to device with alias: {}", alias);
+    } else if let Some(serial_number) = serial_number {
+        log::info!("Connecting to device with S/N: {}", serial_number);
     } else {
         log::info!("Connecting to first available device");
     }
 
-    let client = HostClient::new_raw_nusb(
-        |d| {
-            let res = {
-                if serial_number.is_some() {
-                    d.serial_number() == serial_number
-                } else {
-                    d.product_string() == Some(product_string)
-                }
-            };
-            // Sadly HostClient doesn't expose the DeviceInfo struct
-            if res {
-                let version = d.device_version();
-                let patch = version & 0x000F;
-                let minor = (version & 0x00F0) >> 4;
-                let major = ((version & 0x0F00) >> 8) + 10 * ((version & 0xF000) >> 12);
-                let version = format!("{major}.{minor}.{patch}");
-
-                log::info!(
-                    "Found device: {} v{} (SN: {})",
-                    d.product_string().unwrap_or("Unknown"),
-                    version,
-                    d.serial_number().unwrap_or("N/A")
-                );
-
-                if version != env!("CARGO_PKG_VERSION") {
-                    log::warn!(
-                        "Device version {} does not match host version {}. Consider flashing using the client's `flash` command.",
-                        version,
-                        env!("CARGO_PKG_VERSION")
-                    );
-                }
+    let start = Instant::now();
+    // Boards already asked for their alias are not asked again while waiting for the board.
+    let mut asked = HashSet::new();
+    let client = loop {
+        let found = match alias {
+            Some(alias) => connect_by_alias(product_string, alias, policy, &mut asked).await,
+            None => HostClient::try_new_raw_nusb(
+                |d| is_board(d, product_string, serial_number),
+                ERROR_PATH,
+                8,
+                VarSeqKind::Seq2,
+            )
+            .map_err(|e| {
+                BoardError::DeviceNotFound(format!(
+                    "No {} board{} found: {}",
+                    product_string,
+                    serial_number
+                        .map(|serial_number| format!(" with S/N {}", serial_number))
+                        .unwrap_or_default(),
+                    e
+                ))
+            }),
+        };
+        match found {
+            Ok(client) => break client,
+            Err(BoardError::DeviceNotFound(msg)) if start.elapsed() >= connect_timeout => {
+                return Err(BoardError::DeviceNotFound(format!(
+                    "{}. {}",
+                    msg,
+                    seen_boards()
+                )));
             }
-            res
-        },
-        ERROR_PATH,
-        8,
-        VarSeqKind::Seq2,
-    );
+            Err(BoardError::DeviceNotFound(_)) => tokio::time::sleep(RECONNECT_INTERVAL).await,
+            Err(e) => return Err(e),
+        }
+    };
+
+    log::info!("Connected to {} board", product_string);
+
+    init_client(client, policy).await
+}
+
+/// Find the board with the alias among the boards with the product string. Boards showing
+/// the alias in the product string are matched directly, the others not in `asked` are asked
+/// for it with the request `policy` and added to it.
+async fn connect_by_alias(
+    product_string: &str,
+    alias: &str,
+    policy: RequestPolicy,
+    asked: &mut HashSet<String>,
+) -> BoardResult<HostClient<WireError>> {
+    let devices = nusb::list_devices()
+        .map_err(|e| BoardError::DeviceNotFound(format!("Failed to list USB devices: {}", e)))?;
+    let serial_numbers = devices
+        .filter(|d| {
+            is_rustpill(d)
+                && d.product_string()
+                    .is_some_and(|product| is_product(product, product_string))
+        })
+        .filter_map(|d| {
+            let serial_number = d.serial_number()?.to_owned();
+            let shown = d.product_string().and_then(product_alias) == Some(alias);
+            Some((serial_number, shown))
+        })
+        .collect::<Vec<_>>();
+
+    for (serial_number, shown) in &serial_numbers {
+        if !shown && asked.contains(serial_number) {
+            continue;
+        }
+        let Ok(client) = HostClient::try_new_raw_nusb(
+            |d| is_board(d, product_string, Some(serial_number)),
+            ERROR_PATH,
+            8,
+            VarSeqKind::Seq2,
+        ) else {
+            continue;
+        };
+        if *shown {
+            return Ok(client);
+        }
+
+        // Shared endpoints have the same key in every protocol module. A board that does
+        // not answer in time is skipped rather than holding up the others.
+        asked.insert(serial_number.clone());
+        let response = BoardClient::new(client.clone(), policy)
+            .query::<protocol::minimal::GetAlias>(&())
+            .await;
+        match response {
+            Ok(board_alias) if board_alias == alias => return Ok(client),
+            Ok(_) => {}
+            Err(e) => log::debug!("Could not get the alias of {}: {:?}", serial_number, e),
+        }
+        client.close();
+    }
+
+    Err(BoardError::DeviceNotFound(format!(
+        "No {} board with alias {:?} among {} connected",
+        product_string,
+        alias,
+        serial_numbers.len()
+    )))
+}
+
+/// Reset the board and connect the client to it again once it is back on the bus.
+pub async fn reset_board(client: &mut BoardClient, product_string: &str) -> BoardResult<()> {
+    let serial_number = board_serial_number(client).await?;
+
+    client
+        .send_resp::<protocol::minimal::ResetEndpoint>(&())
+        .await?;
+    client.close();
+    log::info!("Board reset, waiting for it to reconnect");
+    tokio::time::sleep(RESET_DELAY).await;
+
+    let reconnected = reconnect_to_board::<Infallible>(
+        product_string,
+        &serial_number,
+        RECONNECT_TIMEOUT,
+        client.policy(),
+    )
+    .await?;
+    client.replace(reconnected);
+    Ok(())
+}
+
+/// Serial number of the connected board, used to find it again after a reset.
+pub async fn board_serial_number(client: &BoardClient) -> Result<String, RequestError> {
+    // Shared endpoints have the same key in every protocol module.
+    let serial_number = client
+        .query::<protocol::minimal::GetUniqueIdEndpoint>(&())
+        .await?;
+    Ok(String::from_utf8_lossy(&serial_number).into_owned())
+}
+
+/// Poll the bus until the board with the given serial number is back, or the timeout passes.
+/// The requests of the new connection follow `policy`.
+pub async fn reconnect_to_board<E: Debug>(
+    product_string: &str,
+    serial_number: &str,
+    timeout: Duration,
+    policy: RequestPolicy,
+) -> BoardResult<HostClient<WireError>, E> {
+    let start = Instant::now();
+    loop {
+        tokio::time::sleep(RECONNECT_INTERVAL).await;
+
+        match HostClient::try_new_raw_nusb(
+            |d| is_board(d, product_string, Some(serial_number)),
+            ERROR_PATH,
+            8,
+            VarSeqKind::Seq2,
+        ) {
+            Ok(client) => return init_client(client, policy).await,
+            Err(e) if start.elapsed() > timeout => {
+                return Err(BoardError::DeviceNotFound(format!(
+                    "Board {} did not reconnect after reset: {}. {}",
+                    serial_number,
+                    e,
+                    seen_boards()
+                )));
+            }
+            Err(_) => {}
+        }
+    }
+}
+
+/// The product string is followed by the alias, as "name (alias)", if the board was set
+/// to show it.
+pub fn is_product(product: &str, product_string: &str) -> bool {
+    product
+        .strip_prefix(product_string)
+        .is_some_and(|rest| rest.is_empty() || rest.starts_with(" ("))
+}
+
+/// Alias shown in the product string, if any.
+pub fn product_alias(product: &str) -> Option<&str> {
+    product
+        .split_once(" (")
+        .and_then(|(_, alias)| alias.strip_suffix(')'))
+}
+
+/// Whether the device has the VID/PID of the boards.
+pub fn is_rustpill(d: &DeviceInfo) -> bool {
+    d.vendor_id() == USB_VID && d.product_id() == USB_PID
+}
 
-    log::info!("Connected to servo board");
+/// Match the device by VID/PID and product string, and by serial number if given, so a
+/// client never attaches to a board running another firmware.
+fn is_board(d: &DeviceInfo, product_string: &str, serial_number: Option<&str>) -> bool {
+    let res = is_rustpill(d)
+        && d.product_string()
+            .is_some_and(|product| is_product(product, product_string))
+        && serial_number.is_none_or(|serial_number| d.serial_number() == Some(serial_number));
+    // Sadly HostClient doesn't expose the DeviceInfo struct
+    if res {
+        let version = firmware_version(d);
 
-    let mut logsub = client.subscribe_multi::<LoggingTopic>(64).await.unwrap();
+        log::info!(
+            "Found device: {} v{} (SN: {})",
+            d.product_string().unwrap_or("Unknown"),
+            version,
+            d.serial_number().unwrap_or("N/A")
+        );
+
+        if version != env!("CARGO_PKG_VERSION") {
+            log::warn!(
+                "Device version {} does not match host version {}. Consider flashing using the client's `flash` command.",
+                version,
+                env!("CARGO_PKG_VERSION")
+            );
+        }
+    }
+    res
+}
+
+/// Firmware version from the BCD `bcdDevice` of the descriptor, as "major.minor.patch".
+pub fn firmware_version(d: &DeviceInfo) -> String {
+    let version = d.device_version();
+    let patch = version & 0x000F;
+    let minor = (version & 0x00F0) >> 4;
+    let major = ((version & 0x0F00) >> 8) + 10 * ((version & 0xF000) >> 12);
+    format!("{major}.{minor}.{patch}")
+}
+
+/// Boards on the bus, listed in the error when the wanted one is not found.
+fn seen_boards() -> String {
+    let boards = nusb::list_devices()
+        .map(|devices| {
+            devices
+                .filter(is_rustpill)
+                .map(|d| {
+                    format!(
+                        "{} v{} (SN: {})",
+                        d.product_string().unwrap_or("Unknown"),
+                        firmware_version(&d),
+                        d.serial_number().unwrap_or("N/A")
+                    )
+                })
+                .collect::<Vec<_>>()
+        })
+        .unwrap_or_default();
+
+    if boards.is_empty() {
+        "No boards are connected".to_string()
+    } else {
+        format!("Connected boards: {}", boards.join(", "))
+    }
+}
+
+/// Forward the firmware logs and report a previous crash of a freshly connected board.
+async fn init_client<E: Debug>(
+    client: HostClient<WireError>,
+    policy: RequestPolicy,
+) -> BoardResult<HostClient<WireError>, E> {
+    let Ok(mut logsub) = client.subscribe_multi::<LoggingTopic>(64).await else {
+        client.close();
+        return Err(BoardError::Comms(HostErr::Closed));
+    };
 
     log::info!("Created log subscription");
 
-    // Spawn a background task to handle log messages
+    // Spawn a background task to handle log messages, until the client is closed
+    let closed = client.clone();
     core::mem::drop(tokio::task::spawn(async move {
         log::info!("Starting log subscription");
         loop {
-            match logsub.recv().await {
-                Ok(log) => {
-                    log::info!("FIRMWARE: {}", log);
-                }
-                Err(e) => {
-                    log::error!("Log subscription error: {:?}", e);
-                    break;
-                }
+            tokio::select! {
+                _ = closed.wait_closed() => break,
+                log = logsub.recv() => match log {
+                    Ok(log) => log_firmware_record(&log),
+                    Err(e) => {
+                        log::error!("Log subscription error: {:?}", e);
+                        break;
+                    }
+                },
             }
         }
     }));
 
+    // Shared endpoints have the same key in every protocol module.
+    match BoardClient::new(client.clone(), policy)
+        .query::<protocol::minimal::GetCrashReport>(&())
+        .await
+    {
+        Ok(Some(report)) => log::warn!("Board reset after a previous crash: {}", report),
+        Ok(None) => {}
+        Err(e) => log::debug!("Could not get the crash report: {:?}", e),
+    }
+
     log::info!("Initialized board client");
 
     Ok(client)
 }
 
+/// Forward a record from the firmware `LoggingTopic` to the Python logger at its level.
+/// Records are formatted as "[LEVEL seconds] message", anything else is logged as info.
+fn log_firmware_record(record: &str) {
+    let parsed = record
+        .strip_prefix('[')
+        .and_then(|record| record.split_once("] "))
+        .and_then(|(header, message)| {
+            let (level, time) = header.split_once(' ')?;
+            Some((LogLevel::parse(level)?, time, message))
+        });
+
+    match parsed {
+        Some((level, time, message)) => {
+            let level = match level {
+                LogLevel::Trace => log::Level::Trace,
+                LogLevel::Debug => log::Level::Debug,
+                LogLevel::Info => log::Level::Info,
+                LogLevel::Warn => log::Level::Warn,
+                LogLevel::Error => log::Level::Error,
+            };
+            log::log!(level, "FIRMWARE [{}s]: {}", time, message);
+        }
+        None => log::info!("FIRMWARE: {}", record),
+    }
+}
+
 #[derive(Debug)]
 pub enum BoardError<E: Debug = Infallible> {
     Comms(HostErr<WireError>),
@@ -90,6 +375,11 @@ pub enum BoardError<E: Debug = Infallible> {
     #[allow(dead_code)]
     Endpoint(E),
     InvalidData(String),
+    DeviceNotFound(String),
+    /// The board did not respond within the timeout.
+    Timeout(Duration),
+    /// Exception raised in Python while the method waited, e.g. KeyboardInterrupt.
+    Python(PyErr),
 }
 
 impl<E: Debug> From<HostErr<WireError>> for BoardError<E> {
@@ -104,21 +394,29 @@ impl<E: Debug> From<SchemaError<WireError>> for BoardError<E> {
     }
 }
 
-impl<E: Debug> From<BoardError<E>> for PyErr {
+impl<E: Debug> From<PyErr> for BoardError<E> {
+    fn from(value: PyErr) -> Self {
+        Self::Python(value)
+    }
+}
+
+impl<E: EndpointPayload> From<BoardError<E>> for PyErr {
     fn from(val: BoardError<E>) -> Self {
         match val {
-            BoardError::Comms(err) => {
-                PyErr::new::<pyo3::exceptions::PyRuntimeError, _>(format!("Comms error: {:?}", err))
+            BoardError::Comms(err) => exceptions::comms_error(err),
+            BoardError::Protocol(err) => {
+                exceptions::ProtocolMismatchError::new_err(format!("Protocol error: {:?}", err))
             }
-            BoardError::Protocol(err) => PyErr::new::<pyo3::exceptions::PyRuntimeError, _>(
-                format!("Protocol error: {:?}", err),
-            ),
-            BoardError::Endpoint(err) => PyErr::new::<pyo3::exceptions::PyRuntimeError, _>(
-                format!("Endpoint error: {:?}", err),
-            ),
+            BoardError::Endpoint(err) => exceptions::endpoint_error(err),
             BoardError::InvalidData(msg) => {
-                PyErr::new::<pyo3::exceptions::PyValueError, _>(format!("Invalid data: {}", msg))
+                exceptions::InvalidDataError::new_err(format!("Invalid data: {}", msg))
             }
+            BoardError::DeviceNotFound(msg) => exceptions::DeviceNotFoundError::new_err(msg),
+            BoardError::Timeout(timeout) => exceptions::BoardTimeoutError::new_err(format!(
+                "No response from the board within {:?}",
+                timeout
+            )),
+            BoardError::Python(err) => err,
         }
     }
 }
@@ -0,0 +1,62 @@
+use std::time::Duration;
+
+use protocol::device::{AliasError, AliasSettings, DeviceInfo, is_valid_alias};
+use pyo3::{prelude::*, types::PyDict};
+
+use crate::{
+    client::BoardClient,
+    common::{BoardError, BoardResult},
+};
+
+/// Storing the alias erases a flash page, which takes up to a few seconds on the F4.
+const SET_ALIAS_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Get the board, firmware version, serial number and alias of the board as a Python dict.
+pub async fn get_device_info(client: &BoardClient) -> BoardResult<Py<PyDict>> {
+    // Shared endpoints have the same key in every protocol module.
+    let info = client
+        .query::<protocol::minimal::GetDeviceInfo>(&())
+        .await?;
+
+    Python::with_gil(|py| device_info_dict(py, &info).map(Bound::unbind))
+        .map_err(BoardError::Python)
+}
+
+/// Store the alias on the board, an empty alias clears it.
+pub async fn set_alias(
+    client: &BoardClient,
+    alias: &str,
+    usb_product: bool,
+) -> BoardResult<(), AliasError> {
+    if !is_valid_alias(alias) {
+        return Err(BoardError::InvalidData(format!(
+            "Invalid alias {:?}, use up to {} ASCII letters, digits, '-', '_' or '.'",
+            alias,
+            protocol::device::ALIAS_LEN
+        )));
+    }
+    let settings = AliasSettings {
+        alias: alias.try_into().unwrap(),
+        usb_product,
+    };
+    let timeout = client
+        .timeout()
+        .map(|timeout| timeout.max(SET_ALIAS_TIMEOUT));
+    client
+        .send_resp_with::<protocol::minimal::SetAlias>(&settings, timeout)
+        .await?
+        .map_err(BoardError::Endpoint)
+}
+
+fn device_info_dict<'py>(py: Python<'py>, info: &DeviceInfo) -> PyResult<Bound<'py, PyDict>> {
+    let dict = PyDict::new(py);
+    dict.set_item("board", info.board)?;
+    dict.set_item("version", info.version.as_str())?;
+    dict.set_item(
+        "serial_number",
+        String::from_utf8_lossy(&info.serial_number),
+    )?;
+    dict.set_item("alias", info.alias.alias.as_str())?;
+    dict.set_item("alias_in_usb_product", info.alias.usb_product)?;
+    Ok(dict)
+}
@@ -0,0 +1,103 @@
+//! Listing the boards attached to the USB bus, without connecting to them.
+
+use nusb::DeviceInfo;
+use pyo3::prelude::*;
+use pyo3_stub_gen::derive::*;
+
+use crate::common::{
+    BoardError, BoardResult, firmware_version, is_product, is_rustpill, product_alias,
+};
+
+/// Product strings of the firmwares the clients talk to.
+const PRODUCT_STRINGS: [&str; 6] = [
+    protocol::minimal::USB_DEVICE_NAME,
+    protocol::servo::USB_DEVICE_NAME,
+    protocol::sequencer::USB_DEVICE_NAME,
+    protocol::pid::USB_DEVICE_NAME,
+    protocol::scope::USB_DEVICE_NAME,
+    protocol::dac::USB_DEVICE_NAME,
+];
+
+/// A board found on the USB bus by `list_devices` or the `list` method of a client.
+#[gen_stub_pyclass]
+#[pyclass(get_all)]
+#[derive(Debug, Clone)]
+pub struct UsbDevice {
+    /// Product string of the descriptor, followed by the alias if the board shows it.
+    pub product: String,
+    /// Firmware running on the board, such as "servo" for `ServoClient`.
+    pub firmware: String,
+    /// Firmware version, as "major.minor.patch".
+    pub version: String,
+    pub serial_number: Option<String>,
+    /// Alias shown in the product string. Boards that hide it are not asked for it, as
+    /// that needs a connection.
+    pub alias: Option<String>,
+    /// USB bus of the board.
+    pub bus: String,
+    /// Hub ports from the bus to the board, as in "1-2.4" on Linux, which stays the same
+    /// when the board is plugged into the same port again.
+    pub port_path: String,
+}
+
+#[gen_stub_pymethods]
+#[pymethods]
+impl UsbDevice {
+    fn __repr__(&self) -> String {
+        format!(
+            "UsbDevice(firmware={:?}, version={:?}, serial_number={:?}, alias={:?}, port_path={:?})",
+            self.firmware, self.version, self.serial_number, self.alias, self.port_path
+        )
+    }
+}
+
+impl UsbDevice {
+    /// Entry for the device if it runs one of the client firmwares, or the given one.
+    fn from_device(d: &DeviceInfo, product_string: Option<&str>) -> Option<Self> {
+        if !is_rustpill(d) {
+            return None;
+        }
+        let product = d.product_string()?;
+        let name = PRODUCT_STRINGS
+            .into_iter()
+            .filter(|name| product_string.is_none_or(|product_string| *name == product_string))
+            .find(|name| is_product(product, name))?;
+
+        let bus = d.bus_id().to_owned();
+        let ports = d
+            .port_chain()
+            .iter()
+            .map(|port| port.to_string())
+            .collect::<Vec<_>>()
+            .join(".");
+        Some(Self {
+            product: product.to_owned(),
+            firmware: name.strip_prefix("bluepill-").unwrap_or(name).to_owned(),
+            version: firmware_version(d),
+            serial_number: d.serial_number().map(str::to_owned),
+            alias: product_alias(product).map(str::to_owned),
+            port_path: format!("{}-{}", bus, ports),
+            bus,
+        })
+    }
+}
+
+/// Boards with the product string on the USB bus, or with any client firmware if None.
+pub fn list_boards(product_string: Option<&str>) -> BoardResult<Vec<UsbDevice>> {
+    let devices = nusb::list_devices()
+        .map_err(|e| BoardError::DeviceNotFound(format!("Failed to list USB devices: {}", e)))?;
+    Ok(devices
+        .filter_map(|d| UsbDevice::from_device(&d, product_string))
+        .collect())
+}
+
+/// List the boards attached to the USB bus that run one of the client firmwares, without
+/// connecting to them.
+///
+/// :return: An entry with the firmware, version, serial number, alias and USB port of
+///     each board.
+#[gen_stub_pyfunction]
+#[pyfunction]
+pub fn list_devices() -> BoardResult<Vec<UsbDevice>> {
+    list_boards(None)
+}
@@ -0,0 +1,201 @@
+//! Python exceptions raised for `common::BoardError`.
+
+use std::{convert::Infallible, str::Utf8Error};
+
+use postcard_rpc::{host_client::HostErr, standard_icd::WireError};
+use protocol::{
+    dac::DacError, device::AliasError, pid::PidError, scope::ScopeError, sequencer::SequencerError,
+    update::UpdateError,
+};
+use pyo3::{
+    IntoPyObjectExt,
+    exceptions::{PyException, PyTimeoutError, PyValueError},
+    prelude::*,
+    sync::GILOnceCell,
+    types::{PyDict, PyTuple, PyType},
+};
+use pyo3_stub_gen::create_exception;
+
+create_exception!(
+    rustpill_clients,
+    BoardError,
+    PyException,
+    "Base class of the errors raised by the clients."
+);
+create_exception!(
+    rustpill_clients,
+    ConnectionLostError,
+    BoardError,
+    "The board was unplugged, reset or the connection was closed."
+);
+create_exception!(
+    rustpill_clients,
+    DeviceNotFoundError,
+    BoardError,
+    "No connected board matches the product string, serial number or alias."
+);
+create_exception!(
+    rustpill_clients,
+    ProtocolMismatchError,
+    BoardError,
+    "The board does not understand the request or sent a response the client does not, \
+     usually because the firmware and the client are different versions."
+);
+create_exception!(
+    rustpill_clients,
+    EndpointError,
+    BoardError,
+    "The board rejected the request. The `payload` attribute holds the error of the \
+     endpoint, such as `DacError.Busy`."
+);
+
+/// Exceptions that also derive from a builtin one, so `except ValueError` and
+/// `except TimeoutError` keep catching them. `create_exception!` takes a single base, so
+/// the classes are made with `type()` when the module is loaded.
+macro_rules! create_builtin_exception {
+    ($name:ident, $builtin:ty, $doc:expr) => {
+        pub struct $name;
+
+        impl $name {
+            pub fn type_object(py: Python<'_>) -> PyResult<&Bound<'_, PyType>> {
+                static TYPE_OBJECT: GILOnceCell<Py<PyType>> = GILOnceCell::new();
+                TYPE_OBJECT
+                    .get_or_try_init(py, || {
+                        let bases = PyTuple::new(
+                            py,
+                            [py.get_type::<BoardError>(), py.get_type::<$builtin>()],
+                        )?;
+                        let dict = PyDict::new(py);
+                        dict.set_item("__doc__", $doc)?;
+                        dict.set_item("__module__", "rustpill_clients")?;
+                        Ok(py
+                            .get_type::<PyType>()
+                            .call1((stringify!($name), bases, dict))?
+                            .downcast_into::<PyType>()?
+                            .unbind())
+                    })
+                    .map(|type_object| type_object.bind(py))
+            }
+
+            pub fn new_err(message: String) -> PyErr {
+                Python::with_gil(|py| match Self::type_object(py) {
+                    Ok(type_object) => PyErr::from_type(type_object.clone(), message),
+                    Err(e) => e,
+                })
+            }
+        }
+    };
+}
+
+create_builtin_exception!(
+    InvalidDataError,
+    PyValueError,
+    "The arguments of the call are invalid, or the board is not set up for it, such as a \
+     servo without feedback wiring."
+);
+create_builtin_exception!(
+    BoardTimeoutError,
+    PyTimeoutError,
+    "The board did not respond within the timeout of the client."
+);
+
+/// Stubs of the exceptions made by `create_builtin_exception!`, listing only the
+/// `BoardError` base. These classes are never raised.
+#[allow(dead_code)]
+mod stubs {
+    use super::BoardError;
+    use pyo3_stub_gen::create_exception;
+
+    create_exception!(
+        rustpill_clients,
+        InvalidDataError,
+        BoardError,
+        "The arguments of the call are invalid, or the board is not set up for it, such as \
+         a servo without feedback wiring."
+    );
+    create_exception!(
+        rustpill_clients,
+        BoardTimeoutError,
+        BoardError,
+        "The board did not respond within the timeout of the client."
+    );
+}
+
+/// Register the exceptions in the module.
+pub fn add_exceptions(m: &Bound<'_, PyModule>) -> PyResult<()> {
+    let py = m.py();
+    m.add("BoardError", py.get_type::<BoardError>())?;
+    m.add("ConnectionLostError", py.get_type::<ConnectionLostError>())?;
+    m.add("DeviceNotFoundError", py.get_type::<DeviceNotFoundError>())?;
+    m.add(
+        "ProtocolMismatchError",
+        py.get_type::<ProtocolMismatchError>(),
+    )?;
+    m.add("EndpointError", py.get_type::<EndpointError>())?;
+    m.add("InvalidDataError", InvalidDataError::type_object(py)?)?;
+    m.add("BoardTimeoutError", BoardTimeoutError::type_object(py)?)?;
+    Ok(())
+}
+
+/// Exception for a failed request, by where it failed.
+pub fn comms_error(err: HostErr<WireError>) -> PyErr {
+    match err {
+        HostErr::Closed => ConnectionLostError::new_err("Connection to the board closed"),
+        HostErr::Wire(WireError::FailedToSpawn) => {
+            BoardError::new_err("The board is too busy to handle the request")
+        }
+        err => ProtocolMismatchError::new_err(format!("Comms error: {:?}", err)),
+    }
+}
+
+/// Exception carrying the error an endpoint responded with.
+pub fn endpoint_error<E: EndpointPayload>(err: E) -> PyErr {
+    let message = format!("Endpoint error: {:?}", err);
+    Python::with_gil(|py| {
+        let exception = EndpointError::new_err(message);
+        match err.into_payload(py) {
+            Ok(payload) => match exception.value(py).setattr("payload", payload) {
+                Ok(()) => exception,
+                Err(e) => e,
+            },
+            Err(e) => e,
+        }
+    })
+}
+
+/// Errors of the endpoints, as the `payload` of `EndpointError`.
+pub trait EndpointPayload: std::fmt::Debug {
+    fn into_payload(self, py: Python<'_>) -> PyResult<PyObject>;
+}
+
+impl EndpointPayload for Infallible {
+    fn into_payload(self, _py: Python<'_>) -> PyResult<PyObject> {
+        match self {}
+    }
+}
+
+macro_rules! enum_payload {
+    ($($error:ty),*) => {
+        $(impl EndpointPayload for $error {
+            fn into_payload(self, py: Python<'_>) -> PyResult<PyObject> {
+                self.into_py_any(py)
+            }
+        })*
+    };
+}
+
+enum_payload!(AliasError, DacError, PidError, ScopeError, SequencerError);
+
+/// Has a variant with fields, which the stub generator cannot describe, so it is passed
+/// as the Debug string, such as "CrcMismatch { expected: 1, actual: 2 }".
+impl EndpointPayload for UpdateError {
+    fn into_payload(self, py: Python<'_>) -> PyResult<PyObject> {
+        format!("{:?}", self).into_py_any(py)
+    }
+}
+
+impl EndpointPayload for Utf8Error {
+    fn into_payload(self, py: Python<'_>) -> PyResult<PyObject> {
+        self.to_string().into_py_any(py)
+    }
+}
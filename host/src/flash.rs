@@ -1,9 +1,11 @@
 use std::fs::File;
 use std::io::{BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
 use std::process::{Command, Stdio, exit};
 use std::thread::sleep;
 use std::{env, time};
 
+use protocol::board::Board;
 use pyo3::prelude::*;
 use pyo3_stub_gen_derive::gen_stub_pyfunction;
 use s3_utils::get_bucket;
@@ -58,13 +60,22 @@ pub fn check_probe_rs() {
     }
 }
 
+/// Download the published firmware binary built for the given board and flash it with `probe-rs`.
 #[gen_stub_pyfunction]
 #[pyfunction]
-pub fn flash_binary(binary_name: &str) -> PyResult<()> {
-    check_probe_rs();
+#[pyo3(signature = (binary_name, board = Board::BluepillF103c8))]
+pub fn flash_binary(binary_name: &str, board: Board) -> PyResult<()> {
+    let binary_path = download_binary(binary_name, board.s3_prefix())?;
+    log::info!("Flashing binary: {} to {}", binary_name, board.chip());
+    flash_file(&binary_path, board.chip())
+}
 
+/// Download a published binary from the given directory of the firmware bucket,
+/// unless it is already cached for this version.
+pub fn download_binary(binary_name: &str, prefix: &str) -> PyResult<PathBuf> {
     let binary_path = env::temp_dir()
         .join(env!("CARGO_PKG_VERSION"))
+        .join(prefix)
         .join(binary_name);
 
     if !binary_path.exists() {
@@ -86,7 +97,7 @@ pub fn flash_binary(binary_name: &str) -> PyResult<()> {
         let mut file = File::create(&binary_path)?;
 
         let mut binary = bucket
-            .get_object(["stm32f103c8", env!("CARGO_PKG_VERSION"), binary_name].join("/"))
+            .get_object([prefix, env!("CARGO_PKG_VERSION"), binary_name].join("/"))
             .map_err(|e| {
                 PyErr::new::<pyo3::exceptions::PyRuntimeError, _>(format!(
                     "Failed to get object from S3: {}",
@@ -97,11 +108,17 @@ pub fn flash_binary(binary_name: &str) -> PyResult<()> {
         file.write_all(binary.bytes_mut())?;
         file.flush()?;
     }
-    log::info!("Flashing binary: {}", binary_name);
+
+    Ok(binary_path)
+}
+
+/// Flash an ELF file to the chip with `probe-rs` and reset it.
+pub fn flash_file(binary_path: &Path, chip: &str) -> PyResult<()> {
+    check_probe_rs();
 
     let mut cmd = Command::new("probe-rs");
     cmd.arg("download")
-        .arg("--chip=STM32F103C8")
+        .arg(format!("--chip={}", chip))
         .arg("--non-interactive")
         .arg("--disable-progressbars")
         .arg("--protocol")
@@ -159,7 +176,7 @@ pub fn flash_binary(binary_name: &str) -> PyResult<()> {
 
     Command::new("probe-rs")
         .arg("reset")
-        .arg("--chip=STM32F103C8")
+        .arg(format!("--chip={}", chip))
         .arg("--non-interactive")
         .arg("--protocol")
         .arg("swd")
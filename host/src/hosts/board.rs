@@ -0,0 +1,295 @@
+//! Methods every client shares, such as the timeouts, the metrics and the firmware update.
+
+use std::fmt::Debug;
+
+use crate::common::BoardResult;
+
+/// Parts of the shared methods that depend on what the client keeps about its board.
+pub trait BoardState {
+    /// Load what the client keeps about the board again after `reset` or `update_firmware`
+    /// connected to it, such as the configuration and the subscriptions.
+    async fn reconnected<E: Debug>(&mut self) -> BoardResult<(), E> {
+        Ok(())
+    }
+}
+
+/// Define the Python class of a client, `Class(State)`, for the firmware with the given
+/// protocol module, along with the methods every client shares. The state has to implement
+/// `BoardState` and have the `client` and `metrics_log` fields, and its `close` method can
+/// fail with the endpoint error given after the protocol module.
+macro_rules! board_client {
+    ($(#[$doc:meta])* $class:ident($state:ident), $protocol:ident) => {
+        board_client!($(#[$doc])* $class($state), $protocol, std::convert::Infallible);
+    };
+    ($(#[$doc:meta])* $class:ident($state:ident), $protocol:ident, $close_error:ty) => {
+        $(#[$doc])*
+        /// You can pass a serial number to the constructor to connect to a specific device.
+        /// If no port is passed, it will try to connect to the first available device by
+        /// product string. Pass an alias instead to connect to the board it was given with
+        /// `set_alias`.
+        /// Requests give up after `timeout` seconds, None waits forever, and idempotent ones are
+        /// sent up to `retries` more times before that. The constructor waits up to
+        /// `connect_timeout` seconds for the board to show up on the bus.
+        #[pyo3_stub_gen::derive::gen_stub_pyclass]
+        #[pyo3::pyclass(frozen)]
+        pub struct $class(tokio::sync::RwLock<$state>);
+
+        #[macros::blocking_async($class)]
+        #[pyo3_stub_gen::derive::gen_stub_pymethods]
+        #[pyo3::pymethods]
+        impl $state {
+            /// List the boards with this firmware on the USB bus, without connecting to them.
+            /// Pass the serial number or alias of an entry to the constructor to connect to it.
+            #[staticmethod]
+            fn list() -> $crate::common::BoardResult<Vec<$crate::discovery::UsbDevice>> {
+                $crate::discovery::list_boards(Some(protocol::$protocol::USB_DEVICE_NAME))
+            }
+
+            #[staticmethod]
+            /// Flash the firmware of this client to the board.
+            /// This function will use the `probe-rs` tool to flash the firmware binary to the board.
+            /// :param board: the board the firmware is built for, Blue Pill F103C8 by default.
+            #[pyo3(signature = (board = protocol::board::Board::BluepillF103c8))]
+            fn flash(board: protocol::board::Board) -> pyo3::PyResult<()> {
+                $crate::flash::flash_binary(stringify!($protocol), board)
+            }
+
+            fn __enter__(slf: pyo3::PyRef<'_, Self>) -> pyo3::PyRef<'_, Self> {
+                slf
+            }
+
+            fn __exit__(
+                slf: &pyo3::Bound<'_, Self>,
+                _exc_type: pyo3::PyObject,
+                _exc_value: pyo3::PyObject,
+                _traceback: pyo3::PyObject,
+            ) -> $crate::common::BoardResult<(), $close_error> {
+                slf.get().close(None)
+            }
+
+            fn __aenter__(
+                slf: pyo3::PyRef<'_, Self>,
+                py: pyo3::Python<'_>,
+            ) -> pyo3::PyResult<$crate::asyncio::Awaitable<pyo3::Py<Self>>> {
+                $crate::asyncio::ready(py, slf.into())
+            }
+
+            fn __aexit__(
+                slf: pyo3::PyRef<'_, Self>,
+                py: pyo3::Python<'_>,
+                _exc_type: pyo3::PyObject,
+                _exc_value: pyo3::PyObject,
+                _traceback: pyo3::PyObject,
+            ) -> pyo3::PyResult<
+                $crate::asyncio::Awaitable<$crate::common::BoardResult<(), $close_error>>,
+            > {
+                Self::close_async(slf, py, None)
+            }
+
+            /// Check if the connection to the board is closed.
+            fn is_connected(&self) -> bool {
+                !self.client.is_closed()
+            }
+
+            /// Set the default request timeout of the client.
+            /// :param timeout: seconds to wait for a response, None waits forever.
+            /// :param retries: how many more times to send idempotent requests, such as reading
+            ///     the serial number, when they time out. None keeps the current count.
+            #[pyo3(signature = (timeout, retries = None))]
+            fn set_timeout(
+                &self,
+                timeout: Option<f64>,
+                retries: Option<u8>,
+            ) -> $crate::common::BoardResult<()> {
+                let mut policy = self.client.policy();
+                policy.timeout = $crate::client::timeout_from_secs(timeout)?;
+                policy.retries = retries.unwrap_or(policy.retries);
+                self.client.set_policy(policy);
+                Ok(())
+            }
+
+            /// Get the default request timeout of the client.
+            ///
+            /// :return: The timeout in seconds, None if requests wait forever, and the retries.
+            fn get_timeout(&self) -> (Option<f64>, u8) {
+                let policy = self.client.policy();
+                (
+                    policy.timeout.map(|timeout| timeout.as_secs_f64()),
+                    policy.retries,
+                )
+            }
+
+            /// Change the request timeout of the client inside a `with` block, for example
+            /// `with client.with_timeout(10.0): ...`. Calls from other threads and the background
+            /// tasks use it too, pass `timeout=` to a single slow call instead.
+            /// :param timeout: seconds to wait for a response, None waits forever.
+            fn with_timeout(
+                &self,
+                timeout: Option<f64>,
+            ) -> $crate::common::BoardResult<$crate::client::TimeoutOverride> {
+                Ok(self
+                    .client
+                    .timeout_override($crate::client::timeout_from_secs(timeout)?))
+            }
+
+            /// Get the runtime metrics of the board: uptime, requests handled per endpoint,
+            /// deserialization errors, dropped messages, USB resets and reconnects, peak stack
+            /// usage and the share of the time the executor was idle. Counted since the last reset.
+            ///
+            /// :return: Dict of the metrics, with the request counts keyed by the endpoint path.
+            async fn get_metrics(
+                &self,
+            ) -> $crate::common::BoardResult<pyo3::Py<pyo3::types::PyDict>> {
+                $crate::metrics::get_metrics(&self.client, &protocol::$protocol::ENDPOINT_LIST)
+                    .await
+            }
+
+            /// Log the metrics of the board periodically, until the connection is closed.
+            /// :param interval: seconds between the logs, None stops the logging.
+            #[pyo3(signature = (interval = Some(10.0)))]
+            async fn log_metrics(
+                &mut self,
+                interval: Option<f64>,
+            ) -> $crate::common::BoardResult<()> {
+                if let Some(task) = self.metrics_log.take() {
+                    task.abort();
+                }
+                let Some(interval) = interval else {
+                    return Ok(());
+                };
+                let interval = std::time::Duration::try_from_secs_f64(interval)
+                    .ok()
+                    .filter(|interval| !interval.is_zero())
+                    .ok_or_else(|| {
+                        $crate::common::BoardError::InvalidData(format!(
+                            "Invalid metrics interval: {} s",
+                            interval
+                        ))
+                    })?;
+                self.metrics_log = Some($crate::metrics::spawn_metrics_log(
+                    self.client.clone(),
+                    &protocol::$protocol::ENDPOINT_LIST,
+                    interval,
+                ));
+                Ok(())
+            }
+
+            /// Blink the status LED of the board rapidly for a few seconds, to find which
+            /// physical board this client is connected to.
+            async fn identify(&self) -> $crate::common::BoardResult<()> {
+                self.client
+                    .send_resp::<protocol::$protocol::IdentifyEndpoint>(&())
+                    .await?;
+                Ok(())
+            }
+
+            /// Get the board model, firmware version, serial number and alias of the board.
+            ///
+            /// :return: Dict with the `board`, `version`, `serial_number`, `alias` and
+            ///     `alias_in_usb_product` keys.
+            async fn get_device_info(
+                &self,
+            ) -> $crate::common::BoardResult<pyo3::Py<pyo3::types::PyDict>> {
+                $crate::device::get_device_info(&self.client).await
+            }
+
+            /// Get the alias stored on the board, empty if none was set.
+            async fn get_alias(&self) -> $crate::common::BoardResult<String> {
+                let alias = self
+                    .client
+                    .query::<protocol::$protocol::GetAlias>(&())
+                    .await?;
+                Ok(alias.to_string())
+            }
+
+            /// Store an alias on the board, such as "left-arm", to connect to it with
+            /// `alias="left-arm"` instead of the serial number. The alias survives power cycles.
+            /// :param alias: up to 16 ASCII letters, digits, `-`, `_` or `.`, empty clears it.
+            /// :param usb_product: also show the alias in the USB product string, as
+            ///     "name (alias)", after the next reset.
+            #[pyo3(signature = (alias, usb_product = false))]
+            async fn set_alias(
+                &self,
+                alias: &str,
+                usb_product: bool,
+            ) -> $crate::common::BoardResult<(), protocol::device::AliasError> {
+                $crate::device::set_alias(&self.client, alias, usb_product).await
+            }
+
+            /// Get the serial number of the board.
+            /// The ID is a 92-bit number, which is padded to 128 bits with zeros.
+            ///
+            /// :return: The serial number of the board.
+            async fn get_serial_number(
+                &self,
+            ) -> $crate::common::BoardResult<String, std::str::Utf8Error> {
+                let id = self
+                    .client
+                    .query::<protocol::$protocol::GetUniqueIdEndpoint>(&())
+                    .await?;
+                let id = str::from_utf8(&id).map_err($crate::common::BoardError::Endpoint)?;
+                Ok(id.to_owned())
+            }
+
+            /// Get the report of the crash that caused the last reset of the board, if any.
+            /// The report survives resets, but not power cycles.
+            ///
+            /// :return: Description of the panic or HardFault, None if there was no crash.
+            async fn get_crash_report(&self) -> $crate::common::BoardResult<Option<String>> {
+                let report = self
+                    .client
+                    .query::<protocol::$protocol::GetCrashReport>(&())
+                    .await?;
+                Ok(report.map(|report| report.to_string()))
+            }
+
+            /// Forget the last crash report, so it is not logged on the next connection.
+            async fn clear_crash_report(&self) -> $crate::common::BoardResult<()> {
+                self.client
+                    .send_resp::<protocol::$protocol::ClearCrashReport>(&())
+                    .await?;
+                Ok(())
+            }
+
+            /// Reset the board and connect to it again once it is back.
+            /// Anything that was configured since the board started is lost.
+            async fn reset(&mut self) -> $crate::common::BoardResult<()> {
+                $crate::common::reset_board(
+                    &mut self.client,
+                    protocol::$protocol::USB_DEVICE_NAME,
+                )
+                .await?;
+                $crate::hosts::board::BoardState::reconnected(self).await
+            }
+
+            /// Update the firmware over USB and connect to the new one. The update is swapped in
+            /// by the bootloader and confirmed once the board is back, if it does not come back
+            /// the bootloader reverts it on the next reset. Firmware built without the `bootloader`
+            /// feature is flashed with `probe-rs` instead.
+            /// :param path_or_name: ELF or `.bin` file, or the name of a published binary.
+            async fn update_firmware(
+                &mut self,
+                path_or_name: &str,
+            ) -> $crate::common::BoardResult<(), protocol::update::UpdateError> {
+                $crate::update::update_firmware(
+                    &mut self.client,
+                    protocol::$protocol::USB_DEVICE_NAME,
+                    path_or_name,
+                )
+                .await?;
+                $crate::hosts::board::BoardState::reconnected(self).await
+            }
+
+            /// Reset the board into the bootloader. The connection is closed, as the board no
+            /// longer runs this firmware. Only the Black Pill bootloader supports USB DFU,
+            /// the F103 one talks over USART1.
+            async fn enter_bootloader(&self) -> $crate::common::BoardResult<()> {
+                self.client
+                    .send_resp::<protocol::$protocol::EnterBootloaderEndpoint>(&())
+                    .await?;
+                self.client.close();
+                Ok(())
+            }
+        }
+    };
+}
@@ -0,0 +1,323 @@
+use std::fmt::Debug;
+
+use macros::blocking_async;
+use protocol::dac::*;
+use pyo3::prelude::*;
+use pyo3_stub_gen::derive::*;
+use tokio::task::JoinHandle;
+
+use super::board::BoardState;
+use crate::{
+    client::{
+        BoardClient, DEFAULT_TIMEOUT, RequestPolicy, cleanup_on_drop, connect_timeout_from_secs,
+    },
+    common::{BoardError, BoardResult, connect_to_board},
+};
+
+board_client! {
+    /// This class communicates with Bluepill DAC Rust firmware. The firmware drives an external
+    /// MCP4922 or AD5686 SPI DAC (SCK on PA5, MOSI on PA7, chip select on PA4) at a timer-paced update rate.
+    /// All voltages are on the calibrated outputs, see `set_calibration`.
+    DacClient(DacState), dac
+}
+
+/// State of a `DacClient`, locked by its calls.
+struct DacState {
+    client: BoardClient,
+    metrics_log: Option<JoinHandle<()>>,
+    disable_on_close: bool,
+    config: DacConfig,
+    limits: DacLimits,
+}
+
+#[blocking_async(DacClient)]
+#[gen_stub_pymethods]
+#[pymethods]
+impl DacState {
+    #[new]
+    #[pyo3(signature = (
+        serial_number = None,
+        alias = None,
+        timeout = Some(DEFAULT_TIMEOUT),
+        retries = 0,
+        connect_timeout = 0.0,
+    ))]
+    async fn new(
+        serial_number: Option<&str>,
+        alias: Option<&str>,
+        timeout: Option<f64>,
+        retries: u8,
+        connect_timeout: f64,
+    ) -> BoardResult<Self> {
+        let policy = RequestPolicy::from_secs(timeout, retries)?;
+        let client = connect_to_board(
+            USB_DEVICE_NAME,
+            serial_number,
+            alias,
+            connect_timeout_from_secs(connect_timeout)?,
+            policy,
+        )
+        .await?;
+        let client = BoardClient::new(client, policy);
+
+        let config = client.send_resp::<GetDacConfig>(&()).await?;
+        log::info!("DAC config: {:?}", config);
+        let limits = client.send_resp::<GetDacLimits>(&()).await?;
+
+        Ok(Self {
+            client,
+            metrics_log: None,
+            disable_on_close: false,
+            config,
+            limits,
+        })
+    }
+
+    /// Close the connection to the board and stop logging the metrics. With
+    /// `disable_on_close` set, the outputs are stopped first.
+    /// The client is also closed when it is garbage collected or at the end of a `with` block.
+    async fn close(&mut self) -> BoardResult<()> {
+        if let Some(task) = self.metrics_log.take() {
+            task.abort();
+        }
+        let result = if self.disable_on_close && !self.client.is_closed() {
+            disable_outputs(&self.client).await
+        } else {
+            Ok(())
+        };
+        self.client.close();
+        result
+    }
+
+    /// Whether the outputs are stopped when the client is closed or dropped.
+    #[getter]
+    fn disable_on_close(&self) -> bool {
+        self.disable_on_close
+    }
+
+    #[setter]
+    fn set_disable_on_close(&mut self, disable_on_close: bool) {
+        self.disable_on_close = disable_on_close;
+    }
+
+    #[getter]
+    fn config(&self) -> DacConfig {
+        self.config
+    }
+
+    #[getter]
+    fn limits(&self) -> DacLimits {
+        self.limits
+    }
+
+    /// Get the DAC configuration from the board and store it in `config`.
+    async fn update_config(&mut self) -> BoardResult<()> {
+        self.config = self.client.send_resp::<GetDacConfig>(&()).await?;
+        Ok(())
+    }
+
+    /// Send a complete DAC configuration to the board. The output has to be stopped.
+    /// Uploaded waveform tables are cleared and all channels are set to their lowest output.
+    ///
+    /// :param config: The DacConfig object, usually a modified copy of `config`.
+    async fn set_config(&mut self, config: DacConfig) -> BoardResult<(), DacError> {
+        config.validate().map_err(BoardError::Endpoint)?;
+        self.client
+            .send_resp::<SetDacConfig>(&config)
+            .await?
+            .map_err(BoardError::Endpoint)?;
+        self.config = config;
+        Ok(())
+    }
+
+    /// Set the calibration of a channel, the output is `gain * dac_volts + offset`.
+    /// Same restrictions as `set_config` apply.
+    ///
+    /// :param channel: The channel number, starting at 0.
+    /// :param gain: Gain from the DAC output to the calibrated output, e.g. of a piezo amplifier.
+    /// :param offset: Calibrated output voltage at DAC code 0.
+    #[pyo3(signature = (channel, gain = 1.0, offset = 0.0))]
+    async fn set_calibration(
+        &mut self,
+        channel: u8,
+        gain: f32,
+        offset: f32,
+    ) -> BoardResult<(), DacError> {
+        if channel >= self.config.model.channels() {
+            return Err(BoardError::Endpoint(DacError::InvalidChannel));
+        }
+        let mut config = self.config;
+        config.channels[channel as usize] = ChannelCalibration {
+            gain,
+            offset_v: offset,
+        };
+        self.set_config(config).await
+    }
+
+    /// Get the output range of a channel with the current calibration.
+    ///
+    /// :return: Tuple of the voltages at the lowest and highest DAC code.
+    fn get_range(&self, channel: u8) -> BoardResult<(f32, f32), DacError> {
+        let low = self
+            .config
+            .code_to_volts(channel, 0)
+            .map_err(BoardError::Endpoint)?;
+        let high = self
+            .config
+            .code_to_volts(channel, self.config.model.max_code())
+            .map_err(BoardError::Endpoint)?;
+        Ok((low, high))
+    }
+
+    /// Output a constant voltage on a channel.
+    async fn set_voltage(&self, channel: u8, volts: f32) -> BoardResult<(), DacError> {
+        self.send_waveform(channel, Waveform::Static { volts })
+            .await
+    }
+
+    /// Output a sawtooth ramp on a channel while the output is running.
+    ///
+    /// :param start: Voltage at the start of the ramp.
+    /// :param end: Voltage at the end of the ramp, after which it jumps back to `start`.
+    /// :param points: Number of updates in one ramp, at least 2.
+    async fn ramp(
+        &self,
+        channel: u8,
+        start: f32,
+        end: f32,
+        points: u32,
+    ) -> BoardResult<(), DacError> {
+        let waveform = Waveform::Ramp {
+            start_v: start,
+            end_v: end,
+            points,
+        };
+        self.send_waveform(channel, waveform).await
+    }
+
+    /// Output a triangle on a channel while the output is running.
+    ///
+    /// :param start: Voltage at the start and end of the triangle.
+    /// :param end: Voltage at the turning point.
+    /// :param points: Number of updates going one way, at least 2.
+    async fn triangle(
+        &self,
+        channel: u8,
+        start: f32,
+        end: f32,
+        points: u32,
+    ) -> BoardResult<(), DacError> {
+        let waveform = Waveform::Triangle {
+            start_v: start,
+            end_v: end,
+            points,
+        };
+        self.send_waveform(channel, waveform).await
+    }
+
+    /// Upload an arbitrary waveform and play it on a channel while the output is running.
+    /// The table is converted to DAC codes on the board with the current calibration.
+    ///
+    /// :param channel: The channel number, starting at 0.
+    /// :param volts: One voltage per update, at most `limits.max_points` of them.
+    async fn set_table(&self, channel: u8, volts: Vec<f32>) -> BoardResult<(), DacError> {
+        if volts.is_empty() || volts.len() > self.limits.max_points as usize {
+            return Err(BoardError::InvalidData(format!(
+                "Table length {} out of 1-{} range",
+                volts.len(),
+                self.limits.max_points
+            )));
+        }
+        for (i, chunk) in volts.chunks(CHUNK_SIZE).enumerate() {
+            let chunk = TableChunk {
+                channel,
+                offset: (i * CHUNK_SIZE) as u16,
+                volts: heapless::Vec::from_slice(chunk).unwrap(),
+            };
+            self.client
+                .send_resp::<UploadTable>(&chunk)
+                .await?
+                .map_err(BoardError::Endpoint)?;
+        }
+        log::info!("Uploaded {} points to channel {}", volts.len(), channel);
+
+        let waveform = Waveform::Table {
+            length: volts.len() as u16,
+        };
+        self.send_waveform(channel, waveform).await
+    }
+
+    /// Start updating the outputs. All channels restart from their first point.
+    ///
+    /// :param update_rate: Requested update rate in Hz, the device picks the closest one it can do.
+    /// :return: The actual update rate in Hz.
+    async fn start(&self, update_rate: f64) -> BoardResult<f32, DacError> {
+        let update_rate = self
+            .client
+            .send_resp::<StartOutput>(&(update_rate.round() as u32))
+            .await?
+            .map_err(BoardError::Endpoint)?;
+        log::info!("Started output at {} Hz", update_rate);
+        Ok(update_rate)
+    }
+
+    /// Stop updating the outputs, they keep the last written voltage.
+    async fn stop(&self) -> BoardResult<()> {
+        self.client.send_resp::<StopOutput>(&()).await?;
+        Ok(())
+    }
+
+    /// Get the state of the output.
+    ///
+    /// :return: The DacStatus object.
+    async fn status(&self) -> BoardResult<DacStatus> {
+        let status = self.client.send_resp::<GetDacStatus>(&()).await?;
+        Ok(status)
+    }
+}
+
+impl BoardState for DacState {
+    async fn reconnected<E: Debug>(&mut self) -> BoardResult<(), E> {
+        self.config = self.client.send_resp::<GetDacConfig>(&()).await?;
+        Ok(())
+    }
+}
+
+impl Drop for DacState {
+    fn drop(&mut self) {
+        if let Some(task) = self.metrics_log.take() {
+            task.abort();
+        }
+        if self.client.is_closed() {
+            return;
+        }
+        let client = self.client.clone();
+        let disable_on_close = self.disable_on_close;
+        cleanup_on_drop(self.client.clone(), async move {
+            let result = if disable_on_close {
+                disable_outputs(&client).await
+            } else {
+                Ok(())
+            };
+            if let Err(e) = result {
+                log::warn!("Could not disable the outputs: {:?}", e);
+            }
+        });
+    }
+}
+
+impl DacState {
+    async fn send_waveform(&self, channel: u8, waveform: Waveform) -> BoardResult<(), DacError> {
+        self.client
+            .send_resp::<SetWaveform>(&(channel, waveform))
+            .await?
+            .map_err(BoardError::Endpoint)?;
+        Ok(())
+    }
+}
+
+/// Stop updating the outputs.
+async fn disable_outputs(client: &BoardClient) -> BoardResult<()> {
+    client.send_resp::<StopOutput>(&()).await?;
+    Ok(())
+}
@@ -1,70 +1,80 @@
-use std::{path::Path, str::Utf8Error};
-
-use postcard_rpc::{host_client::HostClient, standard_icd::WireError};
 use pyo3::prelude::*;
 use pyo3_stub_gen::derive::*;
+use tokio::task::JoinHandle;
 
+use super::board::BoardState;
 use crate::{
-    common::{BoardError, BoardResult, connect_to_board},
-    flash::flash_binary,
+    client::{BoardClient, DEFAULT_TIMEOUT, RequestPolicy, connect_timeout_from_secs},
+    common::{BoardResult, connect_to_board},
 };
 use macros::blocking_async;
 
 use protocol::minimal::*; // Change minimal to your protocol module
 
-/// This class communicates with Bluepill Rust firmware. You can pass a serial number to the
-/// constructor to connect to a specific device. If no port is passed, it will try to connect to the first
-/// available device by product string.
-#[gen_stub_pyclass]
-#[pyclass]
-pub struct MinimalClient {
-    client: HostClient<WireError>,
+board_client! {
+    /// This class communicates with Bluepill Rust firmware.
+    MinimalClient(MinimalState), minimal
+}
+
+/// State of a `MinimalClient`, locked by its calls.
+struct MinimalState {
+    client: BoardClient,
+    metrics_log: Option<JoinHandle<()>>,
 }
 
-#[blocking_async]
+#[blocking_async(MinimalClient)]
 #[gen_stub_pymethods]
 #[pymethods]
-impl MinimalClient {
+impl MinimalState {
     #[new]
-    #[pyo3(signature = (serial_number = None))]
-    async fn new(serial_number: Option<&str>) -> BoardResult<Self> {
-        let client = connect_to_board(USB_DEVICE_NAME, serial_number).await?;
-        Ok(Self { client })
-    }
-
-    #[staticmethod]
-    /// Flash the firmware to the board.
-    /// This function will use the `probe-rs` tool to flash the firmware binary to the board.
-    fn flash() -> PyResult<()> {
-        let filename = Path::new(file!())
-        .file_stem()
-        .and_then(|s| s.to_str())
-        .ok_or_else(|| {
-            pyo3::exceptions::PyChildProcessError::new_err(
-                "Mismatch between host filename and binary name. Use the flash_binary function with correct binary name.",
-            )
-        })?;
-        flash_binary(filename)?;
-        Ok(())
+    #[pyo3(signature = (
+        serial_number = None,
+        alias = None,
+        timeout = Some(DEFAULT_TIMEOUT),
+        retries = 0,
+        connect_timeout = 0.0,
+    ))]
+    async fn new(
+        serial_number: Option<&str>,
+        alias: Option<&str>,
+        timeout: Option<f64>,
+        retries: u8,
+        connect_timeout: f64,
+    ) -> BoardResult<Self> {
+        let policy = RequestPolicy::from_secs(timeout, retries)?;
+        let client = connect_to_board(
+            USB_DEVICE_NAME,
+            serial_number,
+            alias,
+            connect_timeout_from_secs(connect_timeout)?,
+            policy,
+        )
+        .await?;
+        let client = BoardClient::new(client, policy);
+        Ok(Self {
+            client,
+            metrics_log: None,
+        })
     }
 
-    /// Close the connection to the board.
-    fn close(&self) {
+    /// Close the connection to the board and stop logging the metrics.
+    /// The client is also closed when it is garbage collected or at the end of a `with` block.
+    async fn close(&mut self) -> BoardResult<()> {
+        if let Some(task) = self.metrics_log.take() {
+            task.abort();
+        }
         self.client.close();
+        Ok(())
     }
+}
 
-    /// Check if the connection to the board is closed.
-    fn is_connected(&self) -> bool {
-        !self.client.is_closed()
-    }
+impl BoardState for MinimalState {}
 
-    /// Get the serial number of the board.
-    /// The ID is a 92-bit number, which is padded to 128 bits with zeros.
-    ///
-    /// :return: The serial number of the board.
-    async fn get_serial_number(&self) -> BoardResult<String, Utf8Error> {
-        let id = self.client.send_resp::<GetUniqueIdEndpoint>(&()).await?;
-        let id = str::from_utf8(&id).map_err(BoardError::Endpoint)?;
-        Ok(id.to_owned())
+impl Drop for MinimalState {
+    fn drop(&mut self) {
+        if let Some(task) = self.metrics_log.take() {
+            task.abort();
+        }
+        self.client.close();
     }
 }
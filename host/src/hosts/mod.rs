@@ -1,2 +1,9 @@
+#[macro_use]
+mod board;
+
+pub mod dac;
 pub mod minimal;
+pub mod pid;
+pub mod scope;
+pub mod sequencer;
 pub mod servo;
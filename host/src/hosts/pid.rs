@@ -0,0 +1,296 @@
+use std::{
+    collections::VecDeque,
+    fmt::Debug,
+    sync::{Arc, Mutex},
+};
+
+use macros::blocking_async;
+use postcard_rpc::{
+    host_client::{HostClient, HostErr},
+    standard_icd::WireError,
+};
+use protocol::pid::*;
+use pyo3::prelude::*;
+use pyo3_stub_gen::derive::*;
+use tokio::task::JoinHandle;
+
+use super::board::BoardState;
+use crate::{
+    client::{
+        BoardClient, DEFAULT_TIMEOUT, RequestPolicy, cleanup_on_drop, connect_timeout_from_secs,
+    },
+    common::{BoardError, BoardResult, connect_to_board},
+};
+
+/// Number of telemetry samples kept on the host until they are read.
+const TELEMETRY_BUFFER: usize = 10_000;
+
+board_client! {
+    /// This class communicates with Bluepill PID Rust firmware. The firmware reads the measurement on PA0
+    /// and drives a PWM output on PA6 at a fixed control rate.
+    PidClient(PidState), pid, PidError
+}
+
+/// State of a `PidClient`, locked by its calls.
+struct PidState {
+    client: BoardClient,
+    metrics_log: Option<JoinHandle<()>>,
+    disable_on_close: bool,
+    config: PidConfig,
+    telemetry: Arc<Mutex<VecDeque<PidTelemetry>>>,
+}
+
+#[blocking_async(PidClient)]
+#[gen_stub_pymethods]
+#[pymethods]
+impl PidState {
+    #[new]
+    #[pyo3(signature = (
+        serial_number = None,
+        alias = None,
+        timeout = Some(DEFAULT_TIMEOUT),
+        retries = 0,
+        connect_timeout = 0.0,
+    ))]
+    async fn new(
+        serial_number: Option<&str>,
+        alias: Option<&str>,
+        timeout: Option<f64>,
+        retries: u8,
+        connect_timeout: f64,
+    ) -> BoardResult<Self> {
+        let policy = RequestPolicy::from_secs(timeout, retries)?;
+        let client = connect_to_board(
+            USB_DEVICE_NAME,
+            serial_number,
+            alias,
+            connect_timeout_from_secs(connect_timeout)?,
+            policy,
+        )
+        .await?;
+        let client = BoardClient::new(client, policy);
+
+        let config = client.send_resp::<GetPidConfig>(&()).await?;
+        log::info!("PID config: {:?}", config);
+
+        let telemetry = Arc::new(Mutex::new(VecDeque::with_capacity(TELEMETRY_BUFFER)));
+        subscribe_telemetry(&client, telemetry.clone()).await?;
+
+        Ok(Self {
+            client,
+            metrics_log: None,
+            disable_on_close: false,
+            config,
+            telemetry,
+        })
+    }
+
+    /// Close the connection to the board and stop logging the metrics. With
+    /// `disable_on_close` set, the controller is disabled first.
+    /// The client is also closed when it is garbage collected or at the end of a `with` block.
+    async fn close(&mut self) -> BoardResult<(), PidError> {
+        if let Some(task) = self.metrics_log.take() {
+            task.abort();
+        }
+        let result = if self.disable_on_close && !self.client.is_closed() {
+            disable_outputs(&self.client, &mut self.config).await
+        } else {
+            Ok(())
+        };
+        self.client.close();
+        result
+    }
+
+    /// Whether the controller is disabled when the client is closed or dropped.
+    #[getter]
+    fn disable_on_close(&self) -> bool {
+        self.disable_on_close
+    }
+
+    #[setter]
+    fn set_disable_on_close(&mut self, disable_on_close: bool) {
+        self.disable_on_close = disable_on_close;
+    }
+
+    #[getter]
+    fn config(&self) -> PidConfig {
+        self.config
+    }
+
+    /// Get the controller configuration from the board and store it in `config`.
+    async fn update_config(&mut self) -> BoardResult<()> {
+        self.config = self.client.send_resp::<GetPidConfig>(&()).await?;
+        Ok(())
+    }
+
+    /// Send a complete controller configuration to the board.
+    /// The configuration is validated on the host and again on the device.
+    ///
+    /// :param config: The PidConfig object, usually a modified copy of `config`.
+    async fn set_config(&mut self, config: PidConfig) -> BoardResult<(), PidError> {
+        config.validate().map_err(BoardError::Endpoint)?;
+        self.client
+            .send_resp::<SetPidConfig>(&config)
+            .await?
+            .map_err(BoardError::Endpoint)?;
+        self.config = config;
+        Ok(())
+    }
+
+    /// Set the controller gains. Leave arguments as None to not change them.
+    ///
+    /// :param kp: Proportional gain, in output fraction per measurement unit.
+    /// :param ki: Integral gain, in output fraction per measurement unit and second.
+    /// :param kd: Derivative gain, in output fraction per measurement unit per second.
+    #[pyo3(signature = (kp = None, ki = None, kd = None))]
+    async fn set_gains(
+        &mut self,
+        kp: Option<f32>,
+        ki: Option<f32>,
+        kd: Option<f32>,
+    ) -> BoardResult<(), PidError> {
+        let config = PidConfig {
+            kp: kp.unwrap_or(self.config.kp),
+            ki: ki.unwrap_or(self.config.ki),
+            kd: kd.unwrap_or(self.config.kd),
+            ..self.config
+        };
+        self.set_config(config).await
+    }
+
+    /// Set the output limits as duty cycle fractions in the 0-1 range.
+    async fn set_output_limits(
+        &mut self,
+        output_min: f32,
+        output_max: f32,
+    ) -> BoardResult<(), PidError> {
+        let config = PidConfig {
+            output_min,
+            output_max,
+            ..self.config
+        };
+        self.set_config(config).await
+    }
+
+    /// Set the setpoint in measurement units.
+    async fn set_setpoint(&mut self, setpoint: f32) -> BoardResult<(), PidError> {
+        self.client
+            .send_resp::<SetSetpoint>(&setpoint)
+            .await?
+            .map_err(BoardError::Endpoint)?;
+        self.config.setpoint = setpoint;
+        Ok(())
+    }
+
+    /// Start closing the loop. The controller starts with a cleared integral.
+    async fn enable(&mut self) -> BoardResult<(), PidError> {
+        let config = PidConfig {
+            enabled: true,
+            ..self.config
+        };
+        self.set_config(config).await
+    }
+
+    /// Stop the controller, the output is held at 0.
+    async fn disable(&mut self) -> BoardResult<(), PidError> {
+        let config = PidConfig {
+            enabled: false,
+            ..self.config
+        };
+        self.set_config(config).await
+    }
+
+    /// Clear the integral and filter states of the controller.
+    async fn reset_controller(&self) -> BoardResult<()> {
+        self.client.send_resp::<ResetController>(&()).await?;
+        Ok(())
+    }
+
+    /// Get the telemetry received since the last call, oldest first.
+    /// The board publishes a sample every `config.telemetry_divider` control steps.
+    ///
+    /// :return: List of PidTelemetry objects.
+    fn get_telemetry(&self) -> Vec<PidTelemetry> {
+        self.telemetry.lock().unwrap().drain(..).collect()
+    }
+}
+
+impl BoardState for PidState {
+    async fn reconnected<E: Debug>(&mut self) -> BoardResult<(), E> {
+        self.config = self.client.send_resp::<GetPidConfig>(&()).await?;
+        subscribe_telemetry(&self.client, self.telemetry.clone()).await?;
+        Ok(())
+    }
+}
+
+impl Drop for PidState {
+    fn drop(&mut self) {
+        if let Some(task) = self.metrics_log.take() {
+            task.abort();
+        }
+        if self.client.is_closed() {
+            return;
+        }
+        let client = self.client.clone();
+        let mut config = self.config;
+        let disable_on_close = self.disable_on_close;
+        cleanup_on_drop(self.client.clone(), async move {
+            let result = if disable_on_close {
+                disable_outputs(&client, &mut config).await
+            } else {
+                Ok(())
+            };
+            if let Err(e) = result {
+                log::warn!("Could not disable the outputs: {:?}", e);
+            }
+        });
+    }
+}
+
+/// Collect telemetry in the background, oldest samples are dropped when nobody reads them.
+async fn subscribe_telemetry(
+    client: &HostClient<WireError>,
+    buffer: Arc<Mutex<VecDeque<PidTelemetry>>>,
+) -> Result<(), HostErr<WireError>> {
+    let mut subscription = client
+        .subscribe_multi::<PidTelemetryTopic>(64)
+        .await
+        .map_err(|_| HostErr::Closed)?;
+
+    core::mem::drop(tokio::task::spawn(async move {
+        loop {
+            match subscription.recv().await {
+                Ok(sample) => {
+                    let mut buffer = buffer.lock().unwrap();
+                    if buffer.len() == TELEMETRY_BUFFER {
+                        buffer.pop_front();
+                    }
+                    buffer.push_back(sample);
+                }
+                Err(e) => {
+                    log::error!("Telemetry subscription error: {:?}", e);
+                    break;
+                }
+            }
+        }
+    }));
+
+    Ok(())
+}
+
+/// Stop the controller, the output is held at 0.
+async fn disable_outputs(
+    client: &BoardClient,
+    config: &mut PidConfig,
+) -> BoardResult<(), PidError> {
+    let disabled = PidConfig {
+        enabled: false,
+        ..*config
+    };
+    client
+        .send_resp::<SetPidConfig>(&disabled)
+        .await?
+        .map_err(BoardError::Endpoint)?;
+    *config = disabled;
+    Ok(())
+}
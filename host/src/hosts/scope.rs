@@ -0,0 +1,329 @@
+use std::time::{Duration, Instant};
+
+use macros::blocking_async;
+use protocol::scope::*;
+use pyo3::prelude::*;
+use pyo3_stub_gen::derive::*;
+use tokio::task::JoinHandle;
+
+use super::board::BoardState;
+use crate::{
+    client::{
+        BoardClient, DEFAULT_TIMEOUT, RequestPolicy, cleanup_on_drop, connect_timeout_from_secs,
+    },
+    common::{BoardError, BoardResult, connect_to_board},
+};
+
+/// Interval between status requests while waiting for a capture.
+const POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+board_client! {
+    /// This class communicates with Bluepill Scope Rust firmware. The firmware records bursts of ADC samples
+    /// around a trigger condition on one of the analog inputs PA0-PA7 (channels 0-7) or PB0-PB1 (channels 8-9).
+    ScopeClient(ScopeState), scope
+}
+
+/// State of a `ScopeClient`, locked by its calls.
+struct ScopeState {
+    client: BoardClient,
+    metrics_log: Option<JoinHandle<()>>,
+    limits: ScopeLimits,
+}
+
+#[blocking_async(ScopeClient)]
+#[gen_stub_pymethods]
+#[pymethods]
+impl ScopeState {
+    #[new]
+    #[pyo3(signature = (
+        serial_number = None,
+        alias = None,
+        timeout = Some(DEFAULT_TIMEOUT),
+        retries = 0,
+        connect_timeout = 0.0,
+    ))]
+    async fn new(
+        serial_number: Option<&str>,
+        alias: Option<&str>,
+        timeout: Option<f64>,
+        retries: u8,
+        connect_timeout: f64,
+    ) -> BoardResult<Self> {
+        let policy = RequestPolicy::from_secs(timeout, retries)?;
+        let client = connect_to_board(
+            USB_DEVICE_NAME,
+            serial_number,
+            alias,
+            connect_timeout_from_secs(connect_timeout)?,
+            policy,
+        )
+        .await?;
+        let client = BoardClient::new(client, policy);
+
+        let limits = client.send_resp::<GetScopeLimits>(&()).await?;
+        log::info!("Scope limits: {:?}", limits);
+
+        Ok(Self {
+            client,
+            metrics_log: None,
+            limits,
+        })
+    }
+
+    /// Close the connection to the board and stop logging the metrics.
+    /// The client is also closed when it is garbage collected or at the end of a `with` block.
+    async fn close(&mut self) -> BoardResult<()> {
+        if let Some(task) = self.metrics_log.take() {
+            task.abort();
+        }
+        self.client.close();
+        Ok(())
+    }
+
+    #[getter]
+    fn limits(&self) -> ScopeLimits {
+        self.limits
+    }
+
+    /// Arm a capture. The board starts sampling immediately and keeps `pre_trigger` samples
+    /// from before the trigger condition is met.
+    ///
+    /// :param channel: ADC channel (0-9).
+    /// :param sample_rate: Requested sample rate in Hz, the device picks the closest one it can do.
+    /// :param length: Total number of samples.
+    /// :param pre_trigger: Number of samples before the trigger.
+    /// :param trigger: Trigger condition.
+    /// :param level: Trigger level in volts.
+    /// :param hysteresis: For edge triggers, how far in volts the signal must move away from the level first.
+    /// :return: The actual sample rate in Hz.
+    #[pyo3(signature = (
+        channel = 0,
+        sample_rate = 100_000.0,
+        length = 1024,
+        pre_trigger = 128,
+        trigger = ScopeTrigger::Rising,
+        level = 1.65,
+        hysteresis = 0.05,
+    ))]
+    #[allow(clippy::too_many_arguments)]
+    async fn arm(
+        &self,
+        channel: u8,
+        sample_rate: f64,
+        length: u16,
+        pre_trigger: u16,
+        trigger: ScopeTrigger,
+        level: f32,
+        hysteresis: f32,
+    ) -> BoardResult<f32, ScopeError> {
+        let request = capture_request(
+            &self.limits,
+            channel,
+            sample_rate,
+            length,
+            pre_trigger,
+            trigger,
+            level,
+            hysteresis,
+        )?;
+        arm_capture(&self.client, &request).await
+    }
+
+    /// Stop the current capture.
+    async fn abort(&self) -> BoardResult<()> {
+        self.client.send_resp::<AbortCapture>(&()).await?;
+        Ok(())
+    }
+
+    /// Get the state of the current capture.
+    ///
+    /// :return: The CaptureStatus object.
+    async fn status(&self) -> BoardResult<CaptureStatus> {
+        let status = self.client.send_resp::<GetCaptureStatus>(&()).await?;
+        Ok(status)
+    }
+
+    /// Download a finished capture.
+    ///
+    /// :return: Tuple of time axis in seconds with the trigger at 0, values in volts and the sample rate in Hz.
+    async fn read(&self) -> BoardResult<(Vec<f64>, Vec<f32>, f32), ScopeError> {
+        read_capture(&self.client).await
+    }
+
+    /// Arm a capture, wait for it to finish and download it. Takes the same arguments as `arm`.
+    /// Use `numpy.asarray` on the returned lists for numeric work.
+    ///
+    /// :param trigger_timeout: Seconds to wait for the trigger, the capture is aborted
+    ///     afterwards and `BoardTimeoutError` is raised. The `timeout` of the call applies to
+    ///     each request instead.
+    /// :return: Tuple of time axis in seconds with the trigger at 0, values in volts and the sample rate in Hz.
+    #[pyo3(signature = (
+        channel = 0,
+        sample_rate = 100_000.0,
+        length = 1024,
+        pre_trigger = 128,
+        trigger = ScopeTrigger::Rising,
+        level = 1.65,
+        hysteresis = 0.05,
+        trigger_timeout = 1.0,
+    ))]
+    #[allow(clippy::too_many_arguments)]
+    async fn capture(
+        &self,
+        channel: u8,
+        sample_rate: f64,
+        length: u16,
+        pre_trigger: u16,
+        trigger: ScopeTrigger,
+        level: f32,
+        hysteresis: f32,
+        trigger_timeout: f64,
+    ) -> BoardResult<(Vec<f64>, Vec<f32>, f32), ScopeError> {
+        let wait = Duration::try_from_secs_f64(trigger_timeout).map_err(|_| {
+            BoardError::InvalidData(format!("Invalid trigger timeout: {} s", trigger_timeout))
+        })?;
+        let request = capture_request(
+            &self.limits,
+            channel,
+            sample_rate,
+            length,
+            pre_trigger,
+            trigger,
+            level,
+            hysteresis,
+        )?;
+        let sample_rate = arm_capture(&self.client, &request).await?;
+
+        // The capture itself takes length / sample_rate on top of waiting for the trigger.
+        let wait = wait + Duration::from_secs_f64(length as f64 / sample_rate as f64);
+        // A deadline past the end of time waits forever.
+        let deadline = Instant::now().checked_add(wait);
+        while self.client.send_resp::<GetCaptureStatus>(&()).await?.state != CaptureState::Done {
+            if deadline.is_some_and(|deadline| Instant::now() > deadline) {
+                self.client.send_resp::<AbortCapture>(&()).await?;
+                return Err(BoardError::Timeout(wait));
+            }
+            tokio::time::sleep(POLL_INTERVAL).await;
+        }
+        read_capture(&self.client).await
+    }
+}
+
+impl BoardState for ScopeState {}
+
+impl Drop for ScopeState {
+    fn drop(&mut self) {
+        if let Some(task) = self.metrics_log.take() {
+            task.abort();
+        }
+        if self.client.is_closed() {
+            return;
+        }
+        // Do not leave a capture armed for the next client.
+        let client = self.client.clone();
+        cleanup_on_drop(self.client.clone(), async move {
+            if let Err(e) = client.send_resp::<AbortCapture>(&()).await {
+                log::warn!("Could not abort the capture: {:?}", e);
+            }
+        });
+    }
+}
+
+/// Check the capture arguments against the limits of the board.
+#[allow(clippy::too_many_arguments)]
+fn capture_request(
+    limits: &ScopeLimits,
+    channel: u8,
+    sample_rate: f64,
+    length: u16,
+    pre_trigger: u16,
+    trigger: ScopeTrigger,
+    level: f32,
+    hysteresis: f32,
+) -> BoardResult<CaptureRequest, ScopeError> {
+    if channel >= limits.channels {
+        return Err(BoardError::InvalidData(format!(
+            "Channel {} out of 0-{} range",
+            channel,
+            limits.channels - 1
+        )));
+    }
+    if !(limits.min_sample_rate_hz as f64..=limits.max_sample_rate_hz as f64).contains(&sample_rate)
+    {
+        return Err(BoardError::InvalidData(format!(
+            "Sample rate {} Hz out of {}-{} Hz range",
+            sample_rate, limits.min_sample_rate_hz, limits.max_sample_rate_hz
+        )));
+    }
+    if length == 0 || length > limits.max_length || pre_trigger > length {
+        return Err(BoardError::InvalidData(format!(
+            "Length must be in 1-{} range and not shorter than the {} pre-trigger samples",
+            limits.max_length, pre_trigger
+        )));
+    }
+    if !(0.0..=ADC_REFERENCE_V).contains(&level) || hysteresis < 0.0 {
+        return Err(BoardError::InvalidData(format!(
+            "Level must be in 0-{} V range and hysteresis positive",
+            ADC_REFERENCE_V
+        )));
+    }
+
+    Ok(CaptureRequest {
+        channel,
+        sample_rate_hz: sample_rate.round() as u32,
+        length,
+        pre_trigger,
+        trigger,
+        level: volts_to_counts(level),
+        hysteresis: volts_to_counts(hysteresis),
+    })
+}
+
+/// Arm the capture, returning the sample rate the board picked.
+async fn arm_capture(
+    client: &BoardClient,
+    request: &CaptureRequest,
+) -> BoardResult<f32, ScopeError> {
+    let sample_rate = client
+        .send_resp::<ArmCapture>(request)
+        .await?
+        .map_err(BoardError::Endpoint)?;
+    log::info!("Armed capture at {} Hz", sample_rate);
+    Ok(sample_rate)
+}
+
+/// Download a finished capture, with the time axis relative to the trigger.
+async fn read_capture(client: &BoardClient) -> BoardResult<(Vec<f64>, Vec<f32>, f32), ScopeError> {
+    let status = client.send_resp::<GetCaptureStatus>(&()).await?;
+    if status.state != CaptureState::Done {
+        return Err(BoardError::Endpoint(ScopeError::NotReady));
+    }
+
+    let mut values = Vec::with_capacity(status.length as usize);
+    while values.len() < status.length as usize {
+        let chunk = client
+            .send_resp::<ReadCapture>(&(values.len() as u16))
+            .await?
+            .map_err(BoardError::Endpoint)?;
+        if chunk.samples.is_empty() {
+            return Err(BoardError::InvalidData("Capture ended early".to_string()));
+        }
+        values.extend(chunk.samples.iter().map(|&s| counts_to_volts(s)));
+    }
+
+    let period = 1.0 / status.sample_rate_hz as f64;
+    let times = (0..values.len())
+        .map(|i| (i as f64 - status.trigger_index as f64) * period)
+        .collect();
+    Ok((times, values, status.sample_rate_hz))
+}
+
+fn volts_to_counts(volts: f32) -> u16 {
+    (volts / ADC_REFERENCE_V * ADC_MAX as f32)
+        .round()
+        .clamp(0.0, ADC_MAX as f32) as u16
+}
+
+fn counts_to_volts(counts: u16) -> f32 {
+    counts as f32 * ADC_REFERENCE_V / ADC_MAX as f32
+}
@@ -0,0 +1,416 @@
+use std::fmt::Debug;
+
+use macros::blocking_async;
+use protocol::sequencer::*;
+use pyo3::prelude::*;
+use pyo3_stub_gen::derive::*;
+use tokio::task::JoinHandle;
+
+use super::board::BoardState;
+use crate::{
+    client::{
+        BoardClient, DEFAULT_TIMEOUT, RequestPolicy, cleanup_on_drop, connect_timeout_from_secs,
+    },
+    common::{BoardError, BoardResult, connect_to_board},
+};
+
+board_client! {
+    /// This class communicates with Bluepill Sequencer Rust firmware. The sequencer plays a table of
+    /// time-stamped output states on pins PB8-PB15 with 1 us resolution.
+    SequencerClient(SequencerState), sequencer
+}
+
+/// State of a `SequencerClient`, locked by its calls.
+struct SequencerState {
+    client: BoardClient,
+    metrics_log: Option<JoinHandle<()>>,
+    disable_on_close: bool,
+    limits: SequencerLimits,
+    /// Times of the uploaded table, kept to validate arm requests.
+    times_us: Vec<u32>,
+}
+
+#[blocking_async(SequencerClient)]
+#[gen_stub_pymethods]
+#[pymethods]
+impl SequencerState {
+    #[new]
+    #[pyo3(signature = (
+        serial_number = None,
+        alias = None,
+        timeout = Some(DEFAULT_TIMEOUT),
+        retries = 0,
+        connect_timeout = 0.0,
+    ))]
+    async fn new(
+        serial_number: Option<&str>,
+        alias: Option<&str>,
+        timeout: Option<f64>,
+        retries: u8,
+        connect_timeout: f64,
+    ) -> BoardResult<Self> {
+        let policy = RequestPolicy::from_secs(timeout, retries)?;
+        let client = connect_to_board(
+            USB_DEVICE_NAME,
+            serial_number,
+            alias,
+            connect_timeout_from_secs(connect_timeout)?,
+            policy,
+        )
+        .await?;
+        let client = BoardClient::new(client, policy);
+
+        let limits = client.send_resp::<GetSequencerLimits>(&()).await?;
+        log::info!("Sequencer limits: {:?}", limits);
+
+        Ok(Self {
+            client,
+            metrics_log: None,
+            disable_on_close: false,
+            limits,
+            times_us: Vec::new(),
+        })
+    }
+
+    /// Close the connection to the board and stop logging the metrics. With
+    /// `disable_on_close` set, the sequence is stopped first.
+    /// The client is also closed when it is garbage collected or at the end of a `with` block.
+    async fn close(&mut self) -> BoardResult<()> {
+        if let Some(task) = self.metrics_log.take() {
+            task.abort();
+        }
+        let result = if self.disable_on_close && !self.client.is_closed() {
+            disable_outputs(&self.client).await
+        } else {
+            Ok(())
+        };
+        self.client.close();
+        result
+    }
+
+    /// Whether the sequence is stopped when the client is closed or dropped.
+    #[getter]
+    fn disable_on_close(&self) -> bool {
+        self.disable_on_close
+    }
+
+    #[setter]
+    fn set_disable_on_close(&mut self, disable_on_close: bool) {
+        self.disable_on_close = disable_on_close;
+    }
+
+    #[getter]
+    fn limits(&self) -> SequencerLimits {
+        self.limits
+    }
+
+    /// Upload a timing table to the board.
+    /// The table is validated against the device limits before anything is sent. Both arguments
+    /// accept Python lists or numpy arrays of the same length.
+    ///
+    /// :param times_us: Time of each entry in microseconds since the start of the sequence, rounded to 1 us.
+    ///     Times must increase by at least `limits.min_step_us`.
+    /// :param states: Output state of each entry, bit n drives channel n (pin PB8 + n).
+    async fn upload(
+        &mut self,
+        times_us: Vec<f64>,
+        states: Vec<u32>,
+    ) -> BoardResult<(), SequencerError> {
+        let entries =
+            build_table(&self.limits, &times_us, &states).map_err(BoardError::InvalidData)?;
+        let chunk_size = (self.limits.chunk_size as usize).min(CHUNK_SIZE);
+        if chunk_size == 0 {
+            return Err(BoardError::InvalidData(
+                "Device reports a chunk size of 0".to_string(),
+            ));
+        }
+
+        for (i, chunk) in entries.chunks(chunk_size).enumerate() {
+            let chunk = TableChunk {
+                offset: (i * chunk_size) as u16,
+                entries: heapless::Vec::from_slice(chunk)
+                    .map_err(|_| BoardError::InvalidData("Chunk too large".to_string()))?,
+            };
+            self.client
+                .send_resp::<UploadChunk>(&chunk)
+                .await?
+                .map_err(BoardError::Endpoint)?;
+        }
+
+        self.times_us = entries.iter().map(|e| e.time_us).collect();
+        log::info!("Uploaded {} entries", entries.len());
+        Ok(())
+    }
+
+    /// Arm the uploaded table for playback.
+    /// With a software trigger the sequence waits for `start`, with an edge trigger it starts
+    /// on the given edge of pin PA0. The edge starts the board's timer in hardware, so the
+    /// start does not depend on USB, and an entry at 0 us is applied 2 us after it.
+    ///
+    /// :param repeat: Number of passes through the table, 0 repeats until `stop` is called.
+    /// :param period_us: Time after which the sequence starts over when repeating. Defaults to the
+    ///     last entry time plus the minimum step.
+    /// :param trigger: What starts the playback.
+    /// :param idle_state: Output state applied while waiting for the trigger.
+    #[pyo3(signature = (repeat = 1, period_us = None, trigger = Trigger::Software, idle_state = 0))]
+    async fn arm(
+        &mut self,
+        repeat: u32,
+        period_us: Option<u32>,
+        trigger: Trigger,
+        idle_state: u8,
+    ) -> BoardResult<(), SequencerError> {
+        let (Some(first), Some(last)) = (self.times_us.first(), self.times_us.last()) else {
+            return Err(BoardError::InvalidData("No table uploaded".to_string()));
+        };
+        let too_long = || {
+            BoardError::InvalidData(format!(
+                "Table ending at {} us leaves no room to start over",
+                last
+            ))
+        };
+        let earliest_wrap = last
+            .checked_add(self.limits.min_step_us)
+            .ok_or_else(too_long)?;
+        let period_us = period_us.unwrap_or(earliest_wrap);
+        let wrap = period_us.checked_add(*first).ok_or_else(|| {
+            BoardError::InvalidData(format!(
+                "Period of {} us is too long for a table starting at {} us",
+                period_us, first
+            ))
+        })?;
+        if repeat != 1 && wrap < earliest_wrap {
+            return Err(BoardError::InvalidData(format!(
+                "Period of {} us is too short for a table ending at {} us",
+                period_us, last
+            )));
+        }
+
+        let request = ArmRequest {
+            length: self.times_us.len() as u16,
+            period_us,
+            repeat,
+            trigger,
+            idle_state,
+        };
+        self.client
+            .send_resp::<ArmSequencer>(&request)
+            .await?
+            .map_err(BoardError::Endpoint)?;
+        Ok(())
+    }
+
+    /// Start an armed sequence immediately.
+    async fn start(&self) -> BoardResult<(), SequencerError> {
+        self.client
+            .send_resp::<StartSequencer>(&())
+            .await?
+            .map_err(BoardError::Endpoint)?;
+        Ok(())
+    }
+
+    /// Stop the playback or cancel waiting for a trigger. Outputs keep their last state.
+    async fn stop(&self) -> BoardResult<()> {
+        self.client.send_resp::<StopSequencer>(&()).await?;
+        Ok(())
+    }
+
+    /// Get the playback state of the sequencer.
+    ///
+    /// :return: The SequencerStatus object.
+    async fn status(&self) -> BoardResult<SequencerStatus> {
+        let status = self.client.send_resp::<GetSequencerStatus>(&()).await?;
+        Ok(status)
+    }
+}
+
+impl BoardState for SequencerState {
+    async fn reconnected<E: Debug>(&mut self) -> BoardResult<(), E> {
+        // The uploaded table is gone with the reset or update.
+        self.times_us.clear();
+        Ok(())
+    }
+}
+
+impl Drop for SequencerState {
+    fn drop(&mut self) {
+        if let Some(task) = self.metrics_log.take() {
+            task.abort();
+        }
+        if self.client.is_closed() {
+            return;
+        }
+        let client = self.client.clone();
+        let disable_on_close = self.disable_on_close;
+        cleanup_on_drop(self.client.clone(), async move {
+            let result = if disable_on_close {
+                disable_outputs(&client).await
+            } else {
+                Ok(())
+            };
+            if let Err(e) = result {
+                log::warn!("Could not disable the outputs: {:?}", e);
+            }
+        });
+    }
+}
+
+/// Convert the Python table to device entries, checking it against the device limits.
+fn build_table(
+    limits: &SequencerLimits,
+    times_us: &[f64],
+    states: &[u32],
+) -> Result<Vec<SeqEntry>, String> {
+    if times_us.len() != states.len() {
+        return Err(format!(
+            "Got {} times and {} states",
+            times_us.len(),
+            states.len()
+        ));
+    }
+    if limits.channels as u32 > u8::BITS {
+        return Err(format!(
+            "Device reports {} channels, entries hold at most {}",
+            limits.channels,
+            u8::BITS
+        ));
+    }
+    if times_us.is_empty() {
+        return Err("Table is empty".to_string());
+    }
+    if times_us.len() > limits.max_entries as usize {
+        return Err(format!(
+            "Table has {} entries, device holds at most {}",
+            times_us.len(),
+            limits.max_entries
+        ));
+    }
+
+    let mut entries = Vec::with_capacity(times_us.len());
+    for (i, (&time, &state)) in times_us.iter().zip(states).enumerate() {
+        let time = (time / limits.resolution_us as f64).round() * limits.resolution_us as f64;
+        if !time.is_finite() || time < 0.0 || time > u32::MAX as f64 {
+            return Err(format!("Entry {}: time {} us out of range", i, time));
+        }
+        if state >> limits.channels != 0 {
+            return Err(format!(
+                "Entry {}: state {:#x} uses more than {} channels",
+                i, state, limits.channels
+            ));
+        }
+        let entry = SeqEntry {
+            time_us: time as u32,
+            state: state as u8,
+        };
+        if i == 0 && entry.time_us == 1 {
+            return Err("Entry 0: the first entry can be at 0 us or from 2 us on".to_string());
+        }
+        if let Some(previous) = entries.last().map(|e: &SeqEntry| e.time_us) {
+            if previous
+                .checked_add(limits.min_step_us)
+                .is_none_or(|earliest| entry.time_us < earliest)
+            {
+                return Err(format!(
+                    "Entry {}: {} us follows {} us, minimum step is {} us",
+                    i, entry.time_us, previous, limits.min_step_us
+                ));
+            }
+        }
+        entries.push(entry);
+    }
+    Ok(entries)
+}
+
+/// Stop the sequence.
+async fn disable_outputs(client: &BoardClient) -> BoardResult<()> {
+    client.send_resp::<StopSequencer>(&()).await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn limits() -> SequencerLimits {
+        SequencerLimits {
+            max_entries: 4,
+            chunk_size: 2,
+            channels: 4,
+            resolution_us: 1,
+            min_step_us: 5,
+        }
+    }
+
+    #[test]
+    fn builds_entries() {
+        let entries = build_table(&limits(), &[0.0, 10.4, 20.6], &[0b1, 0b1010, 0]).unwrap();
+        let entries: Vec<_> = entries.iter().map(|e| (e.time_us, e.state)).collect();
+        assert_eq!(entries, [(0, 0b1), (10, 0b1010), (21, 0)]);
+    }
+
+    #[test]
+    fn rounds_to_the_resolution() {
+        let limits = SequencerLimits {
+            resolution_us: 10,
+            ..limits()
+        };
+        let entries = build_table(&limits, &[0.0, 14.0, 26.0], &[0, 1, 0]).unwrap();
+        let times: Vec<_> = entries.iter().map(|e| e.time_us).collect();
+        assert_eq!(times, [0, 10, 30]);
+    }
+
+    #[test]
+    fn rejects_mismatched_lengths() {
+        assert!(build_table(&limits(), &[0.0, 10.0], &[0]).is_err());
+    }
+
+    #[test]
+    fn rejects_empty_and_oversized_tables() {
+        assert!(build_table(&limits(), &[], &[]).is_err());
+        let times = [0.0, 10.0, 20.0, 30.0, 40.0];
+        assert!(build_table(&limits(), &times, &[0; 5]).is_err());
+    }
+
+    #[test]
+    fn rejects_times_out_of_range() {
+        assert!(build_table(&limits(), &[-1.0], &[0]).is_err());
+        assert!(build_table(&limits(), &[f64::NAN], &[0]).is_err());
+        assert!(build_table(&limits(), &[u32::MAX as f64 + 1.0], &[0]).is_err());
+    }
+
+    #[test]
+    fn rejects_states_beyond_the_channels() {
+        assert!(build_table(&limits(), &[0.0], &[0b1111]).is_ok());
+        assert!(build_table(&limits(), &[0.0], &[0b1_0000]).is_err());
+    }
+
+    #[test]
+    fn rejects_more_channels_than_an_entry_holds() {
+        for channels in [9, 32, u8::MAX] {
+            let limits = SequencerLimits {
+                channels,
+                ..limits()
+            };
+            assert!(build_table(&limits, &[0.0], &[0]).is_err());
+        }
+    }
+
+    #[test]
+    fn rejects_a_first_entry_at_1_us() {
+        assert!(build_table(&limits(), &[1.0], &[0]).is_err());
+        assert!(build_table(&limits(), &[2.0], &[0]).is_ok());
+    }
+
+    #[test]
+    fn rejects_steps_below_the_minimum() {
+        assert!(build_table(&limits(), &[0.0, 5.0], &[0, 1]).is_ok());
+        assert!(build_table(&limits(), &[0.0, 4.0], &[0, 1]).is_err());
+        assert!(build_table(&limits(), &[10.0, 10.0], &[0, 1]).is_err());
+    }
+
+    #[test]
+    fn rejects_steps_past_the_end_of_time() {
+        let times = [u32::MAX as f64 - 2.0, u32::MAX as f64];
+        assert!(build_table(&limits(), &times, &[0, 1]).is_err());
+    }
+}
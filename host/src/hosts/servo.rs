@@ -1,119 +1,365 @@
-use std::{path::Path, str::Utf8Error};
+use std::{
+    collections::VecDeque,
+    fmt::Debug,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
 
 use macros::blocking_async;
-use postcard_rpc::{host_client::HostClient, standard_icd::WireError};
-use protocol::{servo::*, utils::PwmChannel};
+use postcard_rpc::{
+    host_client::{HostClient, HostErr},
+    standard_icd::WireError,
+};
+use protocol::{board::Board, servo::*, utils::PwmChannel};
 use pyo3::prelude::*;
 use pyo3_stub_gen::derive::*;
+use tokio::task::JoinHandle;
 
+use super::board::BoardState;
 use crate::{
+    client::{
+        BoardClient, DEFAULT_TIMEOUT, RequestPolicy, cleanup_on_drop, connect_timeout_from_secs,
+    },
     common::{BoardError, BoardResult, connect_to_board},
-    flash::flash_binary,
 };
 
 const STM32_PWM_RESOLUTION_BITS: u8 = 16;
+/// Number of current reports kept on the host until they are read.
+const CURRENT_BUFFER: usize = 10_000;
+
+board_client! {
+    /// This class communicates with Bluepill Servo Rust firmware.
+    ServoClient(ServoState), servo
+}
 
-/// This class communicates with Bluepill Servo Rust firmware. You can pass a serial number to the
-/// constructor to connect to a specific device. If no port is passed, it will try to connect to the first
-/// available device by product string.
-#[gen_stub_pyclass]
-#[pyclass]
-pub struct ServoClient {
-    client: HostClient<WireError>,
-    #[pyo3(get)]
+/// State of a `ServoClient`, locked by its calls.
+struct ServoState {
+    client: BoardClient,
+    metrics_log: Option<JoinHandle<()>>,
+    disable_on_close: bool,
     config: ServoConfig,
+    currents: Arc<Mutex<VecDeque<ServoCurrent>>>,
+    stalls: Arc<Mutex<Vec<ServoStall>>>,
 }
 
-#[blocking_async]
+#[blocking_async(ServoClient)]
 #[gen_stub_pymethods]
 #[pymethods]
-impl ServoClient {
+impl ServoState {
     #[new]
-    #[pyo3(signature = (serial_number = None))]
-    async fn new(serial_number: Option<&str>) -> BoardResult<Self> {
-        let client = connect_to_board(USB_DEVICE_NAME, serial_number).await?;
+    #[pyo3(signature = (
+        serial_number = None,
+        alias = None,
+        timeout = Some(DEFAULT_TIMEOUT),
+        retries = 0,
+        connect_timeout = 0.0,
+    ))]
+    async fn new(
+        serial_number: Option<&str>,
+        alias: Option<&str>,
+        timeout: Option<f64>,
+        retries: u8,
+        connect_timeout: f64,
+    ) -> BoardResult<Self> {
+        let policy = RequestPolicy::from_secs(timeout, retries)?;
+        let client = connect_to_board(
+            USB_DEVICE_NAME,
+            serial_number,
+            alias,
+            connect_timeout_from_secs(connect_timeout)?,
+            policy,
+        )
+        .await?;
+        let client = BoardClient::new(client, policy);
 
-        let config = client.send_resp::<GetServoConfig>(&()).await?;
+        let config = client.query::<GetServoConfig>(&()).await?;
         log::info!("Servo config: {:?}", config);
 
-        Ok(Self { client, config })
-    }
+        let currents = Arc::new(Mutex::new(VecDeque::with_capacity(CURRENT_BUFFER)));
+        let stalls = Arc::new(Mutex::new(Vec::new()));
+        subscribe_reports(&client, currents.clone(), stalls.clone()).await?;
 
-    #[staticmethod]
-    /// Flash the servo firmware to the board.
-    /// This function will use the `probe-rs` tool to flash the firmware binary to the board.
-    fn flash() -> PyResult<()> {
-        let filename = Path::new(file!())
-        .file_stem()
-        .and_then(|s| s.to_str())
-        .ok_or_else(|| {
-            pyo3::exceptions::PyChildProcessError::new_err(
-                "Mismatch between host filename and binary name. Use the flash_binary function with correct binary name.",
-            )
-        })?;
-        flash_binary(filename)?;
-        Ok(())
+        Ok(Self {
+            client,
+            metrics_log: None,
+            disable_on_close: false,
+            config,
+            currents,
+            stalls,
+        })
     }
 
-    /// Close the connection to the board.
-    fn close(&self) {
+    /// Close the connection to the board and stop logging the metrics. With
+    /// `disable_on_close` set, the enabled servo channels are disabled first.
+    /// The client is also closed when it is garbage collected or at the end of a `with` block.
+    async fn close(&mut self) -> BoardResult<()> {
+        if let Some(task) = self.metrics_log.take() {
+            task.abort();
+        }
+        let result = if self.disable_on_close && !self.client.is_closed() {
+            disable_outputs(&self.client, &mut self.config).await
+        } else {
+            Ok(())
+        };
         self.client.close();
+        result
     }
 
-    /// Check if the connection to the board is closed.
-    fn is_connected(&self) -> bool {
-        !self.client.is_closed()
+    /// Whether the enabled servo channels are disabled when the client is closed or dropped.
+    #[getter]
+    fn disable_on_close(&self) -> bool {
+        self.disable_on_close
     }
 
-    /// Get the serial number of the board.
-    /// The ID is a 92-bit number, which is padded to 128 bits with zeros.
-    ///
-    /// :return: The serial number of the board.
-    async fn get_serial_number(&self) -> BoardResult<String, Utf8Error> {
-        let id = self.client.send_resp::<GetUniqueIdEndpoint>(&()).await?;
-        let id = str::from_utf8(&id).map_err(BoardError::Endpoint)?;
-        Ok(id.to_owned())
+    #[setter]
+    fn set_disable_on_close(&mut self, disable_on_close: bool) {
+        self.disable_on_close = disable_on_close;
+    }
+
+    #[getter]
+    fn config(&self) -> ServoConfig {
+        self.config.clone()
     }
 
     /// Set the angle of the servo.
     ///
     /// :param channel: The channel to set the servo on (1-4), corresponding to PWM channels on pins PB6-PB9.
-    /// :param angle: The angle to set the servo to (0-180).
-    fn set_angle(&mut self, channel: u8, angle: u8) -> BoardResult<()> {
-        PwmChannel::try_from(channel)?;
-        if angle > 180 {
-            return Err(BoardError::InvalidData("Invalid angle".to_string()));
+    /// :param angle: The angle in degrees, within the range of the channel (0-180 by default).
+    ///     Kept within the soft limits of the channel, the trim and inversion are applied by
+    ///     the firmware. The angle is rounded to the `angle_step` of the channel.
+    async fn set_angle(&mut self, channel: u8, angle: f32) -> BoardResult<()> {
+        let channel = PwmChannel::try_from(channel)?;
+        let channel_config = &self.config.channels[channel as usize];
+        if !(channel_config.range_start..=channel_config.range_end).contains(&angle) {
+            return Err(BoardError::InvalidData(format!(
+                "Angle {} out of the {}-{} range of channel {}",
+                angle,
+                channel_config.range_start,
+                channel_config.range_end,
+                channel as u8 + 1
+            )));
         }
+        if channel_config.mode != ServoMode::Positional {
+            return Err(BoardError::InvalidData(format!(
+                "Channel {} is a continuous servo, use set_speed",
+                channel as u8 + 1
+            )));
+        }
+        let duty_cycle = channel_config.angle_to_duty_cycle(angle);
 
-        let channel_config = &self.config.channels[channel as usize - 1];
+        let channel_config = ServoChannelConfig {
+            enabled: true,
+            current_duty_cycle: duty_cycle,
+            ..*channel_config
+        };
+        send_channel_config(&self.client, &mut self.config, channel, channel_config).await
+    }
 
-        self.configure_channel(
-            channel,
-            Some(true),
-            Some(self.angle_to_duty_cycle(
-                angle,
-                channel_config.min_angle_duty_cycle,
-                channel_config.max_angle_duty_cycle,
-            )),
-            None,
-            None,
-        )?;
-        Ok(())
+    /// Get the commanded angle of the servo on channel 1-4, before the trim and inversion.
+    ///
+    /// :return: The angle of the servo in degrees, within the range of the channel.
+    fn get_angle(&self, channel: u8) -> BoardResult<f32> {
+        let channel = PwmChannel::try_from(channel)?;
+
+        let channel_config = &self.config.channels[channel as usize];
+        Ok(channel_config.duty_cycle_to_angle(channel_config.current_duty_cycle))
     }
 
-    /// Get the angle of the servo on channel 1-4.
+    /// Get the smallest angle change of the servo on channel 1-4, as reported by the board.
+    /// It depends on the range, the duty cycle limits and the PWM frequency.
     ///
-    /// :return: The angle of the servo (0-180).
-    fn get_angle(&self, channel: u8) -> BoardResult<u8> {
+    /// :return: Degrees per duty cycle step.
+    fn get_angle_step(&self, channel: u8) -> BoardResult<f32> {
         let channel = PwmChannel::try_from(channel)?;
+        Ok(self.config.channels[channel as usize].angle_step)
+    }
+
+    /// Get the commanded and the measured position of the servo on channel 1-4.
+    ///
+    /// :return: The ServoFeedback object, without the measurement if feedback is disabled.
+    async fn get_feedback(&self, channel: u8) -> BoardResult<ServoFeedback> {
+        let channel = PwmChannel::try_from(channel)?;
+        let feedback = self.client.query::<GetServoFeedback>(&channel).await?;
+        Ok(feedback)
+    }
+
+    /// Get the angle of the servo on channel 1-4 measured from its potentiometer.
+    ///
+    /// :return: The measured angle in degrees, in the range of the channel.
+    async fn get_measured_angle(&self, channel: u8) -> BoardResult<f32> {
+        let channel = PwmChannel::try_from(channel)?;
+        let feedback = self.client.query::<GetServoFeedback>(&channel).await?;
+        feedback.measured_angle.ok_or_else(|| {
+            BoardError::InvalidData(format!(
+                "Feedback of channel {} is disabled",
+                channel as u8 + 1
+            ))
+        })
+    }
+
+    /// Sweep the servo on channel 1-4 across its soft limits and fit the mapping from the
+    /// feedback readings to the angle. Enables the feedback of the channel and returns the
+    /// servo to the angle it was at. If the calibration fails, the channel is configured as
+    /// it was before.
+    ///
+    /// :param points: The number of angles to stop at, at least 2.
+    /// :param settle_time: Seconds to wait at each angle before reading the feedback.
+    /// :return: The largest difference between the fit and the readings, in degrees.
+    #[pyo3(signature = (channel, points = 10, settle_time = 0.5))]
+    async fn calibrate_feedback(
+        &mut self,
+        channel: u8,
+        points: u32,
+        settle_time: f64,
+    ) -> BoardResult<f32> {
+        let channel = PwmChannel::try_from(channel)?;
+        let settle_time = Duration::try_from_secs_f64(settle_time)
+            .map_err(|e| BoardError::InvalidData(format!("Invalid settle time: {}", e)))?;
+        calibrate_feedback(&self.client, &mut self.config, channel, points, settle_time).await
+    }
+
+    /// Set the speed of a continuous servo.
+    ///
+    /// :param channel: The channel of the servo (1-4), configured with `mode=ServoMode.Continuous`.
+    /// :param speed: The speed from -1.0 (full speed backwards) to 1.0 (full speed forwards),
+    ///     0.0 stops the servo at the neutral pulse.
+    async fn set_speed(&mut self, channel: u8, speed: f32) -> BoardResult<()> {
+        let channel = PwmChannel::try_from(channel)?;
+        if !(-1.0..=1.0).contains(&speed) {
+            return Err(BoardError::InvalidData(format!(
+                "Speed {} out of -1.0..1.0 range",
+                speed
+            )));
+        }
 
         let channel_config = &self.config.channels[channel as usize];
-        let angle = self.duty_cycle_to_angle(
-            channel_config.current_duty_cycle,
-            channel_config.min_angle_duty_cycle,
-            channel_config.max_angle_duty_cycle,
-        );
-        Ok(angle)
+        if channel_config.mode != ServoMode::Continuous {
+            return Err(BoardError::InvalidData(format!(
+                "Channel {} is a positional servo, use set_angle",
+                channel as u8 + 1
+            )));
+        }
+        let duty_cycle = channel_config.speed_to_duty_cycle(speed);
+
+        let channel_config = ServoChannelConfig {
+            enabled: true,
+            current_duty_cycle: duty_cycle,
+            ..*channel_config
+        };
+        send_channel_config(&self.client, &mut self.config, channel, channel_config).await
+    }
+
+    /// Get the commanded speed of the continuous servo on channel 1-4.
+    ///
+    /// :return: The speed of the servo (-1.0 to 1.0), 0.0 within the dead band.
+    fn get_speed(&self, channel: u8) -> BoardResult<f32> {
+        let channel = PwmChannel::try_from(channel)?;
+
+        let channel_config = &self.config.channels[channel as usize];
+        Ok(channel_config.duty_cycle_to_speed(channel_config.current_duty_cycle))
+    }
+
+    /// Stop the continuous servos when no request comes for a while, for example when
+    /// the script driving them hangs. They are always stopped when USB is disconnected.
+    /// :param timeout: seconds without requests before stopping, None to only stop on disconnect.
+    #[pyo3(signature = (timeout = None))]
+    async fn set_failsafe_timeout(&mut self, timeout: Option<f64>) -> BoardResult<()> {
+        let timeout_ms = match timeout {
+            Some(timeout) if timeout > 0.0 && timeout * 1000.0 <= u32::MAX as f64 => {
+                (timeout * 1000.0).round() as u32
+            }
+            Some(timeout) => {
+                return Err(BoardError::InvalidData(format!(
+                    "Invalid failsafe timeout: {} s",
+                    timeout
+                )));
+            }
+            None => 0,
+        };
+        self.client
+            .send_resp::<SetFailsafeTimeout>(&timeout_ms)
+            .await?;
+        self.config.failsafe_timeout_ms = timeout_ms;
+        Ok(())
+    }
+
+    /// Configure the current sensing and the stall protection of the servos. Leave arguments
+    /// as None to not change them on device.
+    ///
+    /// :param sense: `CurrentSense.PerChannel` with shunts on PA2, PA3, PA6 and PA7 for channels 1-4,
+    ///     or `CurrentSense.Rail` with one shunt on PB1 for all the servos.
+    /// :param scale_ma: Milliamps per ADC count, from the shunt resistance and the amplifier gain.
+    /// :param offset: ADC reading at zero current.
+    /// :param stall_current_ma: Current above which a servo counts as stalled, 0 disables the protection.
+    /// :param stall_time: Seconds the current has to stay above `stall_current_ma`.
+    /// :param stall_action: `StallAction.Disable` or `StallAction.BackOff` for stalled servos.
+    /// :param back_off_angle: Degrees a positional servo without feedback is backed off by.
+    /// :param report_divider: Publish the currents every N samples of 10 ms, 0 disables it.
+    #[pyo3(signature = (
+        sense = None,
+        scale_ma = None,
+        offset = None,
+        stall_current_ma = None,
+        stall_time = None,
+        stall_action = None,
+        back_off_angle = None,
+        report_divider = None,
+    ))]
+    #[allow(clippy::too_many_arguments)]
+    async fn configure_current(
+        &mut self,
+        sense: Option<CurrentSense>,
+        scale_ma: Option<f32>,
+        offset: Option<u16>,
+        stall_current_ma: Option<f32>,
+        stall_time: Option<f64>,
+        stall_action: Option<StallAction>,
+        back_off_angle: Option<f32>,
+        report_divider: Option<u16>,
+    ) -> BoardResult<()> {
+        let mut current = self.config.current;
+
+        current.sense = sense.unwrap_or(current.sense);
+        current.scale_ma = scale_ma.unwrap_or(current.scale_ma);
+        current.offset = offset.unwrap_or(current.offset);
+        current.stall_current_ma = stall_current_ma.unwrap_or(current.stall_current_ma);
+        if let Some(stall_time) = stall_time {
+            current.stall_time_ms = Duration::try_from_secs_f64(stall_time)
+                .ok()
+                .and_then(|stall_time| u32::try_from(stall_time.as_millis()).ok())
+                .ok_or_else(|| {
+                    BoardError::InvalidData(format!("Invalid stall time: {} s", stall_time))
+                })?;
+        }
+        current.stall_action = stall_action.unwrap_or(current.stall_action);
+        current.back_off_angle = back_off_angle.unwrap_or(current.back_off_angle);
+        current.report_divider = report_divider.unwrap_or(current.report_divider);
+
+        self.client.send_resp::<SetCurrentConfig>(&current).await?;
+        self.config.current = current;
+        Ok(())
+    }
+
+    /// Get the currents received since the last call, oldest first.
+    /// The board publishes them every `config.current.report_divider` samples.
+    ///
+    /// :return: List of ServoCurrent objects.
+    fn get_currents(&self) -> Vec<ServoCurrent> {
+        self.currents.lock().unwrap().drain(..).collect()
+    }
+
+    /// Get the stalls the board detected since the last call, oldest first. They are also
+    /// logged as warnings when they arrive. A disabled channel has to be enabled again
+    /// with `configure_channel`.
+    ///
+    /// :return: List of ServoStall objects.
+    async fn get_stall_events(&mut self) -> BoardResult<Vec<ServoStall>> {
+        let stalls = std::mem::take(&mut *self.stalls.lock().unwrap());
+        if !stalls.is_empty() {
+            // The board changed the stalled channels.
+            self.config = self.client.query::<GetServoConfig>(&()).await?;
+        }
+        Ok(stalls)
     }
 
     /// Configure the servo channel.
@@ -126,13 +372,41 @@ impl ServoClient {
     /// :param current_duty_cycle: The current duty cycle of the channel. Set to 0 on boot.
     /// :param min_angle_duty_cycle: The minimum duty cycle for the channel. By default uses values corresponding to a pulse width of 500us.
     /// :param max_angle_duty_cycle: The maximum duty cycle for the channel. By default uses values corresponding to a pulse width of 2500us.
+    /// :param range_start: The angle in degrees at the minimum duty cycle, 0 by default.
+    /// :param range_end: The angle in degrees at the maximum duty cycle, 180 by default.
+    ///     For example 0-270 for 270 degree servos, or -90-90 to center the range.
+    /// :param inverted: Mirror the servo, so the start of the range drives it to the maximum duty cycle.
+    /// :param center_trim: Degrees added to every angle, for horns mounted off center.
+    /// :param min_angle: The lowest angle the channel can be commanded to, the start of the range by default.
+    /// :param max_angle: The highest angle the channel can be commanded to, the end of the range by default.
+    ///     The firmware keeps every duty cycle within these limits, raw ones included.
+    /// :param mode: `ServoMode.Positional` or `ServoMode.Continuous`, for servos where the pulse sets the speed.
+    /// :param neutral_duty_cycle: The duty cycle at which a continuous servo stands still. By default a pulse width of 1500us.
+    /// :param dead_band: Duty cycles this close to neutral that do not turn a continuous servo yet.
+    /// :param feedback: Sample the servo potentiometer on the feedback pin of the channel,
+    ///     PA0, PA1, PA4 and PB0 for channels 1-4. Calibrate it with `calibrate_feedback`.
+    /// :param feedback_start: The feedback ADC reading at the start of the range.
+    /// :param feedback_end: The feedback ADC reading at the end of the range.
     #[pyo3(signature = (
         channel,
         enabled = None,
         current_duty_cycle = None,
         min_angle_duty_cycle = None,
         max_angle_duty_cycle = None,
+        range_start = None,
+        range_end = None,
+        inverted = None,
+        center_trim = None,
+        min_angle = None,
+        max_angle = None,
+        mode = None,
+        neutral_duty_cycle = None,
+        dead_band = None,
+        feedback = None,
+        feedback_start = None,
+        feedback_end = None,
     ))]
+    #[allow(clippy::too_many_arguments)]
     async fn configure_channel(
         &mut self,
         channel: u8,
@@ -140,9 +414,21 @@ impl ServoClient {
         current_duty_cycle: Option<u16>,
         min_angle_duty_cycle: Option<u16>,
         max_angle_duty_cycle: Option<u16>,
+        range_start: Option<f32>,
+        range_end: Option<f32>,
+        inverted: Option<bool>,
+        center_trim: Option<f32>,
+        min_angle: Option<f32>,
+        max_angle: Option<f32>,
+        mode: Option<ServoMode>,
+        neutral_duty_cycle: Option<u16>,
+        dead_band: Option<u16>,
+        feedback: Option<bool>,
+        feedback_start: Option<f32>,
+        feedback_end: Option<f32>,
     ) -> BoardResult<()> {
         let channel = PwmChannel::try_from(channel)?;
-        let channel_config = &mut self.config.channels[channel as usize];
+        let mut channel_config = self.config.channels[channel as usize];
 
         channel_config.enabled = enabled.unwrap_or(channel_config.enabled);
         channel_config.current_duty_cycle =
@@ -151,20 +437,46 @@ impl ServoClient {
             min_angle_duty_cycle.unwrap_or(channel_config.min_angle_duty_cycle);
         channel_config.max_angle_duty_cycle =
             max_angle_duty_cycle.unwrap_or(channel_config.max_angle_duty_cycle);
+        if range_start.is_some() || range_end.is_some() {
+            // Limits of the previous range make no sense in the new one.
+            channel_config.range_start = range_start.unwrap_or(channel_config.range_start);
+            channel_config.range_end = range_end.unwrap_or(channel_config.range_end);
+            channel_config.min_angle = channel_config.range_start;
+            channel_config.max_angle = channel_config.range_end;
+        }
+        channel_config.inverted = inverted.unwrap_or(channel_config.inverted);
+        channel_config.center_trim = center_trim.unwrap_or(channel_config.center_trim);
+        channel_config.min_angle = min_angle.unwrap_or(channel_config.min_angle);
+        channel_config.max_angle = max_angle.unwrap_or(channel_config.max_angle);
+        channel_config.mode = mode.unwrap_or(channel_config.mode);
+        channel_config.neutral_duty_cycle =
+            neutral_duty_cycle.unwrap_or(channel_config.neutral_duty_cycle);
+        channel_config.dead_band = dead_band.unwrap_or(channel_config.dead_band);
+        channel_config.feedback = feedback.unwrap_or(channel_config.feedback);
+        channel_config.feedback_start = feedback_start.unwrap_or(channel_config.feedback_start);
+        channel_config.feedback_end = feedback_end.unwrap_or(channel_config.feedback_end);
 
-        let channel_config = channel_config.clone();
+        if channel_config.range_start >= channel_config.range_end {
+            return Err(BoardError::InvalidData(format!(
+                "Range start {} is not below the range end {}",
+                channel_config.range_start, channel_config.range_end
+            )));
+        }
+        if channel_config.min_angle > channel_config.max_angle {
+            return Err(BoardError::InvalidData(format!(
+                "Minimum angle {} is above the maximum angle {}",
+                channel_config.min_angle, channel_config.max_angle
+            )));
+        }
 
-        self.client
-            .send_resp::<ConfigureChannel>(&(channel, channel_config))
-            .await?;
-        Ok(())
+        send_channel_config(&self.client, &mut self.config, channel, channel_config).await
     }
 
     /// Get the servo configuration.
     /// This function returns the current configuration of the servo channels.
     /// :return: The ServoConfig object.
     async fn update_config(&mut self) -> BoardResult<()> {
-        let config = self.client.send_resp::<GetServoConfig>(&()).await?;
+        let config = self.client.query::<GetServoConfig>(&()).await?;
         self.config = config;
         Ok(())
     }
@@ -179,29 +491,20 @@ impl ServoClient {
         self.client
             .send_resp::<SetFrequencyEndpoint>(&frequency)
             .await?;
-        self.update_config()
+        self.config = self.client.query::<GetServoConfig>(&()).await?;
+        Ok(())
     }
 
-    fn angle_to_duty_cycle(&self, angle: u8, min_duty_cycle: u16, max_duty_cycle: u16) -> u16 {
-        if angle > 180 {
-            return max_duty_cycle;
-        }
-        let duty_cycle = ((angle as f32) / 180.0) * (max_duty_cycle - min_duty_cycle) as f32
-            + min_duty_cycle as f32;
-        duty_cycle.round() as u16
+    /// Convert an angle in degrees to the duty cycle of the channel, before the trim and inversion.
+    fn angle_to_duty_cycle(&self, channel: u8, angle: f32) -> BoardResult<u16> {
+        let channel = PwmChannel::try_from(channel)?;
+        Ok(self.config.channels[channel as usize].angle_to_duty_cycle(angle))
     }
 
-    fn duty_cycle_to_angle(&self, duty_cycle: u16, min_duty_cycle: u16, max_duty_cycle: u16) -> u8 {
-        if duty_cycle < min_duty_cycle {
-            return 0;
-        }
-        if duty_cycle > max_duty_cycle {
-            return 180;
-        }
-        let angle = ((duty_cycle - min_duty_cycle) as f32
-            / (max_duty_cycle - min_duty_cycle) as f32)
-            * 180.0;
-        angle.round() as u8
+    /// Convert a duty cycle of the channel to the angle in degrees, before the trim and inversion.
+    fn duty_cycle_to_angle(&self, channel: u8, duty_cycle: u16) -> BoardResult<f32> {
+        let channel = PwmChannel::try_from(channel)?;
+        Ok(self.config.channels[channel as usize].duty_cycle_to_angle(duty_cycle))
     }
 
     /// Convert microseconds to duty cycle.
@@ -214,3 +517,234 @@ impl ServoClient {
         duty_cycle.round() as u16
     }
 }
+
+impl BoardState for ServoState {
+    async fn reconnected<E: Debug>(&mut self) -> BoardResult<(), E> {
+        self.config = self.client.query::<GetServoConfig>(&()).await?;
+        subscribe_reports(&self.client, self.currents.clone(), self.stalls.clone()).await?;
+        Ok(())
+    }
+}
+
+impl Drop for ServoState {
+    fn drop(&mut self) {
+        if let Some(task) = self.metrics_log.take() {
+            task.abort();
+        }
+        if self.client.is_closed() {
+            return;
+        }
+        let client = self.client.clone();
+        let mut config = self.config.clone();
+        let disable_on_close = self.disable_on_close;
+        cleanup_on_drop(self.client.clone(), async move {
+            let result = if disable_on_close {
+                disable_outputs(&client, &mut config).await
+            } else {
+                Ok(())
+            };
+            if let Err(e) = result {
+                log::warn!("Could not disable the outputs: {:?}", e);
+            }
+        });
+    }
+}
+
+/// Configure the channel and keep the config applied by the firmware, with the duty cycle
+/// within the soft limits and the angle step filled in.
+async fn send_channel_config(
+    client: &BoardClient,
+    config: &mut ServoConfig,
+    channel: PwmChannel,
+    channel_config: ServoChannelConfig,
+) -> BoardResult<()> {
+    config.channels[channel as usize] = client
+        .send_resp::<ConfigureChannel>(&(channel, channel_config))
+        .await?;
+    Ok(())
+}
+
+/// Step the servo through `points` angles between the soft limits, read the feedback at
+/// each and fit a line through the readings. Returns the largest residual in degrees.
+async fn calibrate_feedback(
+    client: &BoardClient,
+    config: &mut ServoConfig,
+    channel: PwmChannel,
+    points: u32,
+    settle_time: Duration,
+) -> BoardResult<f32> {
+    let original = config.channels[channel as usize];
+    if original.mode != ServoMode::Positional {
+        return Err(BoardError::InvalidData(
+            "Only positional servos have feedback".to_string(),
+        ));
+    }
+    if points < 2 {
+        return Err(BoardError::InvalidData(
+            "Calibration needs at least 2 points".to_string(),
+        ));
+    }
+
+    let result = sweep_feedback(client, config, channel, original, points, settle_time).await;
+    if result.is_err() {
+        // Leave the channel as it was, instead of at the last point of the sweep.
+        if let Err(e) = send_channel_config(client, config, channel, original).await {
+            log::warn!(
+                "Could not restore the configuration of channel {}: {:?}",
+                channel as u8 + 1,
+                e
+            );
+        }
+    }
+    result
+}
+
+/// Sweep of `calibrate_feedback`, which restores the `original` channel when this fails.
+async fn sweep_feedback(
+    client: &BoardClient,
+    config: &mut ServoConfig,
+    channel: PwmChannel,
+    original: ServoChannelConfig,
+    points: u32,
+    settle_time: Duration,
+) -> BoardResult<f32> {
+    let mut samples = Vec::with_capacity(points as usize);
+    for i in 0..points {
+        let angle = original.min_angle
+            + (original.max_angle - original.min_angle) * i as f32 / (points - 1) as f32;
+        let channel_config = ServoChannelConfig {
+            enabled: true,
+            feedback: true,
+            current_duty_cycle: original.angle_to_duty_cycle(angle),
+            ..original
+        };
+        send_channel_config(client, config, channel, channel_config).await?;
+        tokio::time::sleep(settle_time).await;
+
+        let feedback = client.query::<GetServoFeedback>(&channel).await?;
+        let raw = feedback.raw.ok_or_else(|| {
+            BoardError::InvalidData("The board did not sample the feedback".to_string())
+        })?;
+        log::debug!("Feedback at {} degrees: {}", angle, raw);
+        samples.push((angle, raw as f32));
+    }
+
+    // Least squares fit of raw = slope * angle + offset.
+    let n = samples.len() as f32;
+    let mean_angle = samples.iter().map(|(angle, _)| angle).sum::<f32>() / n;
+    let mean_raw = samples.iter().map(|(_, raw)| raw).sum::<f32>() / n;
+    let covariance = samples
+        .iter()
+        .map(|(angle, raw)| (angle - mean_angle) * (raw - mean_raw))
+        .sum::<f32>();
+    let variance = samples
+        .iter()
+        .map(|(angle, _)| (angle - mean_angle).powi(2))
+        .sum::<f32>();
+    let slope = covariance / variance;
+    if !slope.is_finite() || slope.abs() < 0.1 {
+        return Err(BoardError::InvalidData(format!(
+            "Feedback of channel {} does not follow the angle, check the wiring",
+            channel as u8 + 1
+        )));
+    }
+    let offset = mean_raw - slope * mean_angle;
+
+    let calibrated = ServoChannelConfig {
+        feedback: true,
+        feedback_start: slope * original.range_start + offset,
+        feedback_end: slope * original.range_end + offset,
+        ..original
+    };
+    let max_error = samples
+        .iter()
+        .map(|(angle, raw)| (calibrated.feedback_to_angle(raw.round() as u16) - angle).abs())
+        .fold(0.0, f32::max);
+    send_channel_config(client, config, channel, calibrated).await?;
+    log::info!(
+        "Calibrated the feedback of channel {}, largest error {:.2} degrees",
+        channel as u8 + 1,
+        max_error
+    );
+
+    Ok(max_error)
+}
+
+/// Collect the current reports and the stall events in the background. The oldest
+/// reports are dropped when nobody reads them, the stalls are all kept.
+async fn subscribe_reports(
+    client: &HostClient<WireError>,
+    currents: Arc<Mutex<VecDeque<ServoCurrent>>>,
+    stalls: Arc<Mutex<Vec<ServoStall>>>,
+) -> Result<(), HostErr<WireError>> {
+    let mut current_subscription = client
+        .subscribe_multi::<ServoCurrentTopic>(64)
+        .await
+        .map_err(|_| HostErr::Closed)?;
+    let mut stall_subscription = client
+        .subscribe_multi::<ServoStallTopic>(8)
+        .await
+        .map_err(|_| HostErr::Closed)?;
+
+    core::mem::drop(tokio::task::spawn(async move {
+        loop {
+            match current_subscription.recv().await {
+                Ok(current) => {
+                    let mut currents = currents.lock().unwrap();
+                    if currents.len() == CURRENT_BUFFER {
+                        currents.pop_front();
+                    }
+                    currents.push_back(current);
+                }
+                Err(e) => {
+                    log::error!("Current subscription error: {:?}", e);
+                    break;
+                }
+            }
+        }
+    }));
+
+    core::mem::drop(tokio::task::spawn(async move {
+        loop {
+            match stall_subscription.recv().await {
+                Ok(stall) => {
+                    log::warn!(
+                        "Servo stall at {} mA on channels {:?}, {:?}",
+                        stall.current_ma,
+                        (1..=4)
+                            .filter(|channel| stall.channels[channel - 1])
+                            .collect::<Vec<_>>(),
+                        stall.action
+                    );
+                    stalls.lock().unwrap().push(stall);
+                }
+                Err(e) => {
+                    log::error!("Stall subscription error: {:?}", e);
+                    break;
+                }
+            }
+        }
+    }));
+
+    Ok(())
+}
+
+/// Disable the enabled channels, so the servos stop holding their position.
+async fn disable_outputs(client: &BoardClient, config: &mut ServoConfig) -> BoardResult<()> {
+    for channel in [
+        PwmChannel::Channel1,
+        PwmChannel::Channel2,
+        PwmChannel::Channel3,
+        PwmChannel::Channel4,
+    ] {
+        let channel_config = config.channels[channel as usize];
+        if channel_config.enabled {
+            let channel_config = ServoChannelConfig {
+                enabled: false,
+                ..channel_config
+            };
+            send_channel_config(client, config, channel, channel_config).await?;
+        }
+    }
+    Ok(())
+}
@@ -1,12 +1,23 @@
 use pyo3::{ffi::c_str, prelude::*};
 
+mod asyncio;
+mod client;
 mod common;
+mod device;
+mod discovery;
+mod exceptions;
 mod flash;
 mod hosts;
+mod metrics;
+mod update;
 
 use pyo3_stub_gen::define_stub_info_gatherer;
 
+use hosts::dac::DacClient;
 use hosts::minimal::MinimalClient;
+use hosts::pid::PidClient;
+use hosts::scope::ScopeClient;
+use hosts::sequencer::SequencerClient;
 use hosts::servo::ServoClient;
 
 /// This module hosts Python wrappers for communicating with Bluepill Rust firmware.
@@ -30,11 +41,36 @@ if not logging.getLogger().hasHandlers():
 
     pyo3_log::init();
 
+    exceptions::add_exceptions(m)?;
+
     m.add_function(wrap_pyfunction!(flash::check_probe_rs, m)?)?;
     m.add_function(wrap_pyfunction!(flash::flash_binary, m)?)?;
+    m.add_function(wrap_pyfunction!(discovery::list_devices, m)?)?;
+    m.add_class::<protocol::board::Board>()?;
 
+    m.add_class::<client::TimeoutOverride>()?;
+    m.add_class::<discovery::UsbDevice>()?;
+    m.add_class::<protocol::device::AliasError>()?;
     m.add_class::<MinimalClient>()?;
     m.add_class::<ServoClient>()?;
+    m.add_class::<protocol::servo::ServoMode>()?;
+    m.add_class::<protocol::servo::ServoFeedback>()?;
+    m.add_class::<protocol::servo::CurrentSense>()?;
+    m.add_class::<protocol::servo::StallAction>()?;
+    m.add_class::<protocol::servo::ServoCurrent>()?;
+    m.add_class::<protocol::servo::ServoStall>()?;
+    m.add_class::<SequencerClient>()?;
+    m.add_class::<protocol::sequencer::Trigger>()?;
+    m.add_class::<protocol::sequencer::SequencerError>()?;
+    m.add_class::<PidClient>()?;
+    m.add_class::<protocol::pid::PidError>()?;
+    m.add_class::<ScopeClient>()?;
+    m.add_class::<protocol::scope::ScopeTrigger>()?;
+    m.add_class::<protocol::scope::CaptureState>()?;
+    m.add_class::<protocol::scope::ScopeError>()?;
+    m.add_class::<DacClient>()?;
+    m.add_class::<protocol::dac::DacModel>()?;
+    m.add_class::<protocol::dac::DacError>()?;
 
     Ok(())
 }
@@ -0,0 +1,107 @@
+use std::time::Duration;
+
+use postcard_rpc::EndpointMap;
+use protocol::metrics::Metrics;
+use pyo3::{prelude::*, types::PyDict};
+use tokio::task::JoinHandle;
+
+use crate::{
+    client::{BoardClient, RequestError},
+    common::{BoardError, BoardResult},
+};
+
+/// Get the metrics of the board as a Python dict. The request counts are keyed by the
+/// endpoint path, looked up in the endpoint list of the client's protocol module.
+pub async fn get_metrics(client: &BoardClient, endpoints: &EndpointMap) -> BoardResult<Py<PyDict>> {
+    // Shared endpoints have the same key in every protocol module.
+    let metrics = client.query::<protocol::minimal::GetMetrics>(&()).await?;
+
+    Python::with_gil(|py| metrics_dict(py, &metrics, endpoints).map(Bound::unbind))
+        .map_err(BoardError::Python)
+}
+
+/// Log the metrics of the board every `interval`, until the client is closed or the
+/// returned task is aborted. A board that does not respond in time is skipped once.
+pub fn spawn_metrics_log(
+    client: BoardClient,
+    endpoints: &'static EndpointMap,
+    interval: Duration,
+) -> JoinHandle<()> {
+    tokio::spawn(async move {
+        let mut ticker = tokio::time::interval(interval);
+        loop {
+            ticker.tick().await;
+            match client.query::<protocol::minimal::GetMetrics>(&()).await {
+                Ok(metrics) => log::info!("Metrics: {}", format_metrics(&metrics, endpoints)),
+                Err(RequestError::Timeout(timeout)) => {
+                    log::warn!("No metrics from the board within {:?}", timeout)
+                }
+                Err(e) => {
+                    log::error!("Metrics logging stopped: {:?}", e);
+                    break;
+                }
+            }
+        }
+    })
+}
+
+fn endpoint_path(key: &[u8; 8], endpoints: &EndpointMap) -> String {
+    endpoints
+        .endpoints
+        .iter()
+        .find(|(_, req_key, _)| req_key.to_bytes() == *key)
+        .map(|(path, _, _)| path.to_string())
+        .unwrap_or_else(|| format!("{:02x?}", key))
+}
+
+fn metrics_dict<'py>(
+    py: Python<'py>,
+    metrics: &Metrics,
+    endpoints: &EndpointMap,
+) -> PyResult<Bound<'py, PyDict>> {
+    let requests = PyDict::new(py);
+    for endpoint in &metrics.requests {
+        requests.set_item(endpoint_path(&endpoint.key, endpoints), endpoint.count)?;
+    }
+
+    let dict = PyDict::new(py);
+    dict.set_item("uptime_s", metrics.uptime_ms as f64 / 1000.0)?;
+    dict.set_item("requests", requests)?;
+    dict.set_item("deserialization_errors", metrics.deserialization_errors)?;
+    dict.set_item("dropped_messages", metrics.dropped_messages)?;
+    dict.set_item("usb_resets", metrics.usb_resets)?;
+    dict.set_item("usb_reconnects", metrics.usb_reconnects)?;
+    dict.set_item("stack_peak", metrics.stack_peak)?;
+    dict.set_item("stack_size", metrics.stack_size)?;
+    dict.set_item("idle_percent", metrics.idle_percent)?;
+    Ok(dict)
+}
+
+fn format_metrics(metrics: &Metrics, endpoints: &EndpointMap) -> String {
+    let requests = metrics
+        .requests
+        .iter()
+        .map(|endpoint| {
+            format!(
+                "{}={}",
+                endpoint_path(&endpoint.key, endpoints),
+                endpoint.count
+            )
+        })
+        .collect::<Vec<_>>()
+        .join(", ");
+
+    format!(
+        "uptime {:.1}s, idle {:.1}%, stack {}/{} B, deserialization errors {}, \
+         dropped messages {}, USB resets {}, reconnects {}, requests [{}]",
+        metrics.uptime_ms as f64 / 1000.0,
+        metrics.idle_percent,
+        metrics.stack_peak,
+        metrics.stack_size,
+        metrics.deserialization_errors,
+        metrics.dropped_messages,
+        metrics.usb_resets,
+        metrics.usb_reconnects,
+        requests
+    )
+}
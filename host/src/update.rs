@@ -0,0 +1,173 @@
+use std::{path::Path, time::Duration};
+
+use object::{
+    Endianness,
+    elf::PT_LOAD,
+    read::elf::{ElfFile32, ProgramHeader},
+};
+use protocol::{
+    board::Board,
+    minimal::{ConfirmUpdate, FinishUpdate, GetUpdateInfo, StartUpdate, WriteUpdateChunk},
+    update::{UPDATE_ALIGNMENT, UPDATE_CHUNK_SIZE, UpdateChunk, UpdateError, UpdateStart, crc32},
+};
+
+use crate::{
+    client::BoardClient,
+    common::{BoardError, BoardResult, RESET_DELAY, board_serial_number, reconnect_to_board},
+    flash::{download_binary, flash_binary, flash_file},
+};
+
+/// The bootloader swaps the images page by page before the new firmware starts.
+const UPDATE_RECONNECT_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Update the firmware of the connected board over USB and confirm the new image.
+///
+/// `path_or_name` is an ELF or raw `.bin` file, or the name of a published binary.
+/// Boards whose firmware was not built with the `bootloader` feature are flashed with
+/// `probe-rs` instead. The client is connected to the new firmware afterwards.
+pub async fn update_firmware(
+    client: &mut BoardClient,
+    product_string: &str,
+    path_or_name: &str,
+) -> BoardResult<(), UpdateError> {
+    let info = client.send_resp::<GetUpdateInfo>(&()).await?;
+    let serial_number = board_serial_number(client).await?;
+
+    if !info.supported {
+        log::warn!(
+            "The firmware does not run behind the bootloader, flashing {} with probe-rs",
+            path_or_name
+        );
+        client.close();
+        flash_with_probe(path_or_name, info.board).map_err(BoardError::Python)?;
+        let reconnected = reconnect_to_board::<UpdateError>(
+            product_string,
+            &serial_number,
+            UPDATE_RECONNECT_TIMEOUT,
+            client.policy(),
+        )
+        .await?;
+        client.replace(reconnected);
+        return Ok(());
+    }
+
+    let image = load_image(path_or_name, info.board).map_err(BoardError::InvalidData)?;
+    if image.len() > info.max_size as usize {
+        return Err(BoardError::InvalidData(format!(
+            "Image of {} bytes does not fit the {} bytes partition",
+            image.len(),
+            info.max_size
+        )));
+    }
+
+    log::info!("Uploading {} bytes of {}", image.len(), path_or_name);
+    client
+        .send_resp::<StartUpdate>(&UpdateStart {
+            size: image.len() as u32,
+            crc: crc32(0, &image),
+        })
+        .await?
+        .map_err(BoardError::Endpoint)?;
+
+    for (i, data) in image.chunks(UPDATE_CHUNK_SIZE).enumerate() {
+        let chunk = UpdateChunk {
+            offset: (i * UPDATE_CHUNK_SIZE) as u32,
+            data: heapless::Vec::from_slice(data).unwrap(),
+        };
+        client
+            .send_resp::<WriteUpdateChunk>(&chunk)
+            .await?
+            .map_err(BoardError::Endpoint)?;
+    }
+
+    client
+        .send_resp::<FinishUpdate>(&())
+        .await?
+        .map_err(BoardError::Endpoint)?;
+    client.close();
+    log::info!("Update uploaded, waiting for the bootloader to swap it in");
+    tokio::time::sleep(RESET_DELAY).await;
+
+    let reconnected = reconnect_to_board::<UpdateError>(
+        product_string,
+        &serial_number,
+        UPDATE_RECONNECT_TIMEOUT,
+        client.policy(),
+    )
+    .await?;
+    client.replace(reconnected);
+    client
+        .send_resp::<ConfirmUpdate>(&())
+        .await?
+        .map_err(BoardError::Endpoint)?;
+    log::info!("Update confirmed");
+
+    Ok(())
+}
+
+fn flash_with_probe(path_or_name: &str, board: Board) -> pyo3::PyResult<()> {
+    let path = Path::new(path_or_name);
+    if path.exists() {
+        flash_file(path, board.chip())
+    } else {
+        flash_binary(path_or_name, board)
+    }
+}
+
+/// Image for the active partition, padded with erased flash to `UPDATE_ALIGNMENT`.
+/// Published binaries linked for the bootloader are stored next to the regular ones.
+fn load_image(path_or_name: &str, board: Board) -> Result<Vec<u8>, String> {
+    let Some(layout) = board.flash_layout() else {
+        return Err(format!("The bootloader is not supported on {:?}", board));
+    };
+
+    let mut path = Path::new(path_or_name).to_path_buf();
+    if !path.exists() {
+        let prefix = format!("{}-bootloader", board.s3_prefix());
+        path = download_binary(path_or_name, &prefix).map_err(|e| e.to_string())?;
+    }
+    let data = std::fs::read(&path).map_err(|e| format!("Failed to read {:?}: {}", path, e))?;
+
+    let mut image = if path.extension().is_some_and(|ext| ext == "bin") {
+        data
+    } else {
+        elf_image(&data, layout.active.origin())?
+    };
+    image.resize(image.len().next_multiple_of(UPDATE_ALIGNMENT), 0xff);
+    Ok(image)
+}
+
+/// Place the loadable segments of the ELF at their load addresses relative to `origin`.
+fn elf_image(data: &[u8], origin: u32) -> Result<Vec<u8>, String> {
+    let elf = ElfFile32::<Endianness>::parse(data).map_err(|e| format!("Invalid ELF: {}", e))?;
+    let endian = elf.endian();
+
+    let mut image = Vec::new();
+    for header in elf.elf_program_headers() {
+        if header.p_type(endian) != PT_LOAD {
+            continue;
+        }
+        let segment = header
+            .data(endian, data)
+            .map_err(|_| "Invalid ELF segment".to_string())?;
+        if segment.is_empty() {
+            continue;
+        }
+
+        let address = header.p_paddr(endian);
+        let Some(start) = address.checked_sub(origin) else {
+            return Err(format!(
+                "Segment at {:#010x} is before the active partition at {:#010x}, \
+                 build the firmware with the `bootloader` feature",
+                address, origin
+            ));
+        };
+        let start = start as usize;
+        let end = start + segment.len();
+        if image.len() < end {
+            image.resize(end, 0xff);
+        }
+        image[start..end].copy_from_slice(segment);
+    }
+    Ok(image)
+}
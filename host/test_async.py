@@ -0,0 +1,44 @@
+# %% Awaitable calls on one board. Read-only calls overlap in `asyncio.gather`, calls
+# that change the client take turns instead of failing with "Already mutably borrowed".
+import asyncio
+import sys
+import time
+
+from rustpill_clients import MinimalClient
+
+CALLS = 200
+
+boards = MinimalClient.list()
+if not boards:
+    print("skipped: no bluepill-minimal board attached")
+    sys.exit(0)
+
+
+async def main():
+    async with await MinimalClient.new_async(boards[0].serial_number) as client:
+        # %% One call after the other
+        start = time.perf_counter()
+        for _ in range(CALLS):
+            await client.get_serial_number_async()
+        sequential = time.perf_counter() - start
+        print(f"sequential: {sequential:.2f} s")
+
+        # %% All calls at once
+        start = time.perf_counter()
+        serials = await asyncio.gather(
+            *(client.get_serial_number_async() for _ in range(CALLS))
+        )
+        concurrent = time.perf_counter() - start
+        print(f"gather: {concurrent:.2f} s")
+        assert len(set(serials)) == 1
+        assert concurrent < 0.75 * sequential, "the read-only calls did not overlap"
+
+        # %% Calls changing the client, mixed with reads
+        await asyncio.gather(
+            *(client.log_metrics_async(None) for _ in range(CALLS)),
+            *(client.get_serial_number_async() for _ in range(CALLS)),
+        )
+        print("calls changing the client took turns")
+
+
+asyncio.run(main())
@@ -0,0 +1,80 @@
+# %% Boards driven from Python threads. The clients release the GIL while they wait for
+# USB, so the round trips of two boards overlap instead of queueing behind each other, and
+# the calls of one client from several threads take turns.
+import os
+import sys
+import threading
+import time
+
+from rustpill_clients import MinimalClient
+
+CALLS = 500
+
+# Serial numbers of the two boards, comma separated in RUSTPILL_SERIALS, otherwise the
+# first two boards with the minimal firmware.
+if "RUSTPILL_SERIALS" in os.environ:
+    serial_numbers = os.environ["RUSTPILL_SERIALS"].split(",")
+else:
+    serial_numbers = [board.serial_number for board in MinimalClient.list()]
+if not serial_numbers:
+    print("skipped: needs a bluepill-minimal board attached")
+    sys.exit(0)
+
+
+def poll(client):
+    for _ in range(CALLS):
+        client.get_serial_number()
+
+
+def restart_metrics_log(client):
+    for _ in range(CALLS):
+        client.log_metrics(None)
+
+
+def run_threads(*targets):
+    errors = []
+
+    def run(target, client):
+        try:
+            target(client)
+        except Exception as e:
+            errors.append(e)
+
+    threads = [threading.Thread(target=run, args=target) for target in targets]
+    for thread in threads:
+        thread.start()
+    for thread in threads:
+        thread.join()
+    assert not errors, errors
+
+
+# %% One client shared by two threads, reading and changing it at the same time
+with MinimalClient(serial_numbers[0]) as client:
+    run_threads((poll, client), (restart_metrics_log, client))
+print("same client: ok")
+
+if len(serial_numbers) < 2:
+    print("skipped: needs two bluepill-minimal boards attached for the concurrency check")
+    sys.exit(0)
+
+clients = [MinimalClient(serial) for serial in serial_numbers[:2]]
+
+
+# %% Sequential baseline
+start = time.perf_counter()
+for client in clients:
+    poll(client)
+sequential = time.perf_counter() - start
+print(f"sequential: {sequential:.2f} s")
+
+# %% Both boards at once
+start = time.perf_counter()
+run_threads(*((poll, client) for client in clients))
+concurrent = time.perf_counter() - start
+print(f"threads: {concurrent:.2f} s")
+
+assert concurrent < 0.75 * sequential, "the calls were serialized on the GIL"
+
+# %%
+for client in clients:
+    client.close()
@@ -1,33 +1,427 @@
 extern crate proc_macro;
 
 use proc_macro::TokenStream;
-use quote::quote;
-use syn::{ImplItem, Item, parse_macro_input};
+use proc_macro2::TokenTree;
+use quote::{ToTokens, format_ident, quote};
+use syn::{
+    FnArg, ImplItem, ImplItemFn, ItemImpl, Pat, Path, ReturnType, Type, parse_macro_input,
+    parse_quote,
+};
 
+/// Types tied to the GIL, which can't cross `Python::allow_threads`.
+const GIL_BOUND_TYPES: [&str; 5] = ["Python", "Bound", "Borrowed", "PyRef", "PyRefMut"];
+
+/// Turns a `#[pymethods]` block written for the state of a client into the methods of the
+/// Python class given as the argument, a frozen tuple struct around a
+/// `tokio::sync::RwLock` of the state, as in `#[blocking_async(ServoClient)]` on
+/// `impl ServoState`.
+///
+/// Methods taking `self` stay methods of the state. The class gets a method of the same name
+/// that locks the state, for writing if the method takes `&mut self`, so the calls of one
+/// object take turns however many threads make them. The async ones become blocking
+/// methods with an awaitable `<name>_async` variant for asyncio code, which waits for the
+/// lock on the shared runtime. The async `#[new]`
+/// constructor wraps the new state in the class, its variant is a static `new_async`
+/// method. The other methods, such as static ones, move to the class as they are, so
+/// `Self` in them is the class.
+///
+/// The blocking methods release the GIL while they wait for the lock or the board, so other
+/// Python threads keep running, and raise KeyboardInterrupt on Ctrl-C instead of waiting
+/// forever for a board that stopped responding. They have to return a `Result` whose error
+/// converts from `PyErr`, and can't take or return types tied to the GIL.
+///
+/// Async methods taking `self` get a keyword-only `timeout` argument, the time in seconds
+/// each request of the call waits for the board instead of the timeout of the client.
+/// Methods that already take a `timeout` keep theirs.
+///
+/// The methods use the host crate's `asyncio` and `client` modules, so the macro is only
+/// usable there.
 #[proc_macro_attribute]
-pub fn blocking_async(_attr: TokenStream, item: TokenStream) -> TokenStream {
-    let parsed_item = parse_macro_input!(item as Item);
-
-    match parsed_item {
-        Item::Impl(mut imp) => {
-            for item in &mut imp.items {
-                if let ImplItem::Fn(method) = item {
-                    if method.sig.asyncness.is_some() {
-                        method.sig.asyncness = None;
-                        let stmts = &method.block.stmts;
-                        method.block = syn::parse_quote! {
-                            {
-                                pyo3_async_runtimes::tokio::get_runtime().block_on(async move {
-                                    #(#stmts)*
-                                })
-                            }
-                        };
-                    }
+pub fn blocking_async(attr: TokenStream, item: TokenStream) -> TokenStream {
+    let class = parse_macro_input!(attr as Path);
+    let state_impl = parse_macro_input!(item as ItemImpl);
+    match expand(&class, state_impl) {
+        Ok(tokens) => tokens.into(),
+        Err(e) => e.to_compile_error().into(),
+    }
+}
+
+fn expand(class: &Path, state_impl: ItemImpl) -> syn::Result<proc_macro2::TokenStream> {
+    let state = &state_impl.self_ty;
+    let mut class_items = Vec::new();
+    let mut state_items = Vec::new();
+    for item in state_impl.items.iter().cloned() {
+        let ImplItem::Fn(method) = item else {
+            class_items.push(item);
+            continue;
+        };
+        let is_constructor = method.attrs.iter().any(|attr| attr.path().is_ident("new"));
+        let receiver = match method.sig.inputs.first() {
+            Some(FnArg::Receiver(receiver)) => Some(receiver.mutability.is_some()),
+            _ => None,
+        };
+        if receiver.is_none() && !is_constructor {
+            class_items.push(ImplItem::Fn(method));
+            continue;
+        }
+        if !releases_gil(&method) {
+            return Err(syn::Error::new_spanned(
+                &method.sig,
+                "blocking_async methods can't take or return types tied to the GIL",
+            ));
+        }
+
+        let name = method.sig.ident.clone();
+        let args = argument_names(&method)?;
+        let is_async = method.sig.asyncness.is_some();
+        let mut wrapper = method.clone();
+        wrapper.sig.asyncness = None;
+
+        let mut state_method = method;
+        state_method
+            .attrs
+            .retain(|attr| attr.path().is_ident("allow"));
+        state_items.push(ImplItem::Fn(state_method));
+
+        let Some(mutable) = receiver else {
+            if !is_async {
+                return Err(syn::Error::new_spanned(
+                    &wrapper.sig,
+                    "blocking_async constructors have to be async",
+                ));
+            }
+            wrapper.block = blocking(quote! {
+                <#state>::#name(#(#args),*)
+                    .await
+                    .map(|state| Self(tokio::sync::RwLock::new(state)))
+            });
+            class_items.push(ImplItem::Fn(async_variant(&wrapper, state, None, false)));
+            class_items.push(ImplItem::Fn(wrapper));
+            continue;
+        };
+
+        wrapper.sig.inputs[0] = parse_quote!(&self);
+        if !is_async {
+            let lock = if mutable {
+                quote! { let mut state = crate::asyncio::write_state(&self.0); }
+            } else {
+                quote! { let state = crate::asyncio::read_state(&self.0); }
+            };
+            wrapper.block = parse_quote! {
+                {
+                    #lock
+                    state.#name(#(#args),*)
+                }
+            };
+            class_items.push(ImplItem::Fn(wrapper));
+            continue;
+        }
+
+        let lock = if mutable {
+            quote! { let mut state = self.0.write().await; }
+        } else {
+            quote! { let state = self.0.read().await; }
+        };
+        let call = quote! { state.#name(#(#args),*) };
+        let has_timeout = add_timeout_argument(&mut wrapper);
+        wrapper.block = if has_timeout {
+            let body = blocking(quote! {
+                #lock
+                crate::client::with_call_timeout(call_timeout, #call).await
+            });
+            parse_quote! {
+                {
+                    let call_timeout = crate::client::call_timeout(timeout)?;
+                    #body
+                }
+            }
+        } else {
+            blocking(quote! {
+                #lock
+                #call.await
+            })
+        };
+        class_items.push(ImplItem::Fn(async_variant(
+            &wrapper,
+            state,
+            Some(mutable),
+            has_timeout,
+        )));
+        class_items.push(ImplItem::Fn(wrapper));
+    }
+
+    let attrs = &state_impl.attrs;
+    let (impl_generics, _, where_clause) = state_impl.generics.split_for_impl();
+    Ok(quote! {
+        #(#attrs)*
+        impl #class {
+            #(#class_items)*
+        }
+
+        impl #impl_generics #state #where_clause {
+            #(#state_items)*
+        }
+    })
+}
+
+/// Body running the async statements to completion with the GIL released.
+fn blocking(stmts: proc_macro2::TokenStream) -> syn::Block {
+    parse_quote! {
+        {
+            pyo3::Python::with_gil(|py| {
+                py.allow_threads(move || crate::asyncio::block_on(async move { #stmts }))
+            })
+            .unwrap_or_else(|e| Err(e.into()))
+        }
+    }
+}
+
+/// Names of the arguments of a method, without the receiver.
+fn argument_names(method: &ImplItemFn) -> syn::Result<Vec<syn::Ident>> {
+    method
+        .sig
+        .inputs
+        .iter()
+        .filter_map(|input| match input {
+            FnArg::Receiver(_) => None,
+            FnArg::Typed(typed) => Some(match &*typed.pat {
+                Pat::Ident(pat) => Ok(pat.ident.clone()),
+                pat => Err(syn::Error::new_spanned(
+                    pat,
+                    "blocking_async only supports plain argument names",
+                )),
+            }),
+        })
+        .collect()
+}
+
+/// Add the `timeout` argument of a single call, which `client::with_call_timeout` applies to
+/// the requests of the call. Returns false for methods that already take a `timeout`.
+fn add_timeout_argument(method: &mut ImplItemFn) -> bool {
+    let mut has_receiver = false;
+    let mut names = Vec::new();
+    for input in &method.sig.inputs {
+        match input {
+            FnArg::Receiver(_) => has_receiver = true,
+            FnArg::Typed(typed) => {
+                if let Pat::Ident(pat) = &*typed.pat {
+                    names.push(pat.ident.clone());
+                }
+            }
+        }
+    }
+    if !has_receiver || names.iter().any(|name| name == "timeout") {
+        return false;
+    }
+
+    method.sig.inputs.push(parse_quote!(timeout: Option<f64>));
+
+    let mut has_signature = false;
+    for attr in &mut method.attrs {
+        let syn::Meta::List(list) = &mut attr.meta else {
+            continue;
+        };
+        if !list.path.is_ident("pyo3") {
+            continue;
+        }
+        let mut tokens: Vec<TokenTree> = list.tokens.clone().into_iter().collect();
+        for i in 0..tokens.len() {
+            let is_signature =
+                matches!(&tokens[i], TokenTree::Ident(ident) if ident == "signature");
+            if let (true, Some(TokenTree::Group(group))) = (is_signature, tokens.get(i + 2)) {
+                let params = with_timeout_parameter(group.stream());
+                tokens[i + 2] =
+                    TokenTree::Group(proc_macro2::Group::new(group.delimiter(), params));
+                has_signature = true;
+            }
+        }
+        list.tokens = tokens.into_iter().collect();
+    }
+    if !has_signature {
+        method
+            .attrs
+            .push(parse_quote!(#[pyo3(signature = (#(#names,)* *, timeout = None))]));
+    }
+
+    method.attrs.push(parse_quote!(#[doc = ""]));
+    method.attrs.push(parse_quote!(#[doc = " :param timeout: seconds each request of this call waits for the board, instead of the timeout of the client. `math.inf` waits forever."]));
+    true
+}
+
+/// Append the keyword-only `timeout = None` to the parameters of a `signature` attribute.
+fn with_timeout_parameter(params: proc_macro2::TokenStream) -> proc_macro2::TokenStream {
+    let tokens: Vec<TokenTree> = params.into_iter().collect();
+    let keyword_only = tokens
+        .iter()
+        .any(|token| matches!(token, TokenTree::Punct(punct) if punct.as_char() == '*'));
+    let needs_comma = match tokens.last() {
+        None => false,
+        Some(TokenTree::Punct(punct)) => punct.as_char() != ',',
+        Some(_) => true,
+    };
+
+    let mut params: proc_macro2::TokenStream = tokens.into_iter().collect();
+    if needs_comma {
+        params.extend(quote!(,));
+    }
+    if !keyword_only {
+        params.extend(quote!(*,));
+    }
+    params.extend(quote!(timeout = None));
+    params
+}
+
+/// Whether no argument or result of the method is tied to the GIL.
+fn releases_gil(method: &ImplItemFn) -> bool {
+    let arguments = method.sig.inputs.iter().filter_map(|input| match input {
+        FnArg::Receiver(_) => None,
+        FnArg::Typed(typed) => Some(typed.ty.to_token_stream()),
+    });
+    let result = match &method.sig.output {
+        ReturnType::Default => None,
+        ReturnType::Type(_, ty) => Some(ty.to_token_stream()),
+    };
+    !arguments.chain(result).any(mentions_gil_bound_type)
+}
+
+fn mentions_gil_bound_type(tokens: proc_macro2::TokenStream) -> bool {
+    tokens.into_iter().any(|token| match token {
+        TokenTree::Ident(ident) => GIL_BOUND_TYPES.iter().any(|ty| ident == ty),
+        TokenTree::Group(group) => mentions_gil_bound_type(group.stream()),
+        _ => false,
+    })
+}
+
+/// Awaitable variant of a blocking method, which runs the method of the state on the shared
+/// runtime once the lock of `mutable` kind is taken, or the constructor of the state for
+/// None. `has_timeout` is set when the macro added the `timeout` argument of the call.
+/// Borrowed string arguments become owned, as the call outlives the Python arguments.
+fn async_variant(
+    method: &ImplItemFn,
+    state: &Type,
+    mutable: Option<bool>,
+    has_timeout: bool,
+) -> ImplItemFn {
+    let name = &method.sig.ident;
+    let async_name = format_ident!("{}_async", name);
+    let is_constructor = method.attrs.iter().any(|attr| attr.path().is_ident("new"));
+
+    let mut attrs = Vec::new();
+    for attr in &method.attrs {
+        if attr.path().is_ident("new") {
+            attrs.push(parse_quote!(#[staticmethod]));
+        } else {
+            attrs.push(attr.clone());
+        }
+    }
+    let doc = if is_constructor {
+        " Awaitable version of the constructor, which does not block the asyncio event loop."
+            .to_string()
+    } else {
+        format!(" Awaitable version of `{name}`, which does not block the asyncio event loop.")
+    };
+    attrs.push(parse_quote!(#[doc = ""]));
+    attrs.push(parse_quote!(#[doc = #doc]));
+    if method.sig.inputs.len() > 5 {
+        // The variant also takes the Python token and the object.
+        attrs.push(parse_quote!(#[allow(clippy::too_many_arguments)]));
+    }
+
+    let mut params = Vec::new();
+    let mut args = Vec::new();
+    for input in &method.sig.inputs {
+        match input {
+            FnArg::Receiver(_) => {}
+            FnArg::Typed(typed) => {
+                let Pat::Ident(pat) = &*typed.pat else {
+                    panic!("blocking_async only supports plain argument names");
+                };
+                let ident = &pat.ident;
+                let ty = &*typed.ty;
+                if has_timeout && ident == "timeout" {
+                    // Applied to the call instead of passed to it.
+                    params.push(quote! { #ident: #ty });
+                } else if is_str_ref(ty) {
+                    params.push(quote! { #ident: String });
+                    args.push(quote! { &#ident });
+                } else if is_optional_str_ref(ty) {
+                    params.push(quote! { #ident: Option<String> });
+                    args.push(quote! { #ident.as_deref() });
+                } else {
+                    params.push(quote! { #ident: #ty });
+                    args.push(quote! { #ident });
                 }
             }
-            // Return the potentially modified impl block
-            quote! { #imp }.into()
         }
-        _ => TokenStream::from(quote! { #parsed_item }),
     }
+
+    let output = match &method.sig.output {
+        ReturnType::Default => quote! { () },
+        ReturnType::Type(_, ty) => quote! { #ty },
+    };
+
+    let Some(mutable) = mutable else {
+        return parse_quote! {
+            #(#attrs)*
+            fn #async_name(
+                py: pyo3::Python<'_>,
+                #(#params),*
+            ) -> pyo3::PyResult<crate::asyncio::Awaitable<#output>> {
+                crate::asyncio::spawn(py, async move {
+                    <#state>::#name(#(#args),*)
+                        .await
+                        .map(|state| Self(tokio::sync::RwLock::new(state)))
+                })
+            }
+        };
+    };
+
+    let lock = if mutable {
+        quote! { let mut state = slf.get().0.write().await; }
+    } else {
+        quote! { let state = slf.get().0.read().await; }
+    };
+    let call = quote! { state.#name(#(#args),*) };
+    let (call_timeout, call) = if has_timeout {
+        (
+            quote! { let call_timeout = crate::client::call_timeout(timeout)?; },
+            quote! { crate::client::with_call_timeout(call_timeout, #call) },
+        )
+    } else {
+        (quote! {}, call)
+    };
+    parse_quote! {
+        #(#attrs)*
+        fn #async_name(
+            slf: pyo3::PyRef<'_, Self>,
+            py: pyo3::Python<'_>,
+            #(#params),*
+        ) -> pyo3::PyResult<crate::asyncio::Awaitable<#output>> {
+            let slf: pyo3::Py<Self> = slf.into();
+            #call_timeout
+            // The call waits for the lock of the object without taking a thread.
+            crate::asyncio::spawn(py, async move {
+                #lock
+                #call.await
+            })
+        }
+    }
+}
+
+fn is_str_ref(ty: &Type) -> bool {
+    matches!(ty, Type::Reference(r) if matches!(&*r.elem, Type::Path(p) if p.path.is_ident("str")))
+}
+
+fn is_optional_str_ref(ty: &Type) -> bool {
+    let Type::Path(path) = ty else {
+        return false;
+    };
+    let Some(segment) = path.path.segments.last() else {
+        return false;
+    };
+    let syn::PathArguments::AngleBracketed(generics) = &segment.arguments else {
+        return false;
+    };
+    segment.ident == "Option"
+        && matches!(generics.args.first(), Some(syn::GenericArgument::Type(ty)) if is_str_ref(ty))
 }
@@ -0,0 +1,167 @@
+use postcard_schema::Schema;
+use serde::{Deserialize, Serialize};
+
+#[cfg(feature = "use-std")]
+use pyo3::prelude::*;
+#[cfg(feature = "use-std")]
+use pyo3_stub_gen::derive::*;
+
+/// Boards supported by the firmware. Each one is selected with the cargo feature of
+/// the same name in the `firmware` crate.
+#[cfg_attr(feature = "use-std", gen_stub_pyclass_enum, pyclass(eq, eq_int))]
+#[derive(Serialize, Deserialize, Schema, Debug, Default, PartialEq, Eq, Clone, Copy)]
+pub enum Board {
+    /// Blue Pill with the 64 kB STM32F103C8.
+    #[default]
+    BluepillF103c8,
+    /// Blue Pill with the 128 kB STM32F103CB.
+    BluepillF103cb,
+    /// Nucleo-F103RB, clocked from the 8 MHz MCO of the on-board ST-LINK.
+    NucleoF103rb,
+    /// WeAct Black Pill with the STM32F401CC.
+    BlackpillF401,
+    /// WeAct Black Pill with the STM32F411CE.
+    BlackpillF411,
+}
+
+impl Board {
+    pub const ALL: [Board; 5] = [
+        Board::BluepillF103c8,
+        Board::BluepillF103cb,
+        Board::NucleoF103rb,
+        Board::BlackpillF401,
+        Board::BlackpillF411,
+    ];
+
+    /// Name of the `firmware` cargo feature selecting the board.
+    pub const fn feature(&self) -> &'static str {
+        match self {
+            Board::BluepillF103c8 => "bluepill-f103c8",
+            Board::BluepillF103cb => "bluepill-f103cb",
+            Board::NucleoF103rb => "nucleo-f103rb",
+            Board::BlackpillF401 => "blackpill-f401",
+            Board::BlackpillF411 => "blackpill-f411",
+        }
+    }
+
+    /// Chip name as understood by `probe-rs`.
+    pub const fn chip(&self) -> &'static str {
+        match self {
+            Board::BluepillF103c8 => "STM32F103C8",
+            Board::BluepillF103cb => "STM32F103CB",
+            Board::NucleoF103rb => "STM32F103RB",
+            Board::BlackpillF401 => "STM32F401CCUx",
+            Board::BlackpillF411 => "STM32F411CEUx",
+        }
+    }
+
+    /// Directory of the published binaries in the firmware bucket. The Blue Pill keeps
+    /// the chip name used before other boards were supported.
+    pub const fn s3_prefix(&self) -> &'static str {
+        match self {
+            Board::BluepillF103c8 => "stm32f103c8",
+            _ => self.feature(),
+        }
+    }
+
+    pub fn from_feature(feature: &str) -> Option<Self> {
+        Board::ALL.into_iter().find(|b| b.feature() == feature)
+    }
+
+    pub const fn flash_size(&self) -> u32 {
+        match self {
+            Board::BluepillF103c8 => 64 * 1024,
+            Board::BluepillF103cb | Board::NucleoF103rb => 128 * 1024,
+            Board::BlackpillF401 => 256 * 1024,
+            Board::BlackpillF411 => 512 * 1024,
+        }
+    }
+
+    pub const fn ram_size(&self) -> u32 {
+        match self {
+            Board::BluepillF103c8 | Board::BluepillF103cb | Board::NucleoF103rb => 20 * 1024,
+            Board::BlackpillF401 => 64 * 1024,
+            Board::BlackpillF411 => 128 * 1024,
+        }
+    }
+
+    /// Top of the RAM, kept for the records that survive a reset, such as the crash report.
+    /// Neither the firmware nor the bootloader link anything else there, so the records
+    /// also survive a reset through the bootloader.
+    pub const fn noinit_ram_origin(&self) -> u32 {
+        RAM_ORIGIN + self.ram_size() - NOINIT_RAM_SIZE
+    }
+
+    /// Flash page or sector kept for the settings stored on the board. The F1 boards use
+    /// the last 1 kB page and the firmware is linked before it. The F4 sectors at the end
+    /// of the flash are 128 kB and slow to erase, so the F4 boards use the 16 kB sector 1
+    /// and only the vector table is linked before it, see `text_offset`.
+    pub const fn settings(&self) -> Partition {
+        match self {
+            Board::BluepillF103c8 | Board::BluepillF103cb | Board::NucleoF103rb => {
+                Partition::new(self.flash_size() - 1024, 1024)
+            }
+            Board::BlackpillF401 | Board::BlackpillF411 => Partition::new(16 * 1024, 16 * 1024),
+        }
+    }
+
+    /// Where the code of the firmware starts when it is not right after the vector table,
+    /// after the settings sector of the F4 boards.
+    pub const fn text_offset(&self) -> Option<u32> {
+        match self {
+            Board::BlackpillF401 | Board::BlackpillF411 => {
+                Some(self.settings().offset + self.settings().size)
+            }
+            _ => None,
+        }
+    }
+
+    /// Flash partitions used with the bootloader, `None` if the board cannot fit it.
+    /// The STM32F103C8 has too little flash for two copies of the firmware and the
+    /// F4 sectors of up to 128 kB are too large to swap them page by page. The partitions
+    /// end before `settings`.
+    pub const fn flash_layout(&self) -> Option<FlashLayout> {
+        match self {
+            // 1 kB pages.
+            Board::BluepillF103cb | Board::NucleoF103rb => Some(FlashLayout {
+                bootloader: Partition::new(0, 16 * 1024),
+                state: Partition::new(16 * 1024, 2 * 1024),
+                active: Partition::new(18 * 1024, 54 * 1024),
+                dfu: Partition::new(72 * 1024, 55 * 1024),
+            }),
+            _ => None,
+        }
+    }
+}
+
+pub const FLASH_ORIGIN: u32 = 0x0800_0000;
+pub const RAM_ORIGIN: u32 = 0x2000_0000;
+/// Size of the RAM at `Board::noinit_ram_origin`.
+pub const NOINIT_RAM_SIZE: u32 = 256;
+
+/// Region of the flash, the offset is relative to `FLASH_ORIGIN`.
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub struct Partition {
+    pub offset: u32,
+    pub size: u32,
+}
+
+impl Partition {
+    pub const fn new(offset: u32, size: u32) -> Self {
+        Self { offset, size }
+    }
+
+    pub const fn origin(&self) -> u32 {
+        FLASH_ORIGIN + self.offset
+    }
+}
+
+/// Flash partitions of the embassy-boot bootloader. The firmware runs from `active`,
+/// updates are written to `dfu`, which has to be one page larger, and swapped in on reset.
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub struct FlashLayout {
+    pub bootloader: Partition,
+    pub state: Partition,
+    pub active: Partition,
+    pub dfu: Partition,
+}
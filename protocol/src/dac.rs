@@ -0,0 +1,261 @@
+use heapless::Vec;
+use postcard_rpc::{TopicDirection, endpoints, topics};
+use postcard_schema::Schema;
+use serde::{Deserialize, Serialize};
+
+#[cfg(feature = "use-std")]
+use pyo3::prelude::*;
+#[cfg(feature = "use-std")]
+use pyo3_stub_gen::derive::*;
+
+use crate::device::{Alias, AliasResult, AliasSettings, DeviceInfo};
+use crate::metrics::Metrics;
+use crate::update::{UpdateChunk, UpdateInfo, UpdateResult, UpdateStart};
+use crate::utils::LastCrash;
+
+pub const USB_DEVICE_NAME: &'static str = "bluepill-dac";
+
+/// Highest number of channels of the supported DACs.
+pub const CHANNEL_COUNT: usize = 4;
+/// Length of the arbitrary waveform table of each channel.
+pub const MAX_POINTS: usize = 1024;
+/// Number of table points sent in a single upload request.
+pub const CHUNK_SIZE: usize = 64;
+pub const MIN_UPDATE_RATE_HZ: u32 = 1;
+/// Every update writes all channels over SPI from the timer interrupt, which has to fit in the period.
+pub const MAX_UPDATE_RATE_HZ: u32 = 20_000;
+
+endpoints! {
+    list = ENDPOINT_LIST;
+    omit_std = true;
+    | EndpointTy                | RequestTy                            | ResponseTy            | Path              |
+    | ----------                | ---------                            | ----------            | ----              |
+    | GetUniqueIdEndpoint       | ()                                   | [u8; 24]              | "unique_id/get"   |
+    | GetCrashReport            | ()                                   | LastCrash             | "crash/get"       |
+    | ClearCrashReport          | ()                                   | ()                    | "crash/clear"     |
+    | ResetEndpoint             | ()                                   | ()                    | "reset"           |
+    | EnterBootloaderEndpoint   | ()                                   | ()                    | "bootloader"      |
+    | GetUpdateInfo             | ()                                   | UpdateInfo            | "update/info"     |
+    | StartUpdate               | UpdateStart                          | UpdateResult          | "update/start"    |
+    | WriteUpdateChunk          | UpdateChunk                          | UpdateResult          | "update/write"    |
+    | FinishUpdate              | ()                                   | UpdateResult          | "update/finish"   |
+    | ConfirmUpdate             | ()                                   | UpdateResult          | "update/confirm"  |
+    | GetMetrics                | ()                                   | Metrics               | "metrics"         |
+    | IdentifyEndpoint          | ()                                   | ()                    | "identify"        |
+    | GetDeviceInfo             | ()                                   | DeviceInfo            | "device/info"     |
+    | GetAlias                  | ()                                   | Alias                 | "alias/get"       |
+    | SetAlias                  | AliasSettings                        | AliasResult           | "alias/set"       |
+    | GetDacLimits              | ()                                   | DacLimits             | "dac/limits"      |
+    | GetDacConfig              | ()                                   | DacConfig             | "dac/config/get"  |
+    | SetDacConfig              | DacConfig                            | DacResult             | "dac/config/set"  |
+    | SetWaveform               | (u8, Waveform)                       | DacResult             | "dac/waveform"    |
+    | UploadTable               | TableChunk                           | DacResult             | "dac/upload"      |
+    | StartOutput               | u32                                  | StartResult           | "dac/start"       |
+    | StopOutput                | ()                                   | ()                    | "dac/stop"        |
+    | GetDacStatus              | ()                                   | DacStatus             | "dac/status"      |
+}
+
+topics! {
+    list = TOPICS_IN_LIST;
+    direction = TopicDirection::ToServer;
+    | TopicTy                   | MessageTy     | Path              |
+    | -------                   | ---------     | ----              |
+}
+
+topics! {
+    list = TOPICS_OUT_LIST;
+    direction = TopicDirection::ToClient;
+    | TopicTy                   | MessageTy     | Path              | Cfg                           |
+    | -------                   | ---------     | ----              | ---                           |
+}
+
+#[cfg_attr(feature = "use-std", gen_stub_pyclass_enum, pyclass(eq, eq_int))]
+#[derive(Serialize, Deserialize, Schema, Debug, Default, PartialEq, Clone, Copy)]
+pub enum DacModel {
+    /// Dual 12 bit DAC with a 16 bit frame. LDAC has to be tied low.
+    #[default]
+    Mcp4922,
+    /// Quad 16 bit DAC with a 24 bit frame. LDAC has to be tied low and RESET high.
+    Ad5686,
+}
+
+impl DacModel {
+    pub const fn channels(&self) -> u8 {
+        match self {
+            DacModel::Mcp4922 => 2,
+            DacModel::Ad5686 => 4,
+        }
+    }
+
+    /// Number of codes, the output is `reference_v * code / full_scale`.
+    pub const fn full_scale(&self) -> u32 {
+        match self {
+            DacModel::Mcp4922 => 1 << 12,
+            DacModel::Ad5686 => 1 << 16,
+        }
+    }
+
+    pub const fn max_code(&self) -> u16 {
+        (self.full_scale() - 1) as u16
+    }
+}
+
+/// Maps the DAC output to the calibrated output, `volts = gain * dac_volts + offset_v`.
+/// Use the gain to account for an amplifier after the DAC, e.g. a piezo driver.
+#[cfg_attr(feature = "use-std", gen_stub_pyclass, pyclass(get_all, set_all))]
+#[derive(Serialize, Deserialize, Schema, Debug, PartialEq, Clone, Copy)]
+pub struct ChannelCalibration {
+    pub gain: f32,
+    pub offset_v: f32,
+}
+
+impl ChannelCalibration {
+    pub const fn new() -> Self {
+        Self {
+            gain: 1.0,
+            offset_v: 0.0,
+        }
+    }
+}
+
+impl Default for ChannelCalibration {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg_attr(feature = "use-std", gen_stub_pyclass, pyclass(get_all, set_all))]
+#[derive(Serialize, Deserialize, Schema, Debug, PartialEq, Clone, Copy)]
+pub struct DacConfig {
+    pub model: DacModel,
+    /// Voltage on the DAC reference pin.
+    pub reference_v: f32,
+    pub channels: [ChannelCalibration; CHANNEL_COUNT],
+}
+
+impl DacConfig {
+    pub const fn new() -> Self {
+        Self {
+            model: DacModel::Mcp4922,
+            reference_v: 3.3,
+            channels: [ChannelCalibration::new(); CHANNEL_COUNT],
+        }
+    }
+
+    pub fn validate(&self) -> DacResult {
+        if !(self.reference_v > 0.0 && self.reference_v.is_finite()) {
+            return Err(DacError::InvalidConfig);
+        }
+        for calibration in &self.channels {
+            if calibration.gain == 0.0
+                || !calibration.gain.is_finite()
+                || !calibration.offset_v.is_finite()
+            {
+                return Err(DacError::InvalidConfig);
+            }
+        }
+        Ok(())
+    }
+
+    /// Code that produces `volts` on the calibrated output of `channel`.
+    pub fn volts_to_code(&self, channel: u8, volts: f32) -> Result<u16, DacError> {
+        let calibration = self.calibration(channel)?;
+        let code = (volts - calibration.offset_v) / calibration.gain / self.reference_v
+            * self.model.full_scale() as f32;
+        // Written so that NaN fails the check as well.
+        if !(code > -0.5 && code < self.model.max_code() as f32 + 0.5) {
+            return Err(DacError::OutOfRange);
+        }
+        Ok((code + 0.5) as u16)
+    }
+
+    /// Calibrated output voltage of `channel` for `code`.
+    pub fn code_to_volts(&self, channel: u8, code: u16) -> Result<f32, DacError> {
+        let calibration = self.calibration(channel)?;
+        let dac_volts = code as f32 * self.reference_v / self.model.full_scale() as f32;
+        Ok(calibration.gain * dac_volts + calibration.offset_v)
+    }
+
+    fn calibration(&self, channel: u8) -> Result<&ChannelCalibration, DacError> {
+        if channel >= self.model.channels() {
+            return Err(DacError::InvalidChannel);
+        }
+        Ok(&self.channels[channel as usize])
+    }
+}
+
+impl Default for DacConfig {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// What a channel outputs on every timer update. Voltages are on the calibrated output.
+#[derive(Serialize, Deserialize, Schema, Debug, PartialEq, Clone, Copy)]
+pub enum Waveform {
+    /// Constant voltage, written immediately.
+    Static { volts: f32 },
+    /// Sawtooth going from `start_v` to `end_v` in `points` updates, then jumping back.
+    Ramp {
+        start_v: f32,
+        end_v: f32,
+        points: u32,
+    },
+    /// Going from `start_v` to `end_v` in `points` updates and back the same way.
+    Triangle {
+        start_v: f32,
+        end_v: f32,
+        points: u32,
+    },
+    /// The first `length` points of the uploaded table.
+    Table { length: u16 },
+}
+
+/// Part of the waveform table of a channel. Points are converted to codes with the
+/// calibration at upload time, so the tables are cleared when the config changes.
+#[derive(Serialize, Deserialize, Schema, Debug, Default, PartialEq, Clone)]
+pub struct TableChunk {
+    pub channel: u8,
+    pub offset: u16,
+    pub volts: Vec<f32, CHUNK_SIZE>,
+}
+
+#[cfg_attr(feature = "use-std", gen_stub_pyclass, pyclass(get_all))]
+#[derive(Serialize, Deserialize, Schema, Debug, PartialEq, Clone, Copy)]
+pub struct DacLimits {
+    pub max_points: u16,
+    pub chunk_size: u16,
+    pub min_update_rate_hz: u32,
+    pub max_update_rate_hz: u32,
+}
+
+#[cfg_attr(feature = "use-std", gen_stub_pyclass, pyclass(get_all))]
+#[derive(Serialize, Deserialize, Schema, Debug, Default, PartialEq, Clone, Copy)]
+pub struct DacStatus {
+    pub running: bool,
+    /// Update rate resulting from the timer dividers, can differ from the requested one.
+    pub update_rate_hz: f32,
+    /// Number of updates since the output was started.
+    pub updates: u32,
+}
+
+#[cfg_attr(feature = "use-std", gen_stub_pyclass_enum, pyclass(eq, eq_int))]
+#[derive(Serialize, Deserialize, Schema, Debug, PartialEq, Clone, Copy)]
+pub enum DacError {
+    InvalidChannel,
+    InvalidConfig,
+    /// Voltage outside of the calibrated range of the channel.
+    OutOfRange,
+    /// Ramps and triangles need at least 2 points.
+    InvalidPoints,
+    /// Table write past the end of the table or not following the previous chunk.
+    TableOverflow,
+    /// Playing more points than were uploaded.
+    TableTooShort,
+    InvalidUpdateRate,
+    /// The output has to be stopped first.
+    Busy,
+}
+
+pub type DacResult = Result<(), DacError>;
+pub type StartResult = Result<f32, DacError>;
@@ -0,0 +1,55 @@
+use heapless::String;
+use postcard_schema::Schema;
+use serde::{Deserialize, Serialize};
+
+#[cfg(feature = "use-std")]
+use pyo3::prelude::*;
+#[cfg(feature = "use-std")]
+use pyo3_stub_gen::derive::*;
+
+use crate::board::Board;
+
+/// USB vendor ID of every board, the firmwares are told apart by the product string.
+pub const USB_VID: u16 = 0xc0de;
+/// USB product ID of every board.
+pub const USB_PID: u16 = 0xcafe;
+
+/// Longest alias of a board, in ASCII characters.
+pub const ALIAS_LEN: usize = 16;
+
+pub type Alias = String<ALIAS_LEN>;
+
+/// Aliases are ASCII letters, digits, `-`, `_` and `.`, so they can be shown in the USB
+/// product string. An empty alias clears it.
+pub fn is_valid_alias(alias: &str) -> bool {
+    alias.len() <= ALIAS_LEN
+        && alias
+            .bytes()
+            .all(|b| b.is_ascii_alphanumeric() || matches!(b, b'-' | b'_' | b'.'))
+}
+
+#[derive(Serialize, Deserialize, Schema, Debug, PartialEq, Clone)]
+pub struct AliasSettings {
+    pub alias: Alias,
+    /// Append the alias to the USB product string, as "name (alias)", after the next reset.
+    pub usb_product: bool,
+}
+
+#[cfg_attr(feature = "use-std", gen_stub_pyclass_enum, pyclass(eq, eq_int))]
+#[derive(Serialize, Deserialize, Schema, Debug, PartialEq, Clone, Copy)]
+pub enum AliasError {
+    /// The alias has characters other than ASCII letters, digits, `-`, `_` and `.`.
+    Invalid,
+    Flash,
+}
+
+pub type AliasResult = Result<(), AliasError>;
+
+#[derive(Serialize, Deserialize, Schema, Debug, PartialEq, Clone)]
+pub struct DeviceInfo {
+    pub board: Board,
+    /// Version of the firmware crate.
+    pub version: String<16>,
+    pub serial_number: [u8; 24],
+    pub alias: AliasSettings,
+}
@@ -1,5 +1,13 @@
 #![cfg_attr(not(feature = "use-std"), no_std)]
 
+pub mod board;
+pub mod dac;
+pub mod device;
+pub mod metrics;
 pub mod minimal;
+pub mod pid;
+pub mod scope;
+pub mod sequencer;
 pub mod servo;
+pub mod update;
 pub mod utils;
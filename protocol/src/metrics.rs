@@ -0,0 +1,33 @@
+use heapless::Vec;
+use postcard_schema::Schema;
+use serde::{Deserialize, Serialize};
+
+/// Most endpoints counted by `Metrics::requests`, more than any protocol module has.
+pub const MAX_ENDPOINTS: usize = 32;
+
+#[derive(Serialize, Deserialize, Schema, Debug, PartialEq, Clone, Copy)]
+pub struct EndpointRequests {
+    /// Request key of the endpoint, as in the `ENDPOINT_LIST` of the protocol module.
+    pub key: [u8; 8],
+    pub count: u32,
+}
+
+/// Counters since the last reset of the board.
+#[derive(Serialize, Deserialize, Schema, Debug, PartialEq, Clone)]
+pub struct Metrics {
+    pub uptime_ms: u64,
+    /// Requests handled per endpoint, endpoints never called are left out.
+    pub requests: Vec<EndpointRequests, MAX_ENDPOINTS>,
+    /// Requests whose body did not match the endpoint schema.
+    pub deserialization_errors: u32,
+    /// Responses, topic messages and logs that could not be sent to the host.
+    pub dropped_messages: u32,
+    pub usb_resets: u32,
+    /// Times the host configured the device again after the first enumeration.
+    pub usb_reconnects: u32,
+    /// Deepest stack usage seen since boot, out of `stack_size` bytes.
+    pub stack_peak: u32,
+    pub stack_size: u32,
+    /// Share of the last second the executor had nothing else to run than the idle task.
+    pub idle_percent: f32,
+}
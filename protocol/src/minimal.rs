@@ -1,5 +1,10 @@
 use postcard_rpc::{TopicDirection, endpoints, topics};
 
+use crate::device::{Alias, AliasResult, AliasSettings, DeviceInfo};
+use crate::metrics::Metrics;
+use crate::update::{UpdateChunk, UpdateInfo, UpdateResult, UpdateStart};
+use crate::utils::LastCrash;
+
 pub const USB_DEVICE_NAME: &'static str = "bluepill-minimal";
 
 endpoints! {
@@ -8,6 +13,20 @@ endpoints! {
     | EndpointTy                | RequestTy                            | ResponseTy            | Path              |
     | ----------                | ---------                            | ----------            | ----              |
     | GetUniqueIdEndpoint       | ()                                   | [u8; 24]              | "unique_id/get"   |
+    | GetCrashReport            | ()                                   | LastCrash             | "crash/get"       |
+    | ClearCrashReport          | ()                                   | ()                    | "crash/clear"     |
+    | ResetEndpoint             | ()                                   | ()                    | "reset"           |
+    | EnterBootloaderEndpoint   | ()                                   | ()                    | "bootloader"      |
+    | GetUpdateInfo             | ()                                   | UpdateInfo            | "update/info"     |
+    | StartUpdate               | UpdateStart                          | UpdateResult          | "update/start"    |
+    | WriteUpdateChunk          | UpdateChunk                          | UpdateResult          | "update/write"    |
+    | FinishUpdate              | ()                                   | UpdateResult          | "update/finish"   |
+    | ConfirmUpdate             | ()                                   | UpdateResult          | "update/confirm"  |
+    | GetMetrics                | ()                                   | Metrics               | "metrics"         |
+    | IdentifyEndpoint          | ()                                   | ()                    | "identify"        |
+    | GetDeviceInfo             | ()                                   | DeviceInfo            | "device/info"     |
+    | GetAlias                  | ()                                   | Alias                 | "alias/get"       |
+    | SetAlias                  | AliasSettings                        | AliasResult           | "alias/set"       |
 }
 
 topics! {
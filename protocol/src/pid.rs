@@ -0,0 +1,419 @@
+use postcard_rpc::{TopicDirection, endpoints, topics};
+use postcard_schema::Schema;
+use serde::{Deserialize, Serialize};
+
+#[cfg(feature = "use-std")]
+use pyo3::prelude::*;
+#[cfg(feature = "use-std")]
+use pyo3_stub_gen::derive::*;
+
+use crate::device::{Alias, AliasResult, AliasSettings, DeviceInfo};
+use crate::metrics::Metrics;
+use crate::update::{UpdateChunk, UpdateInfo, UpdateResult, UpdateStart};
+use crate::utils::LastCrash;
+
+pub const USB_DEVICE_NAME: &'static str = "bluepill-pid";
+
+/// Highest supported control loop rate.
+pub const MAX_RATE_HZ: u32 = 1_000;
+
+endpoints! {
+    list = ENDPOINT_LIST;
+    omit_std = true;
+    | EndpointTy                | RequestTy                            | ResponseTy            | Path              |
+    | ----------                | ---------                            | ----------            | ----              |
+    | GetUniqueIdEndpoint       | ()                                   | [u8; 24]              | "unique_id/get"   |
+    | GetCrashReport            | ()                                   | LastCrash             | "crash/get"       |
+    | ClearCrashReport          | ()                                   | ()                    | "crash/clear"     |
+    | ResetEndpoint             | ()                                   | ()                    | "reset"           |
+    | EnterBootloaderEndpoint   | ()                                   | ()                    | "bootloader"      |
+    | GetUpdateInfo             | ()                                   | UpdateInfo            | "update/info"     |
+    | StartUpdate               | UpdateStart                          | UpdateResult          | "update/start"    |
+    | WriteUpdateChunk          | UpdateChunk                          | UpdateResult          | "update/write"    |
+    | FinishUpdate              | ()                                   | UpdateResult          | "update/finish"   |
+    | ConfirmUpdate             | ()                                   | UpdateResult          | "update/confirm"  |
+    | GetMetrics                | ()                                   | Metrics               | "metrics"         |
+    | IdentifyEndpoint          | ()                                   | ()                    | "identify"        |
+    | GetDeviceInfo             | ()                                   | DeviceInfo            | "device/info"     |
+    | GetAlias                  | ()                                   | Alias                 | "alias/get"       |
+    | SetAlias                  | AliasSettings                        | AliasResult           | "alias/set"       |
+    | GetPidConfig              | ()                                   | PidConfig             | "pid/config/get"  |
+    | SetPidConfig              | PidConfig                            | PidResult             | "pid/config/set"  |
+    | SetSetpoint               | f32                                  | PidResult             | "pid/setpoint"    |
+    | ResetController           | ()                                   | ()                    | "pid/reset"       |
+}
+
+topics! {
+    list = TOPICS_IN_LIST;
+    direction = TopicDirection::ToServer;
+    | TopicTy                   | MessageTy     | Path              |
+    | -------                   | ---------     | ----              |
+}
+
+topics! {
+    list = TOPICS_OUT_LIST;
+    direction = TopicDirection::ToClient;
+    | TopicTy                   | MessageTy     | Path              | Cfg                           |
+    | -------                   | ---------     | ----              | ---                           |
+    | PidTelemetryTopic         | PidTelemetry  | "pid/telemetry"   |                               |
+}
+
+/// Settings of the control loop. The measurement is the ADC reading as a fraction of full scale,
+/// converted with `input_scale` and `input_offset` to user units (e.g. degrees). The output is the
+/// PWM duty cycle as a fraction, so output limits must lie in the 0-1 range.
+#[cfg_attr(feature = "use-std", gen_stub_pyclass, pyclass(get_all, set_all))]
+#[derive(Serialize, Deserialize, Schema, Debug, PartialEq, Clone, Copy)]
+pub struct PidConfig {
+    pub kp: f32,
+    pub ki: f32,
+    pub kd: f32,
+    pub setpoint: f32,
+    pub output_min: f32,
+    pub output_max: f32,
+    /// Back-calculation gain, bleeds the integral when the output saturates. 0 only clamps the integral.
+    pub anti_windup: f32,
+    /// Time constant of the derivative low-pass filter in seconds.
+    pub derivative_tau: f32,
+    /// Time constant of the measurement low-pass filter in seconds, 0 disables it.
+    pub measurement_tau: f32,
+    pub input_scale: f32,
+    pub input_offset: f32,
+    pub rate_hz: u32,
+    /// Publish telemetry every N control steps, 0 disables it.
+    pub telemetry_divider: u16,
+    pub enabled: bool,
+}
+
+impl PidConfig {
+    pub const fn new() -> Self {
+        Self {
+            kp: 0.0,
+            ki: 0.0,
+            kd: 0.0,
+            setpoint: 0.0,
+            output_min: 0.0,
+            output_max: 1.0,
+            anti_windup: 0.0,
+            derivative_tau: 0.0,
+            measurement_tau: 0.0,
+            input_scale: 1.0,
+            input_offset: 0.0,
+            rate_hz: 100,
+            telemetry_divider: 10,
+            enabled: false,
+        }
+    }
+
+    pub fn validate(&self) -> PidResult {
+        let floats = [
+            self.kp,
+            self.ki,
+            self.kd,
+            self.setpoint,
+            self.anti_windup,
+            self.derivative_tau,
+            self.measurement_tau,
+            self.input_scale,
+            self.input_offset,
+        ];
+        if floats.iter().any(|v| !v.is_finite()) {
+            return Err(PidError::NotFinite);
+        }
+        if !(0.0 <= self.output_min && self.output_min < self.output_max && self.output_max <= 1.0)
+        {
+            return Err(PidError::InvalidLimits);
+        }
+        if self.rate_hz == 0 || self.rate_hz > MAX_RATE_HZ {
+            return Err(PidError::InvalidRate);
+        }
+        if self.anti_windup < 0.0 || self.derivative_tau < 0.0 || self.measurement_tau < 0.0 {
+            return Err(PidError::NegativeConstant);
+        }
+        Ok(())
+    }
+}
+
+impl Default for PidConfig {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg_attr(feature = "use-std", gen_stub_pyclass, pyclass(get_all))]
+#[derive(Serialize, Deserialize, Schema, Debug, Default, PartialEq, Clone, Copy)]
+pub struct PidTelemetry {
+    /// Device uptime in microseconds, wraps after about 71 minutes.
+    pub time_us: u32,
+    pub setpoint: f32,
+    pub measurement: f32,
+    pub output: f32,
+    pub error: f32,
+}
+
+#[cfg_attr(feature = "use-std", gen_stub_pyclass_enum, pyclass(eq, eq_int))]
+#[derive(Serialize, Deserialize, Schema, Debug, PartialEq, Clone, Copy)]
+pub enum PidError {
+    NotFinite,
+    InvalidLimits,
+    InvalidRate,
+    NegativeConstant,
+}
+
+pub type PidResult = Result<(), PidError>;
+
+/// Result of a single controller step.
+#[derive(Debug, Default, PartialEq, Clone, Copy)]
+pub struct PidStep {
+    /// Filtered measurement used by the controller.
+    pub measurement: f32,
+    pub error: f32,
+    pub output: f32,
+}
+
+/// PID controller with derivative on measurement, first order filters on the measurement and
+/// the derivative term and back-calculation anti-windup. Kept free of hardware dependencies,
+/// so that it can be checked on the host.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct PidController {
+    integral: f32,
+    derivative: f32,
+    measurement: f32,
+    initialized: bool,
+}
+
+impl PidController {
+    pub const fn new() -> Self {
+        Self {
+            integral: 0.0,
+            derivative: 0.0,
+            measurement: 0.0,
+            initialized: false,
+        }
+    }
+
+    /// Forget the integral and filter states, the next step starts from scratch.
+    pub fn reset(&mut self) {
+        *self = Self::new();
+    }
+
+    /// Run one step of the controller, `dt` is the time since the previous step in seconds.
+    pub fn update(&mut self, config: &PidConfig, measurement: f32, dt: f32) -> PidStep {
+        if !self.initialized {
+            self.measurement = measurement;
+            self.initialized = true;
+        }
+
+        let previous = self.measurement;
+        self.measurement += low_pass_alpha(config.measurement_tau, dt) * (measurement - previous);
+
+        let error = config.setpoint - self.measurement;
+
+        // Derivative on measurement avoids kicks on setpoint changes.
+        let raw_derivative = -(self.measurement - previous) / dt;
+        self.derivative +=
+            low_pass_alpha(config.derivative_tau, dt) * (raw_derivative - self.derivative);
+
+        self.integral += config.ki * error * dt;
+        self.integral = self.integral.clamp(config.output_min, config.output_max);
+
+        let unsaturated = config.kp * error + self.integral + config.kd * self.derivative;
+        let output = unsaturated.clamp(config.output_min, config.output_max);
+
+        self.integral += config.anti_windup * (output - unsaturated) * dt;
+
+        PidStep {
+            measurement: self.measurement,
+            error,
+            output,
+        }
+    }
+}
+
+/// Smoothing factor of a discrete first order low-pass filter, 1 passes the input through.
+fn low_pass_alpha(tau: f32, dt: f32) -> f32 {
+    if tau <= 0.0 { 1.0 } else { dt / (tau + dt) }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const DT: f32 = 0.1;
+
+    fn config() -> PidConfig {
+        PidConfig {
+            setpoint: 0.5,
+            ..PidConfig::new()
+        }
+    }
+
+    fn assert_close(actual: f32, expected: f32) {
+        assert!(
+            (actual - expected).abs() < 1e-5,
+            "{} is not {}",
+            actual,
+            expected
+        );
+    }
+
+    #[test]
+    fn proportional() {
+        let config = PidConfig {
+            kp: 2.0,
+            ..config()
+        };
+        let mut pid = PidController::new();
+        let step = pid.update(&config, 0.25, DT);
+        assert_close(step.error, 0.25);
+        assert_close(step.output, 0.5);
+    }
+
+    #[test]
+    fn integral_accumulates_error() {
+        let config = PidConfig {
+            ki: 1.0,
+            ..config()
+        };
+        let mut pid = PidController::new();
+        let outputs = [0.05, 0.1, 0.15];
+        for expected in outputs {
+            assert_close(pid.update(&config, 0.0, DT).output, expected);
+        }
+
+        pid.reset();
+        assert_close(pid.update(&config, 0.0, DT).output, 0.05);
+    }
+
+    #[test]
+    fn derivative_on_measurement() {
+        let config = PidConfig {
+            kp: 1.0,
+            kd: 0.1,
+            ..config()
+        };
+        let mut pid = PidController::new();
+        // No derivative kick on the first step.
+        assert_close(pid.update(&config, 0.0, DT).output, 0.5);
+        // Rising at 1/s, which the derivative term counters.
+        assert_close(pid.update(&config, 0.1, DT).output, 0.3);
+
+        // A setpoint change alone does not move the derivative term.
+        let config = PidConfig {
+            setpoint: 0.6,
+            ..config
+        };
+        assert_close(pid.update(&config, 0.1, DT).output, 0.5);
+    }
+
+    #[test]
+    fn output_clamped_to_limits() {
+        let config = PidConfig {
+            kp: 10.0,
+            output_min: 0.2,
+            output_max: 0.8,
+            ..config()
+        };
+        let mut pid = PidController::new();
+        assert_close(pid.update(&config, 0.0, DT).output, 0.8);
+        assert_close(pid.update(&config, 1.0, DT).output, 0.2);
+    }
+
+    /// Output after a long saturated climb, once the measurement gets close to the setpoint.
+    fn output_after_windup(anti_windup: f32) -> f32 {
+        let config = PidConfig {
+            kp: 2.0,
+            ki: 1.0,
+            setpoint: 1.0,
+            anti_windup,
+            ..PidConfig::new()
+        };
+        let mut pid = PidController::new();
+        for _ in 0..50 {
+            assert_close(pid.update(&config, 0.0, DT).output, 1.0);
+        }
+        pid.update(&config, 0.8, DT).output
+    }
+
+    #[test]
+    fn anti_windup() {
+        // The integral stays clamped to the output range without back-calculation...
+        assert_close(output_after_windup(0.0), 1.0);
+        // ...and is unwound while saturated with it, so the output drops right away.
+        assert_close(output_after_windup(10.0), 0.4);
+    }
+
+    #[test]
+    fn validate() {
+        assert_eq!(PidConfig::new().validate(), Ok(()));
+
+        let invalid = [
+            (
+                PidConfig {
+                    kp: f32::NAN,
+                    ..PidConfig::new()
+                },
+                PidError::NotFinite,
+            ),
+            (
+                PidConfig {
+                    ki: f32::INFINITY,
+                    ..PidConfig::new()
+                },
+                PidError::NotFinite,
+            ),
+            (
+                PidConfig {
+                    output_min: 0.5,
+                    output_max: 0.5,
+                    ..PidConfig::new()
+                },
+                PidError::InvalidLimits,
+            ),
+            (
+                PidConfig {
+                    output_min: -0.1,
+                    ..PidConfig::new()
+                },
+                PidError::InvalidLimits,
+            ),
+            (
+                PidConfig {
+                    output_max: 1.5,
+                    ..PidConfig::new()
+                },
+                PidError::InvalidLimits,
+            ),
+            (
+                PidConfig {
+                    rate_hz: 0,
+                    ..PidConfig::new()
+                },
+                PidError::InvalidRate,
+            ),
+            (
+                PidConfig {
+                    rate_hz: MAX_RATE_HZ + 1,
+                    ..PidConfig::new()
+                },
+                PidError::InvalidRate,
+            ),
+            (
+                PidConfig {
+                    anti_windup: -1.0,
+                    ..PidConfig::new()
+                },
+                PidError::NegativeConstant,
+            ),
+            (
+                PidConfig {
+                    measurement_tau: -0.1,
+                    ..PidConfig::new()
+                },
+                PidError::NegativeConstant,
+            ),
+        ];
+        for (config, error) in invalid {
+            assert_eq!(config.validate(), Err(error), "{:?}", config);
+        }
+    }
+}
@@ -0,0 +1,160 @@
+use heapless::Vec;
+use postcard_rpc::{TopicDirection, endpoints, topics};
+use postcard_schema::Schema;
+use serde::{Deserialize, Serialize};
+
+#[cfg(feature = "use-std")]
+use pyo3::prelude::*;
+#[cfg(feature = "use-std")]
+use pyo3_stub_gen::derive::*;
+
+use crate::device::{Alias, AliasResult, AliasSettings, DeviceInfo};
+use crate::metrics::Metrics;
+use crate::update::{UpdateChunk, UpdateInfo, UpdateResult, UpdateStart};
+use crate::utils::LastCrash;
+
+pub const USB_DEVICE_NAME: &'static str = "bluepill-scope";
+
+/// Size of the ring buffer the DMA writes to.
+pub const BUFFER_LEN: usize = 4096;
+/// Part of the ring buffer reserved for samples taken while the capture is being stopped.
+pub const STOP_MARGIN: usize = 1024;
+/// Longest capture that can be requested.
+pub const MAX_LENGTH: usize = BUFFER_LEN - STOP_MARGIN;
+/// Number of samples returned by a single read request.
+pub const CHUNK_SIZE: usize = 256;
+/// Number of ADC inputs, ADC_IN0-ADC_IN7 on PA0-PA7 and ADC_IN8-ADC_IN9 on PB0-PB1.
+pub const CHANNEL_COUNT: u8 = 10;
+pub const ADC_MAX: u16 = 4095;
+pub const ADC_REFERENCE_V: f32 = 3.3;
+
+endpoints! {
+    list = ENDPOINT_LIST;
+    omit_std = true;
+    | EndpointTy                | RequestTy                            | ResponseTy            | Path              |
+    | ----------                | ---------                            | ----------            | ----              |
+    | GetUniqueIdEndpoint       | ()                                   | [u8; 24]              | "unique_id/get"   |
+    | GetCrashReport            | ()                                   | LastCrash             | "crash/get"       |
+    | ClearCrashReport          | ()                                   | ()                    | "crash/clear"     |
+    | ResetEndpoint             | ()                                   | ()                    | "reset"           |
+    | EnterBootloaderEndpoint   | ()                                   | ()                    | "bootloader"      |
+    | GetUpdateInfo             | ()                                   | UpdateInfo            | "update/info"     |
+    | StartUpdate               | UpdateStart                          | UpdateResult          | "update/start"    |
+    | WriteUpdateChunk          | UpdateChunk                          | UpdateResult          | "update/write"    |
+    | FinishUpdate              | ()                                   | UpdateResult          | "update/finish"   |
+    | ConfirmUpdate             | ()                                   | UpdateResult          | "update/confirm"  |
+    | GetMetrics                | ()                                   | Metrics               | "metrics"         |
+    | IdentifyEndpoint          | ()                                   | ()                    | "identify"        |
+    | GetDeviceInfo             | ()                                   | DeviceInfo            | "device/info"     |
+    | GetAlias                  | ()                                   | Alias                 | "alias/get"       |
+    | SetAlias                  | AliasSettings                        | AliasResult           | "alias/set"       |
+    | GetScopeLimits            | ()                                   | ScopeLimits           | "scope/limits"    |
+    | ArmCapture                | CaptureRequest                       | ArmResult             | "scope/arm"       |
+    | AbortCapture              | ()                                   | ()                    | "scope/abort"     |
+    | GetCaptureStatus          | ()                                   | CaptureStatus         | "scope/status"    |
+    | ReadCapture               | u16                                  | ReadResult            | "scope/read"      |
+}
+
+topics! {
+    list = TOPICS_IN_LIST;
+    direction = TopicDirection::ToServer;
+    | TopicTy                   | MessageTy     | Path              |
+    | -------                   | ---------     | ----              |
+}
+
+topics! {
+    list = TOPICS_OUT_LIST;
+    direction = TopicDirection::ToClient;
+    | TopicTy                   | MessageTy     | Path              | Cfg                           |
+    | -------                   | ---------     | ----              | ---                           |
+}
+
+#[cfg_attr(feature = "use-std", gen_stub_pyclass_enum, pyclass(eq, eq_int))]
+#[derive(Serialize, Deserialize, Schema, Debug, Default, PartialEq, Clone, Copy)]
+pub enum ScopeTrigger {
+    /// Trigger as soon as the pre-trigger samples are taken.
+    #[default]
+    Immediate,
+    /// Signal crosses the level going up, after being below `level - hysteresis`.
+    Rising,
+    /// Signal crosses the level going down, after being above `level + hysteresis`.
+    Falling,
+    /// Signal is above the level.
+    Above,
+    /// Signal is below the level.
+    Below,
+}
+
+#[derive(Serialize, Deserialize, Schema, Debug, PartialEq, Clone, Copy)]
+pub struct CaptureRequest {
+    pub channel: u8,
+    pub sample_rate_hz: u32,
+    /// Total number of samples, including the pre-trigger ones.
+    pub length: u16,
+    /// Number of samples kept from before the trigger.
+    pub pre_trigger: u16,
+    pub trigger: ScopeTrigger,
+    /// Trigger level in raw ADC counts.
+    pub level: u16,
+    pub hysteresis: u16,
+}
+
+#[cfg_attr(feature = "use-std", gen_stub_pyclass, pyclass(get_all))]
+#[derive(Serialize, Deserialize, Schema, Debug, PartialEq, Clone, Copy)]
+pub struct ScopeLimits {
+    pub max_length: u16,
+    pub chunk_size: u16,
+    pub channels: u8,
+    pub min_sample_rate_hz: u32,
+    pub max_sample_rate_hz: u32,
+}
+
+#[cfg_attr(feature = "use-std", gen_stub_pyclass_enum, pyclass(eq, eq_int))]
+#[derive(Serialize, Deserialize, Schema, Debug, Default, PartialEq, Clone, Copy)]
+pub enum CaptureState {
+    #[default]
+    Idle,
+    /// Filling the pre-trigger samples.
+    Armed,
+    /// Waiting for the trigger condition.
+    Waiting,
+    /// Trigger found, taking the remaining samples.
+    Triggered,
+    /// Capture is complete and can be read.
+    Done,
+}
+
+#[cfg_attr(feature = "use-std", gen_stub_pyclass, pyclass(get_all))]
+#[derive(Serialize, Deserialize, Schema, Debug, Default, PartialEq, Clone, Copy)]
+pub struct CaptureStatus {
+    pub state: CaptureState,
+    /// Sample rate resulting from the timer dividers, can differ from the requested one.
+    pub sample_rate_hz: f32,
+    pub length: u16,
+    /// Index of the first sample meeting the trigger condition.
+    pub trigger_index: u16,
+}
+
+#[derive(Serialize, Deserialize, Schema, Debug, Default, PartialEq, Clone)]
+pub struct CaptureChunk {
+    pub offset: u16,
+    pub samples: Vec<u16, CHUNK_SIZE>,
+}
+
+#[cfg_attr(feature = "use-std", gen_stub_pyclass_enum, pyclass(eq, eq_int))]
+#[derive(Serialize, Deserialize, Schema, Debug, PartialEq, Clone, Copy)]
+pub enum ScopeError {
+    InvalidChannel,
+    InvalidSampleRate,
+    InvalidLength,
+    InvalidLevel,
+    /// A capture is already in progress.
+    Busy,
+    /// No finished capture to read.
+    NotReady,
+    /// Stopping took too long and the start of the capture was overwritten.
+    Overrun,
+}
+
+pub type ArmResult = Result<f32, ScopeError>;
+pub type ReadResult = Result<CaptureChunk, ScopeError>;
@@ -0,0 +1,158 @@
+use heapless::Vec;
+use postcard_rpc::{TopicDirection, endpoints, topics};
+use postcard_schema::Schema;
+use serde::{Deserialize, Serialize};
+
+#[cfg(feature = "use-std")]
+use pyo3::prelude::*;
+#[cfg(feature = "use-std")]
+use pyo3_stub_gen::derive::*;
+
+use crate::device::{Alias, AliasResult, AliasSettings, DeviceInfo};
+use crate::metrics::Metrics;
+use crate::update::{UpdateChunk, UpdateInfo, UpdateResult, UpdateStart};
+use crate::utils::LastCrash;
+
+pub const USB_DEVICE_NAME: &'static str = "bluepill-sequencer";
+
+/// Maximum number of entries the device can store in its timing table.
+pub const MAX_ENTRIES: usize = 1024;
+/// Maximum number of entries transferred in a single upload request.
+pub const CHUNK_SIZE: usize = 32;
+/// Number of digital outputs driven by the sequencer, bit `n` of a state is channel `n`.
+pub const CHANNEL_COUNT: u8 = 8;
+/// Shortest allowed time between two consecutive entries, the playback interrupt
+/// needs some headroom to reload the timer.
+pub const MIN_STEP_US: u32 = 10;
+
+endpoints! {
+    list = ENDPOINT_LIST;
+    omit_std = true;
+    | EndpointTy                | RequestTy                            | ResponseTy            | Path                  |
+    | ----------                | ---------                            | ----------            | ----                  |
+    | GetUniqueIdEndpoint       | ()                                   | [u8; 24]              | "unique_id/get"       |
+    | GetCrashReport            | ()                                   | LastCrash             | "crash/get"           |
+    | ClearCrashReport          | ()                                   | ()                    | "crash/clear"         |
+    | ResetEndpoint             | ()                                   | ()                    | "reset"               |
+    | EnterBootloaderEndpoint   | ()                                   | ()                    | "bootloader"          |
+    | GetUpdateInfo             | ()                                   | UpdateInfo            | "update/info"         |
+    | StartUpdate               | UpdateStart                          | UpdateResult          | "update/start"        |
+    | WriteUpdateChunk          | UpdateChunk                          | UpdateResult          | "update/write"        |
+    | FinishUpdate              | ()                                   | UpdateResult          | "update/finish"       |
+    | ConfirmUpdate             | ()                                   | UpdateResult          | "update/confirm"      |
+    | GetMetrics                | ()                                   | Metrics               | "metrics"             |
+    | IdentifyEndpoint          | ()                                   | ()                    | "identify"            |
+    | GetDeviceInfo             | ()                                   | DeviceInfo            | "device/info"         |
+    | GetAlias                  | ()                                   | Alias                 | "alias/get"           |
+    | SetAlias                  | AliasSettings                        | AliasResult           | "alias/set"           |
+    | GetSequencerLimits        | ()                                   | SequencerLimits       | "sequencer/limits"    |
+    | UploadChunk               | TableChunk                           | SequencerResult       | "sequencer/upload"    |
+    | ArmSequencer              | ArmRequest                           | SequencerResult       | "sequencer/arm"       |
+    | StartSequencer            | ()                                   | SequencerResult       | "sequencer/start"     |
+    | StopSequencer             | ()                                   | ()                    | "sequencer/stop"      |
+    | GetSequencerStatus        | ()                                   | SequencerStatus       | "sequencer/status"    |
+}
+
+topics! {
+    list = TOPICS_IN_LIST;
+    direction = TopicDirection::ToServer;
+    | TopicTy                   | MessageTy     | Path              |
+    | -------                   | ---------     | ----              |
+}
+
+topics! {
+    list = TOPICS_OUT_LIST;
+    direction = TopicDirection::ToClient;
+    | TopicTy                   | MessageTy     | Path              | Cfg                           |
+    | -------                   | ---------     | ----              | ---                           |
+}
+
+/// Single row of the timing table. At `time_us` after the start of the sequence,
+/// the outputs are set to `state`.
+#[derive(Serialize, Deserialize, Schema, Debug, Default, PartialEq, Clone, Copy)]
+pub struct SeqEntry {
+    pub time_us: u32,
+    pub state: u8,
+}
+
+/// Part of the timing table, written starting at entry `offset`.
+#[derive(Serialize, Deserialize, Schema, Debug, Default, PartialEq, Clone)]
+pub struct TableChunk {
+    pub offset: u16,
+    pub entries: Vec<SeqEntry, CHUNK_SIZE>,
+}
+
+#[cfg_attr(feature = "use-std", gen_stub_pyclass_enum, pyclass(eq, eq_int))]
+#[derive(Serialize, Deserialize, Schema, Debug, Default, PartialEq, Clone, Copy)]
+pub enum Trigger {
+    /// Start with the `start` request.
+    #[default]
+    Software,
+    /// Start on the rising edge of the trigger input.
+    RisingEdge,
+    /// Start on the falling edge of the trigger input.
+    FallingEdge,
+}
+
+#[derive(Serialize, Deserialize, Schema, Debug, PartialEq, Clone, Copy)]
+pub struct ArmRequest {
+    /// Number of uploaded entries to play.
+    pub length: u16,
+    /// Time after which the sequence starts over, must be later than the last entry.
+    pub period_us: u32,
+    /// Number of times the sequence is played, 0 repeats until stopped.
+    pub repeat: u32,
+    pub trigger: Trigger,
+    /// Output state applied when arming, before the first entry is reached.
+    pub idle_state: u8,
+}
+
+#[cfg_attr(feature = "use-std", gen_stub_pyclass_enum, pyclass(eq, eq_int))]
+#[derive(Serialize, Deserialize, Schema, Debug, Default, PartialEq, Clone, Copy)]
+pub enum SequencerState {
+    #[default]
+    Idle,
+    Armed,
+    Running,
+    Done,
+}
+
+#[cfg_attr(feature = "use-std", gen_stub_pyclass, pyclass(get_all))]
+#[derive(Serialize, Deserialize, Schema, Debug, Default, PartialEq, Clone, Copy)]
+pub struct SequencerStatus {
+    pub state: SequencerState,
+    /// Number of entries uploaded so far.
+    pub loaded: u16,
+    /// Number of finished passes through the table.
+    pub iteration: u32,
+    /// Index of the next entry to be played.
+    pub next_entry: u16,
+}
+
+#[cfg_attr(feature = "use-std", gen_stub_pyclass, pyclass(get_all))]
+#[derive(Serialize, Deserialize, Schema, Debug, PartialEq, Clone, Copy)]
+pub struct SequencerLimits {
+    pub max_entries: u16,
+    pub chunk_size: u16,
+    pub channels: u8,
+    /// Timer resolution in microseconds.
+    pub resolution_us: u32,
+    pub min_step_us: u32,
+}
+
+#[cfg_attr(feature = "use-std", gen_stub_pyclass_enum, pyclass(eq, eq_int))]
+#[derive(Serialize, Deserialize, Schema, Debug, PartialEq, Clone, Copy)]
+pub enum SequencerError {
+    /// The table cannot be modified or armed while playing.
+    Busy,
+    /// Entries would be written past the end of the table.
+    OutOfRange,
+    /// Entry times are not increasing by at least `MIN_STEP_US`.
+    InvalidTiming,
+    /// Arm requested with no entries.
+    Empty,
+    /// Start requested without arming for a software trigger.
+    NotArmed,
+}
+
+pub type SequencerResult = Result<(), SequencerError>;
@@ -7,7 +7,10 @@ use pyo3::prelude::*;
 #[cfg(feature = "use-std")]
 use pyo3_stub_gen::derive::*;
 
-use crate::utils::PwmChannel;
+use crate::device::{Alias, AliasResult, AliasSettings, DeviceInfo};
+use crate::metrics::Metrics;
+use crate::update::{UpdateChunk, UpdateInfo, UpdateResult, UpdateStart};
+use crate::utils::{LastCrash, PwmChannel};
 
 pub const USB_DEVICE_NAME: &'static str = "bluepill-servo";
 
@@ -17,9 +20,26 @@ endpoints! {
     | EndpointTy                | RequestTy                            | ResponseTy            | Path              |
     | ----------                | ---------                            | ----------            | ----              |
     | GetUniqueIdEndpoint       | ()                                   | [u8; 24]              | "unique_id/get"   |
-    | ConfigureChannel          | (PwmChannel, ServoChannelConfig)     | ()                    | "servo/channel"   |
+    | GetCrashReport            | ()                                   | LastCrash             | "crash/get"       |
+    | ClearCrashReport          | ()                                   | ()                    | "crash/clear"     |
+    | ResetEndpoint             | ()                                   | ()                    | "reset"           |
+    | EnterBootloaderEndpoint   | ()                                   | ()                    | "bootloader"      |
+    | GetUpdateInfo             | ()                                   | UpdateInfo            | "update/info"     |
+    | StartUpdate               | UpdateStart                          | UpdateResult          | "update/start"    |
+    | WriteUpdateChunk          | UpdateChunk                          | UpdateResult          | "update/write"    |
+    | FinishUpdate              | ()                                   | UpdateResult          | "update/finish"   |
+    | ConfirmUpdate             | ()                                   | UpdateResult          | "update/confirm"  |
+    | GetMetrics                | ()                                   | Metrics               | "metrics"         |
+    | IdentifyEndpoint          | ()                                   | ()                    | "identify"        |
+    | GetDeviceInfo             | ()                                   | DeviceInfo            | "device/info"     |
+    | GetAlias                  | ()                                   | Alias                 | "alias/get"       |
+    | SetAlias                  | AliasSettings                        | AliasResult           | "alias/set"       |
+    | ConfigureChannel          | (PwmChannel, ServoChannelConfig)     | ServoChannelConfig    | "servo/channel"   |
     | GetServoConfig            | ()                                   | ServoConfig           | "servo/config"    |
     | SetFrequencyEndpoint      | u32                                  | ()                    | "servo/frequency" |
+    | SetFailsafeTimeout        | u32                                  | ()                    | "servo/failsafe"  |
+    | GetServoFeedback          | PwmChannel                           | ServoFeedback         | "servo/feedback"  |
+    | SetCurrentConfig          | CurrentConfig                        | ()                    | "servo/current"   |
 }
 
 topics! {
@@ -34,15 +54,208 @@ topics! {
     direction = TopicDirection::ToClient;
     | TopicTy                   | MessageTy     | Path              | Cfg                           |
     | -------                   | ---------     | ----              | ---                           |
+    | ServoCurrentTopic         | ServoCurrent  | "servo/current"   |                               |
+    | ServoStallTopic           | ServoStall    | "servo/stall"     |                               |
 }
 
-#[cfg_attr(feature = "use-std", gen_stub_pyclass, pyclass(get_all, set_all))]
+#[cfg_attr(feature = "use-std", gen_stub_pyclass_enum, pyclass(eq, eq_int))]
 #[derive(Serialize, Deserialize, Schema, Debug, Default, PartialEq, Clone, Copy)]
+pub enum ServoMode {
+    /// The pulse width sets the angle.
+    #[default]
+    Positional,
+    /// The pulse width sets the speed, the servo stops at `neutral_duty_cycle`. Stopped by
+    /// the firmware when the host is lost, see `ServoConfig::failsafe_timeout_ms`.
+    Continuous,
+}
+
+/// Full scale of the 12-bit feedback ADC.
+pub const FEEDBACK_ADC_MAX: u16 = 4095;
+
+/// `current_duty_cycle` is the commanded pulse. The firmware keeps it within the soft limits
+/// and drives the pin with `output_duty_cycle`, which applies the trim and the inversion.
+#[cfg_attr(feature = "use-std", gen_stub_pyclass, pyclass(get_all, set_all))]
+#[derive(Serialize, Deserialize, Schema, Debug, PartialEq, Clone, Copy)]
 pub struct ServoChannelConfig {
     pub min_angle_duty_cycle: u16,
     pub max_angle_duty_cycle: u16,
     pub current_duty_cycle: u16,
     pub enabled: bool,
+    /// Angles in degrees at `min_angle_duty_cycle` and `max_angle_duty_cycle`, such as
+    /// 0 and 270, or -90 and 90.
+    pub range_start: f32,
+    pub range_end: f32,
+    /// Degrees per duty cycle step, filled in by the firmware.
+    pub angle_step: f32,
+    /// Mirror the servo, so `range_start` drives it to `max_angle_duty_cycle`. Continuous
+    /// servos turn the other way.
+    pub inverted: bool,
+    /// Degrees added to the commanded angle, for horns mounted off center.
+    pub center_trim: f32,
+    /// Soft limits of the commanded angle in degrees, before the trim and inversion.
+    pub min_angle: f32,
+    pub max_angle: f32,
+    pub mode: ServoMode,
+    /// Pulse at which a continuous servo stands still.
+    pub neutral_duty_cycle: u16,
+    /// Duty cycles this close to neutral do not turn a continuous servo, the slowest
+    /// speed starts past them.
+    pub dead_band: u16,
+    /// Sample the potentiometer of the servo on the feedback pin of the channel.
+    pub feedback: bool,
+    /// Feedback ADC readings at `range_start` and `range_end`, fitted by the calibration.
+    pub feedback_start: f32,
+    pub feedback_end: f32,
+}
+
+impl Default for ServoChannelConfig {
+    fn default() -> Self {
+        Self {
+            min_angle_duty_cycle: 0,
+            max_angle_duty_cycle: 0,
+            current_duty_cycle: 0,
+            enabled: false,
+            range_start: 0.0,
+            range_end: 180.0,
+            angle_step: 0.0,
+            inverted: false,
+            center_trim: 0.0,
+            min_angle: 0.0,
+            max_angle: 180.0,
+            mode: ServoMode::Positional,
+            neutral_duty_cycle: 0,
+            dead_band: 0,
+            feedback: false,
+            feedback_start: 0.0,
+            feedback_end: FEEDBACK_ADC_MAX as f32,
+        }
+    }
+}
+
+impl ServoChannelConfig {
+    fn range_span(&self) -> f32 {
+        self.range_end - self.range_start
+    }
+
+    /// Duty cycle of the angle in degrees, clamped to the travel of the servo.
+    pub fn angle_to_duty_cycle(&self, angle: f32) -> u16 {
+        let min = self.min_angle_duty_cycle as f32;
+        let max = self.max_angle_duty_cycle as f32;
+        if self.range_span() <= 0.0 {
+            return self.min_angle_duty_cycle;
+        }
+        let fraction = ((angle - self.range_start) / self.range_span()).clamp(0.0, 1.0);
+        // No `f32::round` without std, the duty cycle is never negative.
+        (min + fraction * (max - min) + 0.5) as u16
+    }
+
+    /// Angle in degrees of the duty cycle, clamped to the travel of the servo.
+    pub fn duty_cycle_to_angle(&self, duty_cycle: u16) -> f32 {
+        if self.max_angle_duty_cycle <= self.min_angle_duty_cycle {
+            return self.range_start;
+        }
+        let min = self.min_angle_duty_cycle as f32;
+        let max = self.max_angle_duty_cycle as f32;
+        let fraction = ((duty_cycle as f32 - min) / (max - min)).clamp(0.0, 1.0);
+        self.range_start + fraction * self.range_span()
+    }
+
+    /// Angle in degrees of a feedback ADC reading, past the range if the servo is pushed
+    /// beyond it.
+    pub fn feedback_to_angle(&self, raw: u16) -> f32 {
+        let span = self.feedback_end - self.feedback_start;
+        if span == 0.0 {
+            return self.range_start;
+        }
+        self.range_start + (raw as f32 - self.feedback_start) / span * self.range_span()
+    }
+
+    /// Smallest change of the angle in degrees, one duty cycle step.
+    pub fn quantization_step(&self) -> f32 {
+        let steps = self
+            .max_angle_duty_cycle
+            .saturating_sub(self.min_angle_duty_cycle);
+        if steps == 0 {
+            return 0.0;
+        }
+        self.range_span() / steps as f32
+    }
+
+    /// Duty cycle of a continuous servo turning at `speed`, from -1 to 1. Full speed is at
+    /// `min_angle_duty_cycle` and `max_angle_duty_cycle`.
+    pub fn speed_to_duty_cycle(&self, speed: f32) -> u16 {
+        let neutral = self.neutral_duty_cycle as f32;
+        let dead_band = self.dead_band as f32;
+        let speed = speed.clamp(-1.0, 1.0);
+        let duty_cycle = if speed > 0.0 {
+            let span = (self.max_angle_duty_cycle as f32 - neutral - dead_band).max(0.0);
+            neutral + dead_band + speed * span
+        } else if speed < 0.0 {
+            let span = (neutral - dead_band - self.min_angle_duty_cycle as f32).max(0.0);
+            neutral - dead_band + speed * span
+        } else {
+            neutral
+        };
+        (duty_cycle.max(0.0) + 0.5) as u16
+    }
+
+    /// Speed of a continuous servo at the duty cycle, 0 within the dead band.
+    pub fn duty_cycle_to_speed(&self, duty_cycle: u16) -> f32 {
+        let offset = duty_cycle as f32 - self.neutral_duty_cycle as f32;
+        let dead_band = self.dead_band as f32;
+        if offset.abs() <= dead_band {
+            return 0.0;
+        }
+        let span = if offset > 0.0 {
+            self.max_angle_duty_cycle as f32 - self.neutral_duty_cycle as f32 - dead_band
+        } else {
+            self.neutral_duty_cycle as f32 - dead_band - self.min_angle_duty_cycle as f32
+        };
+        if span <= 0.0 {
+            return 0.0;
+        }
+        ((offset.abs() - dead_band) / span * offset.signum()).clamp(-1.0, 1.0)
+    }
+
+    /// Commanded duty cycle within the soft limits, or the travel of a continuous servo.
+    /// A zero duty cycle, no pulses, is kept.
+    pub fn limited_duty_cycle(&self) -> u16 {
+        if self.current_duty_cycle == 0 {
+            return 0;
+        }
+        let (min, max) = match self.mode {
+            ServoMode::Positional => (
+                self.angle_to_duty_cycle(self.min_angle),
+                self.angle_to_duty_cycle(self.max_angle),
+            ),
+            ServoMode::Continuous => (self.min_angle_duty_cycle, self.max_angle_duty_cycle),
+        };
+        self.current_duty_cycle.clamp(min, max.max(min))
+    }
+
+    /// Duty cycle driven on the pin: the limited commanded angle with the trim and the
+    /// inversion applied. Continuous servos are mirrored around the neutral pulse.
+    pub fn output_duty_cycle(&self) -> u16 {
+        let duty_cycle = self.limited_duty_cycle();
+        if duty_cycle == 0 {
+            return 0;
+        }
+        match self.mode {
+            ServoMode::Positional => {
+                let angle = self.duty_cycle_to_angle(duty_cycle) + self.center_trim;
+                let angle = if self.inverted {
+                    self.range_start + self.range_end - angle
+                } else {
+                    angle
+                };
+                self.angle_to_duty_cycle(angle)
+            }
+            ServoMode::Continuous if self.inverted => {
+                self.speed_to_duty_cycle(-self.duty_cycle_to_speed(duty_cycle))
+            }
+            ServoMode::Continuous => duty_cycle,
+        }
+    }
 }
 
 #[cfg_attr(feature = "use-std", gen_stub_pyclass, pyclass(get_all, set_all))]
@@ -51,4 +264,237 @@ pub struct ServoConfig {
     pub servo_frequency: u32,
     pub max_duty_cycle: u16,
     pub channels: [ServoChannelConfig; 4],
+    /// Continuous servos are stopped when no request came for this long, 0 only stops them
+    /// when USB is disconnected or reset.
+    pub failsafe_timeout_ms: u32,
+    pub current: CurrentConfig,
+}
+
+#[cfg_attr(feature = "use-std", gen_stub_pyclass_enum, pyclass(eq, eq_int))]
+#[derive(Serialize, Deserialize, Schema, Debug, Default, PartialEq, Clone, Copy)]
+pub enum CurrentSense {
+    #[default]
+    Disabled,
+    /// A shunt per channel, on PA2, PA3, PA6 and PA7 for channels 1-4.
+    PerChannel,
+    /// One shunt on the supply rail of all the servos, on PB1.
+    Rail,
+}
+
+#[cfg_attr(feature = "use-std", gen_stub_pyclass_enum, pyclass(eq, eq_int))]
+#[derive(Serialize, Deserialize, Schema, Debug, Default, PartialEq, Clone, Copy)]
+pub enum StallAction {
+    /// Stop the pulses of the stalled channel, it has to be enabled again.
+    #[default]
+    Disable,
+    /// Command a positional servo to its measured angle if it has feedback, otherwise
+    /// `back_off_angle` towards the middle of its soft limits. Continuous servos stop.
+    BackOff,
+}
+
+/// Current sensing of the servos and the stall protection. With `CurrentSense::Rail` a
+/// stall applies to every enabled channel.
+#[cfg_attr(feature = "use-std", gen_stub_pyclass, pyclass(get_all, set_all))]
+#[derive(Serialize, Deserialize, Schema, Debug, PartialEq, Clone, Copy)]
+pub struct CurrentConfig {
+    pub sense: CurrentSense,
+    /// Milliamps per ADC count, from the shunt resistance and the amplifier gain.
+    pub scale_ma: f32,
+    /// ADC reading at zero current, the offset of the amplifier.
+    pub offset: u16,
+    /// Current above which a channel counts as stalled, 0 disables the protection.
+    pub stall_current_ma: f32,
+    /// How long the current has to stay above `stall_current_ma`.
+    pub stall_time_ms: u32,
+    pub stall_action: StallAction,
+    pub back_off_angle: f32,
+    /// Publish the currents every N samples of 10 ms, 0 disables it.
+    pub report_divider: u16,
+}
+
+impl CurrentConfig {
+    pub const fn new() -> Self {
+        Self {
+            sense: CurrentSense::Disabled,
+            scale_ma: 1.0,
+            offset: 0,
+            stall_current_ma: 0.0,
+            stall_time_ms: 500,
+            stall_action: StallAction::Disable,
+            back_off_angle: 5.0,
+            report_divider: 10,
+        }
+    }
+
+    /// Current in milliamps of an ADC reading.
+    pub fn to_ma(&self, raw: u16) -> f32 {
+        (raw as f32 - self.offset as f32) * self.scale_ma
+    }
+}
+
+impl Default for CurrentConfig {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Commanded and measured position of a channel.
+#[cfg_attr(feature = "use-std", gen_stub_pyclass, pyclass(get_all))]
+#[derive(Serialize, Deserialize, Schema, Debug, PartialEq, Clone, Copy)]
+pub struct ServoFeedback {
+    pub commanded_angle: f32,
+    /// None if the feedback of the channel is disabled.
+    pub measured_angle: Option<f32>,
+    /// Averaged feedback ADC reading.
+    pub raw: Option<u16>,
+}
+
+/// Currents of the servos in milliamps, depending on `CurrentConfig::sense`.
+#[cfg_attr(feature = "use-std", gen_stub_pyclass, pyclass(get_all))]
+#[derive(Serialize, Deserialize, Schema, Debug, PartialEq, Clone, Copy)]
+pub struct ServoCurrent {
+    pub channels_ma: Option<[f32; 4]>,
+    pub rail_ma: Option<f32>,
+}
+
+/// A stall detected by the firmware, after it took the `action`.
+#[cfg_attr(feature = "use-std", gen_stub_pyclass, pyclass(get_all))]
+#[derive(Serialize, Deserialize, Schema, Debug, PartialEq, Clone, Copy)]
+pub struct ServoStall {
+    /// Channels 1-4 the action was taken on.
+    pub channels: [bool; 4],
+    pub current_ma: f32,
+    pub action: StallAction,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Positional servo over 0-180 degrees from 1000 to 2000, limited to 30-150 degrees.
+    fn channel(current_duty_cycle: u16) -> ServoChannelConfig {
+        ServoChannelConfig {
+            min_angle_duty_cycle: 1000,
+            max_angle_duty_cycle: 2000,
+            current_duty_cycle,
+            enabled: true,
+            min_angle: 30.0,
+            max_angle: 150.0,
+            ..ServoChannelConfig::default()
+        }
+    }
+
+    fn continuous(current_duty_cycle: u16) -> ServoChannelConfig {
+        ServoChannelConfig {
+            mode: ServoMode::Continuous,
+            neutral_duty_cycle: 1500,
+            dead_band: 20,
+            ..channel(current_duty_cycle)
+        }
+    }
+
+    /// Duty cycles computed through angles may round either way.
+    fn assert_near(actual: u16, expected: u16) {
+        assert!(
+            actual.abs_diff(expected) <= 1,
+            "{} is not {}",
+            actual,
+            expected
+        );
+    }
+
+    #[test]
+    fn limits_positional_to_the_soft_limits() {
+        assert_eq!(channel(1500).limited_duty_cycle(), 1500);
+        assert_eq!(channel(1100).limited_duty_cycle(), 1167);
+        assert_eq!(channel(1900).limited_duty_cycle(), 1833);
+    }
+
+    #[test]
+    fn limits_duty_cycles_outside_the_travel() {
+        assert_eq!(channel(500).limited_duty_cycle(), 1167);
+        assert_eq!(channel(2500).limited_duty_cycle(), 1833);
+        assert_eq!(channel(500).output_duty_cycle(), 1167);
+        assert_eq!(channel(2500).output_duty_cycle(), 1833);
+    }
+
+    #[test]
+    fn keeps_zero_duty_cycle() {
+        let inverted = ServoChannelConfig {
+            inverted: true,
+            center_trim: 10.0,
+            ..channel(0)
+        };
+        assert_eq!(inverted.limited_duty_cycle(), 0);
+        assert_eq!(inverted.output_duty_cycle(), 0);
+        assert_eq!(continuous(0).output_duty_cycle(), 0);
+    }
+
+    #[test]
+    fn inverts_at_both_limits() {
+        let inverted = |current| ServoChannelConfig {
+            inverted: true,
+            ..channel(current)
+        };
+        assert_near(inverted(1000).output_duty_cycle(), 1833);
+        assert_near(inverted(2000).output_duty_cycle(), 1167);
+        assert_near(inverted(1500).output_duty_cycle(), 1500);
+    }
+
+    #[test]
+    fn trims_at_both_limits() {
+        let trimmed = |current| ServoChannelConfig {
+            center_trim: 10.0,
+            ..channel(current)
+        };
+        assert_near(trimmed(1000).output_duty_cycle(), 1223);
+        assert_near(trimmed(2000).output_duty_cycle(), 1889);
+    }
+
+    #[test]
+    fn trims_then_inverts_at_both_limits() {
+        let trimmed = |current| ServoChannelConfig {
+            inverted: true,
+            center_trim: 10.0,
+            ..channel(current)
+        };
+        assert_near(trimmed(1000).output_duty_cycle(), 1777);
+        assert_near(trimmed(2000).output_duty_cycle(), 1111);
+    }
+
+    #[test]
+    fn trim_stays_within_the_travel() {
+        let trimmed = ServoChannelConfig {
+            max_angle: 180.0,
+            center_trim: 10.0,
+            ..channel(2000)
+        };
+        assert_eq!(trimmed.output_duty_cycle(), 2000);
+        let inverted = ServoChannelConfig {
+            inverted: true,
+            ..trimmed
+        };
+        assert_eq!(inverted.output_duty_cycle(), 1000);
+    }
+
+    #[test]
+    fn limits_continuous_to_the_travel() {
+        assert_eq!(continuous(1900).limited_duty_cycle(), 1900);
+        assert_eq!(continuous(500).limited_duty_cycle(), 1000);
+        assert_eq!(continuous(2500).limited_duty_cycle(), 2000);
+        assert_eq!(continuous(1900).output_duty_cycle(), 1900);
+    }
+
+    #[test]
+    fn inverts_continuous_around_neutral() {
+        let inverted = |current| ServoChannelConfig {
+            inverted: true,
+            ..continuous(current)
+        };
+        assert_eq!(inverted(2000).output_duty_cycle(), 1000);
+        assert_eq!(inverted(1000).output_duty_cycle(), 2000);
+        assert_eq!(inverted(2500).output_duty_cycle(), 1000);
+        assert_eq!(inverted(1600).output_duty_cycle(), 1400);
+        assert_eq!(inverted(1510).output_duty_cycle(), 1500);
+    }
 }
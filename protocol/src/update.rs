@@ -0,0 +1,78 @@
+use heapless::Vec;
+use postcard_schema::Schema;
+use serde::{Deserialize, Serialize};
+
+use crate::board::Board;
+
+/// Bytes of the image sent in one `UpdateChunk`.
+pub const UPDATE_CHUNK_SIZE: usize = 256;
+
+/// Images are padded with erased flash to a multiple of this, the largest flash write size.
+pub const UPDATE_ALIGNMENT: usize = 4;
+
+#[derive(Serialize, Deserialize, Schema, Debug, PartialEq, Clone, Copy)]
+pub enum FirmwareState {
+    /// The running image is confirmed and stays after a reset.
+    Confirmed,
+    /// The running image was just swapped in, without a confirmation the bootloader
+    /// reverts to the previous one on the next reset.
+    Unconfirmed,
+}
+
+#[derive(Serialize, Deserialize, Schema, Debug, PartialEq, Clone, Copy)]
+pub struct UpdateInfo {
+    pub board: Board,
+    /// Whether the firmware was built to run behind the bootloader.
+    pub supported: bool,
+    /// Largest image that fits the active partition.
+    pub max_size: u32,
+    pub state: FirmwareState,
+}
+
+#[derive(Serialize, Deserialize, Schema, Debug, PartialEq, Clone, Copy)]
+pub struct UpdateStart {
+    /// Size of the image, a multiple of `UPDATE_ALIGNMENT`.
+    pub size: u32,
+    /// `crc32` of the whole image, checked before it is marked for the swap.
+    pub crc: u32,
+}
+
+#[derive(Serialize, Deserialize, Schema, Debug, PartialEq, Clone)]
+pub struct UpdateChunk {
+    /// Position in the image, chunks have to be sent in order.
+    pub offset: u32,
+    pub data: Vec<u8, UPDATE_CHUNK_SIZE>,
+}
+
+#[derive(Serialize, Deserialize, Schema, Debug, PartialEq, Clone, Copy)]
+pub enum UpdateError {
+    /// The firmware does not run behind the bootloader.
+    Unsupported,
+    /// The image does not fit the active partition or is not aligned.
+    InvalidSize,
+    /// No update was started, or the whole image was not written yet.
+    NotStarted,
+    /// The chunk does not continue where the previous one ended.
+    InvalidOffset,
+    /// The running image has to be confirmed before the next update.
+    Unconfirmed,
+    Flash,
+    CrcMismatch {
+        expected: u32,
+        actual: u32,
+    },
+}
+
+pub type UpdateResult = Result<(), UpdateError>;
+
+/// CRC-32 (IEEE 802.3) of the data, continuing from `crc`. Start with 0.
+pub fn crc32(crc: u32, data: &[u8]) -> u32 {
+    let mut crc = !crc;
+    for byte in data {
+        crc ^= *byte as u32;
+        for _ in 0..8 {
+            crc = (crc >> 1) ^ (0xedb8_8320 & (crc & 1).wrapping_neg());
+        }
+    }
+    !crc
+}
@@ -1,3 +1,6 @@
+use core::fmt::{Display, Formatter};
+
+use heapless::String;
 use postcard_schema::Schema;
 use serde::{Deserialize, Serialize};
 
@@ -22,3 +25,78 @@ impl TryFrom<u8> for PwmChannel {
         }
     }
 }
+
+/// Level of the log records the firmware publishes on `LoggingTopic`.
+/// Records are sent as "[LEVEL seconds] message", with the time since boot.
+#[derive(Debug, PartialEq, Eq, PartialOrd, Ord, Clone, Copy)]
+pub enum LogLevel {
+    Trace,
+    Debug,
+    Info,
+    Warn,
+    Error,
+}
+
+impl LogLevel {
+    pub const fn as_str(&self) -> &'static str {
+        match self {
+            LogLevel::Trace => "TRACE",
+            LogLevel::Debug => "DEBUG",
+            LogLevel::Info => "INFO",
+            LogLevel::Warn => "WARN",
+            LogLevel::Error => "ERROR",
+        }
+    }
+
+    pub fn parse(level: &str) -> Option<Self> {
+        match level {
+            "TRACE" => Some(LogLevel::Trace),
+            "DEBUG" => Some(LogLevel::Debug),
+            "INFO" => Some(LogLevel::Info),
+            "WARN" => Some(LogLevel::Warn),
+            "ERROR" => Some(LogLevel::Error),
+            _ => None,
+        }
+    }
+}
+
+/// Longest panic message kept in a crash report, longer ones are truncated.
+pub const CRASH_MESSAGE_LEN: usize = 128;
+
+#[derive(Serialize, Deserialize, Schema, Debug, PartialEq, Clone, Copy)]
+pub enum CrashKind {
+    Panic,
+    HardFault,
+}
+
+/// Reason of the last crash, kept in RAM across the reset that followed it.
+#[derive(Serialize, Deserialize, Schema, Debug, PartialEq, Clone)]
+pub struct CrashReport {
+    pub kind: CrashKind,
+    /// Time since boot when the crash happened.
+    pub uptime_ms: u64,
+    /// Panic location and message, empty for a HardFault.
+    pub message: String<CRASH_MESSAGE_LEN>,
+    /// Registers stacked on a HardFault: r0, r1, r2, r3, r12, lr, pc and xpsr.
+    pub registers: [u32; 8],
+}
+
+impl Display for CrashReport {
+    fn fmt(&self, f: &mut Formatter<'_>) -> core::fmt::Result {
+        let seconds = self.uptime_ms as f32 / 1000.0;
+        match self.kind {
+            CrashKind::Panic => write!(f, "panic after {:.3} s at {}", seconds, self.message),
+            CrashKind::HardFault => {
+                let [r0, r1, r2, r3, r12, lr, pc, xpsr] = self.registers;
+                write!(
+                    f,
+                    "HardFault after {:.3} s at pc={:#010x} lr={:#010x} xpsr={:#010x} \
+                    r0={:#010x} r1={:#010x} r2={:#010x} r3={:#010x} r12={:#010x}",
+                    seconds, pc, lr, xpsr, r0, r1, r2, r3, r12
+                )
+            }
+        }
+    }
+}
+
+pub type LastCrash = Option<CrashReport>;
@@ -4,6 +4,7 @@ use std::{
     process::Command,
 };
 
+use protocol::board::Board;
 use s3_utils::{get_bucket, upload_to_s3};
 
 type DynError = Box<dyn std::error::Error>;
@@ -20,8 +21,17 @@ fn try_main() -> Result<(), DynError> {
     let task = env::args().nth(1);
     let args = env::args().skip(2).collect::<Vec<_>>();
 
+    // Firmware linked to run behind the bootloader.
+    let bootloader = args.iter().any(|arg| arg == "--bootloader");
+    let args = args
+        .into_iter()
+        .filter(|arg| arg != "--bootloader")
+        .collect::<Vec<_>>();
+
     match (task.as_deref(), args.as_slice()) {
-        (Some("flash"), [binary]) => flash(binary)?,
+        (Some("flash"), [binary]) => flash(binary, Board::default(), bootloader)?,
+        (Some("flash"), [binary, board]) => flash(binary, parse_board(board)?, bootloader)?,
+        (Some("flash-bootloader"), [board]) => flash_bootloader(parse_board(board)?)?,
         (Some("pygen"), _) => build_bindings()?,
         (Some("publish"), _) => publish()?,
         _ => print_help(),
@@ -33,26 +43,40 @@ fn print_help() {
     eprintln!(
         "Tasks:
 
-flash <name>            flashes the firmware binary to the device
+flash <name> [board] [--bootloader]
+                        flashes the firmware binary to the device, bluepill-f103c8 by default,
+                        with --bootloader it is linked to run behind the bootloader
+flash-bootloader <board>
+                        flashes the bootloader, needed once before firmware linked for it
 pygen                   generates the Python bindings
-publish                 publishes the Python bindings to PyPI
-"
+publish                 builds the firmwares for every board and publishes the Python bindings to PyPI
+
+Boards: {}
+Boards with the bootloader: {}
+",
+        Board::ALL.map(|b| b.feature()).join(", "),
+        Board::ALL
+            .into_iter()
+            .filter(|b| b.flash_layout().is_some())
+            .map(|b| b.feature())
+            .collect::<Vec<_>>()
+            .join(", ")
     )
 }
 
-fn flash(binary: &str) -> Result<(), DynError> {
-    build_firmware(Some(binary))?;
+fn parse_board(name: &str) -> Result<Board, DynError> {
+    Board::from_feature(name).ok_or_else(|| format!("Unknown board: {}", name).into())
+}
+
+fn flash(binary: &str, board: Board, bootloader: bool) -> Result<(), DynError> {
+    build_firmware(Some(binary), board, bootloader)?;
 
-    let target_bin = project_root()
-        .join("target")
-        .join("thumbv7m-none-eabi")
-        .join("release")
-        .join(binary);
+    let target_bin = firmware_dir(board, bootloader).join(binary);
 
     let mut cmd = Command::new("probe-rs");
 
     cmd.arg("run")
-        .arg("--chip=STM32F103C8")
+        .arg(format!("--chip={}", board.chip()))
         .arg("--protocol")
         .arg("swd")
         .arg(target_bin);
@@ -65,7 +89,56 @@ fn flash(binary: &str) -> Result<(), DynError> {
     Ok(())
 }
 
-fn build_firmware(firmware_name: Option<&str>) -> Result<(), DynError> {
+fn flash_bootloader(board: Board) -> Result<(), DynError> {
+    if board.flash_layout().is_none() {
+        return Err(format!("The bootloader is not supported on {}", board.feature()).into());
+    }
+
+    let cargo = env::var("CARGO").unwrap_or_else(|_| "cargo".to_string());
+    let mut cmd = Command::new(cargo);
+
+    cmd.current_dir(project_root())
+        .arg("build")
+        .arg("-p")
+        .arg("bootloader")
+        .arg("--release")
+        .arg("--no-default-features")
+        .arg(format!("--features={}", board.feature()))
+        .arg(format!(
+            "--target-dir=target/{}",
+            target_subdir(board, true).unwrap()
+        ));
+
+    let status = cmd.status()?;
+    if !status.success() {
+        return Err(format!("Failed to build bootloader: {}", status).into());
+    }
+
+    // The bootloader does not log over RTT, so it is only downloaded and started.
+    for task in ["download", "reset"] {
+        let mut cmd = Command::new("probe-rs");
+        cmd.arg(task)
+            .arg(format!("--chip={}", board.chip()))
+            .arg("--protocol")
+            .arg("swd");
+        if task == "download" {
+            cmd.arg(firmware_dir(board, true).join("bootloader"));
+        }
+
+        let status = cmd.status()?;
+        if !status.success() {
+            return Err(format!("Failed to flash the bootloader: {}", status).into());
+        }
+    }
+
+    Ok(())
+}
+
+fn build_firmware(
+    firmware_name: Option<&str>,
+    board: Board,
+    bootloader: bool,
+) -> Result<(), DynError> {
     let cargo = env::var("CARGO").unwrap_or_else(|_| "cargo".to_string());
     let mut cmd = Command::new(cargo);
 
@@ -76,7 +149,16 @@ fn build_firmware(firmware_name: Option<&str>) -> Result<(), DynError> {
         .arg("build")
         .arg("-p")
         .arg("firmware")
-        .arg("--release");
+        .arg("--release")
+        .arg("--no-default-features")
+        .arg(format!("--features={}", board.feature()));
+
+    if bootloader {
+        cmd.arg("--features=bootloader");
+    }
+    if let Some(subdir) = target_subdir(board, bootloader) {
+        cmd.arg(format!("--target-dir=target/{}", subdir));
+    }
 
     if let Some(name) = firmware_name {
         cmd.arg("--bin").arg(name);
@@ -90,8 +172,16 @@ fn build_firmware(firmware_name: Option<&str>) -> Result<(), DynError> {
 }
 
 fn publish() -> Result<(), DynError> {
-    build_firmware(None)?;
-    upload_firmwares()?;
+    for board in Board::ALL {
+        build_firmware(None, board, false)?;
+        upload_firmwares(board, false)?;
+
+        // Images for `update_firmware`, which needs them linked behind the bootloader.
+        if board.flash_layout().is_some() {
+            build_firmware(None, board, true)?;
+            upload_firmwares(board, true)?;
+        }
+    }
     build_stubs()?;
 
     let mut pycmd = pycmd();
@@ -138,11 +228,12 @@ fn build_stubs() -> Result<(), DynError> {
     Ok(())
 }
 
-fn upload_firmwares() -> Result<(), DynError> {
-    let compiled_firmware_dir = project_root()
-        .join("target")
-        .join("thumbv7m-none-eabi")
-        .join("release");
+fn upload_firmwares(board: Board, bootloader: bool) -> Result<(), DynError> {
+    let compiled_firmware_dir = firmware_dir(board, bootloader);
+    let prefix = match bootloader {
+        true => format!("{}-bootloader", board.s3_prefix()),
+        false => board.s3_prefix().to_string(),
+    };
 
     let firmware_bin_dir = project_root().join("firmware").join("src").join("bin");
 
@@ -157,18 +248,40 @@ fn upload_firmwares() -> Result<(), DynError> {
                 .map(String::from)
         })
         .flatten()
+        // Some binaries are not built for every board.
+        .filter(|name| compiled_firmware_dir.join(name).exists())
     {
         upload_to_s3(
             bucket.clone(),
             &compiled_firmware_dir,
             &firmware_name,
-            "stm32f103c8",
+            &prefix,
         )?;
     }
 
     Ok(())
 }
 
+/// Separate target directories, so binaries of one board are never mistaken for another,
+/// nor the ones linked behind the bootloader for the regular ones.
+fn target_subdir(board: Board, bootloader: bool) -> Option<String> {
+    match (board == Board::default(), bootloader) {
+        (_, true) => Some(format!("{}-bootloader", board.feature())),
+        (true, false) => None,
+        (false, false) => Some(board.feature().to_string()),
+    }
+}
+
+/// Output directory of the release firmware binaries built for the board.
+fn firmware_dir(board: Board, bootloader: bool) -> PathBuf {
+    let mut target_dir = project_root().join("target");
+    if let Some(subdir) = target_subdir(board, bootloader) {
+        target_dir = target_dir.join(subdir);
+    }
+    // Cortex-M3 code, which also runs on the Cortex-M4 of the Black Pill.
+    target_dir.join("thumbv7m-none-eabi").join("release")
+}
+
 pub fn pycmd() -> Command {
     let mut cmd = Command::new("uv");
     cmd.current_dir(project_root().join("host"));